@@ -0,0 +1,63 @@
+//! Throughput of the sharded store with a growing number of threads.
+//!
+//! Run with `cargo bench --bench store`. Every thread does the same mix of
+//! reads and writes on keys spread over the whole store, once with a single
+//! shard (one lock for everything, like the store used to be) and once with
+//! more shards.
+
+use skv::crypto::generate_key;
+use skv::store::ShardedStore;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+/// Keys in the store before the benchmark starts.
+const KEYS: usize = 256;
+/// Operations every thread does.
+const OPERATIONS: usize = 2_000;
+/// Every this many operations is a write, the rest are reads.
+const WRITE_EVERY: usize = 5;
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{} core(s) available", cores);
+    println!("{:>8} {:>8} {:>14}", "shards", "threads", "ops/s");
+
+    for shards in [1, 16, 64] {
+        let mut threads = 1;
+        while threads <= cores.max(1) * 2 {
+            let ops_per_sec = run(shards, threads);
+            println!("{:>8} {:>8} {:>14.0}", shards, threads, ops_per_sec);
+            threads *= 2;
+        }
+    }
+}
+
+fn run(shards: usize, threads: usize) -> f64 {
+    let store = Arc::new(ShardedStore::new(Arc::new(generate_key()), shards));
+    for key in 0..KEYS {
+        store.insert(&format!("key{}", key), "value").unwrap();
+    }
+
+    let started = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|thread| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                for op in 0..OPERATIONS {
+                    let key = format!("key{}", (thread * 31 + op * 7) % KEYS);
+                    if op % WRITE_EVERY == 0 {
+                        store.insert(&key, "new value").unwrap();
+                    } else {
+                        assert!(store.contains_key(&key));
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    (threads * OPERATIONS) as f64 / started.elapsed().as_secs_f64()
+}
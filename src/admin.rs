@@ -0,0 +1,1820 @@
+use crate::backup::{self, ArchiveKey, ArchiveWriter, RestoreMode};
+use crate::connection::{
+    in_namespaces, json_error, parse_body_from_request,
+    parse_header_from_request, parse_key_from_request, split_query,
+    RequestType,
+};
+use crate::crypto::SecretKey;
+use crate::history::{unix_secs, Retention};
+use crate::index::{IndexError, IndexValue};
+use crate::lockout::LockoutTracker;
+use crate::metrics::Metrics;
+use crate::pubsub::{self, Broker};
+use crate::router::{Pattern, RouteError, Router};
+use crate::store::{ShardedStore, SharedSnapshot};
+use crate::stream::{BodyStream, EventStream};
+use crate::transfer::OnExisting;
+use crate::watch::Filter;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// Path every admin endpoint starts with.
+pub const ADMIN_PREFIX: &str = "admin/";
+/// Seconds a snapshot pinned with `PUT /admin/snapshots` is kept by default.
+const DEFAULT_SNAPSHOT_TTL: u64 = 60;
+/// Longest a snapshot can be pinned for, in seconds. Pinned snapshots keep
+/// every value that was overwritten since in memory.
+const MAX_SNAPSHOT_TTL: u64 = 60 * 60;
+
+/// Handle a request to one of the `/admin/` endpoints. Responses have a JSON
+/// body, except for backups and exports, which are streamed, and watches
+/// and subscriptions, which send server-sent events. Errors if there is no
+/// endpoint for the path and method.
+///
+/// Admin requests must be authenticated, whatever the HTTP method, see
+/// [`crate::auth::authenticate`].
+///
+/// - `GET /admin/lockouts` lists clients and keys with failed authentication
+///   attempts.
+/// - `DELETE /admin/lockouts` clears all lockouts.
+/// - `DELETE /admin/lockouts/<subject>` clears the lockout of a single
+///   subject, e.g. `ip:127.0.0.1`.
+/// - `GET /admin/backup?namespace=<ns>,...` streams an encrypted archive of
+///   the store, see [`BackupStream`]. The archive is encrypted with the
+///   `passphrase` header if there is one, and the store's key otherwise.
+/// - `PUT /admin/restore?mode=<merge|replace>&namespace=<ns>,...` restores
+///   the archive in the request body, or with `&path=<path>` the archive in
+///   a file on the server.
+/// - `GET /admin/export?prefix=<prefix>` streams the plaintext entries whose
+///   keys start with `prefix` as NDJSON, one `{"key", "value"}` object per
+///   line, see [`ExportStream`].
+/// - `PUT /admin/import?existing=<overwrite|skip>&dry_run=<true|false>`
+///   inserts the NDJSON entries in the request body and reports per line
+///   what happened. With `dry_run=true` nothing is written.
+/// - `GET /admin/metrics` returns the server's counters, see [`Metrics`].
+/// - `GET /admin/watch?key=<key>` or `?prefix=<prefix>` streams the changes
+///   to a key, or to every key with a prefix, as server-sent events.
+///   `&since=<revision>` resumes after a revision.
+/// - `GET /admin/subscribe?channel=<name>,...&pattern=<pattern>,...` streams
+///   the messages published to channels as server-sent events, see
+///   [`Broker`].
+/// - `POST /admin/publish?channel=<name>` publishes the request body to a
+///   channel.
+/// - `GET /admin/snapshots` returns the latest store version and the pinned
+///   ones.
+/// - `PUT /admin/snapshots?ttl=<seconds>` pins the latest version, so that
+///   it can be read with `?as_of=<version>` until it is released or the ttl
+///   runs out.
+/// - `DELETE /admin/snapshots/<version>` releases a pinned version.
+/// - `GET /admin/history/<key>` lists the values of the key that are still
+///   kept, newest first, with the version and time they were written in.
+/// - `GET /admin/history/<key>?version=<version>` returns one of them.
+/// - `PUT /admin/history/<key>?version=<version>` writes it again as the
+///   latest value.
+/// - `GET /admin/retention` returns how many old values are kept in each
+///   namespace.
+/// - `PUT /admin/retention?namespace=<ns>&versions=<n>&max_age=<seconds>`
+///   sets the retention of a namespace, `DELETE` resets it to the default.
+///   `&trash=<seconds>` sets how long its deleted keys stay in the trash.
+/// - `GET /admin/trash?namespace=<ns>,...` lists the deleted keys that can
+///   still be restored.
+/// - `PUT /admin/trash/<key>` restores a deleted key with its last value.
+/// - `DELETE /admin/trash/<key>` purges a deleted key for good, and
+///   `DELETE /admin/trash?namespace=<ns>,...` every one of them.
+/// - `GET /admin/indexes` lists the secondary indexes.
+/// - `PUT /admin/indexes?namespace=<ns>&field=<pointer>` indexes a field of
+///   the JSON values in a namespace, `DELETE` drops the index.
+/// - `PUT /admin/indexes/rebuild?namespace=<ns>&field=<pointer>` rebuilds an
+///   index while writes go on.
+/// - `GET /admin/indexes/query?namespace=<ns>&field=<pointer>&eq=<value>`
+///   returns the keys whose field has the value. `min`/`gt` and `max`/`lt`
+///   query a range instead.
+pub fn handle_admin_request(
+    buf: &[u8],
+    request_type: &RequestType,
+    lockouts: &Mutex<LockoutTracker>,
+    key_value_store: &Arc<ShardedStore>,
+    encryption_key: &Arc<SecretKey>,
+    metrics: &Metrics,
+    broker: &Arc<Broker>,
+) -> Result<AdminResponse, RouteError> {
+    let path = match parse_key_from_request(buf) {
+        Ok(path) => path,
+        Err(_) => {
+            return Ok(AdminResponse::Json((
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error("Admin endpoint not provided!"),
+            )))
+        }
+    };
+    let (endpoint, query) = split_query(path.trim_start_matches(ADMIN_PREFIX));
+    let route = router().find(request_type, endpoint)?;
+    let request = AdminRequest {
+        buf,
+        param: route.param,
+        query,
+        lockouts,
+        key_value_store,
+        encryption_key,
+        metrics,
+        broker,
+    };
+
+    Ok(match route.handler {
+        Handler::Json(handler) => AdminResponse::Json(handler(&request)),
+        Handler::Stream(handler) => handler(&request),
+    })
+}
+
+/// The response to an admin request.
+pub enum AdminResponse {
+    /// A status line and JSON body.
+    Json((String, String)),
+    /// A 200 response with a body of `content_type` that is sent as it is
+    /// produced.
+    Stream {
+        content_type: &'static str,
+        body: Box<dyn BodyStream>,
+    },
+    /// A 200 response that sends server-sent events from `events`, with
+    /// extra `headers`.
+    Events {
+        events: Box<dyn EventStream>,
+        headers: Vec<(&'static str, String)>,
+    },
+}
+
+/// Methods the admin endpoint at `path` supports, e.g. `admin/metrics`.
+pub fn allowed_methods(path: &str) -> Vec<&'static str> {
+    let (endpoint, _) = split_query(path.trim_start_matches(ADMIN_PREFIX));
+    router().allowed(endpoint)
+}
+
+/// An admin request, with everything its handler may need.
+struct AdminRequest<'a> {
+    buf: &'a [u8],
+    /// The rest of the path after the endpoint, e.g. the key of
+    /// `history/<key>`. See [`Pattern::Prefix`].
+    param: &'a str,
+    query: HashMap<String, String>,
+    lockouts: &'a Mutex<LockoutTracker>,
+    key_value_store: &'a Arc<ShardedStore>,
+    encryption_key: &'a Arc<SecretKey>,
+    metrics: &'a Metrics,
+    broker: &'a Arc<Broker>,
+}
+
+/// Handles an admin request, see [`router`].
+#[derive(Clone, Copy)]
+enum Handler {
+    /// Responds with a JSON body.
+    Json(fn(&AdminRequest) -> (String, String)),
+    /// May stream its body or events, see [`AdminResponse`].
+    Stream(fn(&AdminRequest) -> AdminResponse),
+}
+
+/// The admin endpoints, see [`handle_admin_request`].
+fn router() -> &'static Router<Handler> {
+    use Handler::{Json, Stream};
+    static ROUTER: OnceLock<Router<Handler>> = OnceLock::new();
+
+    ROUTER.get_or_init(|| {
+        Router::<Handler>::new()
+            .route(RequestType::Get, Pattern::Exact("backup"), Stream(backup))
+            .route(RequestType::Put, Pattern::Exact("restore"), Json(restore))
+            .route(RequestType::Get, Pattern::Exact("metrics"), Json(metrics))
+            .route(RequestType::Get, Pattern::Exact("watch"), Stream(watch))
+            .route(
+                RequestType::Get,
+                Pattern::Exact("subscribe"),
+                Stream(subscribe),
+            )
+            .route(RequestType::Post, Pattern::Exact("publish"), Json(publish))
+            .route(RequestType::Get, Pattern::Exact("export"), Stream(export))
+            .route(RequestType::Put, Pattern::Exact("import"), Json(import))
+            .route(
+                RequestType::Get,
+                Pattern::Exact("snapshots"),
+                Json(snapshots),
+            )
+            .route(RequestType::Put, Pattern::Exact("snapshots"), Json(pin))
+            .route(
+                RequestType::Delete,
+                Pattern::Prefix("snapshots/"),
+                Json(unpin),
+            )
+            .route(RequestType::Get, Pattern::Prefix("history/"), Json(history))
+            .route(
+                RequestType::Put,
+                Pattern::Prefix("history/"),
+                Json(restore_version),
+            )
+            .route(
+                RequestType::Get,
+                Pattern::Exact("retention"),
+                Json(retention),
+            )
+            .route(
+                RequestType::Put,
+                Pattern::Exact("retention"),
+                Json(set_retention),
+            )
+            .route(
+                RequestType::Delete,
+                Pattern::Exact("retention"),
+                Json(reset_retention),
+            )
+            .route(RequestType::Get, Pattern::Exact("trash"), Json(trash))
+            .route(
+                RequestType::Delete,
+                Pattern::Exact("trash"),
+                Json(empty_trash),
+            )
+            .route(RequestType::Put, Pattern::Prefix("trash/"), Json(undelete))
+            .route(RequestType::Delete, Pattern::Prefix("trash/"), Json(purge))
+            .route(RequestType::Get, Pattern::Exact("indexes"), Json(indexes))
+            .route(
+                RequestType::Put,
+                Pattern::Exact("indexes"),
+                Json(create_index),
+            )
+            .route(
+                RequestType::Delete,
+                Pattern::Exact("indexes"),
+                Json(drop_index),
+            )
+            .route(
+                RequestType::Put,
+                Pattern::Exact("indexes/rebuild"),
+                Json(rebuild_index),
+            )
+            .route(
+                RequestType::Get,
+                Pattern::Exact("indexes/query"),
+                Json(query_index),
+            )
+            .route(RequestType::Get, Pattern::Exact("lockouts"), Json(lockouts))
+            .route(
+                RequestType::Delete,
+                Pattern::Exact("lockouts"),
+                Json(clear_lockouts),
+            )
+            .route(
+                RequestType::Delete,
+                Pattern::Prefix("lockouts/"),
+                Json(clear_lockout),
+            )
+    })
+}
+
+fn metrics(request: &AdminRequest) -> (String, String) {
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        request.metrics.to_json().to_string(),
+    )
+}
+
+/// Resumes after the revision in `since`, or in the `Last-Event-ID` header
+/// browsers send when they reconnect.
+fn watch(request: &AdminRequest) -> AdminResponse {
+    let query = &request.query;
+    let filter =
+        match (query.get("key"), query.get("prefix")) {
+            (Some(key), None) if !key.is_empty() => Filter::Key(key.clone()),
+            (None, Some(prefix)) => Filter::Prefix(prefix.clone()),
+            _ => return AdminResponse::Json((
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error(
+                    "Watch either a key with ?key=<key> or the keys starting \
+                    with ?prefix=<prefix>.",
+                ),
+            )),
+        };
+    let since = query
+        .get("since")
+        .cloned()
+        .or_else(|| parse_header_from_request(request.buf, "Last-Event-ID"));
+    let since = match since.map(|since| since.parse()) {
+        Some(Ok(since)) => Some(since),
+        Some(Err(_)) => {
+            return AdminResponse::Json((
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error("since must be a revision number."),
+            ))
+        }
+        None => None,
+    };
+
+    match request.key_value_store.watch(filter, since) {
+        Ok(watch) => AdminResponse::Events {
+            headers: vec![("Skv-Revision", watch.revision().to_string())],
+            events: Box::new(watch),
+        },
+        Err(e) => AdminResponse::Json(e.response()),
+    }
+}
+
+fn subscribe(request: &AdminRequest) -> AdminResponse {
+    let subscription = pubsub::topics_from_query(&request.query)
+        .and_then(|topics| request.broker.subscribe(topics));
+
+    match subscription {
+        Ok(subscription) => AdminResponse::Events {
+            events: Box::new(subscription),
+            headers: Vec::new(),
+        },
+        Err(e) => AdminResponse::Json(e.response()),
+    }
+}
+
+/// The response tells how many subscribers the channel has.
+fn publish(request: &AdminRequest) -> (String, String) {
+    match request.query.get("channel") {
+        Some(channel) if !pubsub::is_short_enough(channel) => (
+            "HTTP/1.1 400 Bad Request".to_string(),
+            json_error(&format!(
+                "Channels are at most {} characters long.",
+                pubsub::MAX_TOPIC_LENGTH
+            )),
+        ),
+        Some(channel) if !channel.is_empty() => {
+            let message =
+                parse_body_from_request(request.buf).unwrap_or_default();
+            let receivers = request.broker.publish(channel, &message);
+            (
+                "HTTP/1.1 200 OK".to_string(),
+                serde_json::json!({ "receivers": receivers }).to_string(),
+            )
+        }
+        _ => (
+            "HTTP/1.1 400 Bad Request".to_string(),
+            json_error("Publish to a channel with ?channel=."),
+        ),
+    }
+}
+
+fn lockouts(request: &AdminRequest) -> (String, String) {
+    let lockouts = request
+        .lockouts
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let lockouts: Vec<serde_json::Value> = lockouts
+        .list()
+        .into_iter()
+        .map(|lockout| {
+            serde_json::json!({
+                "subject": lockout.subject,
+                "failures": lockout.failures,
+                "retry_after": lockout.retry_after,
+            })
+        })
+        .collect();
+
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::Value::from(lockouts).to_string(),
+    )
+}
+
+fn clear_lockouts(request: &AdminRequest) -> (String, String) {
+    let mut lockouts = request
+        .lockouts
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::json!({ "cleared": lockouts.clear(None) }).to_string(),
+    )
+}
+
+fn clear_lockout(request: &AdminRequest) -> (String, String) {
+    let subject = request.param;
+    let mut lockouts = request
+        .lockouts
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    match lockouts.clear(Some(subject)) {
+        0 => (
+            "HTTP/1.1 404 NOT FOUND".to_string(),
+            json_error(&format!("No lockout for '{}'.", subject)),
+        ),
+        cleared => (
+            "HTTP/1.1 200 OK".to_string(),
+            serde_json::json!({ "cleared": cleared }).to_string(),
+        ),
+    }
+}
+
+fn snapshots(request: &AdminRequest) -> (String, String) {
+    let pinned: Vec<serde_json::Value> = request
+        .key_value_store
+        .pinned()
+        .into_iter()
+        .map(|(version, expires_in)| {
+            serde_json::json!({
+                "version": version,
+                "expires_in": expires_in.as_secs(),
+            })
+        })
+        .collect();
+
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::json!({
+            "version": request.key_value_store.version(),
+            "pinned": pinned,
+        })
+        .to_string(),
+    )
+}
+
+fn pin(request: &AdminRequest) -> (String, String) {
+    let ttl = match request.query.get("ttl").map(|ttl| ttl.parse::<u64>()) {
+        Some(Ok(ttl)) if ttl <= MAX_SNAPSHOT_TTL => ttl,
+        Some(_) => {
+            return (
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error(&format!(
+                    "ttl must be a number of seconds up to {}.",
+                    MAX_SNAPSHOT_TTL
+                )),
+            )
+        }
+        None => DEFAULT_SNAPSHOT_TTL,
+    };
+    let version = request.key_value_store.pin(Duration::from_secs(ttl));
+
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::json!({ "version": version, "ttl": ttl }).to_string(),
+    )
+}
+
+fn unpin(request: &AdminRequest) -> (String, String) {
+    let version = request.param;
+
+    match version
+        .parse()
+        .map(|version| request.key_value_store.unpin(version))
+    {
+        Ok(true) => (
+            "HTTP/1.1 200 OK".to_string(),
+            serde_json::json!({ "released": version }).to_string(),
+        ),
+        _ => (
+            "HTTP/1.1 404 NOT FOUND".to_string(),
+            json_error(&format!("Version '{}' isn't pinned.", version)),
+        ),
+    }
+}
+
+fn history(request: &AdminRequest) -> (String, String) {
+    let key = request.param;
+    let version = match version_from_query(&request.query) {
+        Ok(version) => version,
+        Err(response) => return response,
+    };
+
+    let version = match version {
+        Some(version) => version,
+        None => {
+            let history = request.key_value_store.history(key);
+            if history.is_empty() {
+                return (
+                    "HTTP/1.1 404 NOT FOUND".to_string(),
+                    json_error(&format!("Key '{}' has no history.", key)),
+                );
+            }
+            let versions: Vec<serde_json::Value> =
+                history.iter().map(|entry| entry.to_json()).collect();
+            return (
+                "HTTP/1.1 200 OK".to_string(),
+                serde_json::json!({ "key": key, "versions": versions })
+                    .to_string(),
+            );
+        }
+    };
+
+    match request.key_value_store.old_value(key, version) {
+        Ok(Some(old_value)) => (
+            "HTTP/1.1 200 OK".to_string(),
+            serde_json::json!({
+                "key": key,
+                "version": old_value.entry.version,
+                "written": unix_secs(old_value.entry.written),
+                "deleted": old_value.entry.deleted,
+                "value": old_value.value,
+            })
+            .to_string(),
+        ),
+        Ok(None) => not_kept(key, version),
+        Err(e) => (
+            "HTTP/1.1 500 Internal Server Error".to_string(),
+            json_error(e),
+        ),
+    }
+}
+
+fn restore_version(request: &AdminRequest) -> (String, String) {
+    let key = request.param;
+    let version = match version_from_query(&request.query) {
+        Ok(Some(version)) => version,
+        Ok(None) => {
+            return (
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error("Provide the version to restore with ?version=."),
+            )
+        }
+        Err(response) => return response,
+    };
+
+    match request.key_value_store.restore_version(key, version) {
+        Some(restored) => (
+            "HTTP/1.1 200 OK".to_string(),
+            serde_json::json!({
+                "key": key,
+                "restored": version,
+                "version": restored,
+            })
+            .to_string(),
+        ),
+        None => not_kept(key, version),
+    }
+}
+
+/// A version number from the `version` query parameter, if it is there.
+///
+/// On failure, the error is the response to send.
+fn version_from_query(
+    query: &HashMap<String, String>,
+) -> Result<Option<u64>, (String, String)> {
+    match query.get("version").map(|version| version.parse()) {
+        Some(Ok(version)) => Ok(Some(version)),
+        Some(Err(_)) => Err((
+            "HTTP/1.1 400 Bad Request".to_string(),
+            json_error("version must be a version number."),
+        )),
+        None => Ok(None),
+    }
+}
+
+fn not_kept(key: &str, version: u64) -> (String, String) {
+    (
+        "HTTP/1.1 404 NOT FOUND".to_string(),
+        json_error(&format!(
+            "No value of key '{}' from version {} is kept.",
+            key, version
+        )),
+    )
+}
+
+fn retention(request: &AdminRequest) -> (String, String) {
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        request.key_value_store.retention().to_json().to_string(),
+    )
+}
+
+fn set_retention(request: &AdminRequest) -> (String, String) {
+    let query = &request.query;
+    let namespace = match namespace_from_query(query) {
+        Ok(namespace) => namespace,
+        Err(response) => return response,
+    };
+    let policy = request.key_value_store.retention();
+
+    let current = policy.for_namespace(namespace);
+    let versions = match query.get("versions").map(|v| v.parse()) {
+        Some(Ok(versions)) => versions,
+        Some(Err(_)) => {
+            return (
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error("versions must be a number."),
+            )
+        }
+        None => current.versions,
+    };
+    let max_age = match secs_from_query(query, "max_age") {
+        Ok(max_age) => max_age.unwrap_or(current.max_age),
+        Err(response) => return response,
+    };
+    let trash = match secs_from_query(query, "trash") {
+        Ok(trash) => trash.unwrap_or(current.trash),
+        Err(response) => return response,
+    };
+
+    let retention = Retention {
+        versions,
+        max_age,
+        trash,
+    };
+    policy.set(namespace, retention);
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::json!({
+            "namespace": namespace,
+            "retention": retention.to_json(),
+        })
+        .to_string(),
+    )
+}
+
+fn reset_retention(request: &AdminRequest) -> (String, String) {
+    let namespace = match namespace_from_query(&request.query) {
+        Ok(namespace) => namespace,
+        Err(response) => return response,
+    };
+
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::json!({
+            "namespace": namespace,
+            "reset": request.key_value_store.retention().reset(namespace),
+        })
+        .to_string(),
+    )
+}
+
+/// The single namespace from the `namespace` query parameter. Keys without
+/// a namespace are in the default namespace, "".
+///
+/// On failure, the error is the response to send.
+fn namespace_from_query(
+    query: &HashMap<String, String>,
+) -> Result<&str, (String, String)> {
+    match query.get("namespace") {
+        Some(namespace) => Ok(namespace),
+        None => Err((
+            "HTTP/1.1 400 Bad Request".to_string(),
+            json_error("Provide the namespace with ?namespace=."),
+        )),
+    }
+}
+
+fn trash(request: &AdminRequest) -> (String, String) {
+    let namespaces = namespaces_from_query(&request.query);
+
+    match request.key_value_store.trash(&namespaces) {
+        Ok(trash) => {
+            let trash: Vec<serde_json::Value> =
+                trash.iter().map(|entry| entry.to_json()).collect();
+            (
+                "HTTP/1.1 200 OK".to_string(),
+                serde_json::Value::from(trash).to_string(),
+            )
+        }
+        Err(e) => (
+            "HTTP/1.1 500 Internal Server Error".to_string(),
+            json_error(e),
+        ),
+    }
+}
+
+fn empty_trash(request: &AdminRequest) -> (String, String) {
+    let namespaces = namespaces_from_query(&request.query);
+    let purged = request.key_value_store.empty_trash(&namespaces);
+
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::json!({ "purged": purged }).to_string(),
+    )
+}
+
+fn undelete(request: &AdminRequest) -> (String, String) {
+    let key = request.param;
+
+    match request.key_value_store.undelete(key) {
+        Some(version) => (
+            "HTTP/1.1 200 OK".to_string(),
+            serde_json::json!({ "key": key, "version": version }).to_string(),
+        ),
+        None => not_in_trash(key),
+    }
+}
+
+fn purge(request: &AdminRequest) -> (String, String) {
+    let key = request.param;
+
+    match request.key_value_store.purge(key) {
+        true => (
+            "HTTP/1.1 200 OK".to_string(),
+            serde_json::json!({ "purged": 1 }).to_string(),
+        ),
+        false => not_in_trash(key),
+    }
+}
+
+fn not_in_trash(key: &str) -> (String, String) {
+    (
+        "HTTP/1.1 404 NOT FOUND".to_string(),
+        json_error(&format!("Key '{}' isn't in the trash.", key)),
+    )
+}
+
+fn indexes(request: &AdminRequest) -> (String, String) {
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        request.key_value_store.indexes().to_json().to_string(),
+    )
+}
+
+fn create_index(request: &AdminRequest) -> (String, String) {
+    let (namespace, field) = match index_from_query(&request.query) {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+
+    index_response(
+        request
+            .key_value_store
+            .create_index(namespace, field)
+            .map(|keys| serde_json::json!({ "keys": keys })),
+    )
+}
+
+fn drop_index(request: &AdminRequest) -> (String, String) {
+    let (namespace, field) = match index_from_query(&request.query) {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+
+    index_response(
+        match request.key_value_store.indexes().remove(namespace, field) {
+            true => Ok(serde_json::json!({ "removed": true })),
+            false => Err(IndexError::NotFound),
+        },
+    )
+}
+
+fn rebuild_index(request: &AdminRequest) -> (String, String) {
+    let (namespace, field) = match index_from_query(&request.query) {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+
+    index_response(
+        request
+            .key_value_store
+            .build_index(namespace, field)
+            .map(|keys| serde_json::json!({ "keys": keys })),
+    )
+}
+
+fn query_index(request: &AdminRequest) -> (String, String) {
+    let (namespace, field) = match index_from_query(&request.query) {
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    let bounds = match bounds_from_query(&request.query) {
+        Ok(bounds) => bounds,
+        Err(response) => return response,
+    };
+
+    index_response(
+        request
+            .key_value_store
+            .indexes()
+            .query(namespace, field, bounds)
+            .map(|keys| serde_json::json!({ "keys": keys })),
+    )
+}
+
+/// The namespace and field of the index a request is about. Keys without a
+/// namespace are in the default namespace, "".
+///
+/// On failure, the error is the response to send.
+fn index_from_query(
+    query: &HashMap<String, String>,
+) -> Result<(&str, &str), (String, String)> {
+    match (query.get("namespace"), query.get("field")) {
+        (Some(namespace), Some(field)) if field.starts_with('/') => {
+            Ok((namespace, field))
+        }
+        _ => Err((
+            "HTTP/1.1 400 Bad Request".to_string(),
+            json_error(
+                "Provide the namespace with ?namespace= and the field as a \
+                JSON Pointer with &field=, e.g. /status.",
+            ),
+        )),
+    }
+}
+
+fn index_response(
+    result: Result<serde_json::Value, IndexError>,
+) -> (String, String) {
+    match result {
+        Ok(body) => ("HTTP/1.1 200 OK".to_string(), body.to_string()),
+        Err(e) => {
+            let status_line = match e {
+                IndexError::NotFound => "HTTP/1.1 404 NOT FOUND",
+                IndexError::Exists | IndexError::Building => {
+                    "HTTP/1.1 409 Conflict"
+                }
+            };
+            (status_line.to_string(), json_error(e.message()))
+        }
+    }
+}
+
+/// The values an index query asks for: `eq`, or a range from `min` or `gt`
+/// to `max` or `lt`.
+///
+/// On failure, the error is the response to send.
+fn bounds_from_query(
+    query: &HashMap<String, String>,
+) -> Result<(Bound<IndexValue>, Bound<IndexValue>), (String, String)> {
+    let value = |name: &str| query.get(name).map(|v| IndexValue::from_query(v));
+
+    if let Some(eq) = value("eq") {
+        return Ok((Bound::Included(eq.clone()), Bound::Included(eq)));
+    }
+    let lower = match (value("min"), value("gt")) {
+        (Some(min), None) => Bound::Included(min),
+        (None, Some(gt)) => Bound::Excluded(gt),
+        (None, None) => Bound::Unbounded,
+        (Some(_), Some(_)) => {
+            return Err((
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error("Use either min or gt."),
+            ))
+        }
+    };
+    let upper = match (value("max"), value("lt")) {
+        (Some(max), None) => Bound::Included(max),
+        (None, Some(lt)) => Bound::Excluded(lt),
+        (None, None) => Bound::Unbounded,
+        (Some(_), Some(_)) => {
+            return Err((
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error("Use either max or lt."),
+            ))
+        }
+    };
+
+    Ok((lower, upper))
+}
+
+/// A number of seconds from the query, if it is there.
+///
+/// On failure, the error is the response to send.
+fn secs_from_query(
+    query: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<Duration>, (String, String)> {
+    match query.get(name).map(|secs| secs.parse()) {
+        Some(Ok(secs)) => Ok(Some(Duration::from_secs(secs))),
+        Some(Err(_)) => Err((
+            "HTTP/1.1 400 Bad Request".to_string(),
+            json_error(&format!("{} must be a number of seconds.", name)),
+        )),
+        None => Ok(None),
+    }
+}
+
+fn backup(request: &AdminRequest) -> AdminResponse {
+    let namespaces = namespaces_from_query(&request.query);
+    let passphrase = passphrase_from_headers(request.buf);
+    let key = match &passphrase {
+        Some(passphrase) => ArchiveKey::Passphrase(passphrase),
+        None => ArchiveKey::Master(request.encryption_key),
+    };
+
+    AdminResponse::Stream {
+        content_type: "application/x-ndjson",
+        body: Box::new(BackupStream {
+            writer: Some(ArchiveWriter::new(&namespaces, key)),
+            snapshot: request.key_value_store.shared_snapshot(),
+            namespaces,
+            shard: 0,
+            header_sent: false,
+        }),
+    }
+}
+
+/// The body of `GET /admin/backup`: an archive of a snapshot of the store,
+/// with a chunk for every shard, see [`ArchiveWriter`].
+///
+/// If a shard can't be read, the last line is a `{"error"}` object instead
+/// of the archive's last chunk, so the archive can't be restored.
+struct BackupStream {
+    /// `None` once the archive is done.
+    writer: Option<ArchiveWriter>,
+    snapshot: SharedSnapshot,
+    namespaces: Vec<String>,
+    /// The next shard to back up.
+    shard: usize,
+    header_sent: bool,
+}
+
+impl BodyStream for BackupStream {
+    fn next_chunk(&mut self) -> Option<String> {
+        if !self.header_sent {
+            self.header_sent = true;
+            return Some(self.writer.as_ref()?.header());
+        }
+
+        while self.shard < self.snapshot.store().shard_count() {
+            let entries =
+                self.snapshot.shard_entries(self.shard, &self.namespaces);
+            self.shard += 1;
+            let chunk = match entries {
+                Ok(entries) if entries.is_empty() => continue,
+                Ok(entries) => self.writer.as_mut()?.chunk(&entries),
+                Err(e) => Err(e),
+            };
+            return Some(chunk.unwrap_or_else(|e| self.fail(e)));
+        }
+
+        let last = self.writer.take()?.finish();
+        Some(last.unwrap_or_else(|e| self.fail(e)))
+    }
+}
+
+impl BackupStream {
+    /// End the archive with an error line instead of its last chunk.
+    fn fail(&mut self, error: &str) -> String {
+        self.writer = None;
+        format!("{}\n", json_error(error))
+    }
+}
+
+fn restore(request: &AdminRequest) -> (String, String) {
+    let namespaces = namespaces_from_query(&request.query);
+    let mode = match request.query.get("mode") {
+        Some(mode) => match RestoreMode::from_name(mode) {
+            Ok(mode) => mode,
+            Err(e) => {
+                return ("HTTP/1.1 400 Bad Request".to_string(), json_error(e))
+            }
+        },
+        None => RestoreMode::Merge,
+    };
+
+    let passphrase = passphrase_from_headers(request.buf);
+    let key = match &passphrase {
+        Some(passphrase) => ArchiveKey::Passphrase(passphrase),
+        None => ArchiveKey::Master(request.encryption_key),
+    };
+
+    // Archives too large for a request body are read from a file on the
+    // server.
+    let path = request.query.get("path");
+    let body = match path {
+        Some(_) => String::new(),
+        None => match parse_body_from_request(request.buf) {
+            Ok(body) => body,
+            Err(e) => {
+                return ("HTTP/1.1 400 Bad Request".to_string(), json_error(e))
+            }
+        },
+    };
+
+    // The archive is read twice, a chunk at a time, so it never has to be
+    // held in memory: once to check all of it before the store is touched,
+    // so that a broken archive isn't restored at all, then to apply it.
+    let mut restored = 0;
+    let checked = read_archive(path, &body)
+        .and_then(|archive| backup::ArchiveReader::new(archive, key))
+        .and_then(|mut reader| {
+            while let Some(entries) = reader.next_chunk()? {
+                restored += entries
+                    .iter()
+                    .filter(|(key, _)| in_namespaces(key, &namespaces))
+                    .count();
+            }
+            Ok(reader)
+        });
+    let mut reader = match checked
+        .and_then(|reader| reader.reopen(read_archive(path, &body)?))
+    {
+        Ok(reader) => reader,
+        Err(e) => {
+            return ("HTTP/1.1 400 Bad Request".to_string(), json_error(e))
+        }
+    };
+
+    // Hold every shard's write lock for the whole restore, and make every
+    // change in the same version, so that no one ever sees a half restored
+    // store. The archive was checked already, so applying it only fails if
+    // the file can't be read anymore.
+    let mut key_value_store = request.key_value_store.write_all();
+    let removed = match mode {
+        RestoreMode::Replace => key_value_store.clear(&namespaces),
+        RestoreMode::Merge => 0,
+    };
+    loop {
+        let entries = match reader.next_chunk() {
+            Ok(Some(entries)) => entries,
+            Ok(None) => break,
+            Err(e) => {
+                return (
+                    "HTTP/1.1 500 Internal Server Error".to_string(),
+                    json_error(e),
+                )
+            }
+        };
+        for (key, value) in &entries {
+            if !in_namespaces(key, &namespaces) {
+                continue;
+            }
+            match request.key_value_store.encrypt_entry(key, value) {
+                Ok(entry) => key_value_store.insert_encrypted(entry),
+                Err(e) => {
+                    return (
+                        "HTTP/1.1 500 Internal Server Error".to_string(),
+                        json_error(e),
+                    )
+                }
+            };
+        }
+    }
+
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::json!({
+            "mode": mode.name(),
+            "restored": restored,
+            "removed": removed,
+        })
+        .to_string(),
+    )
+}
+
+/// The archive to restore: the file at `path` on the server, if given, or
+/// the request body.
+fn read_archive<'a>(
+    path: Option<&String>,
+    body: &'a str,
+) -> Result<Box<dyn BufRead + 'a>, &'static str> {
+    match path {
+        Some(path) => match File::open(path) {
+            Ok(file) => Ok(Box::new(BufReader::new(file))),
+            Err(_) => Err("Failed to open the archive file on the server."),
+        },
+        None => Ok(Box::new(body.as_bytes())),
+    }
+}
+
+fn export(request: &AdminRequest) -> AdminResponse {
+    let prefix = request.query.get("prefix").cloned().unwrap_or_default();
+
+    AdminResponse::Stream {
+        content_type: "application/x-ndjson",
+        body: Box::new(ExportStream {
+            snapshot: request.key_value_store.shared_snapshot(),
+            prefix,
+            shard: 0,
+        }),
+    }
+}
+
+/// The body of `GET /admin/export`. Reads a snapshot of the store, so the
+/// export is consistent, and decrypts one shard per chunk, so only one shard
+/// is ever held in memory. Entries are sorted by key within each shard.
+///
+/// If a shard can't be decrypted, the last line is a `{"error"}` object
+/// instead of an entry.
+struct ExportStream {
+    snapshot: SharedSnapshot,
+    prefix: String,
+    /// The next shard to export.
+    shard: usize,
+}
+
+impl BodyStream for ExportStream {
+    fn next_chunk(&mut self) -> Option<String> {
+        let shards = self.snapshot.store().shard_count();
+        while self.shard < shards {
+            let entries = self.snapshot.shard_entries(self.shard, &[]);
+            self.shard += 1;
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.shard = shards;
+                    return Some(format!("{}\n", json_error(e)));
+                }
+            };
+
+            let mut chunk = String::new();
+            for (key, value) in entries
+                .iter()
+                .filter(|(key, _)| key.starts_with(&self.prefix))
+            {
+                chunk.push_str(
+                    &serde_json::json!({ "key": key, "value": value })
+                        .to_string(),
+                );
+                chunk.push('\n');
+            }
+            if !chunk.is_empty() {
+                return Some(chunk);
+            }
+        }
+
+        None
+    }
+}
+
+fn import(request: &AdminRequest) -> (String, String) {
+    let query = &request.query;
+    let on_existing = match query.get("existing") {
+        Some(name) => match OnExisting::from_name(name) {
+            Ok(on_existing) => on_existing,
+            Err(e) => {
+                return ("HTTP/1.1 400 Bad Request".to_string(), json_error(e))
+            }
+        },
+        None => OnExisting::Overwrite,
+    };
+    let dry_run = query
+        .get("dry_run")
+        .is_some_and(|dry_run| dry_run == "true");
+
+    let body = match parse_body_from_request(request.buf) {
+        Ok(body) => body,
+        Err(e) => {
+            return ("HTTP/1.1 400 Bad Request".to_string(), json_error(e))
+        }
+    };
+
+    // Entries are imported one at a time, each under its own shard's lock,
+    // so an import doesn't stop the rest of the store. Dry runs only read.
+    let key_value_store = request.key_value_store;
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut failed = Vec::new();
+    for (line, entry) in body.lines().enumerate() {
+        let line = line + 1;
+        let entry: serde_json::Value = match serde_json::from_str(entry) {
+            Ok(entry) => entry,
+            Err(_) => {
+                failed.push(serde_json::json!({
+                    "line": line,
+                    "error": "Malformed entry.",
+                }));
+                continue;
+            }
+        };
+        let (key, value) =
+            match (entry["key"].as_str(), entry["value"].as_str()) {
+                (Some(key), Some(value)) if !key.is_empty() => (key, value),
+                _ => {
+                    failed.push(serde_json::json!({
+                    "line": line,
+                    "error": "Entry needs a non-empty string key and value.",
+                }));
+                    continue;
+                }
+            };
+
+        let inserted = match (dry_run, on_existing) {
+            (true, OnExisting::Skip) => Ok(!key_value_store.contains_key(key)),
+            (true, OnExisting::Overwrite) => Ok(true),
+            (false, OnExisting::Skip) => key_value_store.insert_new(key, value),
+            (false, OnExisting::Overwrite) => {
+                key_value_store.insert(key, value).map(|_| true)
+            }
+        };
+        match inserted {
+            Ok(true) => {}
+            Ok(false) => {
+                skipped += 1;
+                continue;
+            }
+            Err(e) => {
+                failed.push(serde_json::json!({ "line": line, "error": e }));
+                continue;
+            }
+        }
+        imported += 1;
+    }
+
+    (
+        "HTTP/1.1 200 OK".to_string(),
+        serde_json::json!({
+            "existing": on_existing.name(),
+            "dry_run": dry_run,
+            "imported": imported,
+            "skipped": skipped,
+            "failed": failed,
+        })
+        .to_string(),
+    )
+}
+
+/// Namespaces from a comma separated `namespace` query parameter.
+fn namespaces_from_query(query: &HashMap<String, String>) -> Vec<String> {
+    match query.get("namespace") {
+        Some(namespaces) => namespaces
+            .split(',')
+            .filter(|namespace| !namespace.is_empty())
+            .map(|namespace| namespace.to_string())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+fn passphrase_from_headers(buf: &[u8]) -> Option<Zeroizing<String>> {
+    parse_header_from_request(buf, "passphrase")
+        .filter(|passphrase| !passphrase.is_empty())
+        .map(Zeroizing::new)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::generate_key;
+    use crate::lockout::Subject;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn new_store() -> (Arc<ShardedStore>, Arc<SecretKey>) {
+        let key = Arc::new(generate_key());
+        (Arc::new(ShardedStore::new(Arc::clone(&key), 4)), key)
+    }
+
+    /// Like [`handle_admin_request`], with streamed bodies read in full.
+    fn admin_request(
+        buf: &[u8],
+        request_type: &RequestType,
+        lockouts: &Mutex<LockoutTracker>,
+        key_value_store: &Arc<ShardedStore>,
+        encryption_key: &Arc<SecretKey>,
+        metrics: &Metrics,
+    ) -> Result<(String, String), RouteError> {
+        let response = handle_admin_request(
+            buf,
+            request_type,
+            lockouts,
+            key_value_store,
+            encryption_key,
+            metrics,
+            &Arc::new(Broker::new(Arc::new(Metrics::new()))),
+        )?;
+
+        Ok(match response {
+            AdminResponse::Json(response) => response,
+            AdminResponse::Stream { mut body, .. } => {
+                let mut text = String::new();
+                while let Some(chunk) = body.next_chunk() {
+                    text.push_str(&chunk);
+                }
+                ("HTTP/1.1 200 OK".to_string(), text)
+            }
+            AdminResponse::Events { .. } => {
+                ("HTTP/1.1 200 OK".to_string(), String::new())
+            }
+        })
+    }
+
+    fn request(text: &str) -> Vec<u8> {
+        text.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_list_and_clear_lockouts() {
+        let (store, key) = new_store();
+        let lockouts = Mutex::new(LockoutTracker::new());
+        let subject = Subject::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        lockouts.lock().unwrap().record_failure(&[subject]);
+
+        let list = request("GET /admin/lockouts HTTP/1.1\r\n\r\n");
+        let (status_line, body) = admin_request(
+            &list,
+            &RequestType::Get,
+            &lockouts,
+            &store,
+            &key,
+            &Metrics::new(),
+        )
+        .unwrap();
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(
+            body,
+            r#"[{"failures":1,"retry_after":0,"subject":"ip:127.0.0.1"}]"#
+        );
+
+        let clear =
+            request("DELETE /admin/lockouts/ip:127.0.0.1 HTTP/1.1\r\n\r\n");
+        let (status_line, _) = admin_request(
+            &clear,
+            &RequestType::Delete,
+            &lockouts,
+            &store,
+            &key,
+            &Metrics::new(),
+        )
+        .unwrap();
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        let (status_line, _) = admin_request(
+            &clear,
+            &RequestType::Delete,
+            &lockouts,
+            &store,
+            &key,
+            &Metrics::new(),
+        )
+        .unwrap();
+        assert_eq!(status_line, "HTTP/1.1 404 NOT FOUND");
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let (store, key) = new_store();
+        let lockouts = Mutex::new(LockoutTracker::new());
+        {
+            let mut store = store.write_all();
+            store.insert("users:alice", "admin").unwrap();
+            store.insert("users:bob", "guest").unwrap();
+            store.insert("motd", "hello").unwrap();
+        }
+
+        let backup = request(
+            "GET /admin/backup?namespace=users HTTP/1.1\r\n\
+            passphrase: hunter2\r\n\r\n",
+        );
+        let (status_line, archive) = admin_request(
+            &backup,
+            &RequestType::Get,
+            &lockouts,
+            &store,
+            &key,
+            &Metrics::new(),
+        )
+        .unwrap();
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+
+        // Restore into a different server, replacing what it has.
+        let (other_store, other_key) = new_store();
+        other_store.insert("users:eve", "x").unwrap();
+        other_store.insert("motd", "hi").unwrap();
+        let restore_archive = |archive: &str| {
+            let restore = request(&format!(
+                "PUT /admin/restore?mode=replace&namespace=users HTTP/1.1\r\n\
+                passphrase: hunter2\r\nContent-Length: {}\r\n\r\n{}",
+                archive.len(),
+                archive
+            ));
+            admin_request(
+                &restore,
+                &RequestType::Put,
+                &lockouts,
+                &other_store,
+                &other_key,
+                &Metrics::new(),
+            )
+            .unwrap()
+        };
+
+        // A broken archive leaves the store as it was.
+        let version = other_store.version();
+        let (status_line, _) = restore_archive(&archive[..archive.len() - 8]);
+        assert_eq!(status_line, "HTTP/1.1 400 Bad Request");
+        assert_eq!(other_store.version(), version);
+        assert_eq!(other_store.entries(&[]).unwrap().len(), 2);
+
+        // Clearing and restoring is a single store version.
+        let (status_line, body) = restore_archive(&archive);
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body, r#"{"mode":"replace","removed":1,"restored":2}"#);
+        assert_eq!(other_store.version(), version + 1);
+        assert_eq!(
+            other_store.entries(&[]).unwrap(),
+            vec![
+                ("motd".to_string(), "hi".to_string()),
+                ("users:alice".to_string(), "admin".to_string()),
+                ("users:bob".to_string(), "guest".to_string()),
+            ]
+        );
+
+        // Or from a file on the server, without a body.
+        let path = std::env::temp_dir()
+            .join(format!("skv-test-restore-{}.skv", std::process::id()));
+        std::fs::write(&path, &archive).unwrap();
+        let other_store_entries = other_store.entries(&[]).unwrap();
+        other_store.insert("users:bob", "changed").unwrap();
+        let restore = request(&format!(
+            "PUT /admin/restore?mode=merge&path={} HTTP/1.1\r\n\
+            passphrase: hunter2\r\n\r\n",
+            path.display()
+        ));
+        let (status_line, body) = admin_request(
+            &restore,
+            &RequestType::Put,
+            &lockouts,
+            &other_store,
+            &other_key,
+            &Metrics::new(),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body, r#"{"mode":"merge","removed":0,"restored":2}"#);
+        assert_eq!(other_store.entries(&[]).unwrap(), other_store_entries);
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let (store, key) = new_store();
+        let lockouts = Mutex::new(LockoutTracker::new());
+        store.insert("seed:a", "old").unwrap();
+
+        let entries = "{\"key\":\"seed:a\",\"value\":\"new\"}\n\
+            {\"key\":\"seed:b\",\"value\":\"b\"}\n\
+            {\"key\":\"seed:c\"}\n";
+        let import = |query: &str| {
+            request(&format!(
+                "PUT /admin/import?{} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                query,
+                entries.len(),
+                entries
+            ))
+        };
+
+        let (status_line, body) = admin_request(
+            &import("existing=skip&dry_run=true"),
+            &RequestType::Put,
+            &lockouts,
+            &store,
+            &key,
+            &Metrics::new(),
+        )
+        .unwrap();
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        let summary: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(summary["imported"], 1);
+        assert_eq!(summary["skipped"], 1);
+        assert_eq!(summary["failed"][0]["line"], 3);
+        assert_eq!(store.entries(&[]).unwrap().len(), 1);
+
+        let (_, body) = admin_request(
+            &import("existing=overwrite"),
+            &RequestType::Put,
+            &lockouts,
+            &store,
+            &key,
+            &Metrics::new(),
+        )
+        .unwrap();
+        let summary: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(summary["imported"], 2);
+
+        let export =
+            request("GET /admin/export?prefix=seed:b HTTP/1.1\r\n\r\n");
+        let (status_line, body) = admin_request(
+            &export,
+            &RequestType::Get,
+            &lockouts,
+            &store,
+            &key,
+            &Metrics::new(),
+        )
+        .unwrap();
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body, "{\"key\":\"seed:b\",\"value\":\"b\"}\n");
+    }
+
+    #[test]
+    fn test_export_reads_a_snapshot() {
+        let (store, key) = new_store();
+        for i in 0..20 {
+            store.insert(&format!("seed:{:02}", i), "old").unwrap();
+        }
+
+        let export = request("GET /admin/export HTTP/1.1\r\n\r\n");
+        let response = handle_admin_request(
+            &export,
+            &RequestType::Get,
+            &Mutex::new(LockoutTracker::new()),
+            &store,
+            &key,
+            &Metrics::new(),
+            &Arc::new(Broker::new(Arc::new(Metrics::new()))),
+        )
+        .unwrap();
+        let mut body = match response {
+            AdminResponse::Stream { body, .. } => body,
+            _ => panic!("Export should be streamed."),
+        };
+
+        // Writes made while the export runs aren't part of it.
+        let first = body.next_chunk().unwrap();
+        for i in 0..20 {
+            store.insert(&format!("seed:{:02}", i), "new").unwrap();
+        }
+        store.insert("seed:late", "new").unwrap();
+        let mut lines: Vec<String> =
+            first.lines().map(str::to_string).collect();
+        while let Some(chunk) = body.next_chunk() {
+            lines.extend(chunk.lines().map(str::to_string));
+        }
+
+        lines.sort();
+        let expected: Vec<String> = (0..20)
+            .map(|i| format!("{{\"key\":\"seed:{:02}\",\"value\":\"old\"}}", i))
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn test_pin_and_release_snapshots() {
+        let (store, key) = new_store();
+        let lockouts = Mutex::new(LockoutTracker::new());
+        store.insert("motd", "hello").unwrap();
+        let admin = |method: RequestType, path: &str| {
+            let buf = request(&format!(
+                "{} /admin/{} HTTP/1.1\r\n\r\n",
+                method.method(),
+                path
+            ));
+            admin_request(
+                &buf,
+                &method,
+                &lockouts,
+                &store,
+                &key,
+                &Metrics::new(),
+            )
+            .unwrap()
+        };
+
+        let (status_line, body) = admin(RequestType::Put, "snapshots?ttl=30");
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body, r#"{"ttl":30,"version":1}"#);
+        let (status_line, _) = admin(RequestType::Put, "snapshots?ttl=x");
+        assert_eq!(status_line, "HTTP/1.1 400 Bad Request");
+
+        store.insert("motd", "bye").unwrap();
+        let (_, body) = admin(RequestType::Get, "snapshots");
+        let snapshots: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(snapshots["version"], 2);
+        assert_eq!(snapshots["pinned"][0]["version"], 1);
+
+        let (status_line, _) = admin(RequestType::Delete, "snapshots/1");
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        let (status_line, _) = admin(RequestType::Delete, "snapshots/1");
+        assert_eq!(status_line, "HTTP/1.1 404 NOT FOUND");
+    }
+
+    #[test]
+    fn test_history_and_retention() {
+        let (store, key) = new_store();
+        let admin = |text: &str, request_type: RequestType| {
+            let (status_line, body) = admin_request(
+                &request(text),
+                &request_type,
+                &Mutex::new(LockoutTracker::new()),
+                &store,
+                &key,
+                &Metrics::new(),
+            )
+            .unwrap();
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            (status_line, body)
+        };
+
+        let (status_line, body) = admin(
+            "PUT /admin/retention?namespace=users&versions=3 HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body["retention"]["versions"], 3);
+        let (_, body) =
+            admin("GET /admin/retention HTTP/1.1\r\n\r\n", RequestType::Get);
+        assert_eq!(body["namespaces"]["users"]["versions"], 3);
+        assert_eq!(body["default"]["versions"], 0);
+
+        store.insert("users:alice", "old").unwrap();
+        store.insert("users:alice", "new").unwrap();
+        let (status_line, body) = admin(
+            "GET /admin/history/users:alice HTTP/1.1\r\n\r\n",
+            RequestType::Get,
+        );
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body["versions"].as_array().unwrap().len(), 2);
+        assert_eq!(body["versions"][1]["version"], 1);
+
+        let (_, body) = admin(
+            "GET /admin/history/users:alice?version=1 HTTP/1.1\r\n\r\n",
+            RequestType::Get,
+        );
+        assert_eq!(body["value"], "old");
+        let (status_line, body) = admin(
+            "PUT /admin/history/users:alice?version=1 HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body["version"], 3);
+        let entries = store.entries(&[]).unwrap();
+        assert_eq!(entries[0].1, "old");
+
+        let (status_line, _) = admin(
+            "GET /admin/history/users:alice?version=9 HTTP/1.1\r\n\r\n",
+            RequestType::Get,
+        );
+        assert_eq!(status_line, "HTTP/1.1 404 NOT FOUND");
+        let (status_line, _) = admin(
+            "GET /admin/history/users:bob HTTP/1.1\r\n\r\n",
+            RequestType::Get,
+        );
+        assert_eq!(status_line, "HTTP/1.1 404 NOT FOUND");
+
+        let (_, body) = admin(
+            "DELETE /admin/retention?namespace=users HTTP/1.1\r\n\r\n",
+            RequestType::Delete,
+        );
+        assert_eq!(body["reset"], true);
+    }
+
+    #[test]
+    fn test_trash() {
+        let (store, key) = new_store();
+        let admin = |text: &str, request_type: RequestType| {
+            let (status_line, body) = admin_request(
+                &request(text),
+                &request_type,
+                &Mutex::new(LockoutTracker::new()),
+                &store,
+                &key,
+                &Metrics::new(),
+            )
+            .unwrap();
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            (status_line, body)
+        };
+        admin(
+            "PUT /admin/retention?namespace=users&trash=3600 HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        store.insert("users:alice", "a").unwrap();
+        store.insert("users:bob", "b").unwrap();
+        store.write_all().clear(&["users".to_string()]);
+
+        let (status_line, body) =
+            admin("GET /admin/trash HTTP/1.1\r\n\r\n", RequestType::Get);
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body[0]["key"], "users:alice");
+        assert_eq!(body[1]["key"], "users:bob");
+
+        let (status_line, _) = admin(
+            "PUT /admin/trash/users:alice HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert!(store.contains_key("users:alice"));
+        let (status_line, _) = admin(
+            "PUT /admin/trash/users:alice HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        assert_eq!(status_line, "HTTP/1.1 404 NOT FOUND");
+
+        let (_, body) = admin(
+            "DELETE /admin/trash?namespace=users HTTP/1.1\r\n\r\n",
+            RequestType::Delete,
+        );
+        assert_eq!(body["purged"], 1);
+        let (_, body) =
+            admin("GET /admin/trash HTTP/1.1\r\n\r\n", RequestType::Get);
+        assert_eq!(body, serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_indexes() {
+        let (store, key) = new_store();
+        let admin = |text: &str, request_type: RequestType| {
+            let (status_line, body) = admin_request(
+                &request(text),
+                &request_type,
+                &Mutex::new(LockoutTracker::new()),
+                &store,
+                &key,
+                &Metrics::new(),
+            )
+            .unwrap();
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            (status_line, body)
+        };
+        let put_json = |key: &str, body: &str| {
+            store.handle_put_request(&request(&format!(
+                "PUT /{} HTTP/1.1\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\n\r\n{}",
+                key,
+                body.len(),
+                body
+            )));
+        };
+        put_json("jobs:1", r#"{"status": "failed", "tries": 3}"#);
+        put_json("jobs:2", r#"{"status": "done", "tries": 1}"#);
+        store.insert("jobs:3", "failed").unwrap();
+
+        let (status_line, body) = admin(
+            "PUT /admin/indexes?namespace=jobs&field=/status HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body["keys"], 2);
+        let (status_line, _) = admin(
+            "PUT /admin/indexes?namespace=jobs&field=/status HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        assert_eq!(status_line, "HTTP/1.1 409 Conflict");
+        let (status_line, _) = admin(
+            "PUT /admin/indexes?namespace=jobs&field=status HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        assert_eq!(status_line, "HTTP/1.1 400 Bad Request");
+        admin(
+            "PUT /admin/indexes?namespace=jobs&field=/tries HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+
+        let query = |params: &str| {
+            admin(
+                &format!(
+                    "GET /admin/indexes/query?namespace=jobs&{} HTTP/1.1\r\n\r\n",
+                    params
+                ),
+                RequestType::Get,
+            )
+        };
+        let (_, body) = query("field=/status&eq=failed");
+        assert_eq!(body["keys"], serde_json::json!(["jobs:1"]));
+        let (_, body) = query("field=/tries&min=1&lt=3");
+        assert_eq!(body["keys"], serde_json::json!(["jobs:2"]));
+        let (status_line, _) = query("field=/tries&min=1&gt=3");
+        assert_eq!(status_line, "HTTP/1.1 400 Bad Request");
+        let (status_line, _) = query("field=/owner&eq=x");
+        assert_eq!(status_line, "HTTP/1.1 404 NOT FOUND");
+
+        // Writes keep the indexes up to date.
+        put_json("jobs:2", r#"{"status": "failed"}"#);
+        store
+            .handle_delete_request(&request("DELETE /jobs:1 HTTP/1.1\r\n\r\n"));
+        let (_, body) = query("field=/status&eq=failed");
+        assert_eq!(body["keys"], serde_json::json!(["jobs:2"]));
+        let (_, body) = query("field=/tries");
+        assert_eq!(body["keys"], serde_json::json!([]));
+
+        let (status_line, body) = admin(
+            "PUT /admin/indexes/rebuild?namespace=jobs&field=/status HTTP/1.1\r\n\r\n",
+            RequestType::Put,
+        );
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(body["keys"], 1);
+        let (_, body) =
+            admin("GET /admin/indexes HTTP/1.1\r\n\r\n", RequestType::Get);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        let (status_line, _) = admin(
+            "DELETE /admin/indexes?namespace=jobs&field=/tries HTTP/1.1\r\n\r\n",
+            RequestType::Delete,
+        );
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        let (status_line, _) = query("field=/tries&eq=1");
+        assert_eq!(status_line, "HTTP/1.1 404 NOT FOUND");
+
+        // Strings that aren't JSON are compared as they are, once decoded.
+        put_json("jobs:4", r#"{"status": "in progress"}"#);
+        put_json("jobs:5", r#"{"status": "\"quoted\" job"}"#);
+        let (_, body) = query("field=/status&eq=in%20progress");
+        assert_eq!(body["keys"], serde_json::json!(["jobs:4"]));
+        let (_, body) = query("field=/status&eq=%22in%20progress%22");
+        assert_eq!(body["keys"], serde_json::json!(["jobs:4"]));
+        let (_, body) = query("field=/status&eq=%22quoted%22%20job");
+        assert_eq!(body["keys"], serde_json::json!(["jobs:5"]));
+    }
+
+    #[test]
+    fn test_unknown_endpoints() {
+        let (store, key) = new_store();
+        let admin = |text: &str, request_type: RequestType| {
+            admin_request(
+                &request(text),
+                &request_type,
+                &Mutex::new(LockoutTracker::new()),
+                &store,
+                &key,
+                &Metrics::new(),
+            )
+        };
+
+        assert_eq!(
+            admin("PUT /admin/metrics HTTP/1.1\r\n\r\n", RequestType::Put),
+            Err(RouteError::MethodNotAllowed(vec!["GET"]))
+        );
+        assert_eq!(
+            admin("POST /admin/trash HTTP/1.1\r\n\r\n", RequestType::Post),
+            Err(RouteError::MethodNotAllowed(vec!["GET", "DELETE"]))
+        );
+        assert_eq!(
+            admin("GET /admin/nothing HTTP/1.1\r\n\r\n", RequestType::Get),
+            Err(RouteError::NotFound)
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::connection;
+use crate::metrics::Metrics;
+use crate::server::{Response, ResponseStream, Server};
+use crate::stream::{self, BodyStream, EventStream};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use tokio::{task, time};
+use zeroize::Zeroize;
+
+/// How often [`AsyncServer::shutdown`] checks whether the connections are
+/// done.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait before accepting again after accepting failed, e.g.
+/// because the process ran out of file descriptors.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Serves the same API as the thread pool on a tokio runtime.
+///
+/// Every connection is a task, so a client that is slow to send its request
+/// only costs a little memory instead of a worker thread. Requests are still
+/// handled by the blocking [`Server`], on the runtime's blocking threads.
+pub struct AsyncServer {
+    runtime: Runtime,
+    server: Arc<Server>,
+}
+
+impl AsyncServer {
+    pub fn new(server: Arc<Server>) -> io::Result<Self> {
+        let runtime = Builder::new_multi_thread().enable_all().build()?;
+
+        Ok(Self { runtime, server })
+    }
+
+    /// Accept connections on `listener` until `received_signal` is set.
+    ///
+    /// Whoever sets it has to connect to the listener afterwards, as the
+    /// signal is only checked once a connection comes in.
+    pub fn run(
+        &self,
+        listener: std::net::TcpListener,
+        received_signal: &AtomicI32,
+    ) -> io::Result<()> {
+        self.runtime.block_on(async {
+            listener.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(listener)?;
+
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        eprintln!("Failed to accept connection: {}", e);
+                        time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                };
+                if received_signal.load(Ordering::SeqCst) != 0 {
+                    break;
+                }
+
+                // Counted right away, so shutdown waits for the connection
+                // even if its task hasn't started yet.
+                let open = OpenConnection::new(&self.server);
+                let server = Arc::clone(&self.server);
+                tokio::spawn(async move {
+                    handle_connection(&server, stream).await;
+                    drop(open);
+                });
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Wait up to `timeout` for the connections that are still open, then
+    /// stop the runtime.
+    ///
+    /// Returns how many connections were cut off.
+    pub fn shutdown(self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let metrics = self.server.metrics();
+        while metrics.open_connections() > 0 && Instant::now() < deadline {
+            std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
+        let open = metrics.open_connections() as usize;
+        // Requests that are still being handled on a blocking thread can't be
+        // cancelled, don't wait for them.
+        self.runtime.shutdown_background();
+
+        open
+    }
+}
+
+/// A connection that is being served, see [`Metrics::open_connections`].
+struct OpenConnection(Arc<Metrics>);
+
+impl OpenConnection {
+    fn new(server: &Server) -> Self {
+        server.metrics().record_connection_open();
+        Self(Arc::clone(server.metrics()))
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.record_connection_close();
+    }
+}
+
+/// Read a request from the stream, handle it and write the response.
+async fn handle_connection(server: &Arc<Server>, mut stream: TcpStream) {
+    let client = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Error encountered: {}", e);
+            return;
+        }
+    };
+
+    server.metrics().record_request();
+    let request = read_request(&mut stream).await;
+
+    let mut response = match handle_request(server, request, client).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error encountered: {}", e);
+            return;
+        }
+    };
+    match response.stream.take() {
+        Some(ResponseStream::Events(events)) => {
+            send_events(server, stream, &response.head_bytes(), events).await;
+            return;
+        }
+        Some(ResponseStream::Body(body)) => {
+            send_body(stream, &response.head_bytes(), body).await;
+            return;
+        }
+        None => {}
+    }
+
+    let bytes = response.to_bytes();
+    if stream.write_all(&bytes).await.is_err() {
+        eprintln!("Error encountered: Failed to write to stream.");
+    } else if stream.flush().await.is_err() {
+        eprintln!("Error encountered: Failed to flush stream.");
+    }
+}
+
+/// Write the head of a streaming response, then its events as they come in,
+/// like [`Server::handle_connection`]. Waiting for events doesn't take up a
+/// thread.
+async fn send_events(
+    server: &Server,
+    mut stream: TcpStream,
+    head: &[u8],
+    mut events: Box<dyn EventStream>,
+) {
+    if stream.write_all(head).await.is_err() {
+        return;
+    }
+
+    let wakeup = events.wakeup();
+    loop {
+        let seen = wakeup.generation();
+        let frames = match events.poll() {
+            Some(frames) if !server.is_closing() => frames,
+            _ => break,
+        };
+        let frames = match frames.is_empty() {
+            true => {
+                if wakeup.wait_async(seen, stream::KEEPALIVE_INTERVAL).await {
+                    continue;
+                }
+                stream::KEEPALIVE.to_string()
+            }
+            false => frames,
+        };
+
+        if stream.write_all(frames.as_bytes()).await.is_err()
+            || stream.flush().await.is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Write the head of a streaming response, then its body a chunk at a time,
+/// like [`Server::handle_connection`]. Chunks are produced on a blocking
+/// thread.
+async fn send_body(
+    mut stream: TcpStream,
+    head: &[u8],
+    mut body: Box<dyn BodyStream>,
+) {
+    if stream.write_all(head).await.is_err() {
+        return;
+    }
+
+    loop {
+        let next = task::spawn_blocking(move || {
+            let chunk = body.next_chunk();
+            (body, chunk)
+        })
+        .await;
+        let chunk = match next {
+            Ok((next_body, Some(chunk))) => {
+                body = next_body;
+                chunk
+            }
+            _ => break,
+        };
+
+        if stream.write_all(chunk.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+    let _ = stream.flush().await;
+}
+
+/// Hand the request to the server on a blocking thread, as it takes locks
+/// and may decrypt the whole store.
+async fn handle_request(
+    server: &Arc<Server>,
+    request: Result<Vec<u8>, &'static str>,
+    client: SocketAddr,
+) -> Result<Response, task::JoinError> {
+    let server = Arc::clone(server);
+
+    task::spawn_blocking(move || match request {
+        Ok(buf) => server.handle_buffer(buf, client),
+        Err(e) => server.reject(client, e),
+    })
+    .await
+}
+
+/// Read the stream into a buffer, like [`connection::buf_from_stream`].
+async fn read_request(stream: &mut TcpStream) -> Result<Vec<u8>, &'static str> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let read =
+            time::timeout(connection::READ_TIMEOUT, stream.read(&mut chunk))
+                .await;
+        let read = match read {
+            Ok(Ok(read)) => read,
+            _ => return Err("Failed to read stream to buffer"),
+        };
+        buf.extend_from_slice(&chunk[..read]);
+
+        if read == 0 || connection::is_request_complete(&buf)? {
+            break;
+        }
+    }
+    chunk.zeroize();
+
+    Ok(buf)
+}
@@ -0,0 +1,527 @@
+use crate::crypto::{hmac_sha256_hex, SecretKey};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The `prev` hash of the very first record.
+const GENESIS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Who a request was made by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Identity {
+    /// The request carried the store's encryption key.
+    Master,
+    /// The request carried no valid encryption key, e.g. a PUT request or a
+    /// request that failed authentication.
+    Anonymous,
+}
+
+impl Identity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Identity::Master => "master",
+            Identity::Anonymous => "anonymous",
+        }
+    }
+}
+
+/// A single handled request, as seen by the audit log.
+pub struct AuditRecord<'a> {
+    pub client: SocketAddr,
+    pub identity: Identity,
+    pub method: &'a str,
+    /// Key (or endpoint) the request was for. Only its HMAC is logged, see
+    /// [`AuditLog`].
+    pub key: &'a str,
+    /// Status line of the response.
+    pub status_line: &'a str,
+}
+
+/// Append-only, hash-chained log of every request handled by the server.
+///
+/// Each line is a JSON record holding the hash of the record before it, so
+/// modifying or removing any record breaks the chain. Hashes are
+/// HMAC-SHA256 under the audit key, which is kept apart from the log, so
+/// whoever can write the log can't recompute the chain after changing it.
+/// Key names are logged as their HMAC under the same key.
+///
+/// Removing records from the end of the log is caught by the head file next
+/// to the log (`<log>.head`), which holds the sequence number and hash of
+/// the last record as of the last [`AuditLog::sync`], and their HMAC under
+/// the audit key so the head can't be moved back along with the log. See
+/// [`verify`].
+pub struct AuditLog {
+    file: File,
+    key: SecretKey,
+    head_path: PathBuf,
+    seq: u64,
+    last_hash: String,
+    /// Sequence number of the record the head file points to.
+    head_seq: u64,
+}
+
+impl AuditLog {
+    /// Open the audit log at `path` for appending, creating it if needed.
+    ///
+    /// Fails if the last record wasn't written with `key`.
+    pub fn open(path: &Path, key: SecretKey) -> Result<Self, &'static str> {
+        let (seq, last_hash) = match last_record(path)? {
+            Some(mut record) => {
+                let hash = record
+                    .as_object_mut()
+                    .and_then(|record| record.remove("hash"));
+                match hash {
+                    Some(Value::String(hash))
+                        if chain_hash(&key, &record) == hash =>
+                    {
+                        (record["seq"].as_u64().unwrap_or(0), hash)
+                    }
+                    _ => {
+                        return Err("Last record of the audit log wasn't \
+                            written with this audit key.")
+                    }
+                }
+            }
+            None => (0, GENESIS_HASH.to_string()),
+        };
+
+        let head_path = head_path(path);
+        let head = read_head(&head_path, &key);
+        let head_seq = match head {
+            Ok(Some((head_seq, _))) => head_seq,
+            _ => 0,
+        };
+        // The head may lag behind the log, but never be missing, modified or
+        // ahead.
+        if seq > 0 && !matches!(head, Ok(Some(_)) if head_seq <= seq) {
+            eprintln!(
+                "Audit log {} does not match its head file, run `skv audit \
+                verify` to check it.",
+                path.display()
+            );
+        }
+
+        let file = match OpenOptions::new().create(true).append(true).open(path)
+        {
+            Ok(file) => file,
+            Err(_) => return Err("Failed to open audit log."),
+        };
+
+        let mut audit_log = Self {
+            file,
+            key,
+            head_path,
+            seq,
+            last_hash,
+            head_seq,
+        };
+        if matches!(head, Ok(None)) && seq == 0 {
+            audit_log.write_head()?;
+        }
+
+        Ok(audit_log)
+    }
+
+    /// Append a record to the log.
+    pub fn record(&mut self, record: &AuditRecord) -> Result<(), &'static str> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        let outcome = record
+            .status_line
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or("unknown");
+
+        let mut entry = json!({
+            "seq": self.seq + 1,
+            "timestamp": timestamp,
+            "client": record.client.to_string(),
+            "identity": record.identity.as_str(),
+            "method": record.method,
+            "key": hmac_sha256_hex(&self.key, record.key.as_bytes()),
+            "outcome": outcome,
+            "prev": self.last_hash,
+        });
+        let hash = chain_hash(&self.key, &entry);
+        entry["hash"] = Value::from(hash.clone());
+
+        if writeln!(self.file, "{}", entry).is_err() {
+            return Err("Failed to write to audit log.");
+        }
+
+        self.seq += 1;
+        self.last_hash = hash;
+        Ok(())
+    }
+
+    /// Flush the log to disk and move the head file to its last record.
+    ///
+    /// Called every now and then and on shutdown rather than per record, so
+    /// the head may lag a few records behind the log.
+    pub fn sync(&mut self) -> Result<(), &'static str> {
+        if self.file.sync_all().is_err() {
+            return Err("Failed to sync audit log.");
+        }
+
+        match self.head_seq == self.seq {
+            true => Ok(()),
+            false => self.write_head(),
+        }
+    }
+
+    fn write_head(&mut self) -> Result<(), &'static str> {
+        write_head(&self.head_path, &self.key, self.seq, &self.last_hash)?;
+        self.head_seq = self.seq;
+        Ok(())
+    }
+}
+
+/// Verify the audit log at `path` with the key it was written with.
+///
+/// Checks that every record links to the one before it, that no record was
+/// modified, and that the log goes on at least up to the record its head
+/// file points to. Returns the number of records and the hash of the last
+/// one.
+pub fn verify(path: &Path, key: &SecretKey) -> Result<(u64, String), String> {
+    let file = File::open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let head = read_head(&head_path(path), key)?;
+
+    let mut seq = 0;
+    let mut last_hash = GENESIS_HASH.to_string();
+    let mut head_hash = None;
+
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line_number = line_number + 1;
+        let line = line.map_err(|e| {
+            format!("Failed to read line {}: {}", line_number, e)
+        })?;
+
+        let mut record: Value = serde_json::from_str(&line).map_err(|_| {
+            format!("Line {} is not a valid record.", line_number)
+        })?;
+        let hash = match record.as_object_mut().and_then(|r| r.remove("hash")) {
+            Some(Value::String(hash)) => hash,
+            _ => return Err(format!("Line {} has no hash.", line_number)),
+        };
+
+        if record["seq"].as_u64() != Some(seq + 1) {
+            return Err(format!(
+                "Line {} is out of sequence, records were removed or \
+                reordered.",
+                line_number
+            ));
+        }
+        if record["prev"].as_str() != Some(last_hash.as_str()) {
+            return Err(format!(
+                "Line {} does not link to the record before it.",
+                line_number
+            ));
+        }
+        if chain_hash(key, &record) != hash {
+            return Err(format!("Line {} was modified.", line_number));
+        }
+
+        seq += 1;
+        last_hash = hash;
+        if head.as_ref().is_some_and(|(head_seq, _)| *head_seq == seq) {
+            head_hash = Some(last_hash.clone());
+        }
+    }
+
+    match head {
+        Some((head_seq, _)) if head_seq > seq => Err(format!(
+            "Log was truncated, it has {} records but should have at least \
+            {}.",
+            seq, head_seq
+        )),
+        Some((0, hash)) if hash == GENESIS_HASH => Ok((seq, last_hash)),
+        Some((_, hash)) if head_hash.as_ref() == Some(&hash) => {
+            Ok((seq, last_hash))
+        }
+        Some(_) => Err("Log does not match its head file.".to_string()),
+        None if seq == 0 => Ok((seq, last_hash)),
+        None => Err("Head file is missing.".to_string()),
+    }
+}
+
+/// HMAC of a record (without its own `hash` field) chained to the previous
+/// record through the record's `prev` field.
+fn chain_hash(key: &SecretKey, record: &Value) -> String {
+    hmac_sha256_hex(key, record.to_string().as_bytes())
+}
+
+/// HMAC of the head file's sequence number and hash.
+fn head_mac(key: &SecretKey, seq: u64, hash: &str) -> String {
+    hmac_sha256_hex(key, format!("head {} {}", seq, hash).as_bytes())
+}
+
+/// Check that the audit key file isn't kept in the audit log's directory,
+/// where whoever can change the log could read it as well.
+pub fn check_key_location(
+    log_path: &Path,
+    key_path: &Path,
+) -> Result<(), &'static str> {
+    let directory = |path: &Path| {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::canonicalize(parent).ok()
+    };
+
+    match (directory(log_path), directory(key_path)) {
+        (Some(log_dir), Some(key_dir)) if log_dir != key_dir => Ok(()),
+        (Some(_), Some(_)) => {
+            Err("The audit key file must not be in the audit log's directory.")
+        }
+        _ => Err("The directory of the audit log or its key doesn't exist."),
+    }
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut head_path = path.as_os_str().to_owned();
+    head_path.push(".head");
+    PathBuf::from(head_path)
+}
+
+/// Sequence number and hash of the record the head file points to, or `None`
+/// if there is no head file.
+///
+/// Fails if the head wasn't written with `key`, or was changed since.
+fn read_head(
+    head_path: &Path,
+    key: &SecretKey,
+) -> Result<Option<(u64, String)>, &'static str> {
+    let head = match fs::read_to_string(head_path) {
+        Ok(head) => head,
+        Err(_) => return Ok(None),
+    };
+
+    let mut fields = head.split_whitespace();
+    let head = match (fields.next(), fields.next(), fields.next()) {
+        (Some(seq), Some(hash), Some(mac)) => match seq.parse() {
+            Ok(seq) => (seq, hash.to_string(), mac),
+            Err(_) => return Err("Head file is malformed."),
+        },
+        _ => return Err("Head file is malformed."),
+    };
+    if head_mac(key, head.0, &head.1) != head.2 {
+        return Err("Head file was modified or written with another key.");
+    }
+
+    Ok(Some((head.0, head.1)))
+}
+
+/// Replace the head file. The new head is written next to the old one and
+/// renamed over it, so the head file is never half written.
+fn write_head(
+    head_path: &Path,
+    key: &SecretKey,
+    seq: u64,
+    hash: &str,
+) -> Result<(), &'static str> {
+    let mut tmp_path = head_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let head = format!("{} {} {}\n", seq, hash, head_mac(key, seq, hash));
+    if fs::write(&tmp_path, head).is_err()
+        || fs::rename(&tmp_path, head_path).is_err()
+    {
+        return Err("Failed to write audit log head.");
+    }
+
+    Ok(())
+}
+
+fn last_record(path: &Path) -> Result<Option<Value>, &'static str> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+
+    let last_line =
+        match BufReader::new(file).lines().map_while(Result::ok).last() {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+    match serde_json::from_str(&last_line) {
+        Ok(record) => Ok(Some(record)),
+        Err(_) => Err("Last record of the audit log is malformed."),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const AUDIT_KEY: &str =
+        "606edace3053c4e9222515b7ba0e16e41648c40c56860edb464f813cd53c5726";
+
+    fn key() -> SecretKey {
+        SecretKey::from_hex(AUDIT_KEY).unwrap()
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "skv-test-audit-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn write_records(path: &Path, count: usize) {
+        let mut log = AuditLog::open(path, key()).unwrap();
+        for _ in 0..count {
+            log.record(&AuditRecord {
+                client: "127.0.0.1:50000".parse().unwrap(),
+                identity: Identity::Master,
+                method: "GET",
+                key: "SampleKey",
+                status_line: "HTTP/1.1 200 OK",
+            })
+            .unwrap();
+        }
+        log.sync().unwrap();
+    }
+
+    fn cleanup(path: &Path) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(head_path(path));
+    }
+
+    #[test]
+    fn test_verify_intact_log() {
+        let path = log_path("intact");
+        write_records(&path, 2);
+        // Reopening continues the existing chain.
+        write_records(&path, 1);
+
+        let result = verify(&path, &key());
+        let contents = fs::read_to_string(&path).unwrap();
+        cleanup(&path);
+
+        assert_eq!(result.unwrap().0, 3);
+        assert!(!contents.contains("SampleKey"));
+        assert!(contents.contains(&hmac_sha256_hex(&key(), b"SampleKey")));
+        assert!(!contents.contains(&crate::crypto::sha256_hex(b"SampleKey")));
+    }
+
+    #[test]
+    fn test_head_is_written_on_sync() {
+        let path = log_path("unsynced");
+        write_records(&path, 1);
+        let mut log = AuditLog::open(&path, key()).unwrap();
+        log.record(&AuditRecord {
+            client: "127.0.0.1:50000".parse().unwrap(),
+            identity: Identity::Anonymous,
+            method: "PUT",
+            key: "SampleKey",
+            status_line: "HTTP/1.1 200 OK",
+        })
+        .unwrap();
+
+        // The head still points to the first record, which is fine.
+        let head = read_head(&head_path(&path), &key()).unwrap().unwrap();
+        let result = verify(&path, &key());
+        log.sync().unwrap();
+        let synced = read_head(&head_path(&path), &key()).unwrap().unwrap();
+        cleanup(&path);
+
+        assert_eq!(head.0, 1);
+        assert_eq!(result.unwrap().0, 2);
+        assert_eq!(synced.0, 2);
+    }
+
+    #[test]
+    fn test_verify_needs_the_audit_key() {
+        let path = log_path("other-key");
+        write_records(&path, 1);
+
+        let other = crate::crypto::generate_key();
+        let verified = verify(&path, &other);
+        let opened = AuditLog::open(&path, crate::crypto::generate_key());
+        cleanup(&path);
+
+        assert_eq!(
+            verified.unwrap_err(),
+            "Head file was modified or written with another key."
+        );
+        assert!(opened.is_err());
+    }
+
+    #[test]
+    fn test_verify_detects_modification() {
+        let path = log_path("modified");
+        write_records(&path, 3);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replacen("\"200\"", "\"404\"", 1)).unwrap();
+
+        let result = verify(&path, &key());
+        cleanup(&path);
+
+        assert_eq!(result.unwrap_err(), "Line 1 was modified.");
+    }
+
+    #[test]
+    fn test_verify_detects_truncation() {
+        let path = log_path("truncated");
+        write_records(&path, 3);
+        let contents = fs::read_to_string(&path).unwrap();
+        let truncated: Vec<&str> = contents.lines().take(2).collect();
+        fs::write(&path, truncated.join("\n") + "\n").unwrap();
+
+        let result = verify(&path, &key());
+        cleanup(&path);
+
+        assert!(result.unwrap_err().starts_with("Log was truncated"));
+    }
+
+    #[test]
+    fn test_verify_detects_truncation_with_a_rewritten_head() {
+        let path = log_path("truncated-head");
+        write_records(&path, 3);
+        let head = fs::read_to_string(head_path(&path)).unwrap();
+        let old_mac = head.split_whitespace().nth(2).unwrap().to_string();
+        let contents = fs::read_to_string(&path).unwrap();
+        let truncated: Vec<&str> = contents.lines().take(2).collect();
+        fs::write(&path, truncated.join("\n") + "\n").unwrap();
+
+        // The head is moved back to the new last record, without a MAC or
+        // with the one of the old head.
+        let record: Value = serde_json::from_str(truncated[1]).unwrap();
+        let hash = record["hash"].as_str().unwrap();
+        fs::write(head_path(&path), format!("2 {}\n", hash)).unwrap();
+        let without_mac = verify(&path, &key());
+        fs::write(head_path(&path), format!("2 {} {}\n", hash, old_mac))
+            .unwrap();
+        let with_old_mac = verify(&path, &key());
+        let opened = AuditLog::open(&path, key());
+        cleanup(&path);
+
+        assert_eq!(without_mac.unwrap_err(), "Head file is malformed.");
+        assert_eq!(
+            with_old_mac.unwrap_err(),
+            "Head file was modified or written with another key."
+        );
+        // The server still starts, with a warning.
+        assert!(opened.is_ok());
+    }
+
+    #[test]
+    fn test_key_must_not_be_next_to_the_log() {
+        let dir = std::env::temp_dir();
+        let log = dir.join("audit.log");
+
+        assert!(check_key_location(&log, &dir.join("audit.key")).is_err());
+        assert!(check_key_location(&log, Path::new("/audit.key")).is_ok());
+    }
+}
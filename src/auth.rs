@@ -0,0 +1,114 @@
+use crate::connection::{json_error, parse_encryption_key_from_headers};
+use crate::crypto::SecretKey;
+
+/// Why a request failed authentication.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// No `key` header was sent with the request.
+    MissingKey,
+    /// A `key` header was sent, but it isn't the store's encryption key.
+    WrongKey,
+}
+
+impl AuthError {
+    /// Status line and JSON body to answer the failed request with.
+    ///
+    /// A missing key is a 401, a wrong key is a 403.
+    pub fn response(&self) -> (String, String) {
+        match self {
+            AuthError::MissingKey => (
+                "HTTP/1.1 401 Unauthorized".to_string(),
+                json_error(
+                    "User encryption key not provided in request headers.",
+                ),
+            ),
+            AuthError::WrongKey => (
+                "HTTP/1.1 403 Forbidden".to_string(),
+                json_error("Invalid encryption key! Check your key."),
+            ),
+        }
+    }
+}
+
+/// Validate the `key` header of a request against the store's encryption key.
+///
+/// This is done once per request, before the request is handed to the
+/// key-value store. The comparison is constant time and happens before the
+/// provided key is used for anything else, so a wrong key never reaches
+/// decryption.
+pub fn authenticate(
+    buf: &[u8],
+    encryption_key: &SecretKey,
+) -> Result<(), AuthError> {
+    let user_provided_encryption_key =
+        match parse_encryption_key_from_headers(buf) {
+            Ok(key) => key,
+            Err(_) => return Err(AuthError::MissingKey),
+        };
+
+    // A key that isn't even valid hex is just as wrong as any other key.
+    let user_provided_encryption_key =
+        match SecretKey::from_hex(&user_provided_encryption_key) {
+            Ok(key) => key,
+            Err(_) => return Err(AuthError::WrongKey),
+        };
+
+    if user_provided_encryption_key != *encryption_key {
+        return Err(AuthError::WrongKey);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::generate_key;
+
+    fn request(text: &str) -> [u8; 1024] {
+        let mut buf = [0; 1024];
+        buf[..text.len()].copy_from_slice(text.as_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let encryption_key = generate_key();
+        let other_key = generate_key();
+
+        let missing = request("GET /ls HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let wrong = request(&format!(
+            "GET /ls HTTP/1.1\r\nkey: {}\r\n\r\n",
+            other_key.to_hex().as_str()
+        ));
+        let malformed = request("GET /ls HTTP/1.1\r\nkey: nope\r\n\r\n");
+        let right = request(&format!(
+            "GET /ls HTTP/1.1\r\nkey: {}\r\n\r\n",
+            encryption_key.to_hex().as_str()
+        ));
+
+        assert_eq!(
+            authenticate(&missing, &encryption_key),
+            Err(AuthError::MissingKey)
+        );
+        assert_eq!(
+            authenticate(&wrong, &encryption_key),
+            Err(AuthError::WrongKey)
+        );
+        assert_eq!(
+            authenticate(&malformed, &encryption_key),
+            Err(AuthError::WrongKey)
+        );
+        assert_eq!(authenticate(&right, &encryption_key), Ok(()));
+    }
+
+    #[test]
+    fn test_auth_error_response() {
+        let (status_line, body) = AuthError::MissingKey.response();
+        assert_eq!(status_line, "HTTP/1.1 401 Unauthorized");
+        assert!(body.starts_with("{\"error\":"));
+
+        let (status_line, _) = AuthError::WrongKey.response();
+        assert_eq!(status_line, "HTTP/1.1 403 Forbidden");
+    }
+}
@@ -0,0 +1,565 @@
+use crate::crypto::{
+    decrypt_with_associated_data, derive_key, encrypt_with_associated_data,
+    random_bytes, SecretKey,
+};
+use serde_json::{json, Value};
+use std::io::{BufRead, Lines};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+/// Identifies a file as an skv backup archive.
+const FORMAT: &str = "skv-backup";
+/// Archive format version written and read by this build.
+const VERSION: u64 = 2;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+/// Iterations an archive may ask for. The header is only authenticated once
+/// the key has been derived, so it must neither make a restore spend minutes
+/// on the key nor get away with a key that is cheap to guess.
+const PBKDF2_ITERATIONS_RANGE: RangeInclusive<u64> = 10_000..=1_000_000;
+const SALT_SIZE: usize = 16;
+/// Bytes of the random id of an archive.
+const ID_SIZE: usize = 16;
+const DECRYPT_ERROR: &str = "Failed to decrypt archive! Check your key or \
+    passphrase, or the archive was modified.";
+
+/// What an archive is encrypted with.
+pub enum ArchiveKey<'a> {
+    /// The store's encryption key. Such an archive can only be restored by a
+    /// server that uses the same key.
+    Master(&'a Arc<SecretKey>),
+    /// A key derived from a passphrase, to move data between servers.
+    Passphrase(&'a str),
+}
+
+/// What restoring an archive does with the data already in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Keep existing keys, overwriting those that are also in the archive.
+    Merge,
+    /// Remove existing keys (of the restored namespaces) first.
+    Replace,
+}
+
+impl RestoreMode {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "merge" => Ok(RestoreMode::Merge),
+            "replace" => Ok(RestoreMode::Replace),
+            _ => Err("Restore mode must be either 'merge' or 'replace'."),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RestoreMode::Merge => "merge",
+            RestoreMode::Replace => "replace",
+        }
+    }
+}
+
+/// Writes an encrypted archive a chunk at a time, so that a backup of the
+/// whole store never has to be held in memory.
+///
+/// The archive is a file of JSON lines. The first line is a plaintext header
+/// describing the archive. Every other line is a chunk: a JSON object with
+/// its position and the encrypted entries in its `ciphertext` field, as
+/// NDJSON. Each chunk is authenticated together with the header and its
+/// position, and the last one records how many entries the archive has, so
+/// chunks can't be tampered with, reordered, moved between archives or cut
+/// off unnoticed.
+pub struct ArchiveWriter {
+    /// The header line, authenticated with every chunk.
+    header: String,
+    key: HeldKey,
+    /// Chunks written so far.
+    chunks: u64,
+    /// Entries written so far.
+    entries: u64,
+}
+
+impl ArchiveWriter {
+    /// `namespaces` is only recorded in the header, the entries have to be
+    /// filtered already.
+    pub fn new(namespaces: &[String], key: ArchiveKey) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+
+        let mut header = json!({
+            "format": FORMAT,
+            "version": VERSION,
+            // Tells archives made with the same key apart, so that chunks
+            // can't be moved from one to the other.
+            "id": hex::encode(random_bytes(ID_SIZE)),
+            "created": created,
+            "cipher": "aes-256-gcm",
+            "namespaces": namespaces,
+        });
+
+        let key = match key {
+            ArchiveKey::Master(key) => {
+                header["key"] = Value::from("master");
+                HeldKey::Shared(Arc::clone(key))
+            }
+            ArchiveKey::Passphrase(passphrase) => {
+                let salt = random_bytes(SALT_SIZE);
+                header["key"] = Value::from("passphrase");
+                header["kdf"] = json!({
+                    "name": "pbkdf2-hmac-sha256",
+                    "iterations": PBKDF2_ITERATIONS,
+                    "salt": hex::encode(&salt),
+                });
+                HeldKey::Derived(derive_key(
+                    passphrase,
+                    &salt,
+                    PBKDF2_ITERATIONS,
+                ))
+            }
+        };
+
+        Self {
+            header: header.to_string(),
+            key,
+            chunks: 0,
+            entries: 0,
+        }
+    }
+
+    /// The first line of the archive.
+    pub fn header(&self) -> String {
+        format!("{}\n", self.header)
+    }
+
+    /// The next line of the archive, holding `entries`.
+    pub fn chunk(
+        &mut self,
+        entries: &[(String, String)],
+    ) -> Result<String, &'static str> {
+        self.write_chunk(entries, false)
+    }
+
+    /// The last line of the archive. Without it, the archive is considered
+    /// cut off.
+    pub fn finish(mut self) -> Result<String, &'static str> {
+        self.write_chunk(&[], true)
+    }
+
+    fn write_chunk(
+        &mut self,
+        entries: &[(String, String)],
+        last: bool,
+    ) -> Result<String, &'static str> {
+        let mut plaintext = Zeroizing::new(String::new());
+        for (key, value) in entries {
+            plaintext
+                .push_str(&json!({ "key": key, "value": value }).to_string());
+            plaintext.push('\n');
+        }
+        self.entries += entries.len() as u64;
+
+        let mut chunk = json!({ "chunk": self.chunks });
+        if last {
+            chunk["last"] = Value::from(true);
+            chunk["entries"] = Value::from(self.entries);
+        }
+        let ciphertext = encrypt_with_associated_data(
+            plaintext.as_bytes(),
+            &associated_data(&self.header, &chunk),
+            self.key.get(),
+        )?;
+        chunk["ciphertext"] = Value::from(hex::encode(ciphertext));
+        self.chunks += 1;
+
+        Ok(format!("{}\n", chunk))
+    }
+}
+
+/// Pack key-value pairs into an archive in one go, see [`ArchiveWriter`].
+pub fn create_archive(
+    entries: &[(String, String)],
+    namespaces: &[String],
+    key: ArchiveKey,
+) -> Result<String, &'static str> {
+    let mut writer = ArchiveWriter::new(namespaces, key);
+    let mut archive = writer.header();
+    archive.push_str(&writer.chunk(entries)?);
+    archive.push_str(&writer.finish()?);
+
+    Ok(archive)
+}
+
+/// Reads an archive made by [`ArchiveWriter`] a chunk at a time, checking
+/// every chunk as it is read, so that the archive never has to be held in
+/// memory in full.
+pub struct ArchiveReader<R> {
+    lines: Lines<R>,
+    /// The header line, authenticated with every chunk.
+    header: String,
+    key: HeldKey,
+    /// Chunks read so far.
+    chunks: u64,
+    /// Entries read so far.
+    entries: u64,
+    /// Whether the last chunk has been read.
+    done: bool,
+}
+
+impl<R: BufRead> ArchiveReader<R> {
+    /// Read the header of the archive and get the key to decrypt it with.
+    pub fn new(archive: R, key: ArchiveKey) -> Result<Self, &'static str> {
+        let mut lines = archive.lines();
+        let header_line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => return Err("Archive is not a valid skv backup."),
+        };
+        let header: Value = match serde_json::from_str(&header_line) {
+            Ok(header) => header,
+            Err(_) => return Err("Archive is not a valid skv backup."),
+        };
+
+        if header["format"] != FORMAT {
+            return Err("Archive is not a valid skv backup.");
+        }
+        match header["version"].as_u64() {
+            Some(VERSION) => (),
+            Some(version) if version > VERSION => {
+                return Err("Archive was made by a newer version of skv.")
+            }
+            _ => return Err("Archive is not a valid skv backup."),
+        }
+        let key = archive_key(&header, key)?;
+
+        Ok(Self {
+            lines,
+            header: header_line,
+            key,
+            chunks: 0,
+            entries: 0,
+            done: false,
+        })
+    }
+
+    /// Read the same archive again from its start, without deriving its key
+    /// again, e.g. to apply it once it has been checked in full.
+    ///
+    /// Fails if `archive` has another header, so it isn't the same archive.
+    pub fn reopen<S: BufRead>(
+        self,
+        archive: S,
+    ) -> Result<ArchiveReader<S>, &'static str> {
+        let mut lines = archive.lines();
+        match lines.next() {
+            Some(Ok(line)) if line == self.header => (),
+            _ => return Err("Archive changed while it was being read."),
+        }
+
+        Ok(ArchiveReader {
+            lines,
+            header: self.header,
+            key: self.key,
+            chunks: 0,
+            entries: 0,
+            done: false,
+        })
+    }
+
+    /// The key-value pairs of the next chunk, or `None` once the archive
+    /// has been read in full.
+    ///
+    /// Fails if the chunk was tampered with, is out of place, or the archive
+    /// is cut off. Chunks read before then are valid, but the archive as a
+    /// whole isn't.
+    pub fn next_chunk(
+        &mut self,
+    ) -> Result<Option<Vec<(String, String)>>, &'static str> {
+        let line = match self.lines.next() {
+            Some(Ok(_)) if self.done => {
+                return Err("Archive has data after its last chunk.")
+            }
+            Some(Ok(line)) => line,
+            Some(Err(_)) => return Err("Failed to read archive."),
+            None if self.done => return Ok(None),
+            None => {
+                return Err("Archive is cut off, its last chunk is missing.")
+            }
+        };
+
+        let mut chunk: Value = match serde_json::from_str(&line) {
+            Ok(chunk) => chunk,
+            Err(_) => return Err("Archive contains a malformed chunk."),
+        };
+        let ciphertext = take_ciphertext(&mut chunk)?;
+        if chunk["chunk"].as_u64() != Some(self.chunks) {
+            return Err("Archive chunks are missing or out of order.");
+        }
+
+        let plaintext = decrypt_with_associated_data(
+            &ciphertext,
+            &associated_data(&self.header, &chunk),
+            self.key.get(),
+        )
+        .map_err(|_| DECRYPT_ERROR)?;
+        let mut entries = Vec::new();
+        parse_entries(&plaintext, &mut entries)?;
+        self.chunks += 1;
+        self.entries += entries.len() as u64;
+
+        if chunk["last"] == true {
+            if chunk["entries"].as_u64() != Some(self.entries) {
+                return Err(
+                    "Archive does not contain the expected number of entries.",
+                );
+            }
+            self.done = true;
+        }
+
+        Ok(Some(entries))
+    }
+}
+
+/// Decrypt and check an archive made by [`ArchiveWriter`] in one go, see
+/// [`ArchiveReader`].
+///
+/// Returns the key-value pairs in the archive. Every chunk is checked before
+/// anything is returned, so an archive that is only partly valid isn't
+/// restored at all.
+pub fn open_archive(
+    archive: impl BufRead,
+    key: ArchiveKey,
+) -> Result<Vec<(String, String)>, &'static str> {
+    let mut reader = ArchiveReader::new(archive, key)?;
+    let mut entries = Vec::new();
+    while let Some(chunk) = reader.next_chunk()? {
+        entries.extend(chunk);
+    }
+
+    Ok(entries)
+}
+
+/// What every chunk is authenticated with, see [`ArchiveWriter`].
+fn associated_data(header: &str, chunk: &Value) -> Vec<u8> {
+    format!("{}\n{}", header, chunk).into_bytes()
+}
+
+/// Remove the hex encoded `ciphertext` field, leaving what it was
+/// authenticated with.
+fn take_ciphertext(object: &mut Value) -> Result<Vec<u8>, &'static str> {
+    let ciphertext = match object
+        .as_object_mut()
+        .and_then(|object| object.remove("ciphertext"))
+    {
+        Some(Value::String(ciphertext)) => ciphertext,
+        _ => return Err("Archive has no ciphertext."),
+    };
+
+    match hex::decode(ciphertext) {
+        Ok(ciphertext) => Ok(ciphertext),
+        Err(_) => Err("Hex decode failed to decode archive."),
+    }
+}
+
+/// The key to decrypt the archive with, checking that it is the kind the
+/// header asks for.
+fn archive_key(
+    header: &Value,
+    key: ArchiveKey,
+) -> Result<HeldKey, &'static str> {
+    match (header["key"].as_str(), key) {
+        (Some("master"), ArchiveKey::Master(key)) => {
+            Ok(HeldKey::Shared(Arc::clone(key)))
+        }
+        (Some("passphrase"), ArchiveKey::Passphrase(passphrase)) => {
+            let kdf = &header["kdf"];
+            let salt = kdf["salt"].as_str().and_then(|s| hex::decode(s).ok());
+            let iterations = kdf["iterations"].as_u64();
+            match (salt, iterations) {
+                (Some(salt), Some(iterations))
+                    if PBKDF2_ITERATIONS_RANGE.contains(&iterations) =>
+                {
+                    Ok(HeldKey::Derived(derive_key(
+                        passphrase,
+                        &salt,
+                        iterations as u32,
+                    )))
+                }
+                _ => Err("Archive has invalid key derivation parameters."),
+            }
+        }
+        (Some("master"), _) => {
+            Err("Archive is encrypted with the master key, not a passphrase.")
+        }
+        (Some("passphrase"), _) => Err(
+            "Archive is encrypted with a passphrase, provide it to restore.",
+        ),
+        _ => Err("Archive is encrypted with an unknown key."),
+    }
+}
+
+/// Append the NDJSON entries of a decrypted archive to `entries`.
+fn parse_entries(
+    plaintext: &[u8],
+    entries: &mut Vec<(String, String)>,
+) -> Result<(), &'static str> {
+    let plaintext = match std::str::from_utf8(plaintext) {
+        Ok(plaintext) => plaintext,
+        Err(_) => return Err("Archive contents are not valid UTF-8."),
+    };
+
+    for line in plaintext.lines() {
+        let entry: Value = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(_) => return Err("Archive contains a malformed entry."),
+        };
+        match (entry["key"].as_str(), entry["value"].as_str()) {
+            (Some(key), Some(value)) => {
+                entries.push((key.to_string(), value.to_string()))
+            }
+            _ => return Err("Archive contains a malformed entry."),
+        }
+    }
+
+    Ok(())
+}
+
+/// The key an archive is encrypted with, once it is known.
+enum HeldKey {
+    Shared(Arc<SecretKey>),
+    Derived(SecretKey),
+}
+
+impl HeldKey {
+    fn get(&self) -> &SecretKey {
+        match self {
+            HeldKey::Shared(key) => key,
+            HeldKey::Derived(key) => key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::generate_key;
+
+    fn sample_entries() -> Vec<(String, String)> {
+        vec![
+            ("users:alice".to_string(), "{\"admin\": true}".to_string()),
+            ("motd".to_string(), "multi\nline ünïcode".to_string()),
+        ]
+    }
+
+    /// An archive with a chunk for every sample entry, as lines.
+    fn chunked_archive(key: &Arc<SecretKey>) -> Vec<String> {
+        let mut writer = ArchiveWriter::new(&[], ArchiveKey::Master(key));
+        let mut lines = vec![writer.header()];
+        for entry in sample_entries() {
+            lines.push(writer.chunk(&[entry]).unwrap());
+        }
+        lines.push(writer.finish().unwrap());
+
+        lines
+    }
+
+    #[test]
+    fn test_master_key_round_trip() {
+        let key = Arc::new(generate_key());
+        let archive =
+            create_archive(&sample_entries(), &[], ArchiveKey::Master(&key))
+                .unwrap();
+
+        let entries =
+            open_archive(archive.as_bytes(), ArchiveKey::Master(&key)).unwrap();
+        assert_eq!(entries, sample_entries());
+
+        let other_key = Arc::new(generate_key());
+        assert!(open_archive(
+            archive.as_bytes(),
+            ArchiveKey::Master(&other_key)
+        )
+        .is_err());
+        assert!(open_archive(
+            archive.as_bytes(),
+            ArchiveKey::Passphrase("key")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_passphrase_round_trip() {
+        let archive = create_archive(
+            &sample_entries(),
+            &[],
+            ArchiveKey::Passphrase("hunter2"),
+        )
+        .unwrap();
+
+        let entries =
+            open_archive(archive.as_bytes(), ArchiveKey::Passphrase("hunter2"))
+                .unwrap();
+        assert_eq!(entries, sample_entries());
+        assert!(open_archive(
+            archive.as_bytes(),
+            ArchiveKey::Passphrase("hunter3")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_tampered_archives_are_rejected() {
+        let key = Arc::new(generate_key());
+        let open = |lines: &[String]| {
+            open_archive(lines.concat().as_bytes(), ArchiveKey::Master(&key))
+        };
+        let lines = chunked_archive(&key);
+        assert_eq!(open(&lines).unwrap(), sample_entries());
+
+        // The header is authenticated with every chunk.
+        let mut tampered = lines.clone();
+        tampered[0] = tampered[0].replace("[]", "[\"users\"]");
+        assert_ne!(tampered, lines);
+        assert!(open(&tampered).is_err());
+
+        // Chunks can't be reordered, dropped or cut off.
+        let mut reordered = lines.clone();
+        reordered.swap(1, 2);
+        assert!(open(&reordered).is_err());
+        let dropped = [&lines[..1], &lines[2..]].concat();
+        assert!(open(&dropped).is_err());
+        assert_eq!(
+            open(&lines[..3]),
+            Err("Archive is cut off, its last chunk is missing.")
+        );
+
+        // Nor moved from another archive made with the same key.
+        let mut moved = lines.clone();
+        moved[1] = chunked_archive(&key)[1].clone();
+        assert!(open(&moved).is_err());
+    }
+
+    #[test]
+    fn test_iterations_out_of_range_are_rejected() {
+        let archive = create_archive(
+            &sample_entries(),
+            &[],
+            ArchiveKey::Passphrase("hunter2"),
+        )
+        .unwrap();
+
+        for iterations in ["1", "9999", "1000001", "4294967295"] {
+            let tampered = archive.replace(
+                "\"iterations\":100000",
+                &format!("\"iterations\":{}", iterations),
+            );
+            assert_ne!(archive, tampered);
+            assert_eq!(
+                open_archive(
+                    tampered.as_bytes(),
+                    ArchiveKey::Passphrase("hunter2")
+                ),
+                Err("Archive has invalid key derivation parameters.")
+            );
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+/// A response received from an skv server.
+pub struct ClientResponse {
+    pub status: u16,
+    /// Header names and values, in the order they were sent.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl ClientResponse {
+    /// Value of the first header called `name` (case insensitive), if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A response whose body hasn't been read yet.
+pub struct StreamingResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// The rest of the response. The server closes the connection once the
+    /// response is written, so the body ends where the stream does.
+    pub body: BufReader<TcpStream>,
+}
+
+/// Escape a query parameter value, the reverse of
+/// [`crate::connection::percent_decode`].
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~'
+            | b':'
+            | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Send a single request to the skv server on localhost and wait for the
+/// response.
+///
+/// Used by the subcommands that work with a running server, e.g.
+/// `skv backup`.
+pub fn send_request(
+    port: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<ClientResponse, &'static str> {
+    let mut response =
+        send_request_streaming(port, method, path, headers, body)?;
+
+    let mut body = Vec::new();
+    if response.body.read_to_end(&mut body).is_err() {
+        return Err("Failed to read response from skv server.");
+    }
+
+    Ok(ClientResponse {
+        status: response.status,
+        headers: response.headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
+
+/// Like [`send_request`], but only reads the status and headers of the
+/// response, so large bodies can be processed as they arrive.
+pub fn send_request_streaming(
+    port: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<StreamingResponse, &'static str> {
+    let mut stream = match TcpStream::connect(format!("localhost:{}", port)) {
+        Ok(stream) => stream,
+        Err(_) => return Err("Failed to connect to skv server."),
+    };
+
+    let mut request =
+        format!("{} /{} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str(&format!(
+        "Content-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    ));
+
+    if stream.write_all(request.as_bytes()).is_err() {
+        return Err("Failed to send request to skv server.");
+    }
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    if reader.read_line(&mut status_line).is_err() {
+        return Err("Failed to read response from skv server.");
+    }
+    let status = match status_line.split_whitespace().nth(1).map(str::parse) {
+        Some(Ok(status)) => status,
+        _ => return Err("Malformed response from skv server."),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        match reader.read_line(&mut header) {
+            Ok(0) => return Err("Malformed response from skv server."),
+            Ok(_) if header == "\r\n" => break,
+            Ok(_) => {
+                if let Some((name, value)) = header.split_once(':') {
+                    headers.push((name.to_string(), value.trim().to_string()));
+                }
+            }
+            Err(_) => return Err("Failed to read response from skv server."),
+        }
+    }
+
+    Ok(StreamingResponse {
+        status,
+        headers,
+        body: reader,
+    })
+}
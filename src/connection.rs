@@ -1,12 +1,23 @@
-use crate::crypto::{decrypt, encrypt, generate_key};
+use crate::crypto::{decrypt, encrypt, generate_key, SecretKey};
+use crate::history::{HistoryEntry, Retention, RetentionPolicy};
+use crate::index::Indexes;
+use crate::patch::{Number, Patch, PatchError};
+use crate::typed::{TypedValue, ValueType};
+use crate::watch::{EventKind, Events};
 use regex::Regex;
 use std::{
     collections::HashMap,
-    fs,
     io::{Read, Write},
     net::TcpStream,
-    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
+use zeroize::{Zeroize, Zeroizing};
+
+/// How long to wait for a client to send its request.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest request, headers and body, that is read from a stream.
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
 pub struct DataObject {
@@ -23,127 +34,233 @@ impl DataObject {
     }
 }
 
+/// A response of the key-value store.
+pub struct StoreResponse {
+    pub status_line: String,
+    pub body: String,
+    /// Store version the response is about, e.g. the version a value was
+    /// written in. See [`crate::store::ShardedStore`].
+    pub version: Option<u64>,
+}
+
+impl StoreResponse {
+    pub fn new((status_line, body): (String, String)) -> Self {
+        Self {
+            status_line,
+            body,
+            version: None,
+        }
+    }
+
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = Some(version);
+        self
+    }
+}
+
+/// The value a key had from one store version on.
+#[derive(Debug, Clone)]
+struct Version {
+    version: u64,
+    /// `None` if the key was deleted in this version.
+    value: Option<DataObject>,
+    value_type: ValueType,
+    written: SystemTime,
+}
+
+/// The store version a write is made in, and the oldest version that readers
+/// may still ask for. Versions only older readers could see are dropped by
+/// the write.
+#[derive(Debug, Clone, Copy)]
+pub struct Commit {
+    pub version: u64,
+    pub oldest_snapshot: u64,
+}
+
+/// A key and value encrypted ahead of time, so that inserting them can't
+/// fail, see [`KeyValueStore::insert_encrypted`].
+pub struct EncryptedEntry {
+    key: String,
+    encrypted_key: DataObject,
+    encrypted_value: DataObject,
+}
+
+impl EncryptedEntry {
+    pub fn new(
+        key: &str,
+        value: &str,
+        encryption_key: &SecretKey,
+    ) -> Result<Self, &'static str> {
+        Ok(Self {
+            key: key.to_string(),
+            encrypted_key: encrypt(key, encryption_key)?,
+            encrypted_value: encrypt(value, encryption_key)?,
+        })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
 pub struct KeyValueStore {
-    key_value_store: HashMap<DataObject, DataObject>,
-    encryption_key: String,
+    /// Every key with its versions, oldest first.
+    key_value_store: HashMap<DataObject, Vec<Version>>,
+    encryption_key: Arc<SecretKey>,
+    /// How many old values of each key are kept, on top of those readers
+    /// still need.
+    retention: Arc<RetentionPolicy>,
+    /// Updated on every write to a namespace with indexes.
+    indexes: Arc<Indexes>,
+    /// Told about every change to a key, for watches.
+    events: Arc<Events>,
 }
 
 impl KeyValueStore {
-    /// Create a new key-value store and generate user encryption key.
+    /// Create a new key-value store that encrypts its data with the given
+    /// user encryption key.
     ///
     /// Uses std::Collections::HashMap as the backing data structure at the
     /// moment.
-    pub fn new() -> Self {
+    pub fn new(encryption_key: Arc<SecretKey>) -> Self {
+        Self::with_retention(encryption_key, Arc::default())
+    }
+
+    /// Create a new key-value store that keeps old values of its keys as
+    /// `retention` says, see [`KeyValueStore::history`].
+    pub fn with_retention(
+        encryption_key: Arc<SecretKey>,
+        retention: Arc<RetentionPolicy>,
+    ) -> Self {
         let key_value_store = HashMap::new();
-        let encryption_key = generate_key();
         Self {
             key_value_store,
             encryption_key,
+            retention,
+            indexes: Arc::default(),
+            events: Arc::default(),
         }
     }
 
-    pub fn handle_get_request(&self, buf: &[u8; 1024]) -> (String, String) {
-        let key = match parse_key_from_request(buf) {
-            Ok(key) => key,
-            Err(_) => {
-                return (
-                    "HTTP/1.1 400 Bad Request".to_string(),
-                    "Key for key-value store not provided!".to_string(),
-                )
-            }
-        };
+    /// Keep `indexes` up to date with the JSON documents written to the
+    /// store, see [`Indexes::update`].
+    pub fn with_indexes(mut self, indexes: Arc<Indexes>) -> Self {
+        self.indexes = indexes;
+        self
+    }
 
-        let encryption_key = match parse_encryption_key_from_headers(buf) {
-            Ok(key) => key,
-            Err(_) => {
-                return (
-                    "HTTP/1.1 400 Bad Request".to_string(),
-                    "User encryption key not provided in request headers."
-                        .to_string(),
-                )
-            }
-        };
+    /// Publish every change to a key to `events`, see [`Events::publish`].
+    pub fn with_events(mut self, events: Arc<Events>) -> Self {
+        self.events = events;
+        self
+    }
 
-        if key == "ls" {
-            return (
-                "HTTP/1.1 200 OK".to_string(),
-                self.list_keys(encryption_key),
-            );
+    /// Handle a GET request for a key, with the value it had in store version
+    /// `as_of`, or its latest value.
+    ///
+    /// `ls` is handled by [`crate::store::ShardedStore`]. The request must
+    /// already have been authenticated, see [`crate::auth::authenticate`].
+    pub fn handle_get_request(
+        &self,
+        buf: &[u8],
+        as_of: Option<u64>,
+    ) -> StoreResponse {
+        let key = match parse_key_from_request(buf) {
+            Ok(key) => match as_of {
+                Some(_) => split_query(&key).0.to_string(),
+                None => key,
+            },
+            Err(_) => String::new(),
+        };
+        if key.is_empty() {
+            return StoreResponse::new((
+                "HTTP/1.1 400 Bad Request".to_string(),
+                "Key for key-value store not provided!".to_string(),
+            ));
         }
 
-        let found_object = match self.find_object(&encryption_key, &key) {
-            Ok(fo) => fo,
-            Err(e) => {
-                return ("HTTP/1.1 400 Bad Request".to_string(), e.to_string())
+        let found = self
+            .find_object(&key)
+            .ok()
+            .flatten()
+            .and_then(|object| self.visible_version(&object, as_of));
+        let (version, value) = match found {
+            Some(Version {
+                version,
+                value: Some(value),
+                ..
+            }) => (version, value),
+            // Missing, deleted, or not written yet as of the version.
+            _ => {
+                return StoreResponse::new((
+                    "HTTP/1.1 404 NOT FOUND".to_string(),
+                    "Key not found in key-value store.".to_string(),
+                ))
             }
         };
 
-        assert!(found_object.is_some());
-
-        let value = match self.key_value_store.get(&found_object.unwrap()) {
-            Some(val) => val,
-            None => {
-                return (
-                    "HTTP/1.1 404 NOT FOUND".to_string(),
-                    format!(
-                        "\
-Key '{}' not found in key-value store. Either the data \
-does not exist or you have an invalid key. Try using the ls command.",
-                        key
-                    ),
-                )
+        let value = match decrypt(&value, &self.encryption_key) {
+            Ok(value) => value,
+            Err(e) => {
+                return StoreResponse::new((
+                    "HTTP/1.1 500 Internal Server Error".to_string(),
+                    e.to_string(),
+                ))
             }
         };
 
-        let mut value = decrypt(value, &self.encryption_key).unwrap();
-
-        // If the value that corresponds to the given key is a file, read the
-        // file contents and print that to the stream.
-        if Path::exists(Path::new(&value))
-            && fs::metadata(&value).unwrap().is_file()
-        {
-            value = fs::read_to_string(&value).unwrap();
-        }
-
         let status_line = "HTTP/1.1 200 OK".to_string();
-        (status_line, value)
+        StoreResponse::new((status_line, value)).with_version(version)
     }
 
-    pub fn handle_put_request(&mut self, buf: &[u8; 1024]) -> (String, String) {
+    pub fn handle_put_request(
+        &mut self,
+        buf: &[u8],
+        commit: Commit,
+    ) -> StoreResponse {
         let key = match parse_key_from_request(buf) {
             Ok(key) => key,
             Err(_) => {
-                return (
+                return StoreResponse::new((
                     "HTTP/1.1 400 Bad Request".to_string(),
                     "Key for key-value store not provided!".to_string(),
-                )
+                ))
             }
         };
 
-        let mut value = match parse_body_from_request(buf) {
+        let value = match parse_body_from_request(buf) {
             Ok(value) => value,
             Err(_) => {
-                return (
+                return StoreResponse::new((
                     "HTTP/1.1 400 Bad Request".to_string(),
                     format!(
                         "No value provided to store with the key {}!",
                         &key
                     ),
-                )
+                ))
             }
         };
 
-        if Path::exists(Path::new(&value))
-            && fs::metadata(&value).unwrap().is_file()
-        {
-            value = fs::read_to_string(&value).expect("Failed to read file.");
-        }
-
-        let encrypted_key = encrypt(&key, &self.encryption_key).unwrap();
-        let encrypted_value = encrypt(&value, &self.encryption_key).unwrap();
+        // Values sent as JSON are JSON documents, see [`crate::document`].
+        let value_type = match parse_header_from_request(buf, "Content-Type") {
+            Some(content_type)
+                if content_type.starts_with("application/json") =>
+            {
+                if let Err(e) =
+                    serde_json::from_str::<serde_json::Value>(&value)
+                {
+                    return StoreResponse::new((
+                        "HTTP/1.1 400 Bad Request".to_string(),
+                        format!("Value is not valid JSON: {}", e),
+                    ));
+                }
+                ValueType::Json
+            }
+            _ => ValueType::String,
+        };
 
-        match self.key_value_store.insert(encrypted_key, encrypted_value) {
-            Some(_) => (
+        let response = match self.insert_as(&key, &value, value_type, commit) {
+            Ok(true) => (
                 "HTTP/1.1 200 OK".to_string(),
                 format!(
                     "Value associated with key, \"{}\", \
@@ -151,7 +268,7 @@ does not exist or you have an invalid key. Try using the ls command.",
                     key, &value
                 ),
             ),
-            None => (
+            Ok(false) => (
                 "HTTP/1.1 200 OK".to_string(),
                 format!(
                     "[\"{}\", \"{}\"], \n200 - Success: \
@@ -159,71 +276,630 @@ does not exist or you have an invalid key. Try using the ls command.",
                     key, &value
                 ),
             ),
-        }
+            Err(e) => {
+                return StoreResponse::new((
+                    "HTTP/1.1 500 Internal Server Error".to_string(),
+                    e.to_string(),
+                ))
+            }
+        };
+
+        StoreResponse::new(response).with_version(commit.version)
     }
 
+    /// Handle a DELETE request for a key.
+    ///
+    /// The request must already have been authenticated, see
+    /// [`crate::auth::authenticate`].
     pub fn handle_delete_request(
         &mut self,
-        buf: &[u8; 1024],
-    ) -> (String, String) {
+        buf: &[u8],
+        commit: Commit,
+    ) -> StoreResponse {
         let key = match parse_key_from_request(buf) {
             Ok(key) => key,
             Err(_) => {
-                return (
+                return StoreResponse::new((
                     "HTTP/1.1 400 Bad Request".to_string(),
                     "Key for key-value store not provided!".to_string(),
-                )
+                ))
             }
         };
 
-        let encryption_key = match parse_encryption_key_from_headers(buf) {
-            Ok(key) => key,
+        let removed = self
+            .remove(&key, commit)
+            .map(|value| decrypt(&value, &self.encryption_key));
+        match removed {
+            Some(Ok(value)) => StoreResponse::new((
+                "HTTP/1.1 200 OK".to_string(),
+                format!(
+                    "Key-value pair [\"{}\", \"{}\"], removed from key-value store.",
+                    key, value
+                ),
+            ))
+            .with_version(commit.version),
+            // The key is gone all the same, only its value can't be shown.
+            Some(Err(_)) => StoreResponse::new((
+                "HTTP/1.1 200 OK".to_string(),
+                format!("Key '{}' removed from key-value store.", key),
+            ))
+            .with_version(commit.version),
+            None => StoreResponse::new((
+                "HTTP/1.1 404 NOT FOUND".to_string(),
+                format!("Key '{}' not found in key-value store.", key),
+            )),
+        }
+    }
+
+    /// Handle a PATCH request, changing part of the value of a key as the
+    /// query asks, see [`Patch::from_query`].
+    ///
+    /// Answers in JSON with the new length of the value, or the new value of
+    /// a counter, and the version it was written in. The request must
+    /// already have been authenticated, see [`crate::auth::authenticate`].
+    pub fn handle_patch_request(
+        &mut self,
+        buf: &[u8],
+        commit: Commit,
+    ) -> StoreResponse {
+        let path = match parse_key_from_request(buf) {
+            Ok(path) => path,
             Err(_) => {
-                return (
+                return StoreResponse::new((
                     "HTTP/1.1 400 Bad Request".to_string(),
-                    "User encryption key not provided in request headers."
-                        .to_string(),
-                )
+                    json_error("Key for key-value store not provided!"),
+                ))
             }
         };
+        let (key, query) = split_query(&path);
+
+        let patch = parse_body_from_request(buf)
+            .map_err(|e| PatchError::Invalid(e.to_string()))
+            .and_then(|data| Patch::from_query(&query, data));
+        let patch = match patch {
+            Ok(patch) => patch,
+            Err(e) => return StoreResponse::new(e.response()),
+        };
 
-        let found_object = match self.find_object(&encryption_key, &key) {
-            Ok(fo) => fo,
+        let object = self.find_object(key).ok().flatten();
+        let latest = object
+            .as_ref()
+            .and_then(|object| self.visible_version(object, None));
+        if latest.as_ref().is_some_and(|latest| {
+            latest.value.is_some() && latest.value_type != ValueType::String
+        }) {
+            return StoreResponse::new(PatchError::WrongType.response());
+        }
+        let value = latest
+            .and_then(|latest| latest.value)
+            .map(|value| decrypt(&value, &self.encryption_key))
+            .transpose();
+        let value = match value {
+            Ok(value) => value,
             Err(e) => {
-                return ("HTTP/1.1 404 NOT FOUND".to_string(), e.to_string())
+                return StoreResponse::new((
+                    "HTTP/1.1 500 Internal Server Error".to_string(),
+                    json_error(e),
+                ))
+            }
+        };
+        let value = match (value, patch.initial_value()) {
+            (Some(value), _) => value,
+            (None, Some(initial)) => initial.to_string(),
+            (None, None) => {
+                return StoreResponse::new((
+                    "HTTP/1.1 404 NOT FOUND".to_string(),
+                    json_error(&format!(
+                        "Key '{}' not found in key-value store.",
+                        key
+                    )),
+                ))
             }
         };
 
-        // This should never fail. find_object should return an error if it
-        // doesn't find anything which means this code will never be run if
-        // found_object is None.
-        assert!(found_object.is_some());
+        let patched = match patch.apply(&value) {
+            Ok(patched) => patched,
+            Err(e) => return StoreResponse::new(e.response()),
+        };
+        let encrypted = encrypt(&patched, &self.encryption_key);
+        let object = match object {
+            Some(object) => Ok(object),
+            None => encrypt(key, &self.encryption_key),
+        };
+        let (object, encrypted) = match (object, encrypted) {
+            (Ok(object), Ok(encrypted)) => (object, encrypted),
+            (Err(e), _) | (_, Err(e)) => {
+                return StoreResponse::new((
+                    "HTTP/1.1 500 Internal Server Error".to_string(),
+                    json_error(e),
+                ))
+            }
+        };
+        self.push_version(
+            object,
+            key,
+            Some(encrypted),
+            ValueType::String,
+            commit,
+        );
 
-        match self.key_value_store.remove(&found_object.unwrap()) {
-            Some(val) => (
-                "HTTP/1.1 200 OK".to_string(),
-                format!(
-                    "Key-value pair [\"{}\", \"{}\"], removed from key-value store.",
-                    key, decrypt(&val, &encryption_key).unwrap()
-                ),
-            ),
-            None => (
-                "HTTP/1.1 404 NOT FOUND".to_string(),
-                format!("Key '{}' not found in key-value store.", key),
-            ),
+        let body = match patch {
+            Patch::Increment { .. } => serde_json::json!({
+                "value": Number::parse(&patched).map(Number::to_json),
+                "version": commit.version,
+            }),
+            _ => serde_json::json!({
+                "length": patched.len(),
+                "version": commit.version,
+            }),
+        };
+        StoreResponse::new(("HTTP/1.1 200 OK".to_string(), body.to_string()))
+            .with_version(commit.version)
+    }
+
+    /// Insert a key-value pair, replacing the value of the key if it already
+    /// exists.
+    ///
+    /// Returns whether the key already existed.
+    pub fn insert(
+        &mut self,
+        key: &str,
+        value: &str,
+        commit: Commit,
+    ) -> Result<bool, &'static str> {
+        self.insert_as(key, value, ValueType::String, commit)
+    }
+
+    /// Like [`KeyValueStore::insert`], for a value of the given type that is
+    /// already encoded, see [`TypedValue::encode`].
+    fn insert_as(
+        &mut self,
+        key: &str,
+        value: &str,
+        value_type: ValueType,
+        commit: Commit,
+    ) -> Result<bool, &'static str> {
+        let encrypted_value = encrypt(value, &self.encryption_key)?;
+        let existing = self.find_object(key).unwrap_or(None);
+
+        let object = match existing {
+            Some(object) => object,
+            None => encrypt(key, &self.encryption_key)?,
+        };
+        let existed = self.latest_value(&object).is_some();
+        self.push_version(
+            object,
+            key,
+            Some(encrypted_value),
+            value_type,
+            commit,
+        );
+
+        Ok(existed)
+    }
+
+    /// Like [`KeyValueStore::insert`], for a string value that was encrypted
+    /// with this store's key already. Returns whether the key existed.
+    pub fn insert_encrypted(
+        &mut self,
+        entry: EncryptedEntry,
+        commit: Commit,
+    ) -> bool {
+        let object = match self.find_object(&entry.key) {
+            Ok(Some(object)) => object,
+            _ => entry.encrypted_key,
+        };
+        let existed = self.latest_value(&object).is_some();
+        self.push_version(
+            object,
+            &entry.key,
+            Some(entry.encrypted_value),
+            ValueType::String,
+            commit,
+        );
+
+        existed
+    }
+
+    /// Delete a key, returning its encrypted value if it existed.
+    fn remove(&mut self, key: &str, commit: Commit) -> Option<DataObject> {
+        let object = self.find_object(key).ok().flatten()?;
+        let value = self.latest_value(&object)?;
+        self.push_version(object, key, None, ValueType::String, commit);
+
+        Some(value)
+    }
+
+    /// Write the value the key had in store version `version` again, as its
+    /// latest value.
+    ///
+    /// Returns false if the key had no value in that version, or it is no
+    /// longer kept.
+    pub fn restore(&mut self, key: &str, version: u64, commit: Commit) -> bool {
+        let object = match self.find_object(key) {
+            Ok(Some(object)) => object,
+            _ => return false,
+        };
+        let found = self.key_value_store[&object]
+            .iter()
+            .find(|candidate| candidate.version == version)
+            .cloned();
+
+        match found {
+            Some(Version {
+                value: Some(value),
+                value_type,
+                ..
+            }) => {
+                self.push_version(object, key, Some(value), value_type, commit);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Every value of the key that is still kept, newest first, with the
+    /// encrypted value. Empty if the key isn't in the store.
+    pub fn history(
+        &self,
+        key: &str,
+    ) -> Vec<(HistoryEntry, Option<DataObject>)> {
+        let object = match self.find_object(key) {
+            Ok(Some(object)) => object,
+            _ => return Vec::new(),
+        };
+
+        self.key_value_store[&object]
+            .iter()
+            .rev()
+            .map(|version| {
+                let entry = HistoryEntry {
+                    version: version.version,
+                    written: version.written,
+                    deleted: version.value.is_none(),
+                };
+                (entry, version.value.clone())
+            })
+            .collect()
+    }
+
+    /// The value of a key with its type, as it was in store version `as_of`,
+    /// or its latest value. `None` if the key doesn't exist.
+    pub fn typed_value(
+        &self,
+        key: &str,
+        as_of: Option<u64>,
+    ) -> Result<Option<TypedValue>, &'static str> {
+        let object = match self.find_object(key) {
+            Ok(Some(object)) => object,
+            _ => return Ok(None),
+        };
+        let (value, value_type) = match self.visible_version(&object, as_of) {
+            Some(Version {
+                value: Some(value),
+                value_type,
+                ..
+            }) => (value, value_type),
+            _ => return Ok(None),
+        };
+
+        let plaintext = decrypt(&value, &self.encryption_key)?;
+        TypedValue::decode(value_type, plaintext).map(Some)
+    }
+
+    /// Replace the value of a key with a typed value, or delete the key if
+    /// `value` is `None`.
+    pub fn set_typed_value(
+        &mut self,
+        key: &str,
+        value: Option<&TypedValue>,
+        commit: Commit,
+    ) -> Result<(), &'static str> {
+        let value = match value {
+            Some(value) => Some((
+                encrypt(&value.encode(), &self.encryption_key)?,
+                value.value_type(),
+            )),
+            None => None,
+        };
+        let object = match (self.find_object(key).unwrap_or(None), &value) {
+            (Some(object), _) => object,
+            (None, Some(_)) => encrypt(key, &self.encryption_key)?,
+            (None, None) => return Ok(()),
+        };
+
+        match value {
+            Some((value, value_type)) => {
+                self.push_version(object, key, Some(value), value_type, commit)
+            }
+            None => {
+                self.push_version(object, key, None, ValueType::String, commit)
+            }
+        }
+        Ok(())
+    }
+
+    /// The latest value of every key in the namespace that is a JSON
+    /// document, e.g. to build an index.
+    pub fn json_documents(
+        &self,
+        namespace: &str,
+    ) -> Vec<(String, serde_json::Value)> {
+        let mut documents = Vec::new();
+        for (object, versions) in &self.key_value_store {
+            let value = match versions.last() {
+                Some(Version {
+                    value: Some(value),
+                    value_type: ValueType::Json,
+                    ..
+                }) => value,
+                _ => continue,
+            };
+            let key = match decrypt(object, &self.encryption_key) {
+                Ok(key) if namespace_of(&key) == namespace => key,
+                _ => continue,
+            };
+            let document = decrypt(value, &self.encryption_key)
+                .ok()
+                .and_then(|value| serde_json::from_str(&value).ok());
+            if let Some(document) = document {
+                documents.push((key, document));
+            }
+        }
+
+        documents
+    }
+
+    /// Whether the key exists in the store.
+    pub fn contains_key(&self, key: &str) -> bool {
+        match self.find_object(key) {
+            Ok(Some(object)) => self.latest_value(&object).is_some(),
+            _ => false,
         }
     }
 
-    fn list_keys(&self, user_provided_encryption_key: String) -> String {
-        let mut keys = String::new();
-        for key in self.key_value_store.keys() {
-            let key = match decrypt(key, &user_provided_encryption_key) {
-                Ok(key) => key,
-                Err(e) => e.to_string(),
+    /// The encrypted key-value pairs as they were in store version `version`.
+    ///
+    /// Nothing is decrypted, so this is cheap enough to do while holding a
+    /// lock.
+    pub fn entries_at(&self, version: u64) -> Vec<(DataObject, DataObject)> {
+        self.key_value_store
+            .iter()
+            .filter_map(|(key, versions)| {
+                let value = versions
+                    .iter()
+                    .rev()
+                    .find(|candidate| candidate.version <= version)?
+                    .value
+                    .clone()?;
+                Some((key.clone(), value))
+            })
+            .collect()
+    }
+
+    /// The deleted keys whose last value is still kept, with the version and
+    /// time they were deleted in.
+    ///
+    /// Nothing is decrypted, so this is cheap enough to do while holding a
+    /// lock.
+    pub fn trash(&self) -> Vec<(DataObject, u64, SystemTime)> {
+        self.key_value_store
+            .iter()
+            .filter(|(_, versions)| is_trashed(versions))
+            .filter_map(|(key, versions)| {
+                let deleted = versions.last()?;
+                Some((key.clone(), deleted.version, deleted.written))
+            })
+            .collect()
+    }
+
+    /// Drop the old values of a deleted key that no reader at
+    /// `oldest_snapshot` or later can see, whatever the retention says, so
+    /// that it can't be restored anymore.
+    ///
+    /// Returns false if the key isn't in the trash.
+    pub fn purge(&mut self, key: &str, oldest_snapshot: u64) -> bool {
+        let object = match self.find_object(key) {
+            Ok(Some(object)) => object,
+            _ => return false,
+        };
+        self.purge_object(&object, oldest_snapshot)
+    }
+
+    /// Purge every deleted key, or only the keys in `namespaces` if it isn't
+    /// empty, see [`KeyValueStore::purge`].
+    ///
+    /// Returns the number of purged keys.
+    pub fn empty_trash(
+        &mut self,
+        namespaces: &[String],
+        oldest_snapshot: u64,
+    ) -> usize {
+        let trashed: Vec<DataObject> = self
+            .trash()
+            .into_iter()
+            .map(|(object, _, _)| object)
+            .filter(|object| match decrypt(object, &self.encryption_key) {
+                Ok(key) => in_namespaces(&key, namespaces),
+                Err(_) => namespaces.is_empty(),
+            })
+            .collect();
+
+        let mut purged = 0;
+        for object in &trashed {
+            if self.purge_object(object, oldest_snapshot) {
+                purged += 1;
+            }
+        }
+
+        purged
+    }
+
+    fn purge_object(
+        &mut self,
+        object: &DataObject,
+        oldest_snapshot: u64,
+    ) -> bool {
+        let versions = match self.key_value_store.get_mut(object) {
+            Some(versions) if is_trashed(versions) => versions,
+            _ => return false,
+        };
+
+        prune_versions(
+            versions,
+            oldest_snapshot,
+            Retention::default(),
+            SystemTime::now(),
+        );
+        if versions.is_empty() {
+            self.key_value_store.remove(object);
+            self.publish_expired(object);
+        }
+
+        true
+    }
+
+    /// Delete every key, or only the keys in `namespaces` if it isn't empty.
+    ///
+    /// Returns the number of deleted keys.
+    pub fn clear(&mut self, namespaces: &[String], commit: Commit) -> usize {
+        let mut removed = Vec::new();
+        for object in self.key_value_store.keys() {
+            let key = decrypt(object, &self.encryption_key);
+            let in_namespaces = match &key {
+                Ok(key) => in_namespaces(key, namespaces),
+                Err(_) => namespaces.is_empty(),
             };
-            keys.push_str(format!("{}\n", key).as_str());
+            if in_namespaces && self.latest_value(object).is_some() {
+                removed.push((object.clone(), key.unwrap_or_default()));
+            }
+        }
+
+        for (object, key) in &removed {
+            self.push_version(
+                object.clone(),
+                key,
+                None,
+                ValueType::String,
+                commit,
+            );
+        }
+
+        removed.len()
+    }
+
+    /// Drop the versions no reader at `oldest_snapshot` or later can see and
+    /// the retention doesn't keep, and keys that have been deleted for all of
+    /// them.
+    ///
+    /// Returns the number of dropped versions.
+    pub fn prune(&mut self, oldest_snapshot: u64) -> usize {
+        let now = SystemTime::now();
+        // Only decrypt keys if some namespace has a retention of its own.
+        let uniform = self
+            .retention
+            .is_uniform()
+            .then(|| self.retention.for_namespace(""));
+
+        let mut pruned = 0;
+        let mut expired = Vec::new();
+        self.key_value_store.retain(|object, versions| {
+            let retention = uniform.unwrap_or_else(|| {
+                let key = decrypt(object, &self.encryption_key);
+                self.retention.for_key(&key.unwrap_or_default())
+            });
+            pruned += prune_versions(versions, oldest_snapshot, retention, now);
+            if versions.is_empty() {
+                expired.push(object.clone());
+            }
+            !versions.is_empty()
+        });
+        for object in &expired {
+            self.publish_expired(object);
+        }
+
+        pruned
+    }
+
+    fn push_version(
+        &mut self,
+        object: DataObject,
+        key: &str,
+        value: Option<DataObject>,
+        value_type: ValueType,
+        commit: Commit,
+    ) {
+        let versions = self.key_value_store.entry(object.clone()).or_default();
+
+        // A key written more than once in the same version, e.g. by a
+        // restore, only keeps the last value.
+        if versions
+            .last()
+            .is_some_and(|last| last.version == commit.version)
+        {
+            versions.pop();
+        }
+        if self.indexes.covers(namespace_of(key)) {
+            let document = value
+                .as_ref()
+                .filter(|_| value_type == ValueType::Json)
+                .and_then(|value| decrypt(value, &self.encryption_key).ok())
+                .and_then(|value| serde_json::from_str(&value).ok());
+            self.indexes.update(key, document.as_ref());
+        }
+
+        let kind = match value {
+            Some(_) => EventKind::Put,
+            None => EventKind::Delete,
+        };
+        self.events.publish(kind, key, Some(commit.version));
+
+        let written = SystemTime::now();
+        versions.push(Version {
+            version: commit.version,
+            value,
+            value_type,
+            written,
+        });
+
+        prune_versions(
+            versions,
+            commit.oldest_snapshot,
+            self.retention.for_key(key),
+            written,
+        );
+        if versions.is_empty() {
+            self.key_value_store.remove(&object);
+        }
+    }
+
+    /// Tell watches that a deleted key has left the trash for good.
+    fn publish_expired(&self, object: &DataObject) {
+        if let Ok(key) = decrypt(object, &self.encryption_key) {
+            self.events.publish(EventKind::Expire, &key, None);
+        }
+    }
+
+    fn latest_value(&self, object: &DataObject) -> Option<DataObject> {
+        self.visible_version(object, None)?.value
+    }
+
+    /// The version of a key that was current in store version `as_of`, or its
+    /// latest version.
+    fn visible_version(
+        &self,
+        object: &DataObject,
+        as_of: Option<u64>,
+    ) -> Option<Version> {
+        let versions = self.key_value_store.get(object)?;
+        match as_of {
+            Some(as_of) => versions
+                .iter()
+                .rev()
+                .find(|version| version.version <= as_of)
+                .cloned(),
+            None => versions.last().cloned(),
         }
-        keys.trim_end().to_string()
     }
 
     // FIXME: This bit of code is unfortunately slow. Because we encrypt
@@ -239,13 +915,12 @@ does not exist or you have an invalid key. Try using the ls command.",
     // that encryption.
     fn find_object(
         &self,
-        user_provided_encryption_key: &String,
-        key: &String,
+        key: &str,
     ) -> Result<Option<DataObject>, &'static str> {
         let mut found_object: Option<DataObject> = None;
         for object in self.key_value_store.keys() {
             // FIXME: Handle any decryption error.
-            let decrypted_key = decrypt(object, user_provided_encryption_key)?;
+            let decrypted_key = decrypt(object, &self.encryption_key)?;
 
             if decrypted_key == *key {
                 found_object = Some(object.clone());
@@ -260,9 +935,62 @@ does not exist or you have an invalid key. Try using the ls command.",
     }
 }
 
+/// Drop the versions, oldest first, that no reader at `oldest_snapshot` or
+/// later can see and `retention` doesn't keep. Returns how many were
+/// dropped.
+fn prune_versions(
+    versions: &mut Vec<Version>,
+    oldest_snapshot: u64,
+    retention: Retention,
+    now: SystemTime,
+) -> usize {
+    // The newest version at or before the oldest snapshot is still visible.
+    let mut keep_from = versions
+        .iter()
+        .rposition(|version| version.version <= oldest_snapshot)
+        .unwrap_or(0);
+    // A deletion nobody can see past looks just like a missing key.
+    if versions.get(keep_from).is_some_and(|version| {
+        version.version <= oldest_snapshot && version.value.is_none()
+    }) {
+        keep_from += 1;
+    }
+
+    // The retention counts old values, the latest one is never among them.
+    if retention.versions > 0 {
+        let kept = versions.len().saturating_sub(retention.versions + 1);
+        keep_from = keep_from.min(kept);
+    }
+    let age = |version: &Version| {
+        now.duration_since(version.written).unwrap_or_default()
+    };
+    if let Some(young) = versions
+        .iter()
+        .position(|version| age(version) < retention.max_age)
+    {
+        keep_from = keep_from.min(young);
+    }
+    // A deleted key keeps its last value in the trash for a while.
+    if is_trashed(versions)
+        && versions
+            .last()
+            .is_some_and(|deleted| age(deleted) < retention.trash)
+    {
+        keep_from = keep_from.min(versions.len() - 2);
+    }
+
+    versions.drain(..keep_from).count()
+}
+
+/// Whether the key has been deleted and its last value is still kept.
+fn is_trashed(versions: &[Version]) -> bool {
+    versions.len() >= 2
+        && versions.last().is_some_and(|latest| latest.value.is_none())
+}
+
 impl Default for KeyValueStore {
     fn default() -> Self {
-        Self::new()
+        Self::new(Arc::new(generate_key()))
     }
 }
 
@@ -271,18 +999,25 @@ impl Default for KeyValueStore {
 /// Status line is the standard 'HTTP/1.1 200 OK' yadda yadda yadda...
 /// Body is the string data that you want to write to the stream.
 pub fn write_stream(
+    stream: &TcpStream,
+    status_line: String,
+    body: String,
+) -> Result<(), &'static str> {
+    write_response(stream, status_line, &[], body)
+}
+
+/// Write to the provided stream with additional response headers.
+///
+/// Content-Length is always added, so it shouldn't be part of `headers`.
+pub fn write_response(
     mut stream: &TcpStream,
     status_line: String,
+    headers: &[(&str, String)],
     body: String,
 ) -> Result<(), &'static str> {
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        body.len(),
-        body
-    );
+    let response = format_response(&status_line, headers, &body);
 
-    match stream.write(response.as_bytes()) {
+    match stream.write_all(response.as_bytes()) {
         Ok(_) => (),
         Err(_) => return Err("Failed to write to stream."),
     }
@@ -294,23 +1029,66 @@ pub fn write_stream(
     Ok(())
 }
 
+/// Format a complete response, as written by [`write_response`].
+pub fn format_response(
+    status_line: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> String {
+    let mut response = format!("{}\r\n", status_line);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    // A response to HEAD has the length of the body it leaves out.
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+    {
+        response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    response.push_str(&format!("\r\n{}", body));
+
+    response
+}
+
+/// Format an error message as a JSON body, e.g. `{"error":"..."}`.
+pub fn json_error(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum RequestType {
     Get,
+    Head,
+    Post,
     Put,
+    Patch,
     Delete,
+    Options,
     Unknown((String, String)),
 }
 
+impl RequestType {
+    /// The HTTP method of the request, e.g. `GET`.
+    pub fn method(&self) -> &'static str {
+        match self {
+            RequestType::Get => "GET",
+            RequestType::Head => "HEAD",
+            RequestType::Post => "POST",
+            RequestType::Put => "PUT",
+            RequestType::Patch => "PATCH",
+            RequestType::Delete => "DELETE",
+            RequestType::Options => "OPTIONS",
+            RequestType::Unknown(_) => "UNKNOWN",
+        }
+    }
+}
+
 /// Extract the HTTP method from the stream buffer.
 ///
 /// Must be called AFTER buf_from_stream.
-pub fn request_type(buf: &[u8; 1024]) -> RequestType {
-    let get_request = b"GET";
-    let put_request = b"PUT";
-    let delete_request = b"DELETE";
-
-    // Default response if request is anything but get, put, or delete.
+pub fn request_type(buf: &[u8]) -> RequestType {
+    // Default response if request is anything but a method we know.
     let unknown_request = (
         "HTTP/1.1 400 BAD REQUEST".to_string(),
         "This key-value store does not support \
@@ -318,14 +1096,16 @@ pub fn request_type(buf: &[u8; 1024]) -> RequestType {
             .to_string(),
     );
 
-    if buf.starts_with(get_request) {
-        RequestType::Get
-    } else if buf.starts_with(put_request) {
-        RequestType::Put
-    } else if buf.starts_with(delete_request) {
-        RequestType::Delete
-    } else {
-        RequestType::Unknown(unknown_request)
+    let method = buf.split(|byte| *byte == b' ').next().unwrap_or_default();
+    match method {
+        b"GET" => RequestType::Get,
+        b"HEAD" => RequestType::Head,
+        b"POST" => RequestType::Post,
+        b"PUT" => RequestType::Put,
+        b"PATCH" => RequestType::Patch,
+        b"DELETE" => RequestType::Delete,
+        b"OPTIONS" => RequestType::Options,
+        _ => RequestType::Unknown(unknown_request),
     }
 }
 
@@ -336,15 +1116,12 @@ pub fn request_type(buf: &[u8; 1024]) -> RequestType {
 /// # Panics
 ///
 /// Regex::new() can panic, but it shouldn't ever panic because it is hardcoded.
-pub fn verify_request(buf: &[u8; 1024]) -> Result<(), &'static str> {
+pub fn verify_request(buf: &[u8]) -> Result<(), &'static str> {
     // Verify request has valid HTTP header.
     let buf_string = String::from_utf8_lossy(buf);
-    let pattern = Regex::new(r"\w{3,6}\s/\w*\sHTTP/1.1\r\n(\w*\r\n)*").unwrap();
+    let pattern =
+        Regex::new(r"^\w{3,7}\s/\S*\sHTTP/1.1\r\n(\w*\r\n)*").unwrap();
 
-    // FIXME: When hitting localhost:3400 in browser, this message is
-    // displayed in the terminal, yet the output in the browser is fine. Has
-    // to do with the additional information included in the request that
-    // the browser sends.
     if !pattern.is_match(&buf_string) {
         return Err("Invalid HTTP request received.");
     }
@@ -353,27 +1130,90 @@ pub fn verify_request(buf: &[u8; 1024]) -> Result<(), &'static str> {
 }
 
 /// Read the stream into a buffer.
+///
+/// Reads until the end of the headers, then reads as many body bytes as the
+/// Content-Length header announces.
 pub fn buf_from_stream(
     mut stream: &TcpStream,
-) -> Result<[u8; 1024], &'static str> {
-    let mut buf = [0; 1024];
-    match stream.read(&mut buf) {
-        Ok(_) => (),
-        Err(_) => return Err("Failed to read stream to buffer"),
+) -> Result<Vec<u8>, &'static str> {
+    if stream.set_read_timeout(Some(READ_TIMEOUT)).is_err() {
+        return Err("Failed to set read timeout on stream.");
+    }
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let read = match stream.read(&mut chunk) {
+            Ok(read) => read,
+            Err(_) => return Err("Failed to read stream to buffer"),
+        };
+        buf.extend_from_slice(&chunk[..read]);
+
+        if read == 0 || is_request_complete(&buf)? {
+            break;
+        }
     }
+    chunk.zeroize();
 
     Ok(buf)
 }
 
-fn parse_body_from_request(buf: &[u8; 1024]) -> Result<String, &'static str> {
-    let body = match buf.split(|byte| *byte == b'\n').last() {
-        Some(body) => body,
+/// Whether the buffer holds the whole request, headers and body.
+///
+/// Errors once the buffer has grown too large to ever hold a request.
+pub fn is_request_complete(buf: &[u8]) -> Result<bool, &'static str> {
+    if request_len(buf)?.is_some_and(|len| buf.len() >= len) {
+        return Ok(true);
+    }
+    if buf.len() > MAX_REQUEST_SIZE {
+        return Err("Request is too large.");
+    }
+
+    Ok(false)
+}
+
+/// Length of the complete request (headers and body), once all headers have
+/// been read.
+///
+/// Errors if the Content-Length isn't a number, or announces a request too
+/// large to read.
+fn request_len(buf: &[u8]) -> Result<Option<usize>, &'static str> {
+    let body_start = match body_start(buf) {
+        Some(body_start) => body_start,
+        None => return Ok(None),
+    };
+    let content_length = match parse_header_from_request(buf, "Content-Length")
+    {
+        Some(len) => match len.parse::<usize>() {
+            Ok(len) => len,
+            Err(_) => return Err("Invalid Content-Length header."),
+        },
+        None => 0,
+    };
+
+    match body_start.checked_add(content_length) {
+        Some(len) if len <= MAX_REQUEST_SIZE => Ok(Some(len)),
+        _ => Err("Request is too large."),
+    }
+}
+
+/// Index of the first body byte, right after the blank line that ends the
+/// headers.
+fn body_start(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+pub fn parse_body_from_request(buf: &[u8]) -> Result<String, &'static str> {
+    let body = match body_start(buf) {
+        Some(body_start) => &buf[body_start..],
         None => return Err("Failed to parse body out of request"),
     };
 
-    // Body is everything after the final \r\n which means, depending on the
-    // size of the buffer used, it can have a lot of garbage values that look
-    // like [0,0,0,0,...]. This essentially trims all those off.
+    // Depending on the size of the buffer used, the body can be followed by
+    // a lot of garbage values that look like [0,0,0,0,...]. This essentially
+    // trims all those off.
     let body = match body.split(|byte| *byte == 0).next() {
         Some(body) => body,
         None => return Err(
@@ -385,7 +1225,100 @@ fn parse_body_from_request(buf: &[u8; 1024]) -> Result<String, &'static str> {
     Ok(body_str.to_string())
 }
 
-fn parse_key_from_request(buf: &[u8; 1024]) -> Result<String, &'static str> {
+/// Value of the first header called `name` (case insensitive), if any.
+pub fn parse_header_from_request(buf: &[u8], name: &str) -> Option<String> {
+    let headers_end = body_start(buf).unwrap_or(buf.len());
+
+    buf[..headers_end]
+        .split(|byte| *byte == b'\n')
+        .skip(1)
+        .filter_map(|header| {
+            let header = String::from_utf8_lossy(header);
+            let (header_name, value) = header.split_once(':')?;
+            if header_name.trim().eq_ignore_ascii_case(name) {
+                Some(value.trim().to_string())
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+/// Split a request path into the path itself and its query parameters, e.g.
+/// `admin/backup?namespace=users` into `admin/backup` and
+/// `{"namespace": "users"}`.
+///
+/// Names and values are percent-decoded, the path isn't.
+pub fn split_query(path: &str) -> (&str, HashMap<String, String>) {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => return (path, HashMap::new()),
+    };
+
+    let query = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.split_once('=') {
+            Some((name, value)) => {
+                (percent_decode(name), percent_decode(value))
+            }
+            None => (percent_decode(param), String::new()),
+        })
+        .collect();
+
+    (path, query)
+}
+
+/// Decode `%XX` escapes, e.g. `in%20progress` into `in progress`. Anything
+/// that isn't a valid escape is kept as it is.
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'%' => {
+                hex::decode(hex).ok().map(|byte| byte[0])
+            }
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Whether the key is in one of the namespaces. Every key is in an empty list
+/// of namespaces.
+pub fn in_namespaces(key: &str, namespaces: &[String]) -> bool {
+    namespaces.is_empty()
+        || namespaces
+            .iter()
+            .any(|namespace| namespace == namespace_of(key))
+}
+
+/// Namespace of a key.
+///
+/// Keys are grouped into namespaces by prefixing them with the namespace and
+/// a colon, e.g. `users:alice` is in the `users` namespace. Keys without a
+/// colon are in the default namespace, `""`.
+pub fn namespace_of(key: &str) -> &str {
+    match key.split_once(':') {
+        Some((namespace, _)) => namespace,
+        None => "",
+    }
+}
+
+pub fn parse_key_from_request(buf: &[u8]) -> Result<String, &'static str> {
     let mut key = buf.split(|byte| *byte == b' ');
 
     key.next();
@@ -399,39 +1332,22 @@ fn parse_key_from_request(buf: &[u8; 1024]) -> Result<String, &'static str> {
         None => return Err("Failed to parse key out of request"),
     };
 
-    let key = &key[1..];
+    let key = match key.strip_prefix(b"/") {
+        Some(key) => key,
+        None => return Err("Failed to parse key out of request"),
+    };
     let key = String::from_utf8_lossy(key).to_string();
 
     Ok(key)
 }
 
-fn parse_encryption_key_from_headers(
-    buf: &[u8; 1024],
-) -> Result<String, &'static str> {
-    let mut headers = buf.split(|byte| *byte == b'\n');
-
-    // Move to header section of HTTP request.
-    headers.next();
-
-    let headers: Vec<&[u8]> = buf.split(|byte| *byte == b'\n').collect();
-
-    // FIXME: I assume there is a much more rust-y way of doing this using
-    // filter().
-    let mut key_header = String::new();
-    for header in &headers {
-        let text = String::from_utf8_lossy(header);
-        let mut text = text.split(|ch| ch == ':');
-        let cur = text.next().unwrap();
-        if cur == "key" {
-            key_header = text.next().unwrap().trim().to_string();
-        }
-    }
-
-    if key_header.is_empty() {
-        return Err("Please provide key header in request!");
+pub(crate) fn parse_encryption_key_from_headers(
+    buf: &[u8],
+) -> Result<Zeroizing<String>, &'static str> {
+    match parse_header_from_request(buf, "key") {
+        Some(key) if !key.is_empty() => Ok(Zeroizing::new(key)),
+        _ => Err("Please provide key header in request!"),
     }
-
-    Ok(key_header)
 }
 
 #[cfg(test)]
@@ -512,7 +1428,7 @@ mod test {
         let encryption_key =
             parse_encryption_key_from_headers(&SAMPLE_PUT_REQUEST).unwrap();
         assert_eq!(
-            encryption_key,
+            encryption_key.as_str(),
             "606edace3053c4e9222515b7ba0e16e41648c40c56860edb464f813cd53c5726"
         );
     }
@@ -521,10 +1437,45 @@ mod test {
     fn test_request_type() {
         assert_eq!(request_type(&SAMPLE_PUT_REQUEST), RequestType::Put);
         assert_ne!(request_type(&SAMPLE_PUT_REQUEST), RequestType::Get);
+        assert_eq!(request_type(b"PATCH /a HTTP/1.1"), RequestType::Patch);
+        assert_eq!(request_type(b"OPTIONS /a HTTP/1.1"), RequestType::Options);
+        assert!(matches!(
+            request_type(b"PUTS /a HTTP/1.1"),
+            RequestType::Unknown(_)
+        ));
+    }
+
+    #[test]
+    fn test_split_query() {
+        let (path, query) =
+            split_query("admin/indexes/jobs?field=%2Fstatus&eq=%22in%20progress%22&a%26b=100%&x");
+        assert_eq!(path, "admin/indexes/jobs");
+        assert_eq!(query["field"], "/status");
+        assert_eq!(query["eq"], "\"in progress\"");
+        assert_eq!(query["a&b"], "100%");
+        assert_eq!(query["x"], "");
+        assert_eq!(percent_decode("caf%C3%A9%2"), "café%2");
     }
 
     #[test]
     fn test_verify_request() {
         verify_request(&SAMPLE_PUT_REQUEST).unwrap();
+        verify_request(b"A  B GET / HTTP/1.1\r\n\r\n").unwrap_err();
+        parse_key_from_request(b"A  B GET / HTTP/1.1\r\n\r\n").unwrap_err();
+    }
+
+    #[test]
+    fn test_is_request_complete() {
+        let request = b"PUT /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nb";
+        assert_eq!(is_request_complete(request), Ok(false));
+        let request = b"PUT /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nbc";
+        assert_eq!(is_request_complete(request), Ok(true));
+        let request = format!(
+            "PUT /a HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        is_request_complete(request.as_bytes()).unwrap_err();
+        let request = b"PUT /a HTTP/1.1\r\nContent-Length: x\r\n\r\n";
+        is_request_complete(request).unwrap_err();
     }
 }
@@ -0,0 +1,149 @@
+use crate::connection::parse_header_from_request;
+
+/// Seconds browsers may cache the answer to a preflight request.
+const PREFLIGHT_MAX_AGE: u64 = 600;
+/// Response headers scripts from other origins may read.
+const EXPOSED_HEADERS: &str = "Skv-Version, ETag, Retry-After";
+
+/// Which browser origins may call the server, and how.
+///
+/// Browsers only let a page read responses from another origin if the server
+/// says so with `Access-Control-*` headers, and ask first with an `OPTIONS`
+/// preflight request before sending anything but the simplest requests.
+#[derive(Debug, Clone, Default)]
+pub struct CorsConfig {
+    /// Origins allowed to call the server, e.g. `http://localhost:8080`, or
+    /// `*` for any. No CORS headers are sent if this is empty.
+    pub origins: Vec<String>,
+    /// Methods pages from those origins may use. If empty, every method the
+    /// path supports is allowed.
+    pub methods: Vec<String>,
+    /// Request headers pages from those origins may send, e.g. `key`.
+    pub headers: Vec<String>,
+}
+
+impl CorsConfig {
+    /// Headers to add to the response to a request, if it comes from an
+    /// allowed origin.
+    pub fn headers(&self, buf: &[u8]) -> Vec<(&'static str, String)> {
+        let allow_origin = match self.allow_origin(buf) {
+            Some(allow_origin) => allow_origin,
+            None => return Vec::new(),
+        };
+
+        vec![
+            ("Access-Control-Allow-Origin", allow_origin),
+            ("Access-Control-Expose-Headers", EXPOSED_HEADERS.to_string()),
+            ("Vary", "Origin".to_string()),
+        ]
+    }
+
+    /// Headers to add to the response to a preflight request, on top of
+    /// [`CorsConfig::headers`], for a path that supports the `allowed`
+    /// methods.
+    ///
+    /// Empty if the request isn't a preflight from an allowed origin, or asks
+    /// for a method that isn't allowed.
+    pub fn preflight_headers(
+        &self,
+        buf: &[u8],
+        allowed: &[&str],
+    ) -> Vec<(&'static str, String)> {
+        let method =
+            parse_header_from_request(buf, "Access-Control-Request-Method");
+        let method = match method {
+            Some(method) if self.allow_origin(buf).is_some() => method,
+            _ => return Vec::new(),
+        };
+
+        let methods: Vec<&str> = match self.methods.is_empty() {
+            true => allowed.to_vec(),
+            false => allowed
+                .iter()
+                .copied()
+                .filter(|allowed| {
+                    self.methods
+                        .iter()
+                        .any(|method| method.eq_ignore_ascii_case(allowed))
+                })
+                .collect(),
+        };
+        if !methods.iter().any(|allowed| *allowed == method) {
+            return Vec::new();
+        }
+
+        vec![
+            ("Access-Control-Allow-Methods", methods.join(", ")),
+            ("Access-Control-Allow-Headers", self.headers.join(", ")),
+            ("Access-Control-Max-Age", PREFLIGHT_MAX_AGE.to_string()),
+        ]
+    }
+
+    /// The `Access-Control-Allow-Origin` value for the request, if its
+    /// `Origin` is allowed.
+    fn allow_origin(&self, buf: &[u8]) -> Option<String> {
+        let origin = parse_header_from_request(buf, "Origin")?;
+
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some("*".to_string())
+        } else if self.origins.contains(&origin) {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> CorsConfig {
+        CorsConfig {
+            origins: vec!["http://dash".to_string()],
+            methods: Vec::new(),
+            headers: vec!["key".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let request = b"GET /a HTTP/1.1\r\nOrigin: http://dash\r\n\r\n";
+        let headers = config().headers(request);
+        assert_eq!(
+            headers[0],
+            ("Access-Control-Allow-Origin", "http://dash".to_string())
+        );
+
+        let other = b"GET /a HTTP/1.1\r\nOrigin: http://evil\r\n\r\n";
+        assert!(config().headers(other).is_empty());
+        assert!(CorsConfig::default().headers(request).is_empty());
+
+        let any = CorsConfig {
+            origins: vec!["*".to_string()],
+            ..config()
+        };
+        assert_eq!(any.headers(other)[0].1, "*");
+    }
+
+    #[test]
+    fn test_preflight() {
+        let preflight = b"OPTIONS /a HTTP/1.1\r\nOrigin: http://dash\r\n\
+            Access-Control-Request-Method: PUT\r\n\r\n";
+        let headers = config().preflight_headers(preflight, &["GET", "PUT"]);
+        assert_eq!(
+            headers[0],
+            ("Access-Control-Allow-Methods", "GET, PUT".to_string())
+        );
+        assert_eq!(headers[1], ("Access-Control-Allow-Headers", "key".into()));
+
+        let read_only = CorsConfig {
+            methods: vec!["get".to_string()],
+            ..config()
+        };
+        assert!(read_only
+            .preflight_headers(preflight, &["GET", "PUT"])
+            .is_empty());
+        assert!(config().preflight_headers(preflight, &["GET"]).is_empty());
+    }
+}
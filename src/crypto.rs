@@ -1,13 +1,53 @@
 use crate::connection::DataObject;
-use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::aead::{Aead, NewAead, Payload};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
-use std::iter::repeat_with;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{fs::OpenOptions, io::Write, iter::repeat_with, path::Path};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/// Key material for AEAD encryption of data.
+///
+/// The key bytes are wiped from memory as soon as the key is dropped.
+/// `SecretKey` deliberately implements neither `Debug` nor `Clone`, so it
+/// can't end up in a log line or be copied around by accident.
+pub struct SecretKey(Zeroizing<[u8; KEY_SIZE]>);
+
+impl SecretKey {
+    /// Decode a hex encoded key, e.g. the one provided in the `key` header.
+    pub fn from_hex(hex_key: &str) -> Result<Self, &'static str> {
+        let mut key = Zeroizing::new([0; KEY_SIZE]);
+        match hex::decode_to_slice(hex_key.trim(), &mut key[..]) {
+            Ok(_) => Ok(Self(key)),
+            Err(_) => Err("Invalid key format!"),
+        }
+    }
+
+    /// Hex encode the key so that it can be handed to the user.
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        Zeroizing::new(hex::encode(&self.0[..]))
+    }
+}
+
+/// Keys are compared in constant time so that response timing doesn't leak
+/// how much of a guessed key was correct.
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0[..].ct_eq(&other.0[..]).into()
+    }
+}
+
+impl Eq for SecretKey {}
 
 /// Generate key for AEAD encryption of data.
 ///
 /// On creation of the server, a one-time key is generated.
-/// This key is printed out to the screen and will be used in the curl request
-/// to encrypt and decrypt data.
+/// This key is handed to the user once (see [`write_key_file`]) and will be
+/// used in the curl request to encrypt and decrypt data.
 ///
 /// # Examples
 ///
@@ -23,19 +63,42 @@ use std::iter::repeat_with;
 /// let data = String::from("super secret data");
 /// let encrypted_data = encrypt(&data, &key);
 /// ```
-pub fn generate_key() -> String {
+pub fn generate_key() -> SecretKey {
     let rng = fastrand::Rng::new();
 
-    let key: Vec<u8> = repeat_with(|| rng.u8(..)).take(32).collect();
+    let mut key = Zeroizing::new([0; KEY_SIZE]);
+    key.iter_mut().for_each(|byte| *byte = rng.u8(..));
 
-    println!(
-        "
-Save this key and keep it secret! It \
-cannot and will not be regenerated.\n{}",
-        hex::encode(&key)
-    );
+    SecretKey(key)
+}
+
+/// Write the hex encoded key to `path` with 0600 permissions.
+///
+/// The file must not exist yet, an existing key file is never overwritten.
+pub fn write_key_file(
+    key: &SecretKey,
+    path: &Path,
+) -> Result<(), &'static str> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = match options.open(path) {
+        Ok(file) => file,
+        Err(_) => {
+            return Err("Failed to create key file, does it already exist?")
+        }
+    };
 
-    hex::encode(key)
+    match file.write_all(key.to_hex().as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Failed to write key file."),
+    }
 }
 
 /// Returns ciphertext.
@@ -44,21 +107,17 @@ cannot and will not be regenerated.\n{}",
 ///
 /// ```encrypt()``` can panic if passed bad data.
 pub fn encrypt(
-    plaintext: &String,
-    key: &String,
+    plaintext: &str,
+    key: &SecretKey,
 ) -> Result<DataObject, &'static str> {
-    let key = match hex::decode(&key) {
-        Ok(key) => key,
-        Err(_) => return Err("Hex decode failed to decode key."),
-    };
-
-    let key = Key::from_slice(&key);
+    let key = Key::from_slice(&key.0[..]);
 
     let cipher = Aes256Gcm::new(key);
 
     let rng = fastrand::Rng::new();
 
-    let nonce_buf: Vec<u8> = repeat_with(|| rng.u8(..)).take(12).collect();
+    let nonce_buf: Vec<u8> =
+        repeat_with(|| rng.u8(..)).take(NONCE_SIZE).collect();
     let nonce = Nonce::from_slice(&nonce_buf);
 
     assert_eq!(nonce.len(), nonce_buf.len());
@@ -76,19 +135,14 @@ pub fn encrypt(
 /// Returns plaintext given encrypted text and encryption key.
 pub fn decrypt(
     data_object: &DataObject,
-    encryption_key: &String,
+    encryption_key: &SecretKey,
 ) -> Result<String, &'static str> {
-    let encryption_key = match hex::decode(encryption_key) {
-        Ok(key) => key,
-        Err(_) => return Err("Invalid key format!"),
-    };
-
     let ciphertext_bytes = match hex::decode(&data_object.ciphertext) {
         Ok(ct) => ct,
         Err(_) => return Err("Hex decode failed to decode ciphertext."),
     };
 
-    let encryption_key = Key::from_slice(&encryption_key);
+    let encryption_key = Key::from_slice(&encryption_key.0[..]);
     let cipher = Aes256Gcm::new(encryption_key);
 
     let nonce_start_pos = ciphertext_bytes.len() - data_object.nonce_size;
@@ -104,10 +158,99 @@ pub fn decrypt(
         Err(_) => return Err("Failed to decrypt data! Check your key."),
     };
 
-    let decrypted_text: String =
-        decrypted_text.iter().map(|val| *val as char).collect();
+    match String::from_utf8(decrypted_text) {
+        Ok(decrypted_text) => Ok(decrypted_text),
+        Err(_) => Err("Decrypted data is not valid UTF-8."),
+    }
+}
+
+/// Derive an encryption key from a passphrase with PBKDF2-HMAC-SHA256.
+pub fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> SecretKey {
+    let mut key = Zeroizing::new([0; KEY_SIZE]);
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        passphrase.as_bytes(),
+        salt,
+        iterations,
+        &mut key[..],
+    );
+
+    SecretKey(key)
+}
+
+/// Random bytes, e.g. for a salt.
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let rng = fastrand::Rng::new();
+    repeat_with(|| rng.u8(..)).take(len).collect()
+}
+
+/// Encrypt `plaintext` and authenticate it together with `associated_data`,
+/// which itself stays unencrypted.
+///
+/// Returns the ciphertext with the nonce appended, like [`encrypt`].
+pub fn encrypt_with_associated_data(
+    plaintext: &[u8],
+    associated_data: &[u8],
+    key: &SecretKey,
+) -> Result<Vec<u8>, &'static str> {
+    let cipher = Aes256Gcm::new(Key::from_slice(&key.0[..]));
+
+    let mut nonce = random_bytes(NONCE_SIZE);
+    let payload = Payload {
+        msg: plaintext,
+        aad: associated_data,
+    };
+
+    let mut ciphertext =
+        match cipher.encrypt(Nonce::from_slice(&nonce), payload) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return Err("Failed to encrypt data."),
+        };
+    ciphertext.append(&mut nonce);
+
+    Ok(ciphertext)
+}
+
+/// Reverse of [`encrypt_with_associated_data`]. Fails if either the
+/// ciphertext or the associated data was tampered with.
+pub fn decrypt_with_associated_data(
+    ciphertext: &[u8],
+    associated_data: &[u8],
+    key: &SecretKey,
+) -> Result<Zeroizing<Vec<u8>>, &'static str> {
+    if ciphertext.len() < NONCE_SIZE {
+        return Err("Ciphertext is too short.");
+    }
+
+    let cipher = Aes256Gcm::new(Key::from_slice(&key.0[..]));
+
+    let (ciphertext, nonce) =
+        ciphertext.split_at(ciphertext.len() - NONCE_SIZE);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: associated_data,
+    };
+
+    match cipher.decrypt(Nonce::from_slice(nonce), payload) {
+        Ok(plaintext) => Ok(Zeroizing::new(plaintext)),
+        Err(_) => Err("Failed to decrypt data! Check your key."),
+    }
+}
+
+/// Hex encoded SHA-256 digest of `data`, e.g. the checksum of a backup.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
 
-    Ok(decrypted_text)
+/// Hex encoded HMAC-SHA256 of `data` under `key`.
+///
+/// Unlike a plain hash, it can't be recomputed without the key, so it can
+/// identify something sensitive, like a key name, without revealing it to
+/// whoever can guess the plaintext.
+pub fn hmac_sha256_hex(key: &SecretKey, data: &[u8]) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.0[..])
+        .expect("HMAC takes keys of any size");
+    mac.update(data);
+    hex::encode(mac.finalize().into_bytes())
 }
 
 #[cfg(test)]
@@ -129,4 +272,58 @@ mod tests {
         eprintln!("decrypted_text: {}", decrypted_text);
         assert_eq!(decrypted_text, plaintext);
     }
+
+    #[test]
+    fn test_secret_key_hex_round_trip() {
+        let key = generate_key();
+        let decoded = SecretKey::from_hex(&key.to_hex()).unwrap();
+        assert!(decoded == key);
+        assert!(generate_key() != key);
+        assert!(SecretKey::from_hex("not a key").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_key_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir()
+            .join(format!("skv-test-key-{}", std::process::id()));
+        let key = generate_key();
+        write_key_file(&key, &path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        // Never clobber an existing key file.
+        let rewrite = write_key_file(&key, &path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(SecretKey::from_hex(&contents).unwrap() == key);
+        assert!(rewrite.is_err());
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_with_associated_data() {
+        let key = derive_key("correct horse battery staple", b"salt", 1000);
+        let ciphertext =
+            encrypt_with_associated_data(b"plaintext", b"header", &key)
+                .unwrap();
+
+        let plaintext =
+            decrypt_with_associated_data(&ciphertext, b"header", &key).unwrap();
+        assert_eq!(plaintext.as_slice(), b"plaintext");
+
+        assert!(
+            decrypt_with_associated_data(&ciphertext, b"HEADER", &key).is_err()
+        );
+        let other_key =
+            derive_key("correct horse battery staple", b"pepper", 1000);
+        assert!(decrypt_with_associated_data(
+            &ciphertext,
+            b"header",
+            &other_key
+        )
+        .is_err());
+    }
 }
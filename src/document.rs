@@ -0,0 +1,191 @@
+use crate::patch::Number;
+use crate::typed::CommandError;
+use serde_json::Value;
+
+/// The value at `pointer`, a JSON Pointer (RFC 6901) such as
+/// `/servers/0/port`. The empty pointer is the whole document.
+pub fn get<'a>(
+    document: &'a Value,
+    pointer: &str,
+) -> Result<&'a Value, CommandError> {
+    check(pointer)?;
+    document.pointer(pointer).ok_or_else(|| not_found(pointer))
+}
+
+/// Put `value` at `pointer`, replacing what is there. The parent has to
+/// exist already. In arrays, `-` or the length of the array adds the value
+/// to the end.
+pub fn set(
+    document: &mut Value,
+    pointer: &str,
+    value: Value,
+) -> Result<(), CommandError> {
+    check(pointer)?;
+    let (parent, token) = match split(pointer) {
+        Some(split) => split,
+        None => {
+            *document = value;
+            return Ok(());
+        }
+    };
+
+    match document.pointer_mut(parent) {
+        Some(Value::Object(object)) => {
+            object.insert(token, value);
+        }
+        Some(Value::Array(array)) => match array_index(&token, array.len()) {
+            Some(index) if index == array.len() => array.push(value),
+            Some(index) => array[index] = value,
+            None => return Err(not_found(pointer)),
+        },
+        _ => return Err(not_found(pointer)),
+    }
+
+    Ok(())
+}
+
+/// Remove the value at `pointer` from its parent, returning it. The whole
+/// document can't be removed this way.
+pub fn remove(
+    document: &mut Value,
+    pointer: &str,
+) -> Result<Value, CommandError> {
+    check(pointer)?;
+    let (parent, token) = match split(pointer) {
+        Some(split) => split,
+        None => {
+            return Err(CommandError::Invalid(
+                "Delete the key to remove the whole document.".to_string(),
+            ))
+        }
+    };
+
+    let removed = match document.pointer_mut(parent) {
+        Some(Value::Object(object)) => object.remove(&token),
+        Some(Value::Array(array)) => match array_index(&token, array.len()) {
+            Some(index) if index < array.len() => Some(array.remove(index)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    removed.ok_or_else(|| not_found(pointer))
+}
+
+/// Add `value` to the end of the array at `pointer`. Returns the new length
+/// of the array.
+pub fn append(
+    document: &mut Value,
+    pointer: &str,
+    value: Value,
+) -> Result<usize, CommandError> {
+    check(pointer)?;
+    match document.pointer_mut(pointer) {
+        Some(Value::Array(array)) => {
+            array.push(value);
+            Ok(array.len())
+        }
+        Some(_) => Err(CommandError::Invalid(format!(
+            "The value at '{}' is not an array.",
+            pointer
+        ))),
+        None => Err(not_found(pointer)),
+    }
+}
+
+/// Add `delta` to the number at `pointer`. Returns the new number.
+pub fn increment(
+    document: &mut Value,
+    pointer: &str,
+    delta: Number,
+) -> Result<Number, CommandError> {
+    check(pointer)?;
+    let target = document
+        .pointer_mut(pointer)
+        .ok_or_else(|| not_found(pointer))?;
+    let number = Number::from_json(target).ok_or(CommandError::NotANumber)?;
+    let number = number.checked_add(delta).ok_or(CommandError::Overflow)?;
+    *target = number.to_json();
+
+    Ok(number)
+}
+
+fn check(pointer: &str) -> Result<(), CommandError> {
+    match pointer.is_empty() || pointer.starts_with('/') {
+        true => Ok(()),
+        false => Err(CommandError::Invalid(format!(
+            "'{}' is not a JSON Pointer, it has to start with '/'.",
+            pointer
+        ))),
+    }
+}
+
+/// The pointer to the parent and the unescaped last token, or `None` for the
+/// whole document.
+fn split(pointer: &str) -> Option<(&str, String)> {
+    let (parent, token) = pointer.rsplit_once('/')?;
+    Some((parent, token.replace("~1", "/").replace("~0", "~")))
+}
+
+/// The index a token points to in an array of `len` values. `-` is the
+/// index past the end.
+fn array_index(token: &str, len: usize) -> Option<usize> {
+    if token == "-" {
+        return Some(len);
+    }
+    // Leading zeros aren't allowed.
+    if token.len() > 1 && token.starts_with('0') {
+        return None;
+    }
+    token.parse().ok().filter(|index| *index <= len)
+}
+
+fn not_found(pointer: &str) -> CommandError {
+    CommandError::PathNotFound(pointer.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_pointers() {
+        let mut document = json!({ "a/b": 1, "list": [1, 2], "n": { "x": 1 } });
+        assert_eq!(get(&document, "/a~1b").unwrap(), &json!(1));
+        assert_eq!(get(&document, "/list/1").unwrap(), &json!(2));
+        assert_eq!(get(&document, "").unwrap(), &document.clone());
+        assert!(matches!(
+            get(&document, "/nothing"),
+            Err(CommandError::PathNotFound(_))
+        ));
+        assert!(matches!(
+            get(&document, "list"),
+            Err(CommandError::Invalid(_))
+        ));
+
+        set(&mut document, "/n/y", json!("new")).unwrap();
+        set(&mut document, "/list/-", json!(3)).unwrap();
+        set(&mut document, "/list/0", json!(0)).unwrap();
+        assert!(set(&mut document, "/missing/y", json!(1)).is_err());
+        assert!(set(&mut document, "/list/9", json!(1)).is_err());
+        assert_eq!(append(&mut document, "/list", json!(4)).unwrap(), 4);
+        assert!(append(&mut document, "/n", json!(4)).is_err());
+
+        assert_eq!(
+            increment(&mut document, "/n/x", Number::Int(2)).unwrap(),
+            Number::Int(3)
+        );
+        assert_eq!(
+            increment(&mut document, "/n/y", Number::Int(1)),
+            Err(CommandError::NotANumber)
+        );
+
+        assert_eq!(remove(&mut document, "/list/0").unwrap(), json!(0));
+        assert!(remove(&mut document, "").is_err());
+        assert_eq!(
+            document,
+            json!({ "a/b": 1, "list": [2, 3, 4], "n": { "x": 3, "y": "new" } })
+        );
+    }
+}
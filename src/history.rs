@@ -0,0 +1,190 @@
+use crate::connection::namespace_of;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many old values of a key are kept once it is overwritten or deleted,
+/// on top of those a snapshot still needs. An old value is kept if any of
+/// the limits asks for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Number of old values to keep, newest first.
+    pub versions: usize,
+    /// Keep every value written less than this long ago.
+    pub max_age: Duration,
+    /// How long a deleted key keeps its last value, so it can be restored
+    /// from the trash.
+    pub trash: Duration,
+}
+
+impl Retention {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "versions": self.versions,
+            "max_age": self.max_age.as_secs(),
+            "trash": self.trash.as_secs(),
+        })
+    }
+}
+
+/// The retention of every namespace, shared by all shards of a store.
+#[derive(Debug, Default)]
+pub struct RetentionPolicy {
+    default: Retention,
+    namespaces: RwLock<HashMap<String, Retention>>,
+}
+
+impl RetentionPolicy {
+    /// A policy with the same retention for every namespace, until some are
+    /// given their own with [`RetentionPolicy::set`].
+    pub fn new(default: Retention) -> Self {
+        Self {
+            default,
+            namespaces: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Retention of the namespace of `key`, see [`namespace_of`].
+    pub fn for_key(&self, key: &str) -> Retention {
+        self.for_namespace(namespace_of(key))
+    }
+
+    pub fn for_namespace(&self, namespace: &str) -> Retention {
+        self.namespaces
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(namespace)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Whether every namespace has the default retention, so keys don't have
+    /// to be decrypted to find theirs.
+    pub fn is_uniform(&self) -> bool {
+        self.namespaces
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    /// Give a namespace its own retention. It applies from the next time a
+    /// key of the namespace is written or old values are collected.
+    pub fn set(&self, namespace: &str, retention: Retention) {
+        self.namespaces
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(namespace.to_string(), retention);
+    }
+
+    /// Go back to the default retention for a namespace. Returns whether it
+    /// had its own.
+    pub fn reset(&self, namespace: &str) -> bool {
+        self.namespaces
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(namespace)
+            .is_some()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let namespaces: serde_json::Map<String, Value> = self
+            .namespaces
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(namespace, retention)| {
+                (namespace.clone(), retention.to_json())
+            })
+            .collect();
+
+        json!({
+            "default": self.default.to_json(),
+            "namespaces": namespaces,
+        })
+    }
+}
+
+/// One value in the history of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Store version the value was written in.
+    pub version: u64,
+    pub written: SystemTime,
+    /// Whether the key was deleted in this version.
+    pub deleted: bool,
+}
+
+impl HistoryEntry {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "version": self.version,
+            "written": unix_secs(self.written),
+            "deleted": self.deleted,
+        })
+    }
+}
+
+/// A deleted key whose last value is still kept, see
+/// [`crate::store::ShardedStore::trash`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    pub key: String,
+    /// Store version the key was deleted in.
+    pub version: u64,
+    pub deleted: SystemTime,
+    /// Time until the trash retention of the key's namespace runs out. The
+    /// value may be kept for longer if the rest of the retention asks for
+    /// it.
+    pub expires_in: Duration,
+}
+
+impl TrashEntry {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "key": self.key,
+            "version": self.version,
+            "deleted": unix_secs(self.deleted),
+            "expires_in": self.expires_in.as_secs(),
+        })
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retention_per_namespace() {
+        let default = Retention {
+            versions: 1,
+            ..Retention::default()
+        };
+        let users = Retention {
+            versions: 5,
+            max_age: Duration::from_secs(60),
+            trash: Duration::from_secs(3600),
+        };
+        let policy = RetentionPolicy::new(default);
+        assert!(policy.is_uniform());
+
+        policy.set("users", users);
+        assert!(!policy.is_uniform());
+        assert_eq!(policy.for_key("users:alice"), users);
+        assert_eq!(policy.for_key("motd"), default);
+        assert_eq!(
+            policy.to_json()["namespaces"]["users"],
+            json!({ "versions": 5, "max_age": 60, "trash": 3600 })
+        );
+
+        assert!(policy.reset("users"));
+        assert!(!policy.reset("users"));
+        assert_eq!(policy.for_key("users:alice"), default);
+    }
+}
@@ -0,0 +1,442 @@
+use crate::connection::namespace_of;
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+/// A value of an indexed field. Values of different JSON types are ordered
+/// null, booleans, numbers, strings.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IndexValue {
+    Null,
+    Bool(bool),
+    Number(OrderedFloat),
+    String(String),
+}
+
+/// A number that can be ordered. Integers are indexed as floats, so very
+/// large ones may compare equal.
+#[derive(Debug, Clone, Copy)]
+pub struct OrderedFloat(f64);
+
+impl PartialEq for OrderedFloat {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedFloat {}
+
+impl PartialOrd for OrderedFloat {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedFloat {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl IndexValue {
+    /// The indexed value of a JSON value. Only scalars are indexed.
+    pub fn from_json(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(IndexValue::Null),
+            Value::Bool(bool) => Some(IndexValue::Bool(*bool)),
+            // -0.0 and 0.0 are the same number.
+            Value::Number(number) => number
+                .as_f64()
+                .map(|float| IndexValue::Number(OrderedFloat(float + 0.0))),
+            Value::String(string) => Some(IndexValue::String(string.clone())),
+            Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+
+    /// A value from a query parameter: JSON if it is a JSON scalar, e.g.
+    /// `3`, `true` or `"3"`, and a string otherwise.
+    pub fn from_query(text: &str) -> Self {
+        serde_json::from_str(text)
+            .ok()
+            .and_then(|value| Self::from_json(&value))
+            .unwrap_or_else(|| IndexValue::String(text.to_string()))
+    }
+}
+
+/// Keys by the value of one field of their JSON documents.
+#[derive(Debug, Default)]
+struct Index {
+    entries: BTreeMap<IndexValue, BTreeSet<String>>,
+    values: HashMap<String, IndexValue>,
+}
+
+impl Index {
+    /// Index `key` by `value`, or remove it from the index if it is `None`.
+    fn update(&mut self, key: &str, value: Option<IndexValue>) {
+        if let Some(old) = self.values.remove(key) {
+            if let Some(keys) = self.entries.get_mut(&old) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&old);
+                }
+            }
+        }
+
+        if let Some(value) = value {
+            self.entries
+                .entry(value.clone())
+                .or_default()
+                .insert(key.to_string());
+            self.values.insert(key.to_string(), value);
+        }
+    }
+}
+
+/// An index and, while it is being built, the index that will replace it.
+#[derive(Debug, Default)]
+struct IndexState {
+    current: Index,
+    /// Kept up to date by writes while the store is scanned, see
+    /// [`Indexes::start_build`].
+    pending: Option<Index>,
+    /// Whether `current` has been built once, so it can be queried.
+    ready: bool,
+}
+
+/// Why an index operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexError {
+    /// An index on the field already exists.
+    Exists,
+    NotFound,
+    /// The index is being built and can't be built again or queried yet.
+    Building,
+}
+
+impl IndexError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            IndexError::Exists => "The index already exists.",
+            IndexError::NotFound => "No such index.",
+            IndexError::Building => "The index is still being built.",
+        }
+    }
+}
+
+/// Secondary indexes over fields of the JSON documents in each namespace,
+/// shared by all shards of a store.
+///
+/// Fields are JSON Pointers, see [`crate::document::get`]. Keys whose value
+/// isn't a JSON document, or has no scalar at the field, aren't in the
+/// index. Indexes keep field values and keys in memory unencrypted.
+///
+/// Every index has a lock of its own, so writes to different shards only
+/// wait for each other while they update the same index. The map of indexes
+/// is only locked for writing to create or remove one.
+#[derive(Debug, Default)]
+pub struct Indexes {
+    /// Indexes by namespace, then field.
+    indexes: RwLock<HashMap<String, BTreeMap<String, SharedIndex>>>,
+}
+
+/// An index, locked on its own, see [`Indexes`].
+type SharedIndex = Arc<Mutex<IndexState>>;
+
+impl Indexes {
+    /// Whether any field of the namespace is indexed, so writes to its keys
+    /// have to update an index.
+    pub fn covers(&self, namespace: &str) -> bool {
+        self.indexes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(namespace)
+    }
+
+    /// Index the key by its new value, `None` if it was deleted or isn't a
+    /// JSON document.
+    ///
+    /// Called on every write, while holding the lock of the key's shard.
+    pub fn update(&self, key: &str, document: Option<&Value>) {
+        let indexes =
+            self.indexes.read().unwrap_or_else(PoisonError::into_inner);
+        let fields = match indexes.get(namespace_of(key)) {
+            Some(fields) => fields,
+            None => return,
+        };
+
+        for (field, state) in fields {
+            let mut state =
+                state.lock().unwrap_or_else(PoisonError::into_inner);
+            let value = document
+                .and_then(|document| document.pointer(field))
+                .and_then(IndexValue::from_json);
+            if let Some(pending) = &mut state.pending {
+                pending.update(key, value.clone());
+            }
+            state.current.update(key, value);
+        }
+    }
+
+    /// Declare an index on a field of the namespace. It can be queried once
+    /// it has been built, see [`Indexes::start_build`].
+    pub fn create(
+        &self,
+        namespace: &str,
+        field: &str,
+    ) -> Result<(), IndexError> {
+        let mut indexes =
+            self.indexes.write().unwrap_or_else(PoisonError::into_inner);
+        let fields = indexes.entry(namespace.to_string()).or_default();
+        if fields.contains_key(field) {
+            return Err(IndexError::Exists);
+        }
+        fields.insert(field.to_string(), Arc::default());
+
+        Ok(())
+    }
+
+    /// Remove an index. Returns whether it existed.
+    pub fn remove(&self, namespace: &str, field: &str) -> bool {
+        let mut indexes =
+            self.indexes.write().unwrap_or_else(PoisonError::into_inner);
+        let fields = match indexes.get_mut(namespace) {
+            Some(fields) => fields,
+            None => return false,
+        };
+        let removed = fields.remove(field).is_some();
+        if fields.is_empty() {
+            indexes.remove(namespace);
+        }
+
+        removed
+    }
+
+    /// Start building the index again from scratch.
+    ///
+    /// Until [`Indexes::finish_build`], the new index is kept up to date by
+    /// writes as well, while the keys that are already in the store are
+    /// added with [`Indexes::load`]. Queries keep using the old index.
+    pub fn start_build(
+        &self,
+        namespace: &str,
+        field: &str,
+    ) -> Result<(), IndexError> {
+        self.with_state(namespace, field, |state| {
+            if state.pending.is_some() {
+                return Err(IndexError::Building);
+            }
+            state.pending = Some(Index::default());
+            Ok(())
+        })
+    }
+
+    /// Add documents that are in the store to the index being built.
+    ///
+    /// Must be called while holding the lock of the shard the documents are
+    /// from, so no write to them can get in between.
+    pub fn load(
+        &self,
+        namespace: &str,
+        field: &str,
+        documents: &[(String, Value)],
+    ) {
+        let _ = self.with_state(namespace, field, |state| {
+            if let Some(pending) = &mut state.pending {
+                for (key, document) in documents {
+                    let value =
+                        document.pointer(field).and_then(IndexValue::from_json);
+                    pending.update(key, value);
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// Replace the index with the one that has been built. Returns the
+    /// number of indexed keys.
+    pub fn finish_build(
+        &self,
+        namespace: &str,
+        field: &str,
+    ) -> Result<usize, IndexError> {
+        self.with_state(namespace, field, |state| {
+            if let Some(pending) = state.pending.take() {
+                state.current = pending;
+                state.ready = true;
+            }
+            Ok(state.current.values.len())
+        })
+    }
+
+    /// The keys whose field is within the bounds, ordered by the value of
+    /// the field, then by key.
+    pub fn query(
+        &self,
+        namespace: &str,
+        field: &str,
+        bounds: (Bound<IndexValue>, Bound<IndexValue>),
+    ) -> Result<Vec<String>, IndexError> {
+        // BTreeMap::range panics on bounds that can't contain anything.
+        if is_empty_range(&bounds) {
+            return Ok(Vec::new());
+        }
+
+        self.with_state(namespace, field, |state| {
+            if !state.ready {
+                return Err(IndexError::Building);
+            }
+            Ok(state
+                .current
+                .entries
+                .range(bounds.clone())
+                .flat_map(|(_, keys)| keys.iter().cloned())
+                .collect())
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let indexes =
+            self.indexes.read().unwrap_or_else(PoisonError::into_inner);
+        let mut list = Vec::new();
+        for (namespace, fields) in indexes.iter() {
+            for (field, state) in fields {
+                let state =
+                    state.lock().unwrap_or_else(PoisonError::into_inner);
+                list.push(json!({
+                    "namespace": namespace,
+                    "field": field,
+                    "keys": state.current.values.len(),
+                    "ready": state.ready,
+                    "building": state.pending.is_some(),
+                }));
+            }
+        }
+        list.sort_by_key(|index| {
+            (index["namespace"].to_string(), index["field"].to_string())
+        });
+
+        Value::from(list)
+    }
+
+    fn with_state<T>(
+        &self,
+        namespace: &str,
+        field: &str,
+        f: impl FnOnce(&mut IndexState) -> Result<T, IndexError>,
+    ) -> Result<T, IndexError> {
+        let state = self
+            .indexes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(namespace)
+            .and_then(|fields| fields.get(field))
+            .cloned();
+
+        match state {
+            Some(state) => {
+                f(&mut state.lock().unwrap_or_else(PoisonError::into_inner))
+            }
+            None => Err(IndexError::NotFound),
+        }
+    }
+}
+
+/// Whether no value can be within the bounds, e.g. because the lower one
+/// is greater than the upper one.
+fn is_empty_range(bounds: &(Bound<IndexValue>, Bound<IndexValue>)) -> bool {
+    match bounds {
+        (Bound::Included(min), Bound::Included(max)) => min > max,
+        (
+            Bound::Included(min) | Bound::Excluded(min),
+            Bound::Included(max) | Bound::Excluded(max),
+        ) => min >= max,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn all() -> (Bound<IndexValue>, Bound<IndexValue>) {
+        (Bound::Unbounded, Bound::Unbounded)
+    }
+
+    #[test]
+    fn test_index_updates_and_queries() {
+        let indexes = Indexes::default();
+        indexes.create("jobs", "/status").unwrap();
+        assert_eq!(indexes.create("jobs", "/status"), Err(IndexError::Exists));
+        assert!(indexes.covers("jobs"));
+        assert!(!indexes.covers("users"));
+
+        // Writes while the store is scanned end up in the new index.
+        indexes.start_build("jobs", "/status").unwrap();
+        assert_eq!(
+            indexes.query("jobs", "/status", all()),
+            Err(IndexError::Building)
+        );
+        indexes.update("jobs:1", Some(&json!({ "status": "failed" })));
+        indexes.load(
+            "jobs",
+            "/status",
+            &[("jobs:2".to_string(), json!({ "status": "done" }))],
+        );
+        assert_eq!(indexes.finish_build("jobs", "/status"), Ok(2));
+
+        indexes.update("jobs:3", Some(&json!({ "status": "failed" })));
+        indexes.update("jobs:4", Some(&json!({ "other": 1 })));
+        indexes.update("users:1", Some(&json!({ "status": "failed" })));
+        let failed = IndexValue::from_query("failed");
+        let query = (Bound::Included(failed.clone()), Bound::Included(failed));
+        assert_eq!(
+            indexes.query("jobs", "/status", query.clone()).unwrap(),
+            vec!["jobs:1", "jobs:3"]
+        );
+
+        indexes.update("jobs:1", Some(&json!({ "status": "done" })));
+        indexes.update("jobs:3", None);
+        assert!(indexes.query("jobs", "/status", query).unwrap().is_empty());
+        assert_eq!(
+            indexes.query("jobs", "/status", all()).unwrap(),
+            vec!["jobs:1", "jobs:2"]
+        );
+
+        assert!(indexes.remove("jobs", "/status"));
+        assert!(!indexes.covers("jobs"));
+    }
+
+    #[test]
+    fn test_range_queries() {
+        let indexes = Indexes::default();
+        indexes.create("", "/n").unwrap();
+        indexes.start_build("", "/n").unwrap();
+        indexes.finish_build("", "/n").unwrap();
+        for (key, n) in [
+            ("a", json!(3)),
+            ("b", json!(1.5)),
+            ("c", json!("x")),
+            ("d", json!(-2)),
+        ] {
+            indexes.update(key, Some(&json!({ "n": n })));
+        }
+
+        let range = (
+            Bound::Included(IndexValue::from_query("0")),
+            Bound::Excluded(IndexValue::from_query("3")),
+        );
+        assert_eq!(indexes.query("", "/n", range).unwrap(), vec!["b"]);
+        let range = (
+            Bound::Excluded(IndexValue::from_query("1.5")),
+            Bound::Unbounded,
+        );
+        assert_eq!(indexes.query("", "/n", range).unwrap(), vec!["a", "c"]);
+        let reversed = (
+            Bound::Included(IndexValue::from_query("3")),
+            Bound::Included(IndexValue::from_query("1")),
+        );
+        assert!(indexes.query("", "/n", reversed).unwrap().is_empty());
+    }
+}
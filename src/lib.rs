@@ -1,3 +1,24 @@
+pub mod admin;
+pub mod async_server;
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod client;
 pub mod connection;
+pub mod cors;
 pub mod crypto;
+pub mod document;
+pub mod history;
+pub mod index;
+pub mod lockout;
+pub mod metrics;
+pub mod patch;
+pub mod pubsub;
+pub mod router;
+pub mod server;
+pub mod store;
+pub mod stream;
 pub mod thread;
+pub mod transfer;
+pub mod typed;
+pub mod watch;
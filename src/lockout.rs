@@ -0,0 +1,439 @@
+use crate::connection::{json_error, parse_encryption_key_from_headers};
+use crate::crypto::sha256_hex;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Failed attempts that are let through before a subject gets locked out.
+const FREE_ATTEMPTS: u32 = 3;
+/// Length of the first lockout, it doubles with every further failure.
+const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+/// Upper bound for a single lockout.
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Failures are forgotten once a subject has been quiet for this long.
+const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Most subjects whose failures are tracked at once. When it is reached, the
+/// subject that failed least recently and isn't locked out is forgotten
+/// first.
+const MAX_TRACKED_SUBJECTS: usize = 10_000;
+
+/// Something failed authentication attempts are counted against.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Subject {
+    /// The address of the client.
+    Ip(IpAddr),
+    /// A guessed encryption key, identified by a prefix of its SHA-256 hash so
+    /// the guess itself is never stored.
+    Token(String),
+}
+
+impl Subject {
+    /// Subjects for a request: the client address and, if a `key` header was
+    /// sent, the key that was provided.
+    pub fn from_request(ip: IpAddr, buf: &[u8]) -> Vec<Subject> {
+        let mut subjects = vec![Subject::Ip(ip)];
+
+        if let Ok(key) = parse_encryption_key_from_headers(buf) {
+            subjects
+                .push(Subject::Token(sha256_hex(key.as_bytes())[..16].into()));
+        }
+
+        subjects
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Subject::Ip(ip) => write!(f, "ip:{}", ip),
+            Subject::Token(hash) => write!(f, "token:{}", hash),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    /// Whether the failures can be forgotten: the subject isn't locked out
+    /// and has been quiet for long enough.
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_failure) >= FAILURE_WINDOW
+            && self.locked_until.is_none_or(|until| until <= now)
+    }
+
+    /// From when on the failures may be forgotten to make room for another
+    /// subject: right away, unless the subject is locked out.
+    fn evictable_from(&self) -> Instant {
+        self.locked_until.unwrap_or(self.last_failure)
+    }
+}
+
+/// A currently tracked subject, as reported to admins.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Lockout {
+    /// The subject, e.g. `ip:127.0.0.1`.
+    pub subject: String,
+    pub failures: u32,
+    /// Seconds until the subject may try again, 0 if it isn't locked out.
+    pub retry_after: u64,
+}
+
+/// Tracks failed authentication attempts per client address and per provided
+/// key and locks them out with exponential backoff.
+///
+/// The first few failures are free. After that, every failure locks the
+/// subject out for twice as long as the previous one, up to a maximum.
+/// At most [`MAX_TRACKED_SUBJECTS`] subjects are tracked, so a flood of
+/// addresses or keys can't grow the tracker without bounds. Subjects that
+/// are locked out are never forgotten to make room: while every tracked
+/// subject is, requests from new subjects are turned away instead.
+pub struct LockoutTracker {
+    failures: HashMap<Subject, Failures>,
+    /// Tracked subjects ordered by [`Failures::evictable_from`].
+    by_eviction: BTreeSet<(Instant, Subject)>,
+}
+
+impl LockoutTracker {
+    pub fn new() -> Self {
+        Self {
+            failures: HashMap::new(),
+            by_eviction: BTreeSet::new(),
+        }
+    }
+
+    /// Check whether any of the subjects is locked out, or is new while
+    /// there is no room to track it.
+    ///
+    /// Returns how long the caller has to wait before trying again.
+    pub fn check(&self, subjects: &[Subject]) -> Result<(), Duration> {
+        let now = Instant::now();
+
+        let retry_after = subjects
+            .iter()
+            .filter_map(|subject| self.failures.get(subject)?.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until - now)
+            .max();
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+
+        let untracked = subjects
+            .iter()
+            .any(|subject| !self.failures.contains_key(subject));
+        match self.room_from(now) {
+            Some(room_from) if untracked && room_from > now => {
+                Err(room_from - now)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a failed authentication attempt against every subject.
+    pub fn record_failure(&mut self, subjects: &[Subject]) {
+        let now = Instant::now();
+
+        for subject in subjects {
+            let mut failures = match self.remove(subject) {
+                Some(failures) if !failures.expired(now) => failures,
+                Some(_) => new_failures(now),
+                None if self.make_room(now) => new_failures(now),
+                None => {
+                    eprintln!(
+                        "Lockout tracker is full, not tracking {}",
+                        subject
+                    );
+                    continue;
+                }
+            };
+
+            failures.count += 1;
+            failures.last_failure = now;
+
+            if failures.count <= FREE_ATTEMPTS {
+                eprintln!(
+                    "Failed authentication attempt {} for {}",
+                    failures.count, subject
+                );
+            } else {
+                let lockout = lockout_duration(failures.count);
+                failures.locked_until = Some(now + lockout);
+                eprintln!(
+                    "Locked out {} for {}s after {} failed authentication \
+                    attempts",
+                    subject,
+                    lockout.as_secs(),
+                    failures.count
+                );
+            }
+            self.insert(subject.clone(), failures);
+        }
+    }
+
+    /// Forget previous failures of every subject after a successful attempt.
+    pub fn record_success(&mut self, subjects: &[Subject]) {
+        for subject in subjects {
+            self.remove(subject);
+        }
+    }
+
+    /// All subjects with recorded failures.
+    pub fn list(&self) -> Vec<Lockout> {
+        let now = Instant::now();
+
+        let mut lockouts: Vec<Lockout> = self
+            .failures
+            .iter()
+            .filter(|(_, failures)| !failures.expired(now))
+            .map(|(subject, failures)| Lockout {
+                subject: subject.to_string(),
+                failures: failures.count,
+                retry_after: failures.locked_until.map_or(0, |until| {
+                    retry_after_secs(until.saturating_duration_since(now))
+                }),
+            })
+            .collect();
+        lockouts.sort_by(|a, b| a.subject.cmp(&b.subject));
+
+        lockouts
+    }
+
+    /// Clear the failures of the subject with the given name (e.g.
+    /// `ip:127.0.0.1`), or of every subject if `subject` is `None`.
+    ///
+    /// Returns the number of subjects cleared.
+    pub fn clear(&mut self, subject: Option<&str>) -> usize {
+        let cleared = match subject {
+            Some(name) => {
+                let named: Vec<Subject> = self
+                    .failures
+                    .keys()
+                    .filter(|subject| subject.to_string() == name)
+                    .cloned()
+                    .collect();
+                for subject in &named {
+                    self.remove(subject);
+                }
+                named.len()
+            }
+            None => {
+                let cleared = self.failures.len();
+                self.failures.clear();
+                self.by_eviction.clear();
+                cleared
+            }
+        };
+
+        if cleared > 0 {
+            eprintln!(
+                "Cleared {} lockout(s) for {}",
+                cleared,
+                subject.unwrap_or("all subjects")
+            );
+        }
+
+        cleared
+    }
+
+    fn insert(&mut self, subject: Subject, failures: Failures) {
+        self.by_eviction
+            .insert((failures.evictable_from(), subject.clone()));
+        self.failures.insert(subject, failures);
+    }
+
+    fn remove(&mut self, subject: &Subject) -> Option<Failures> {
+        let failures = self.failures.remove(subject)?;
+        self.by_eviction
+            .remove(&(failures.evictable_from(), subject.clone()));
+        Some(failures)
+    }
+
+    /// When there is room for another subject: now if fewer than
+    /// [`MAX_TRACKED_SUBJECTS`] are tracked, otherwise once the first one can
+    /// be forgotten. `None` if there is room.
+    fn room_from(&self, now: Instant) -> Option<Instant> {
+        if self.failures.len() < MAX_TRACKED_SUBJECTS {
+            return None;
+        }
+
+        self.by_eviction.first().map(|(from, _)| (*from).max(now))
+    }
+
+    /// Make room for another subject by forgetting the ones that failed
+    /// least recently, as long as they aren't locked out.
+    ///
+    /// Returns whether there is room.
+    fn make_room(&mut self, now: Instant) -> bool {
+        while self.failures.len() >= MAX_TRACKED_SUBJECTS {
+            let subject = match self.by_eviction.first() {
+                Some((from, subject)) if *from <= now => subject.clone(),
+                _ => return false,
+            };
+            self.remove(&subject);
+        }
+
+        true
+    }
+}
+
+impl Default for LockoutTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn new_failures(now: Instant) -> Failures {
+    Failures {
+        count: 0,
+        last_failure: now,
+        locked_until: None,
+    }
+}
+
+/// Status line and JSON body for a locked out request. The `Retry-After`
+/// header has to be set to [`retry_after_secs`] by the caller.
+pub fn locked_out_response() -> (String, String) {
+    (
+        "HTTP/1.1 429 Too Many Requests".to_string(),
+        json_error("Too many failed authentication attempts. Try again later."),
+    )
+}
+
+/// Whole seconds to wait, rounded up so clients never retry too early.
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+fn lockout_duration(failures: u32) -> Duration {
+    let exponent = (failures - FREE_ATTEMPTS - 1).min(31);
+    BASE_LOCKOUT
+        .checked_mul(1 << exponent)
+        .unwrap_or(MAX_LOCKOUT)
+        .min(MAX_LOCKOUT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn subjects() -> Vec<Subject> {
+        vec![
+            Subject::Ip(CLIENT),
+            Subject::Token("0123456789abcdef".to_string()),
+        ]
+    }
+
+    fn lock_out(tracker: &mut LockoutTracker, subjects: &[Subject]) {
+        for _ in 0..=FREE_ATTEMPTS {
+            tracker.record_failure(subjects);
+        }
+    }
+
+    #[test]
+    fn test_lockout_after_free_attempts() {
+        let mut tracker = LockoutTracker::new();
+        let subjects = subjects();
+
+        for _ in 0..FREE_ATTEMPTS {
+            tracker.record_failure(&subjects);
+            assert!(tracker.check(&subjects).is_ok());
+        }
+
+        tracker.record_failure(&subjects);
+        let retry_after = tracker.check(&subjects).unwrap_err();
+        assert!(retry_after <= BASE_LOCKOUT);
+        assert_eq!(retry_after_secs(retry_after), 1);
+
+        // Either subject on its own is locked out as well.
+        assert!(tracker.check(&subjects[1..]).is_err());
+        // Other clients aren't locked out.
+        let other = Subject::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert!(tracker.check(&[other]).is_ok());
+    }
+
+    #[test]
+    fn test_subjects_from_request() {
+        let buf = b"GET /a HTTP/1.1\r\nkey: guess\r\n\r\n";
+        let subjects = Subject::from_request(CLIENT, buf);
+        assert_eq!(subjects[0].to_string(), "ip:127.0.0.1");
+        assert_eq!(
+            subjects[1].to_string(),
+            format!("token:{}", &sha256_hex(b"guess")[..16])
+        );
+
+        let buf = b"GET /a HTTP/1.1\r\n\r\n";
+        assert_eq!(Subject::from_request(CLIENT, buf).len(), 1);
+    }
+
+    #[test]
+    fn test_lockout_duration_backs_off() {
+        assert_eq!(lockout_duration(FREE_ATTEMPTS + 1), BASE_LOCKOUT);
+        assert_eq!(lockout_duration(FREE_ATTEMPTS + 2), BASE_LOCKOUT * 2);
+        assert_eq!(lockout_duration(FREE_ATTEMPTS + 4), BASE_LOCKOUT * 8);
+        assert_eq!(lockout_duration(u32::MAX), MAX_LOCKOUT);
+    }
+
+    #[test]
+    fn test_success_and_clear() {
+        let mut tracker = LockoutTracker::new();
+        let subjects = subjects();
+
+        tracker.record_failure(&subjects);
+        assert_eq!(tracker.list().len(), 2);
+        tracker.record_success(&subjects);
+        assert!(tracker.list().is_empty());
+
+        lock_out(&mut tracker, &subjects);
+        assert_eq!(tracker.list()[0].subject, "ip:127.0.0.1");
+        assert_eq!(tracker.list()[0].failures, FREE_ATTEMPTS + 1);
+        assert_eq!(tracker.clear(Some("ip:127.0.0.2")), 0);
+        assert_eq!(tracker.clear(Some("ip:127.0.0.1")), 1);
+        assert!(tracker.check(&subjects[..1]).is_ok());
+        assert!(tracker.check(&subjects).is_err());
+        assert_eq!(tracker.clear(None), 1);
+        assert!(tracker.check(&subjects).is_ok());
+        assert!(tracker.by_eviction.is_empty());
+    }
+
+    #[test]
+    fn test_tracked_subjects_are_capped() {
+        let mut tracker = LockoutTracker::new();
+        let client =
+            |i: usize| Subject::Ip(IpAddr::V4(Ipv4Addr::from(i as u32)));
+
+        // Subjects that aren't locked out make room, oldest first.
+        for _ in 0..FREE_ATTEMPTS + 8 {
+            tracker.record_failure(&[Subject::Ip(CLIENT)]);
+        }
+        for i in 0..MAX_TRACKED_SUBJECTS {
+            tracker.record_failure(&[client(i)]);
+        }
+        assert_eq!(tracker.failures.len(), MAX_TRACKED_SUBJECTS);
+        assert_eq!(tracker.by_eviction.len(), MAX_TRACKED_SUBJECTS);
+        assert!(tracker.check(&[Subject::Ip(CLIENT)]).is_err());
+        assert!(!tracker.failures.contains_key(&client(0)));
+        assert!(tracker.failures.contains_key(&client(1)));
+
+        // Subjects that are locked out don't, new ones are turned away
+        // until they would.
+        for i in 1..MAX_TRACKED_SUBJECTS {
+            lock_out(&mut tracker, &[client(i)]);
+        }
+        assert!(tracker.check(&[Subject::Ip(CLIENT)]).is_err());
+        let new = [client(MAX_TRACKED_SUBJECTS)];
+        assert!(tracker.check(&new).is_err());
+        tracker.record_failure(&new);
+        assert!(!tracker.failures.contains_key(&new[0]));
+        assert_eq!(tracker.failures.len(), MAX_TRACKED_SUBJECTS);
+    }
+}
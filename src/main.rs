@@ -1,17 +1,97 @@
-use clap::Parser;
-use skv::connection::{self, KeyValueStore, RequestType};
-use skv::thread::ThreadPool;
+use clap::{Parser, Subcommand};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use signal_hook::low_level::signal_name;
+use skv::async_server::AsyncServer;
+use skv::audit::{self, AuditLog};
+use skv::client;
+use skv::cors::CorsConfig;
+use skv::crypto::{self, SecretKey};
+use skv::history::Retention;
+use skv::server::Server;
+use skv::store::StoreConfig;
+use skv::thread::{PoolConfig, QueuePolicy, ThreadPool};
+use skv::transfer::{self, ExportWriter, Format, ImportSummary};
 use std::{
     error::Error,
-    net::TcpListener,
-    sync::{Arc, RwLock},
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicI32, Ordering},
+    sync::Arc,
+    thread,
+    time::Duration,
 };
+use zeroize::Zeroizing;
 
-const THREAD_COUNT: usize = 4;
+/// Most rows `skv import` sends to the server in a single request.
+const IMPORT_BATCH_ROWS: usize = 500;
+/// Size of the request body after which `skv import` sends a batch, even if it
+/// has fewer rows.
+const IMPORT_BATCH_BYTES: usize = 1024 * 1024;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
+    match &args.command {
+        Some(Command::Audit {
+            command: AuditCommand::Verify { path, key_file },
+        }) => verify_audit_log(path, key_file),
+        Some(Command::Backup {
+            output,
+            namespace,
+            passphrase_file,
+            client,
+        }) => backup(&args.port, client, passphrase_file, output, namespace),
+        Some(Command::Restore {
+            input,
+            on_server,
+            mode,
+            namespace,
+            passphrase_file,
+            client,
+        }) => restore(
+            &args.port,
+            client,
+            passphrase_file,
+            input,
+            *on_server,
+            mode,
+            namespace,
+        ),
+        Some(Command::Export {
+            output,
+            format,
+            prefix,
+            client,
+        }) => export(&args.port, client, output, format, prefix),
+        Some(Command::Import {
+            input,
+            format,
+            prefix,
+            existing,
+            dry_run,
+            client,
+        }) => import(
+            &args.port, client, input, format, prefix, existing, *dry_run,
+        ),
+        None => serve(&args),
+    }
+}
+
+fn serve(args: &Args) -> Result<(), Box<dyn Error>> {
+    let pool_config = PoolConfig {
+        workers: args.workers,
+        min_workers: args.min_workers.unwrap_or(args.workers),
+        max_workers: args.max_workers.unwrap_or(args.workers),
+        queue_capacity: args.queue_depth as usize,
+        idle_timeout: Duration::from_secs(args.worker_idle_timeout),
+    };
+    pool_config.validate()?;
+    let queue_policy = QueuePolicy::from_name(&args.queue_policy)?;
+
     let listener = TcpListener::bind(format!("localhost:{}", args.port))
         .unwrap_or_else(|_| {
             panic!(
@@ -20,49 +100,774 @@ fn main() -> Result<(), Box<dyn Error>> {
             )
         });
 
-    let key_value_store = Arc::new(RwLock::new(KeyValueStore::new()));
+    let encryption_key = crypto::generate_key();
+    match &args.key_file {
+        Some(key_file) => {
+            crypto::write_key_file(&encryption_key, key_file)?;
+            println!("Encryption key written to {}", key_file.display());
+        }
+        None => println!(
+            "
+Save this key and keep it secret! It \
+cannot and will not be regenerated.\n{}",
+            encryption_key.to_hex().as_str()
+        ),
+    }
+
+    let audit_log = match (&args.audit_log, &args.audit_key_file) {
+        (Some(path), Some(key_file)) => {
+            audit::check_key_location(path, key_file)?;
+            Some(AuditLog::open(path, audit_key(key_file)?)?)
+        }
+        (Some(_), None) => {
+            return Err("--audit-log needs an --audit-key-file.".into())
+        }
+        (None, _) => None,
+    };
+
+    let store_config = StoreConfig {
+        shards: args.shards as usize,
+        retention: Retention {
+            versions: args.keep_versions,
+            max_age: Duration::from_secs(args.keep_versions_for),
+            trash: Duration::from_secs(args.trash_retention),
+        },
+        watch_history: args.watch_history as usize,
+    };
+    let cors = CorsConfig {
+        origins: args.cors_origin.clone(),
+        methods: args.cors_method.clone(),
+        headers: args.cors_header.clone(),
+    };
+    let server =
+        Arc::new(Server::new(encryption_key, store_config, audit_log, cors));
+    let received_signal = handle_signals(listener.local_addr()?)?;
+    run_maintenance(
+        Arc::clone(&server),
+        Duration::from_secs(args.maintenance_interval),
+    );
 
-    let thread_pool = ThreadPool::new(THREAD_COUNT);
+    let busy = match args.server_mode.as_str() {
+        "async" => serve_async(args, listener, &server, &received_signal)?,
+        _ => serve_threads(
+            args,
+            listener,
+            &server,
+            &received_signal,
+            pool_config,
+            queue_policy,
+        ),
+    };
+    server.flush();
+
+    if busy > 0 {
+        return Err(format!(
+            "Shutdown timeout ran out with {} request(s) still pending, they \
+            were cut off.",
+            busy
+        )
+        .into());
+    }
+    eprintln!(
+        "Shut down cleanly after {}.",
+        received_signal_name(&received_signal)
+    );
+
+    Ok(())
+}
+
+/// Serve connections on a pool of worker threads until a signal is received.
+///
+/// Returns how many workers were still busy when the shutdown timeout ran
+/// out.
+fn serve_threads(
+    args: &Args,
+    listener: TcpListener,
+    server: &Arc<Server>,
+    received_signal: &AtomicI32,
+    pool_config: PoolConfig,
+    queue_policy: QueuePolicy,
+) -> usize {
+    let mut thread_pool =
+        ThreadPool::with_config(pool_config, Arc::clone(server.metrics()));
 
     for stream in listener.incoming() {
-        let stream = stream.unwrap();
-
-        let buf = connection::buf_from_stream(&stream)?;
-        connection::verify_request(&buf)?;
-        let request_type = connection::request_type(&buf);
-
-        let kv_store = Arc::clone(&key_value_store);
-        thread_pool.execute(move || {
-            let (status_line, body) = match request_type {
-                RequestType::Get => kv_store
-                    .read()
-                    .expect("Failed to acquire read lock for get request.")
-                    .handle_get_request(&buf),
-                RequestType::Put => kv_store
-                    .write()
-                    .expect("Failed to acquire write lock for PUT request.")
-                    .handle_put_request(&buf),
-                RequestType::Delete => kv_store
-                    .write()
-                    .expect("Failed to acquire write lock for DELETE request.")
-                    .handle_put_request(&buf),
-                RequestType::Unknown(unknown_response) => unknown_response,
-            };
-            match connection::write_stream(&stream, status_line, body) {
-                Ok(_) => (),
-                Err(e) => eprintln!("Error encountered: {}", e),
-            };
-        });
+        if received_signal.load(Ordering::SeqCst) != 0 {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        if queue_policy == QueuePolicy::Reject && thread_pool.is_full() {
+            server.shed(stream);
+            continue;
+        }
+
+        let server = Arc::clone(server);
+        thread_pool.execute(move || server.handle_connection(stream));
+    }
+
+    // Stop accepting, then give the requests that are already queued or being
+    // handled time to finish.
+    drop(listener);
+    log_shutdown(args, received_signal);
+    server.close_streams();
+    thread_pool.shutdown(Duration::from_secs(args.shutdown_timeout))
+}
+
+/// Serve connections on an async runtime until a signal is received.
+///
+/// Returns how many connections were still open when the shutdown timeout ran
+/// out.
+fn serve_async(
+    args: &Args,
+    listener: TcpListener,
+    server: &Arc<Server>,
+    received_signal: &AtomicI32,
+) -> Result<usize, Box<dyn Error>> {
+    let async_server = AsyncServer::new(Arc::clone(server))?;
+    async_server.run(listener, received_signal)?;
+
+    log_shutdown(args, received_signal);
+    server.close_streams();
+    Ok(async_server.shutdown(Duration::from_secs(args.shutdown_timeout)))
+}
+
+fn log_shutdown(args: &Args, received_signal: &AtomicI32) {
+    eprintln!(
+        "Received {}, waiting up to {}s for pending requests...",
+        received_signal_name(received_signal),
+        args.shutdown_timeout
+    );
+}
+
+fn received_signal_name(received_signal: &AtomicI32) -> &'static str {
+    signal_name(received_signal.load(Ordering::SeqCst)).unwrap_or("signal")
+}
+
+/// Run the server's housekeeping every `interval` in the background, see
+/// [`Server::run_maintenance`].
+fn run_maintenance(server: Arc<Server>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        server.run_maintenance();
+    });
+}
+
+/// Watch for SIGINT and SIGTERM in the background.
+///
+/// The returned number is set to the first signal received, which also wakes
+/// up the accept loop listening on `addr` so it can stop. A second signal
+/// exits right away, without waiting for pending requests.
+fn handle_signals(addr: SocketAddr) -> Result<Arc<AtomicI32>, Box<dyn Error>> {
+    let received_signal = Arc::new(AtomicI32::new(0));
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+
+    let received = Arc::clone(&received_signal);
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+
+        if let Some(signal) = signals.next() {
+            received.store(signal, Ordering::SeqCst);
+            // The accept loop only checks for the signal once a connection
+            // comes in.
+            let _ = TcpStream::connect(addr);
+        }
+        if let Some(signal) = signals.next() {
+            eprintln!(
+                "Received {} again, exiting without waiting.",
+                signal_name(signal).unwrap_or("signal")
+            );
+            process::exit(128 + signal);
+        }
+    });
+
+    Ok(received_signal)
+}
+
+fn backup(
+    port: &str,
+    client: &ClientArgs,
+    passphrase_file: &Option<PathBuf>,
+    output: &Path,
+    namespaces: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let key = client.key()?;
+    let passphrase = read_passphrase(passphrase_file)?;
+    let mut headers = vec![("key", key.as_str())];
+    if let Some(passphrase) = &passphrase {
+        headers.push(("passphrase", passphrase.as_str()));
+    }
+
+    let mut path = "admin/backup".to_string();
+    if !namespaces.is_empty() {
+        path.push_str(&format!("?namespace={}", namespace_list(namespaces)));
+    }
+
+    let response =
+        client::send_request_streaming(port, "GET", &path, &headers, "")?;
+    let mut body = response.body;
+    if response.status != 200 {
+        let mut error = String::new();
+        body.read_line(&mut error)?;
+        return Err(format!("Backup failed: {}", error).into());
+    }
+
+    // An archive that didn't arrive in full can't be restored, don't leave
+    // it around.
+    let entries = match write_archive(body, output) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let _ = fs::remove_file(output);
+            return Err(e);
+        }
+    };
+    println!("Backed up {} entries to {}", entries, output.display());
+
+    Ok(())
+}
+
+/// Write the archive the server streams to `output` a line at a time, as it
+/// arrives. Returns how many entries it has.
+fn write_archive(
+    body: impl BufRead,
+    output: &Path,
+) -> Result<u64, Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(output)?);
+    for line in body.lines() {
+        let line = line?;
+        let chunk: serde_json::Value = serde_json::from_str(&line)?;
+        if let Some(error) = chunk["error"].as_str() {
+            return Err(format!("Backup failed: {}", error).into());
+        }
+        writeln!(file, "{}", line)?;
+
+        if chunk["last"] == true {
+            file.flush()?;
+            return Ok(chunk["entries"].as_u64().unwrap_or_default());
+        }
+    }
+
+    Err("Backup was cut off before the end of the archive.".into())
+}
+
+fn restore(
+    port: &str,
+    client: &ClientArgs,
+    passphrase_file: &Option<PathBuf>,
+    input: &Path,
+    on_server: bool,
+    mode: &str,
+    namespaces: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let key = client.key()?;
+    let passphrase = read_passphrase(passphrase_file)?;
+    let mut headers = vec![("key", key.as_str())];
+    if let Some(passphrase) = &passphrase {
+        headers.push(("passphrase", passphrase.as_str()));
+    }
+
+    let mut path = format!("admin/restore?mode={}", mode);
+    if !namespaces.is_empty() {
+        path.push_str(&format!("&namespace={}", namespace_list(namespaces)));
+    }
+
+    let archive = match on_server {
+        true => {
+            let input = input.to_str().ok_or("Archive path is not UTF-8.")?;
+            path.push_str(&format!("&path={}", client::percent_encode(input)));
+            String::new()
+        }
+        false => fs::read_to_string(input)?,
+    };
+    let response =
+        client::send_request(port, "PUT", &path, &headers, &archive)?;
+    if response.status != 200 {
+        return Err(format!("Restore failed: {}", response.body).into());
+    }
+
+    let summary: serde_json::Value = serde_json::from_str(&response.body)?;
+    println!(
+        "Restored {} entries from {} ({} mode, {} existing entries removed)",
+        summary["restored"],
+        input.display(),
+        mode,
+        summary["removed"]
+    );
+
+    Ok(())
+}
+
+fn export(
+    port: &str,
+    client: &ClientArgs,
+    output: &Path,
+    format: &Option<String>,
+    prefix: &Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let format = match format {
+        Some(name) => Format::from_name(name)?,
+        None => Format::from_path(output)?,
+    };
+    let key = client.key()?;
+
+    let mut path = "admin/export".to_string();
+    if let Some(prefix) = prefix {
+        path.push_str(&format!("?prefix={}", client::percent_encode(prefix)));
+    }
+
+    let response = client::send_request_streaming(
+        port,
+        "GET",
+        &path,
+        &[("key", key.as_str())],
+        "",
+    )?;
+    let mut body = response.body;
+    if response.status != 200 {
+        let mut error = String::new();
+        body.read_line(&mut error)?;
+        return Err(format!("Export failed: {}", error).into());
+    }
+
+    // The server sends one entry per line, write each out as it arrives. It
+    // ends with an error line instead if it fails part way.
+    let mut writer =
+        ExportWriter::new(format, BufWriter::new(File::create(output)?))?;
+    for line in body.lines() {
+        let entry: serde_json::Value = serde_json::from_str(&line?)?;
+        if let Some(error) = entry["error"].as_str() {
+            return Err(format!("Export failed: {}", error).into());
+        }
+        match (entry["key"].as_str(), entry["value"].as_str()) {
+            (Some(key), Some(value)) => writer.write(key, value)?,
+            _ => return Err("Server sent a malformed entry.".into()),
+        }
+    }
+    let exported = writer.finish()?;
+
+    println!("Exported {} entries to {}", exported, output.display());
+
+    Ok(())
+}
+
+fn import(
+    port: &str,
+    client: &ClientArgs,
+    input: &Path,
+    format: &Option<String>,
+    prefix: &Option<String>,
+    existing: &str,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let format = match format {
+        Some(name) => Format::from_name(name)?,
+        None => Format::from_path(input)?,
+    };
+    let prefix = prefix.as_deref().unwrap_or("");
+    let key = client.key()?;
+
+    let mut path = format!("admin/import?existing={}", existing);
+    if dry_run {
+        path.push_str("&dry_run=true");
+    }
+
+    let mut summary = ImportSummary::default();
+    let mut batch = ImportBatch::default();
+    let send = |batch: &mut ImportBatch, summary: &mut ImportSummary| {
+        batch.send(port, &path, &key, summary)
+    };
+
+    let reader = BufReader::new(File::open(input)?);
+    transfer::read_rows(format, reader, |number, row| {
+        match row {
+            Ok((key, value)) => {
+                batch.push(number, &format!("{}{}", prefix, key), &value)
+            }
+            Err(e) => summary.failed.push((number, e)),
+        }
+        if batch.is_full() {
+            send(&mut batch, &mut summary)?;
+        }
+        Ok(())
+    })?;
+    send(&mut batch, &mut summary)?;
+
+    summary.failed.sort();
+    println!("{}", summary.report(dry_run));
+    if !summary.is_success() {
+        return Err(format!(
+            "{} row(s) of {} failed to import",
+            summary.failed.len(),
+            input.display()
+        )
+        .into());
     }
 
     Ok(())
 }
 
+/// Rows `skv import` has read but not yet sent to the server.
+#[derive(Default)]
+struct ImportBatch {
+    /// Row number of each entry in the body, to report failures by.
+    rows: Vec<usize>,
+    /// The entries as NDJSON, see [`admin::handle_admin_request`].
+    body: String,
+}
+
+impl ImportBatch {
+    fn push(&mut self, number: usize, key: &str, value: &str) {
+        self.rows.push(number);
+        self.body.push_str(
+            &serde_json::json!({ "key": key, "value": value }).to_string(),
+        );
+        self.body.push('\n');
+    }
+
+    fn is_full(&self) -> bool {
+        self.rows.len() >= IMPORT_BATCH_ROWS
+            || self.body.len() >= IMPORT_BATCH_BYTES
+    }
+
+    /// Send the batch to the server, add the outcome to `summary` and empty
+    /// the batch.
+    fn send(
+        &mut self,
+        port: &str,
+        path: &str,
+        key: &str,
+        summary: &mut ImportSummary,
+    ) -> Result<(), String> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let response = client::send_request(
+            port,
+            "PUT",
+            path,
+            &[("key", key)],
+            &self.body,
+        )?;
+        if response.status != 200 {
+            return Err(format!("Import failed: {}", response.body));
+        }
+        let outcome: serde_json::Value = serde_json::from_str(&response.body)
+            .map_err(|_| {
+            "Malformed response from skv server.".to_string()
+        })?;
+
+        let count = |name: &str| outcome[name].as_u64().unwrap_or(0) as usize;
+        summary.imported += count("imported");
+        summary.skipped += count("skipped");
+        for failure in outcome["failed"].as_array().into_iter().flatten() {
+            let line = failure["line"].as_u64().unwrap_or(0) as usize;
+            summary.failed.push((
+                self.rows.get(line.wrapping_sub(1)).copied().unwrap_or(0),
+                failure["error"]
+                    .as_str()
+                    .unwrap_or("Unknown error.")
+                    .to_string(),
+            ));
+        }
+
+        self.rows.clear();
+        self.body.clear();
+        Ok(())
+    }
+}
+
+fn verify_audit_log(
+    path: &Path,
+    key_file: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let key = SecretKey::from_hex(&read_secret_file(key_file)?)?;
+    let (records, head) = audit::verify(path, &key)?;
+    println!("Audit log OK: {} records, head {}", records, head);
+
+    Ok(())
+}
+
 /// A simple key-value (skv) store.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
-    /// Specify port on localhost to run skv server.
-    #[clap(short, long, value_parser, default_value = "3400")]
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Specify port on localhost to run skv server (or of the server to talk
+    /// to).
+    #[clap(short, long, value_parser, default_value = "3400", global = true)]
     pub port: String,
+
+    /// Write the generated encryption key to this file (created with 0600
+    /// permissions) instead of printing it.
+    #[clap(short, long, value_parser)]
+    pub key_file: Option<PathBuf>,
+
+    /// Append a tamper-evident record of every request to this file.
+    #[clap(short, long, value_parser)]
+    pub audit_log: Option<PathBuf>,
+
+    /// File with the key the audit log is signed with, generated with 0600
+    /// permissions if it doesn't exist. Must not be in the audit log's
+    /// directory.
+    #[clap(long, value_parser)]
+    pub audit_key_file: Option<PathBuf>,
+
+    /// Seconds to wait for pending requests on SIGINT or SIGTERM before
+    /// giving up on them.
+    #[clap(long, value_parser, default_value = "30")]
+    pub shutdown_timeout: u64,
+
+    /// Independently locked parts the store is split into. Requests for keys
+    /// in different shards don't wait for each other.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value = "16")]
+    pub shards: u64,
+
+    /// Old values to keep of every key once it is overwritten or deleted, see
+    /// /admin/history. Namespaces can be given their own with
+    /// /admin/retention.
+    #[clap(long, value_parser, default_value = "0")]
+    pub keep_versions: usize,
+
+    /// Also keep every value written less than this many seconds ago.
+    #[clap(long, value_parser, default_value = "0")]
+    pub keep_versions_for: u64,
+
+    /// Seconds a deleted key stays in the trash, see /admin/trash, before it
+    /// is purged for good. 0 deletes right away.
+    #[clap(long, value_parser, default_value = "86400")]
+    pub trash_retention: u64,
+
+    /// Changes to keys kept for watches that reconnect, see /admin/watch. A
+    /// watch that missed more than this has to read its keys again.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value = "1024")]
+    pub watch_history: u64,
+
+    /// Seconds between background housekeeping runs, e.g. dropping old
+    /// values that no snapshot needs anymore.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value = "10")]
+    pub maintenance_interval: u64,
+
+    /// Let pages from this origin call the server from a browser, e.g.
+    /// http://localhost:8080, or * for any origin. Can be given multiple
+    /// times. Without it no CORS headers are sent, and browsers don't let
+    /// pages from other origins read responses.
+    #[clap(long, value_parser)]
+    pub cors_origin: Vec<String>,
+
+    /// Method those pages may use. Can be given multiple times. Defaults to
+    /// every method the path supports.
+    #[clap(long, value_parser)]
+    pub cors_method: Vec<String>,
+
+    /// Request header those pages may send. Can be given multiple times.
+    #[clap(long, value_parser, default_values = &["key", "content-type"])]
+    pub cors_header: Vec<String>,
+
+    /// Serve each connection on a worker thread, or as a task on an async
+    /// runtime. The worker and queue options only apply to threads.
+    #[clap(long, value_parser = ["threads", "async"], default_value = "threads")]
+    pub server_mode: String,
+
+    /// Worker threads to start with.
+    #[clap(long, value_parser, default_value = "4")]
+    pub workers: usize,
+
+    /// Never stop idle workers below this many. Defaults to --workers.
+    #[clap(long, value_parser)]
+    pub min_workers: Option<usize>,
+
+    /// Start more workers, up to this many, while all of them are busy.
+    /// Defaults to --workers.
+    #[clap(long, value_parser)]
+    pub max_workers: Option<usize>,
+
+    /// Seconds a worker above --min-workers waits for work before it stops.
+    #[clap(long, value_parser, default_value = "60")]
+    pub worker_idle_timeout: u64,
+
+    /// Connections that can wait for a free worker.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value = "64")]
+    pub queue_depth: u64,
+
+    /// What to do with new connections when the queue is full: wait for
+    /// room, or answer 503 with a Retry-After header.
+    #[clap(long, value_parser = ["block", "reject"], default_value = "block")]
+    pub queue_policy: String,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Work with the audit log.
+    Audit {
+        #[clap(subcommand)]
+        command: AuditCommand,
+    },
+    /// Save the data of a running server to an encrypted archive.
+    Backup {
+        /// File to write the archive to.
+        #[clap(short, long, value_parser)]
+        output: PathBuf,
+
+        /// Only back up keys in this namespace. Can be given multiple times.
+        #[clap(short, long, value_parser)]
+        namespace: Vec<String>,
+
+        /// File holding a passphrase to encrypt the archive with, instead of
+        /// the server's encryption key. Required to restore into another
+        /// server.
+        #[clap(long, value_parser)]
+        passphrase_file: Option<PathBuf>,
+
+        #[clap(flatten)]
+        client: ClientArgs,
+    },
+    /// Restore an archive made by `skv backup` into a running server.
+    Restore {
+        /// Archive to restore.
+        #[clap(short, long, value_parser)]
+        input: PathBuf,
+
+        /// The archive is a file on the server's machine, which the server
+        /// reads itself. Archives larger than a request can be (16 MiB) have
+        /// to be restored this way.
+        #[clap(long, value_parser)]
+        on_server: bool,
+
+        /// Merge the archive into the existing data, or replace the existing
+        /// data (of the restored namespaces).
+        #[clap(short, long, value_parser = ["merge", "replace"], default_value = "merge")]
+        mode: String,
+
+        /// Only restore keys in this namespace. Can be given multiple times.
+        #[clap(short, long, value_parser)]
+        namespace: Vec<String>,
+
+        /// File holding the passphrase the archive was encrypted with.
+        #[clap(long, value_parser)]
+        passphrase_file: Option<PathBuf>,
+
+        #[clap(flatten)]
+        client: ClientArgs,
+    },
+    /// Write the data of a running server to a plaintext JSON, NDJSON or CSV
+    /// file.
+    Export {
+        /// File to write to.
+        #[clap(short, long, value_parser)]
+        output: PathBuf,
+
+        /// Format of the file, guessed from its extension by default.
+        #[clap(short, long, value_parser = ["json", "ndjson", "csv"])]
+        format: Option<String>,
+
+        /// Only export keys starting with this prefix.
+        #[clap(long, value_parser)]
+        prefix: Option<String>,
+
+        #[clap(flatten)]
+        client: ClientArgs,
+    },
+    /// Load a plaintext JSON, NDJSON or CSV file into a running server.
+    ///
+    /// JSON files hold an array of {"key": ..., "value": ...} objects or a
+    /// single object of key-value pairs, NDJSON files one such object per
+    /// line, and CSV files a header with a `key` and a `value` column.
+    Import {
+        /// File to read from.
+        #[clap(short, long, value_parser)]
+        input: PathBuf,
+
+        /// Format of the file, guessed from its extension by default.
+        #[clap(short, long, value_parser = ["json", "ndjson", "csv"])]
+        format: Option<String>,
+
+        /// Prepend this prefix to every imported key.
+        #[clap(long, value_parser)]
+        prefix: Option<String>,
+
+        /// Overwrite keys that already exist, or skip them.
+        #[clap(short, long, value_parser = ["overwrite", "skip"], default_value = "overwrite")]
+        existing: String,
+
+        /// Check the file and report what would be imported, without writing
+        /// anything.
+        #[clap(long, value_parser)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        client: ClientArgs,
+    },
+}
+
+/// Options for subcommands that talk to a running server.
+#[derive(clap::Args, Debug)]
+pub struct ClientArgs {
+    /// File holding the server's encryption key.
+    #[clap(short, long, value_parser)]
+    pub key_file: PathBuf,
+}
+
+impl ClientArgs {
+    fn key(&self) -> Result<Zeroizing<String>, Box<dyn Error>> {
+        read_secret_file(&self.key_file)
+    }
+}
+
+/// The `namespace` query parameter for a list of namespaces.
+fn namespace_list(namespaces: &[String]) -> String {
+    namespaces
+        .iter()
+        .map(|namespace| client::percent_encode(namespace))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn read_passphrase(
+    passphrase_file: &Option<PathBuf>,
+) -> Result<Option<Zeroizing<String>>, Box<dyn Error>> {
+    match passphrase_file {
+        Some(path) => Ok(Some(read_secret_file(path)?)),
+        None => Ok(None),
+    }
+}
+
+/// The key of the audit log, generated and written to `key_file` the first
+/// time.
+fn audit_key(key_file: &Path) -> Result<SecretKey, Box<dyn Error>> {
+    if key_file.exists() {
+        return Ok(SecretKey::from_hex(&read_secret_file(key_file)?)?);
+    }
+
+    let key = crypto::generate_key();
+    crypto::write_key_file(&key, key_file)?;
+    println!("Audit key written to {}", key_file.display());
+    Ok(key)
+}
+
+fn read_secret_file(path: &Path) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    let contents = Zeroizing::new(fs::read_to_string(path)?);
+    Ok(Zeroizing::new(contents.trim().to_string()))
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AuditCommand {
+    /// Check that no record of the audit log was modified or removed.
+    Verify {
+        /// Path of the audit log.
+        #[clap(value_parser)]
+        path: PathBuf,
+
+        /// File with the key the audit log was signed with.
+        #[clap(short, long, value_parser)]
+        key_file: PathBuf,
+    },
 }
@@ -0,0 +1,277 @@
+use crate::pubsub::Topic;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Counters describing what the server has been doing, served by
+/// `GET /admin/metrics`.
+///
+/// Shared by the accept loop, the thread pool and the request handlers, so
+/// every counter is atomic.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    /// Connections being served right now.
+    open_connections: AtomicU64,
+    /// Most connections that were served at the same time.
+    peak_connections: AtomicU64,
+    panics: AtomicU64,
+    /// Requests turned away because the queue was full.
+    rejected: AtomicU64,
+    queue_capacity: AtomicU64,
+    /// Jobs waiting for a worker right now.
+    queue_depth: AtomicU64,
+    /// Jobs that have been picked up by a worker.
+    dequeued: AtomicU64,
+    queue_wait_micros_total: AtomicU64,
+    queue_wait_micros_max: AtomicU64,
+    /// Workers that are running.
+    workers: Mutex<Vec<Arc<WorkerStats>>>,
+    workers_started: AtomicU64,
+    workers_stopped: AtomicU64,
+    /// Time spent on jobs by workers that have stopped.
+    stopped_busy_micros: AtomicU64,
+    /// Messages published to pub/sub channels.
+    published: AtomicU64,
+    /// Copies of messages queued for subscribers.
+    delivered: AtomicU64,
+    /// Copies of messages dropped because a subscriber fell behind.
+    dropped: AtomicU64,
+    /// Subscribers of every channel and pattern that has any.
+    subscribers: Mutex<SubscriberCounts>,
+}
+
+/// Subscribers by channel and by pattern, see [`Topic`].
+#[derive(Debug, Default)]
+struct SubscriberCounts {
+    channels: BTreeMap<String, u64>,
+    patterns: BTreeMap<String, u64>,
+}
+
+impl SubscriberCounts {
+    /// The counts `topic` is in, and its name there.
+    fn counts<'a>(
+        &mut self,
+        topic: &'a Topic,
+    ) -> (&mut BTreeMap<String, u64>, &'a str) {
+        match topic {
+            Topic::Channel(name) => (&mut self.channels, name),
+            Topic::Pattern(pattern) => (&mut self.patterns, pattern),
+        }
+    }
+}
+
+/// How busy a single worker has been.
+#[derive(Debug)]
+pub struct WorkerStats {
+    id: usize,
+    started: Instant,
+    jobs: AtomicU64,
+    busy_micros: AtomicU64,
+}
+
+impl WorkerStats {
+    /// Count a job the worker spent `busy` on.
+    pub fn record_job(&self, busy: Duration) {
+        self.jobs.fetch_add(1, Ordering::Relaxed);
+        self.busy_micros.fetch_add(micros(busy), Ordering::Relaxed);
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "jobs": self.jobs.load(Ordering::Relaxed),
+            "busy_ms": self.busy_micros.load(Ordering::Relaxed) / 1000,
+            "uptime_ms": self.started.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a request that was read from a connection, valid or not.
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection that is being served.
+    pub fn record_connection_open(&self) {
+        let open = self.open_connections.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_connections.fetch_max(open, Ordering::Relaxed);
+    }
+
+    /// Count a connection that has been answered and closed.
+    pub fn record_connection_close(&self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn open_connections(&self) -> u64 {
+        self.open_connections.load(Ordering::SeqCst)
+    }
+
+    /// Count a panic caught while handling a request or running a job.
+    pub fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// Count a connection that was turned away because the server is busy.
+    pub fn record_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_queue_capacity(&self, capacity: usize) {
+        self.queue_capacity
+            .store(capacity as u64, Ordering::Relaxed);
+    }
+
+    /// Count a job entering the queue.
+    pub fn record_enqueue(&self) {
+        self.queue_depth.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Count a job leaving the queue after waiting for `wait`.
+    pub fn record_dequeue(&self, wait: Duration) {
+        self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+        self.dequeued.fetch_add(1, Ordering::Relaxed);
+
+        let wait = micros(wait);
+        self.queue_wait_micros_total
+            .fetch_add(wait, Ordering::Relaxed);
+        self.queue_wait_micros_max
+            .fetch_max(wait, Ordering::Relaxed);
+    }
+
+    /// Start tracking a new worker.
+    pub fn register_worker(&self, id: usize) -> Arc<WorkerStats> {
+        let stats = Arc::new(WorkerStats {
+            id,
+            started: Instant::now(),
+            jobs: AtomicU64::new(0),
+            busy_micros: AtomicU64::new(0),
+        });
+
+        self.workers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::clone(&stats));
+        self.workers_started.fetch_add(1, Ordering::Relaxed);
+
+        stats
+    }
+
+    /// Stop tracking a worker that has stopped. Its busy time is kept in the
+    /// total of stopped workers.
+    pub fn retire_worker(&self, stats: &Arc<WorkerStats>) {
+        self.workers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|worker| !Arc::ptr_eq(worker, stats));
+        self.workers_stopped.fetch_add(1, Ordering::Relaxed);
+        self.stopped_busy_micros.fetch_add(
+            stats.busy_micros.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Count a message published to a channel, queued for `delivered`
+    /// subscribers and dropped for `dropped` that fell behind.
+    pub fn record_publish(&self, delivered: usize, dropped: usize) {
+        self.published.fetch_add(1, Ordering::Relaxed);
+        self.delivered
+            .fetch_add(delivered as u64, Ordering::Relaxed);
+        self.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+    }
+
+    /// Count a subscriber of `topic`.
+    pub fn record_subscribe(&self, topic: &Topic) {
+        let mut counts = self.subscriber_counts();
+        let (counts, name) = counts.counts(topic);
+        *counts.entry(name.to_string()).or_default() += 1;
+    }
+
+    /// Stop counting a subscriber of `topic`. Topics without subscribers
+    /// aren't listed anymore.
+    pub fn record_unsubscribe(&self, topic: &Topic) {
+        let mut counts = self.subscriber_counts();
+        let (counts, name) = counts.counts(topic);
+        if let Some(count) = counts.get_mut(name) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(name);
+            }
+        }
+    }
+
+    fn subscriber_counts(&self) -> MutexGuard<'_, SubscriberCounts> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn queue_depth(&self) -> u64 {
+        self.queue_depth.load(Ordering::SeqCst)
+    }
+
+    /// All counters as a JSON object.
+    pub fn to_json(&self) -> Value {
+        let dequeued = self.dequeued.load(Ordering::Relaxed);
+        let wait_total = self.queue_wait_micros_total.load(Ordering::Relaxed);
+        let wait_average = wait_total.checked_div(dequeued).unwrap_or(0);
+
+        let running: Vec<Value> = self
+            .workers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|worker| worker.to_json())
+            .collect();
+
+        let subscribers = self.subscriber_counts();
+
+        json!({
+            "requests": self.requests.load(Ordering::Relaxed),
+            "panics": self.panics(),
+            "connections": {
+                "open": self.open_connections(),
+                "peak": self.peak_connections.load(Ordering::Relaxed),
+            },
+            "rejected": self.rejected.load(Ordering::Relaxed),
+            "queue": {
+                "capacity": self.queue_capacity.load(Ordering::Relaxed),
+                "depth": self.queue_depth(),
+                "dequeued": dequeued,
+                "wait_ms_average": wait_average as f64 / 1000.0,
+                "wait_ms_max":
+                    self.queue_wait_micros_max.load(Ordering::Relaxed) as f64
+                        / 1000.0,
+            },
+            "workers": {
+                "started": self.workers_started.load(Ordering::Relaxed),
+                "stopped": self.workers_stopped.load(Ordering::Relaxed),
+                "stopped_busy_ms":
+                    self.stopped_busy_micros.load(Ordering::Relaxed) / 1000,
+                "running": running,
+            },
+            "pubsub": {
+                "published": self.published.load(Ordering::Relaxed),
+                "delivered": self.delivered.load(Ordering::Relaxed),
+                "dropped": self.dropped.load(Ordering::Relaxed),
+                "channels": subscribers.channels,
+                "patterns": subscribers.patterns,
+            },
+        })
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().min(u64::MAX as u128) as u64
+}
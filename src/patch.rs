@@ -0,0 +1,367 @@
+use crate::connection::json_error;
+use crate::typed::wrong_type_response;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// A change to part of a value, made by a PATCH request without reading the
+/// value first.
+///
+/// Offsets and lengths are in bytes of the UTF-8 encoded value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Patch {
+    /// Add the data to the end of the value.
+    Append(String),
+    /// Add the data to the start of the value.
+    Prepend(String),
+    /// Overwrite the value from `offset` on with the data, growing the value
+    /// if the data goes past its end.
+    Range { offset: usize, data: String },
+    /// Add `delta` to a numeric value, then clamp it to `floor` and
+    /// `ceiling`. Keys that don't exist count from zero.
+    Increment {
+        delta: Number,
+        floor: Option<Number>,
+        ceiling: Option<Number>,
+    },
+}
+
+/// A numeric value. Integers stay integers as long as they are only added
+/// to integers, anything else is a float.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+/// Why a patch couldn't be applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// The request doesn't describe a patch that can be applied, e.g. it
+    /// has an unknown op.
+    Invalid(String),
+    /// The value to increment isn't a number.
+    NotANumber,
+    /// The incremented value doesn't fit into a number.
+    Overflow,
+    /// The key holds a list or another typed value, which can't be patched.
+    WrongType,
+}
+
+impl PatchError {
+    /// Status line and JSON body to answer the failed request with.
+    ///
+    /// Values that can't be patched are a 409, with the `type` of the error
+    /// in the body so that clients can tell them apart.
+    pub fn response(&self) -> (String, String) {
+        match self {
+            PatchError::Invalid(message) => {
+                ("HTTP/1.1 400 Bad Request".to_string(), json_error(message))
+            }
+            PatchError::NotANumber => (
+                "HTTP/1.1 409 Conflict".to_string(),
+                json!({
+                    "error": "The value is not a number.",
+                    "type": "not_a_number",
+                })
+                .to_string(),
+            ),
+            PatchError::Overflow => (
+                "HTTP/1.1 409 Conflict".to_string(),
+                json!({
+                    "error": "The value would overflow.",
+                    "type": "overflow",
+                })
+                .to_string(),
+            ),
+            PatchError::WrongType => wrong_type_response(),
+        }
+    }
+}
+
+impl Number {
+    /// Parse a number, e.g. `42` or `-0.5`. Surrounding whitespace is
+    /// ignored.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if let Ok(int) = text.parse() {
+            return Some(Number::Int(int));
+        }
+        text.parse::<f64>()
+            .ok()
+            .filter(|float| float.is_finite())
+            .map(Number::Float)
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(int) => int as f64,
+            Number::Float(float) => float,
+        }
+    }
+
+    /// The sum, if it fits into a number.
+    pub fn checked_add(self, other: Number) -> Option<Number> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => {
+                a.checked_add(b).map(Number::Int)
+            }
+            _ => {
+                let sum = self.as_f64() + other.as_f64();
+                sum.is_finite().then_some(Number::Float(sum))
+            }
+        }
+    }
+
+    fn checked_neg(self) -> Option<Number> {
+        match self {
+            Number::Int(int) => int.checked_neg().map(Number::Int),
+            Number::Float(float) => Some(Number::Float(-float)),
+        }
+    }
+
+    /// The number in a JSON value, if it is one.
+    pub fn from_json(value: &Value) -> Option<Self> {
+        match value.as_i64() {
+            Some(int) => Some(Number::Int(int)),
+            None => value.as_f64().map(Number::Float),
+        }
+    }
+
+    pub fn to_json(self) -> Value {
+        match self {
+            Number::Int(int) => json!(int),
+            Number::Float(float) => json!(float),
+        }
+    }
+}
+
+impl std::fmt::Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Number::Int(int) => write!(f, "{}", int),
+            // Always with a decimal point, so a float stays a float.
+            Number::Float(float) => write!(f, "{:?}", float),
+        }
+    }
+}
+
+impl Patch {
+    /// The patch asked for by the query parameters of a request, with its
+    /// body as the data.
+    ///
+    /// `op` is `append`, `prepend`, `range` (with an `offset`), or `incr` or
+    /// `decr` (by `by`, default 1, clamped to `min` and `max`).
+    pub fn from_query(
+        query: &HashMap<String, String>,
+        data: String,
+    ) -> Result<Self, PatchError> {
+        let op = query.get("op").map(String::as_str).unwrap_or_default();
+        match op {
+            "append" => Ok(Patch::Append(data)),
+            "prepend" => Ok(Patch::Prepend(data)),
+            "range" => {
+                let offset = match query.get("offset").map(|o| o.parse()) {
+                    Some(Ok(offset)) => offset,
+                    _ => {
+                        return Err(PatchError::Invalid(
+                            "offset must be a number of bytes.".to_string(),
+                        ))
+                    }
+                };
+                Ok(Patch::Range { offset, data })
+            }
+            "incr" | "decr" => {
+                let delta =
+                    number_from_query(query, "by")?.unwrap_or(Number::Int(1));
+                let delta = match op {
+                    "decr" => {
+                        delta.checked_neg().ok_or(PatchError::Overflow)?
+                    }
+                    _ => delta,
+                };
+                let floor = number_from_query(query, "min")?;
+                let ceiling = number_from_query(query, "max")?;
+                if let (Some(floor), Some(ceiling)) = (floor, ceiling) {
+                    if floor.as_f64() > ceiling.as_f64() {
+                        return Err(PatchError::Invalid(
+                            "min must not be greater than max.".to_string(),
+                        ));
+                    }
+                }
+
+                Ok(Patch::Increment {
+                    delta,
+                    floor,
+                    ceiling,
+                })
+            }
+            _ => Err(PatchError::Invalid(format!(
+                "Unknown op '{}'. Use append, prepend, range, incr or decr.",
+                op
+            ))),
+        }
+    }
+
+    /// The value to patch if the key doesn't exist. `None` if the patch
+    /// needs an existing value.
+    pub fn initial_value(&self) -> Option<&'static str> {
+        match self {
+            Patch::Increment { .. } => Some("0"),
+            _ => None,
+        }
+    }
+
+    /// The value with the patch applied.
+    pub fn apply(&self, value: &str) -> Result<String, PatchError> {
+        match self {
+            Patch::Append(data) => Ok(format!("{}{}", value, data)),
+            Patch::Prepend(data) => Ok(format!("{}{}", data, value)),
+            Patch::Range { offset, data } => {
+                let offset = *offset;
+                if offset > value.len() {
+                    return Err(PatchError::Invalid(format!(
+                        "Offset {} is past the end of the value ({} bytes).",
+                        offset,
+                        value.len()
+                    )));
+                }
+
+                let end = offset + data.len();
+                if !value.is_char_boundary(offset)
+                    || (end < value.len() && !value.is_char_boundary(end))
+                {
+                    return Err(PatchError::Invalid(
+                        "The range would split a character of the value."
+                            .to_string(),
+                    ));
+                }
+
+                let mut patched = value[..offset].to_string();
+                patched.push_str(data);
+                if end < value.len() {
+                    patched.push_str(&value[end..]);
+                }
+                Ok(patched)
+            }
+            Patch::Increment {
+                delta,
+                floor,
+                ceiling,
+            } => {
+                let value =
+                    Number::parse(value).ok_or(PatchError::NotANumber)?;
+                let mut value =
+                    value.checked_add(*delta).ok_or(PatchError::Overflow)?;
+                if let Some(floor) = floor {
+                    if value.as_f64() < floor.as_f64() {
+                        value = *floor;
+                    }
+                }
+                if let Some(ceiling) = ceiling {
+                    if value.as_f64() > ceiling.as_f64() {
+                        value = *ceiling;
+                    }
+                }
+
+                Ok(value.to_string())
+            }
+        }
+    }
+}
+
+/// The number in a query parameter, if it is there.
+fn number_from_query(
+    query: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<Number>, PatchError> {
+    match query.get(name) {
+        Some(text) => match Number::parse(text) {
+            Some(number) => Ok(Some(number)),
+            None => {
+                Err(PatchError::Invalid(format!("{} must be a number.", name)))
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn query(params: &[(&str, &str)]) -> HashMap<String, String> {
+        params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_patches() {
+        let append = Patch::from_query(&query(&[("op", "append")]), "!".into());
+        assert_eq!(append.unwrap().apply("hello").unwrap(), "hello!");
+        let prepend =
+            Patch::from_query(&query(&[("op", "prepend")]), "> ".into());
+        assert_eq!(prepend.unwrap().apply("hello").unwrap(), "> hello");
+
+        let range = |offset: usize, data: &str| Patch::Range {
+            offset,
+            data: data.to_string(),
+        };
+        assert_eq!(range(1, "EL").apply("hello").unwrap(), "hELlo");
+        assert_eq!(range(3, "p me").apply("hello").unwrap(), "help me");
+        assert_eq!(range(5, "!").apply("hello").unwrap(), "hello!");
+        assert!(range(6, "!").apply("hello").is_err());
+        // "é" is two bytes.
+        assert!(range(1, "a").apply("héllo").is_err());
+        assert_eq!(range(1, "ee").apply("héllo").unwrap(), "heello");
+
+        assert_eq!(
+            Patch::from_query(
+                &query(&[("op", "range"), ("offset", "2")]),
+                "".into()
+            ),
+            Ok(range(2, ""))
+        );
+        assert!(
+            Patch::from_query(&query(&[("op", "range")]), "".into()).is_err()
+        );
+        assert!(Patch::from_query(&query(&[]), "".into()).is_err());
+    }
+
+    #[test]
+    fn test_increments() {
+        let incr = |params: &[(&str, &str)], value: &str| {
+            Patch::from_query(&query(params), String::new())?.apply(value)
+        };
+
+        assert_eq!(incr(&[("op", "incr")], "41").unwrap(), "42");
+        assert_eq!(incr(&[("op", "decr"), ("by", "5")], "3").unwrap(), "-2");
+        assert_eq!(incr(&[("op", "incr"), ("by", "0.5")], "1").unwrap(), "1.5");
+        assert_eq!(incr(&[("op", "incr"), ("by", "1")], "1.5").unwrap(), "2.5");
+        assert_eq!(
+            incr(&[("op", "incr"), ("by", "0.5")], "1.5").unwrap(),
+            "2.0"
+        );
+
+        let clamped =
+            [("op", "incr"), ("by", "10"), ("min", "0"), ("max", "5")];
+        assert_eq!(incr(&clamped, "1").unwrap(), "5");
+        let floored = [("op", "decr"), ("by", "10"), ("min", "0")];
+        assert_eq!(incr(&floored, "1").unwrap(), "0");
+
+        assert_eq!(incr(&[("op", "incr")], "abc"), Err(PatchError::NotANumber));
+        assert_eq!(
+            incr(&[("op", "incr")], &i64::MAX.to_string()),
+            Err(PatchError::Overflow)
+        );
+        assert!(matches!(
+            incr(&[("op", "incr"), ("by", "x")], "1"),
+            Err(PatchError::Invalid(_))
+        ));
+        assert!(matches!(
+            incr(&[("op", "incr"), ("min", "2"), ("max", "1")], "1"),
+            Err(PatchError::Invalid(_))
+        ));
+    }
+}
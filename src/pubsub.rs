@@ -0,0 +1,433 @@
+use crate::metrics::Metrics;
+use crate::stream::{self, EventStream, Wakeup};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Messages that can wait for a subscriber that is slow to read them. Newer
+/// ones are dropped for it until it catches up.
+pub const SUBSCRIBER_QUEUE: usize = 256;
+/// Most subscriptions the broker serves at once. Each holds a connection and
+/// a queue of messages.
+pub const MAX_SUBSCRIPTIONS: usize = 512;
+/// Most channels and patterns a subscription, i.e. a connection, can listen
+/// to.
+pub const MAX_TOPICS: usize = 64;
+/// Most patterns a subscription can listen to. Every message is matched
+/// against every pattern of every subscriber.
+pub const MAX_PATTERNS: usize = 8;
+/// Longest channel name or pattern, in characters.
+pub const MAX_TOPIC_LENGTH: usize = 256;
+
+/// What a subscriber listens to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Topic {
+    Channel(String),
+    /// Every channel whose name matches the pattern, where `*` stands for
+    /// any number of characters and `?` for exactly one, e.g. `news.*`.
+    Pattern(String),
+}
+
+impl Topic {
+    pub fn matches(&self, channel: &str) -> bool {
+        match self {
+            Topic::Channel(name) => name == channel,
+            Topic::Pattern(pattern) => glob_matches(pattern, channel),
+        }
+    }
+}
+
+/// Why a client can't subscribe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeError {
+    NoTopics,
+    TooManyTopics,
+    TooManyPatterns,
+    TopicTooLong,
+    /// The broker serves [`MAX_SUBSCRIPTIONS`] already.
+    TooManySubscriptions,
+}
+
+impl SubscribeError {
+    /// Status line and JSON body to answer the failed request with.
+    pub fn response(&self) -> (String, String) {
+        let status_line = match self {
+            SubscribeError::TooManySubscriptions => {
+                "HTTP/1.1 503 Service Unavailable"
+            }
+            _ => "HTTP/1.1 400 Bad Request",
+        };
+        let error = match self {
+            SubscribeError::NoTopics => {
+                "Subscribe to channels with ?channel=<name>,... or to \
+                patterns with ?pattern=<pattern>,..."
+                    .to_string()
+            }
+            SubscribeError::TooManyTopics => format!(
+                "Subscribe to at most {} channels and patterns at once.",
+                MAX_TOPICS
+            ),
+            SubscribeError::TooManyPatterns => format!(
+                "Subscribe to at most {} patterns at once.",
+                MAX_PATTERNS
+            ),
+            SubscribeError::TopicTooLong => format!(
+                "Channels and patterns are at most {} characters long.",
+                MAX_TOPIC_LENGTH
+            ),
+            SubscribeError::TooManySubscriptions => {
+                "Too many subscriptions. Try again later.".to_string()
+            }
+        };
+
+        (
+            status_line.to_string(),
+            json!({ "error": error }).to_string(),
+        )
+    }
+}
+
+/// A published message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub channel: String,
+    pub payload: String,
+}
+
+impl Message {
+    /// The message as a server-sent event. Messages aren't kept, so they
+    /// have no id to resume from.
+    fn to_frame(&self) -> String {
+        let data = json!({ "channel": self.channel, "message": self.payload });
+        stream::frame(None, "message", &data.to_string())
+    }
+}
+
+/// Passes messages published to named channels on to the clients that
+/// subscribed to them.
+///
+/// Delivery is at most once: a message only reaches the subscribers that
+/// are connected when it is published, it isn't kept or sent again, and
+/// subscribers that fall more than [`SUBSCRIBER_QUEUE`] messages behind
+/// miss the newer ones.
+#[derive(Debug)]
+pub struct Broker {
+    subscribers: Mutex<Subscribers>,
+    wakeup: Arc<Wakeup>,
+    /// Counts the subscribers of every channel and pattern.
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    by_id: HashMap<u64, Subscriber>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    topics: Vec<Topic>,
+    /// Messages that haven't been sent to the subscriber yet.
+    queue: VecDeque<Message>,
+}
+
+impl Broker {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            subscribers: Mutex::default(),
+            wakeup: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// Queue `payload` for every subscriber of `channel`. Returns how many
+    /// subscribers it was queued for.
+    pub fn publish(&self, channel: &str, payload: &str) -> usize {
+        let mut subscribers = self.lock();
+        let (mut delivered, mut dropped) = (0, 0);
+        for subscriber in subscribers.by_id.values_mut() {
+            if !subscriber.topics.iter().any(|topic| topic.matches(channel)) {
+                continue;
+            }
+            if subscriber.queue.len() >= SUBSCRIBER_QUEUE {
+                dropped += 1;
+                continue;
+            }
+
+            subscriber.queue.push_back(Message {
+                channel: channel.to_string(),
+                payload: payload.to_string(),
+            });
+            delivered += 1;
+        }
+        drop(subscribers);
+
+        self.metrics.record_publish(delivered, dropped);
+        if delivered > 0 {
+            self.wakeup.wake();
+        }
+
+        delivered
+    }
+
+    /// Start receiving the messages published to `topics`, until the
+    /// subscription is dropped. Fails if there are [`MAX_SUBSCRIPTIONS`]
+    /// already, see [`topics_from_query`] for the limits on the topics.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        topics: Vec<Topic>,
+    ) -> Result<Subscription, SubscribeError> {
+        let mut subscribers = self.lock();
+        if subscribers.by_id.len() >= MAX_SUBSCRIPTIONS {
+            return Err(SubscribeError::TooManySubscriptions);
+        }
+        for topic in &topics {
+            self.metrics.record_subscribe(topic);
+        }
+
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.by_id.insert(
+            id,
+            Subscriber {
+                topics,
+                queue: VecDeque::new(),
+            },
+        );
+
+        Ok(Subscription {
+            broker: Arc::clone(self),
+            id,
+        })
+    }
+
+    pub fn wakeup(&self) -> &Arc<Wakeup> {
+        &self.wakeup
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Subscribers> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A client receiving messages, see [`Broker::subscribe`].
+#[derive(Debug)]
+pub struct Subscription {
+    broker: Arc<Broker>,
+    id: u64,
+}
+
+impl Subscription {
+    /// The messages that were published since the last call.
+    pub fn next_messages(&mut self) -> Vec<Message> {
+        match self.broker.lock().by_id.get_mut(&self.id) {
+            Some(subscriber) => subscriber.queue.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl EventStream for Subscription {
+    fn poll(&mut self) -> Option<String> {
+        let messages = self.next_messages();
+        Some(messages.iter().map(Message::to_frame).collect())
+    }
+
+    fn wakeup(&self) -> Arc<Wakeup> {
+        Arc::clone(&self.broker.wakeup)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let subscriber = self.broker.lock().by_id.remove(&self.id);
+        for topic in subscriber.iter().flat_map(|s| &s.topics) {
+            self.broker.metrics.record_unsubscribe(topic);
+        }
+    }
+}
+
+/// The topics in the comma separated `channel` and `pattern` query
+/// parameters of a subscription. Fails if there are none, or more than
+/// [`MAX_TOPICS`] or [`MAX_PATTERNS`], or one is longer than
+/// [`MAX_TOPIC_LENGTH`].
+pub fn topics_from_query(
+    query: &HashMap<String, String>,
+) -> Result<Vec<Topic>, SubscribeError> {
+    let names = |param: &str| {
+        query
+            .get(param)
+            .into_iter()
+            .flat_map(|names| names.split(','))
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    let channels = names("channel");
+    let patterns = names("pattern");
+    if channels.is_empty() && patterns.is_empty() {
+        return Err(SubscribeError::NoTopics);
+    }
+    if channels.len() + patterns.len() > MAX_TOPICS {
+        return Err(SubscribeError::TooManyTopics);
+    }
+    if patterns.len() > MAX_PATTERNS {
+        return Err(SubscribeError::TooManyPatterns);
+    }
+    if channels
+        .iter()
+        .chain(&patterns)
+        .any(|name| !is_short_enough(name))
+    {
+        return Err(SubscribeError::TopicTooLong);
+    }
+
+    let channels = channels.into_iter().map(Topic::Channel);
+    let patterns = patterns.into_iter().map(Topic::Pattern);
+    Ok(channels.chain(patterns).collect())
+}
+
+/// Whether a channel name or pattern is at most [`MAX_TOPIC_LENGTH`]
+/// characters long. Messages can only be published to channels that are.
+pub fn is_short_enough(name: &str) -> bool {
+    name.chars().count() <= MAX_TOPIC_LENGTH
+}
+
+/// Whether `text` matches the glob `pattern`, see [`Topic::Pattern`].
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken so far.
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` take one more character and try again.
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        assert!(glob_matches("news.*", "news.sport"));
+        assert!(glob_matches("news.*", "news."));
+        assert!(!glob_matches("news.*", "news"));
+        assert!(glob_matches("*.eu.*", "orders.eu.paid"));
+        assert!(glob_matches("h?llo", "hallo"));
+        assert!(!glob_matches("h?llo", "hllo"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn test_publish_and_subscribe() {
+        let metrics = Arc::new(Metrics::new());
+        let broker = Arc::new(Broker::new(Arc::clone(&metrics)));
+        let mut news = broker
+            .subscribe(vec![
+                Topic::Channel("news".to_string()),
+                Topic::Pattern("news.*".to_string()),
+            ])
+            .unwrap();
+        let mut sport = broker
+            .subscribe(vec![Topic::Pattern("news.sport".to_string())])
+            .unwrap();
+        let pubsub = &metrics.to_json()["pubsub"];
+        assert_eq!(pubsub["channels"]["news"], 1);
+        assert_eq!(pubsub["patterns"]["news.*"], 1);
+
+        assert_eq!(broker.publish("news", "1"), 1);
+        assert_eq!(broker.publish("news.sport", "2"), 2);
+        assert_eq!(broker.publish("weather", "3"), 0);
+        let payloads: Vec<_> = news
+            .next_messages()
+            .into_iter()
+            .map(|message| message.payload)
+            .collect();
+        assert_eq!(payloads, ["1", "2"]);
+        assert_eq!(sport.next_messages()[0].channel, "news.sport");
+        // Messages are only sent once.
+        assert_eq!(news.next_messages(), []);
+
+        // Slow subscribers miss messages once their queue is full.
+        for _ in 0..SUBSCRIBER_QUEUE + 1 {
+            broker.publish("news.sport", "x");
+        }
+        assert_eq!(sport.next_messages().len(), SUBSCRIBER_QUEUE);
+        assert_eq!(metrics.to_json()["pubsub"]["dropped"], 2);
+
+        drop(news);
+        let pubsub = &metrics.to_json()["pubsub"];
+        assert_eq!(pubsub["channels"], json!({}));
+        assert_eq!(pubsub["patterns"], json!({ "news.sport": 1 }));
+    }
+
+    #[test]
+    fn test_subscription_limits() {
+        let query = |channels: usize, patterns: usize, length: usize| {
+            let names = |count: usize| {
+                (0..count)
+                    .map(|i| format!("{:0>1$}", i, length))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            let query = HashMap::from([
+                ("channel".to_string(), names(channels)),
+                ("pattern".to_string(), names(patterns)),
+            ]);
+            topics_from_query(&query).map(|topics| topics.len())
+        };
+        assert_eq!(query(0, 0, 8), Err(SubscribeError::NoTopics));
+        assert_eq!(query(MAX_TOPICS, 0, 8), Ok(MAX_TOPICS));
+        assert_eq!(query(MAX_TOPICS, 1, 8), Err(SubscribeError::TooManyTopics));
+        assert_eq!(query(1, MAX_PATTERNS, 8), Ok(MAX_PATTERNS + 1));
+        assert_eq!(
+            query(1, MAX_PATTERNS + 1, 8),
+            Err(SubscribeError::TooManyPatterns)
+        );
+        assert_eq!(query(1, 1, MAX_TOPIC_LENGTH), Ok(2));
+        assert_eq!(
+            query(1, 0, MAX_TOPIC_LENGTH + 1),
+            Err(SubscribeError::TopicTooLong)
+        );
+
+        let broker = Arc::new(Broker::new(Arc::new(Metrics::new())));
+        let topics = || vec![Topic::Channel("news".to_string())];
+        let mut subscriptions: Vec<_> = (0..MAX_SUBSCRIPTIONS)
+            .map(|_| broker.subscribe(topics()).unwrap())
+            .collect();
+        assert_eq!(
+            broker.subscribe(topics()).unwrap_err(),
+            SubscribeError::TooManySubscriptions
+        );
+        subscriptions.pop();
+        assert!(broker.subscribe(topics()).is_ok());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::connection::RequestType;
+
+/// The paths a route matches. Paths are matched without the leading slash
+/// and the query, e.g. `admin/snapshots/3` for `/admin/snapshots/3?ttl=1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// Exactly this path.
+    Exact(&'static str),
+    /// Every path that starts with this, the rest is handed to the handler,
+    /// e.g. the key of `history/<key>`.
+    Prefix(&'static str),
+    /// Every path, the whole path is handed to the handler.
+    Any,
+}
+
+impl Pattern {
+    /// The part of the path that is handed to the handler, if the pattern
+    /// matches.
+    fn matches<'a>(&self, path: &'a str) -> Option<&'a str> {
+        match self {
+            Pattern::Exact(exact) => (path == *exact).then_some(""),
+            Pattern::Prefix(prefix) => path.strip_prefix(prefix),
+            Pattern::Any => Some(path),
+        }
+    }
+}
+
+struct Route<H> {
+    method: RequestType,
+    pattern: Pattern,
+    handler: H,
+}
+
+/// Maps HTTP methods and paths to handlers.
+///
+/// Routes are tried in the order they were added, the first one whose method
+/// and pattern match wins. More specific patterns have to be added first.
+pub struct Router<H> {
+    routes: Vec<Route<H>>,
+}
+
+/// The handler a request is routed to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Match<'a, H> {
+    pub handler: H,
+    /// The part of the path the pattern handed over, see [`Pattern`].
+    pub param: &'a str,
+}
+
+/// Why a request couldn't be routed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RouteError {
+    /// No route matches the path.
+    NotFound,
+    /// Routes match the path, but only for these methods.
+    MethodNotAllowed(Vec<&'static str>),
+}
+
+impl<H: Copy> Router<H> {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Add a route for requests with `method` whose path matches `pattern`.
+    pub fn route(
+        mut self,
+        method: RequestType,
+        pattern: Pattern,
+        handler: H,
+    ) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern,
+            handler,
+        });
+        self
+    }
+
+    /// The handler for a request.
+    pub fn find<'a>(
+        &self,
+        method: &RequestType,
+        path: &'a str,
+    ) -> Result<Match<'a, H>, RouteError> {
+        let found = self.routes.iter().find_map(|route| {
+            let param = route.pattern.matches(path)?;
+            (route.method == *method).then_some(Match {
+                handler: route.handler,
+                param,
+            })
+        });
+        if let Some(found) = found {
+            return Ok(found);
+        }
+
+        let allowed = self.allowed(path);
+        match allowed.is_empty() {
+            true => Err(RouteError::NotFound),
+            false => Err(RouteError::MethodNotAllowed(allowed)),
+        }
+    }
+
+    /// Methods there are routes for at the path, in the order they were
+    /// added.
+    pub fn allowed(&self, path: &str) -> Vec<&'static str> {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let method = route.method.method();
+            if route.pattern.matches(path).is_some()
+                && !allowed.contains(&method)
+            {
+                allowed.push(method);
+            }
+        }
+
+        allowed
+    }
+}
+
+impl<H: Copy> Default for Router<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        let router = Router::new()
+            .route(RequestType::Get, Pattern::Exact("ls"), 1)
+            .route(RequestType::Delete, Pattern::Prefix("snapshots/"), 2)
+            .route(RequestType::Get, Pattern::Any, 3)
+            .route(RequestType::Put, Pattern::Any, 4);
+
+        assert_eq!(
+            router.find(&RequestType::Get, "ls"),
+            Ok(Match {
+                handler: 1,
+                param: ""
+            })
+        );
+        assert_eq!(
+            router.find(&RequestType::Delete, "snapshots/3"),
+            Ok(Match {
+                handler: 2,
+                param: "3"
+            })
+        );
+        assert_eq!(
+            router.find(&RequestType::Get, "users:alice").unwrap().param,
+            "users:alice"
+        );
+        assert_eq!(
+            router.find(&RequestType::Post, "ls"),
+            Err(RouteError::MethodNotAllowed(vec!["GET", "PUT"]))
+        );
+
+        let empty: Router<u8> = Router::new();
+        assert_eq!(
+            empty.find(&RequestType::Get, "ls"),
+            Err(RouteError::NotFound)
+        );
+    }
+}
@@ -0,0 +1,875 @@
+use crate::admin::{self, AdminResponse};
+use crate::audit::{AuditLog, AuditRecord, Identity};
+use crate::auth::{self, AuthError};
+use crate::connection::{self, RequestType, StoreResponse};
+use crate::cors::CorsConfig;
+use crate::crypto::SecretKey;
+use crate::lockout::{self, LockoutTracker, Subject};
+use crate::metrics::Metrics;
+use crate::pubsub::Broker;
+use crate::router::{Pattern, RouteError, Router};
+use crate::store::{ShardedStore, StoreConfig};
+use crate::stream::{self, BodyStream, EventStream, Wakeup};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use zeroize::Zeroize;
+
+/// Seconds clients turned away by a full queue are told to wait.
+const BUSY_RETRY_AFTER: u64 = 1;
+/// Most bytes read from a connection that is turned away, see
+/// [`Server::shed`].
+const SHED_DRAIN_LIMIT: usize = 64 * 1024;
+/// Most event streams, i.e. watches and subscriptions, that can be open at
+/// once. Each holds a connection, and a thread of its own when the server
+/// runs on threads.
+const MAX_EVENT_STREAMS: usize = 1024;
+
+/// State shared by everything that handles requests, whether that is the
+/// worker threads or the tasks of the async server.
+pub struct Server {
+    key_value_store: Arc<ShardedStore>,
+    encryption_key: Arc<SecretKey>,
+    lockouts: Mutex<LockoutTracker>,
+    audit_log: Option<Mutex<AuditLog>>,
+    metrics: Arc<Metrics>,
+    broker: Arc<Broker>,
+    router: Router<Handler>,
+    cors: CorsConfig,
+    /// Set once the server shuts down, which ends every stream.
+    closing: AtomicBool,
+    /// Event streams that are open, see [`MAX_EVENT_STREAMS`].
+    event_streams: Arc<AtomicUsize>,
+}
+
+/// A request that has been read in full and is valid HTTP.
+struct Request<'a> {
+    buf: &'a [u8],
+    client: SocketAddr,
+    request_type: &'a RequestType,
+    /// The path without the leading slash and the query.
+    path: &'a str,
+}
+
+/// Handles a request, see [`Server::router`]. Also returns who made the
+/// request, for the audit log.
+type Handler = fn(&Server, &Request) -> (Response, Identity);
+
+/// A response that is ready to be written to the stream.
+pub struct Response {
+    pub status_line: String,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+    /// What to send after the headers instead of the body, see
+    /// [`ResponseStream`].
+    pub stream: Option<ResponseStream>,
+}
+
+/// The rest of a response that is sent as it is produced. It has no length,
+/// it ends when the connection is closed.
+pub enum ResponseStream {
+    /// Server-sent events, until the stream ends or the server shuts down.
+    Events(Box<dyn EventStream>),
+    /// A body, e.g. an export, until it is done.
+    Body(Box<dyn BodyStream>),
+}
+
+impl Response {
+    fn new((status_line, body): (String, String)) -> Self {
+        Self {
+            status_line,
+            headers: Vec::new(),
+            body,
+            stream: None,
+        }
+    }
+
+    fn json((status_line, body): (String, String)) -> Self {
+        Self {
+            status_line,
+            headers: vec![("Content-Type", "application/json".to_string())],
+            body,
+            stream: None,
+        }
+    }
+
+    /// A response of the store, with the version it is about in the
+    /// `Skv-Version` header.
+    fn from_store(response: StoreResponse) -> Self {
+        let mut headers = Vec::new();
+        if let Some(version) = response.version {
+            headers.push(("Skv-Version", version.to_string()));
+        }
+
+        Self {
+            status_line: response.status_line,
+            headers,
+            body: response.body,
+            stream: None,
+        }
+    }
+
+    /// The response to a request that couldn't be routed, see
+    /// [`Router::find`].
+    fn from_route_error(error: RouteError, request: &Request) -> Self {
+        let path = request.path;
+        match error {
+            RouteError::NotFound => Self::json((
+                "HTTP/1.1 404 NOT FOUND".to_string(),
+                connection::json_error(&format!(
+                    "Unknown endpoint '/{}'.",
+                    path
+                )),
+            )),
+            RouteError::MethodNotAllowed(allowed) => {
+                let mut response = Self::json((
+                    "HTTP/1.1 405 Method Not Allowed".to_string(),
+                    connection::json_error(&format!(
+                        "{} is not allowed for '/{}'.",
+                        request.request_type.method(),
+                        path
+                    )),
+                ));
+                response.headers.push(("Allow", allowed.join(", ")));
+                response
+            }
+        }
+    }
+
+    /// A response that sends server-sent events from `stream`. It has no
+    /// length, it ends when the connection is closed.
+    fn event_stream(stream: Box<dyn EventStream>) -> Self {
+        Self {
+            status_line: "HTTP/1.1 200 OK".to_string(),
+            headers: vec![
+                ("Content-Type", "text/event-stream".to_string()),
+                ("Cache-Control", "no-cache".to_string()),
+                ("Connection", "close".to_string()),
+            ],
+            body: String::new(),
+            stream: Some(ResponseStream::Events(stream)),
+        }
+    }
+
+    /// A response that sends a body of `content_type` from `body`, a chunk
+    /// at a time.
+    fn body_stream(content_type: &str, body: Box<dyn BodyStream>) -> Self {
+        Self {
+            status_line: "HTTP/1.1 200 OK".to_string(),
+            headers: vec![
+                ("Content-Type", content_type.to_string()),
+                ("Connection", "close".to_string()),
+            ],
+            body: String::new(),
+            stream: Some(ResponseStream::Body(body)),
+        }
+    }
+
+    /// The status line and headers of a streaming response, as they are
+    /// sent before the events.
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{}\r\n", self.status_line);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        head.into_bytes()
+    }
+
+    /// The response as it is sent, see [`connection::format_response`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        connection::format_response(
+            &self.status_line,
+            &self.headers,
+            &self.body,
+        )
+        .into_bytes()
+    }
+}
+
+impl Server {
+    /// A server with an empty store set up by `store_config`, encrypted with
+    /// `encryption_key`, that answers browsers as `cors` says.
+    pub fn new(
+        encryption_key: SecretKey,
+        store_config: StoreConfig,
+        audit_log: Option<AuditLog>,
+        cors: CorsConfig,
+    ) -> Self {
+        let encryption_key = Arc::new(encryption_key);
+        let metrics = Arc::new(Metrics::new());
+
+        Self {
+            key_value_store: Arc::new(ShardedStore::with_config(
+                Arc::clone(&encryption_key),
+                store_config,
+            )),
+            encryption_key,
+            lockouts: Mutex::new(LockoutTracker::new()),
+            audit_log: audit_log.map(Mutex::new),
+            broker: Arc::new(Broker::new(Arc::clone(&metrics))),
+            metrics,
+            router: Self::router(),
+            cors,
+            closing: AtomicBool::new(false),
+            event_streams: Arc::default(),
+        }
+    }
+
+    /// Where requests go. The admin endpoints have routes of their own, see
+    /// [`admin::handle_admin_request`].
+    fn router() -> Router<Handler> {
+        let admin = Pattern::Prefix(admin::ADMIN_PREFIX);
+
+        Router::<Handler>::new()
+            .route(RequestType::Options, Pattern::Any, Self::handle_options)
+            .route(RequestType::Get, admin, Self::handle_admin)
+            .route(RequestType::Head, admin, Self::handle_admin)
+            .route(RequestType::Put, admin, Self::handle_admin)
+            .route(RequestType::Patch, admin, Self::handle_admin)
+            .route(RequestType::Post, admin, Self::handle_admin)
+            .route(RequestType::Delete, admin, Self::handle_admin)
+            .route(RequestType::Get, Pattern::Any, Self::handle_get)
+            .route(RequestType::Head, Pattern::Any, Self::handle_get)
+            .route(RequestType::Put, Pattern::Any, Self::handle_put)
+            .route(RequestType::Patch, Pattern::Any, Self::handle_patch)
+            .route(RequestType::Post, Pattern::Any, Self::handle_command)
+            .route(RequestType::Delete, Pattern::Any, Self::handle_delete)
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// End every stream, e.g. watches, so that their connections don't hold
+    /// up the shutdown. Streams started afterwards end right away.
+    pub fn close_streams(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        self.key_value_store.events().wakeup().wake();
+        self.broker.wakeup().wake();
+    }
+
+    /// Whether streams should end, see [`Server::close_streams`].
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Read a request from the stream, handle it and write the response.
+    ///
+    /// Runs on a worker thread, so a connection that fails to send a valid
+    /// request only ever gets itself a 400 response. Event streams last until
+    /// the client goes away, so they are handed to a thread of their own
+    /// instead of keeping the worker busy.
+    pub fn handle_connection(self: &Arc<Self>, stream: TcpStream) {
+        let client = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                eprintln!("Error encountered: {}", e);
+                return;
+            }
+        };
+
+        self.metrics.record_connection_open();
+        self.metrics.record_request();
+        let mut response = match connection::buf_from_stream(&stream) {
+            Ok(buf) => self.handle_buffer(buf, client),
+            Err(e) => self.reject(client, e),
+        };
+
+        match response.stream.take() {
+            Some(ResponseStream::Events(events)) => {
+                let server = Arc::clone(self);
+                let spawned = thread::Builder::new()
+                    .name("skv-stream".to_string())
+                    .spawn(move || {
+                        server.send_events(&stream, &response, events);
+                        server.metrics.record_connection_close();
+                    });
+                match spawned {
+                    Ok(_) => return,
+                    Err(e) => eprintln!("Failed to start stream thread: {}", e),
+                }
+            }
+            Some(ResponseStream::Body(body)) => {
+                send_body(&stream, &response, body)
+            }
+            None => {
+                if let Err(e) = connection::write_response(
+                    &stream,
+                    response.status_line,
+                    &response.headers,
+                    response.body,
+                ) {
+                    eprintln!("Error encountered: {}", e);
+                }
+            }
+        }
+        self.metrics.record_connection_close();
+    }
+
+    /// Write the head of a streaming response, then its events as they come
+    /// in, until the stream ends, the client goes away or the server shuts
+    /// down.
+    ///
+    /// This keeps the thread busy for as long as the stream lasts.
+    fn send_events(
+        &self,
+        mut stream: &TcpStream,
+        response: &Response,
+        mut events: Box<dyn EventStream>,
+    ) {
+        if stream.write_all(&response.head_bytes()).is_err() {
+            return;
+        }
+
+        let wakeup = events.wakeup();
+        loop {
+            let seen = wakeup.generation();
+            let frames = match events.poll() {
+                Some(frames) if !self.is_closing() => frames,
+                _ => break,
+            };
+            let frames = match frames.is_empty() {
+                true if wakeup.wait(seen, stream::KEEPALIVE_INTERVAL) => {
+                    continue
+                }
+                true => stream::KEEPALIVE.to_string(),
+                false => frames,
+            };
+
+            if stream.write_all(frames.as_bytes()).is_err()
+                || stream.flush().is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Handle a request that has been read in full and record it in the audit
+    /// log.
+    ///
+    /// The buffer is zeroized once it has been handled, as it may carry the
+    /// encryption key.
+    pub fn handle_buffer(
+        &self,
+        mut buf: Vec<u8>,
+        client: SocketAddr,
+    ) -> Response {
+        // A panic must neither take down the worker nor leave the client
+        // without an answer, and the request is audited either way.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.respond(&buf, client)
+        }));
+        let (response, identity, key) = match result {
+            Ok(result) => result,
+            Err(_) => {
+                eprintln!(
+                    "Recovered from a panic handling request from {}",
+                    client
+                );
+                self.metrics.record_panic();
+                let response = Response::json((
+                    "HTTP/1.1 500 Internal Server Error".to_string(),
+                    connection::json_error("Internal server error."),
+                ));
+                (response, Identity::Anonymous, String::new())
+            }
+        };
+        let method = connection::request_type(&buf).method();
+        buf.zeroize();
+
+        self.audit(&AuditRecord {
+            client,
+            identity,
+            method,
+            key: &key,
+            status_line: &response.status_line,
+        });
+
+        response
+    }
+
+    /// Answer a request, with the CORS headers the browser needs, or reject
+    /// it if it isn't valid HTTP.
+    ///
+    /// Also returns who made the request and the key it was for, for the
+    /// audit log.
+    fn respond(
+        &self,
+        buf: &[u8],
+        client: SocketAddr,
+    ) -> (Response, Identity, String) {
+        if let Err(e) = connection::verify_request(buf) {
+            eprintln!("Rejected request from {}: {}", client, e);
+            return (bad_request(e), Identity::Anonymous, String::new());
+        }
+        let request_type = connection::request_type(buf);
+
+        let (mut response, identity) =
+            self.handle_request(buf, client, &request_type);
+        response.headers.extend(self.cors.headers(buf));
+        let key = connection::parse_key_from_request(buf).unwrap_or_default();
+
+        (response, identity, key)
+    }
+
+    /// Turn a connection away with 503 because every worker is busy and the
+    /// queue is full.
+    ///
+    /// Runs on the accept thread, so it never waits for the client.
+    pub fn shed(&self, mut stream: TcpStream) {
+        self.metrics.record_rejected();
+
+        // Closing a socket with unread data resets the connection, which can
+        // discard the response before the client reads it. Drain what has
+        // already arrived, without waiting for more.
+        if stream.set_nonblocking(true).is_ok() {
+            let mut chunk = [0; 4096];
+            let mut drained = 0;
+            while drained < SHED_DRAIN_LIMIT {
+                match stream.read(&mut chunk) {
+                    Ok(read) if read > 0 => drained += read,
+                    _ => break,
+                }
+            }
+            // The request may carry the encryption key in its headers.
+            chunk.zeroize();
+            let _ = stream.set_nonblocking(false);
+        }
+
+        let mut response = Response::json((
+            "HTTP/1.1 503 Service Unavailable".to_string(),
+            connection::json_error("Server is busy. Try again later."),
+        ));
+        response
+            .headers
+            .push(("Retry-After", BUSY_RETRY_AFTER.to_string()));
+
+        if let Err(e) = connection::write_response(
+            &stream,
+            response.status_line,
+            &response.headers,
+            response.body,
+        ) {
+            eprintln!("Error encountered: {}", e);
+        }
+    }
+
+    /// Answer a request that couldn't be read or isn't valid HTTP, and record
+    /// it in the audit log.
+    pub fn reject(&self, client: SocketAddr, error: &str) -> Response {
+        eprintln!("Rejected request from {}: {}", client, error);
+        let response = bad_request(error);
+
+        self.audit(&AuditRecord {
+            client,
+            identity: Identity::Anonymous,
+            method: "UNKNOWN",
+            key: "",
+            status_line: &response.status_line,
+        });
+
+        response
+    }
+
+    /// Route the request to its handler.
+    ///
+    /// Also returns who made the request, for the audit log.
+    fn handle_request(
+        &self,
+        buf: &[u8],
+        client: SocketAddr,
+        request_type: &RequestType,
+    ) -> (Response, Identity) {
+        if let RequestType::Unknown(unknown_response) = request_type {
+            return (
+                Response::new(unknown_response.clone()),
+                Identity::Anonymous,
+            );
+        }
+
+        let path = connection::parse_key_from_request(buf).unwrap_or_default();
+        let request = Request {
+            buf,
+            client,
+            request_type,
+            path: connection::split_query(&path).0,
+        };
+
+        let (mut response, identity) =
+            match self.router.find(request_type, request.path) {
+                Ok(route) => (route.handler)(self, &request),
+                Err(e) => (
+                    Response::from_route_error(e, &request),
+                    Identity::Anonymous,
+                ),
+            };
+
+        // HEAD is answered like GET, without the body. A streamed body isn't
+        // produced at all, its length isn't known.
+        if let RequestType::Head = request_type {
+            if response.stream.take().is_none() {
+                response
+                    .headers
+                    .push(("Content-Length", response.body.len().to_string()));
+            }
+            response.body.clear();
+        }
+
+        (response, identity)
+    }
+
+    /// Answer which methods the path supports, and with the CORS headers if
+    /// the request is a preflight from a browser.
+    ///
+    /// Browsers don't send the encryption key with a preflight, so it isn't
+    /// needed here.
+    fn handle_options(&self, request: &Request) -> (Response, Identity) {
+        let allowed = match request.path.starts_with(admin::ADMIN_PREFIX) {
+            true => {
+                let mut allowed = admin::allowed_methods(request.path);
+                allowed.push(RequestType::Options.method());
+                allowed
+            }
+            false => self.router.allowed(request.path),
+        };
+
+        let mut response = Response::new((
+            "HTTP/1.1 204 No Content".to_string(),
+            String::new(),
+        ));
+        response.headers.push(("Allow", allowed.join(", ")));
+        response
+            .headers
+            .extend(self.cors.preflight_headers(request.buf, &allowed));
+
+        (response, Identity::Anonymous)
+    }
+
+    fn handle_admin(&self, request: &Request) -> (Response, Identity) {
+        if let Err(response) = self.authenticate(request.buf, request.client) {
+            return (response, Identity::Anonymous);
+        }
+
+        let response = match admin::handle_admin_request(
+            request.buf,
+            request.request_type,
+            &self.lockouts,
+            &self.key_value_store,
+            &self.encryption_key,
+            &self.metrics,
+            &self.broker,
+        ) {
+            Ok(AdminResponse::Json(response)) => {
+                // Admin routes answer 503 when the server is too busy, like
+                // the broker with too many subscriptions.
+                let busy = response.0.starts_with("HTTP/1.1 503");
+                let mut response = Response::json(response);
+                if busy {
+                    response
+                        .headers
+                        .push(("Retry-After", BUSY_RETRY_AFTER.to_string()));
+                }
+                response
+            }
+            Ok(AdminResponse::Stream { content_type, body }) => {
+                Response::body_stream(content_type, body)
+            }
+            Ok(AdminResponse::Events { events, headers }) => {
+                self.open_event_stream(events, headers)
+            }
+            Err(e) => Response::from_route_error(e, request),
+        };
+
+        (response, Identity::Master)
+    }
+
+    /// A response that sends the events, unless [`MAX_EVENT_STREAMS`] are
+    /// open already.
+    fn open_event_stream(
+        &self,
+        events: Box<dyn EventStream>,
+        headers: Vec<(&'static str, String)>,
+    ) -> Response {
+        // Counted before checking, so streams opened at the same time can't
+        // all get in. Dropping the stream releases the count again.
+        let open = self.event_streams.fetch_add(1, Ordering::SeqCst);
+        let events = CountedStream {
+            events,
+            open: Arc::clone(&self.event_streams),
+        };
+        if open >= MAX_EVENT_STREAMS {
+            let mut response = Response::json((
+                "HTTP/1.1 503 Service Unavailable".to_string(),
+                connection::json_error(
+                    "Too many open streams. Try again later.",
+                ),
+            ));
+            response
+                .headers
+                .push(("Retry-After", BUSY_RETRY_AFTER.to_string()));
+            return response;
+        }
+
+        let mut response = Response::event_stream(Box::new(events));
+        response.headers.extend(headers);
+        response
+    }
+
+    fn handle_get(&self, request: &Request) -> (Response, Identity) {
+        if let Err(response) = self.authenticate(request.buf, request.client) {
+            return (response, Identity::Anonymous);
+        }
+
+        let response = self.key_value_store.handle_get_request(request.buf);
+        // Every value is written in a version of its own, which makes the
+        // version a fine entity tag.
+        let etag = response.version.map(|version| format!("\"{}\"", version));
+        let mut response = Response::from_store(response);
+        if let Some(etag) = etag {
+            response.headers.push(("ETag", etag));
+        }
+
+        (response, Identity::Master)
+    }
+
+    fn handle_put(&self, request: &Request) -> (Response, Identity) {
+        if let Err(response) = self.authenticate(request.buf, request.client) {
+            return (response, Identity::Anonymous);
+        }
+
+        let response = self.key_value_store.handle_put_request(request.buf);
+        (Response::from_store(response), Identity::Master)
+    }
+
+    fn handle_patch(&self, request: &Request) -> (Response, Identity) {
+        if let Err(response) = self.authenticate(request.buf, request.client) {
+            return (response, Identity::Anonymous);
+        }
+
+        let response = self.key_value_store.handle_patch_request(request.buf);
+        let mut response = Response::from_store(response);
+        response
+            .headers
+            .push(("Content-Type", "application/json".to_string()));
+
+        (response, Identity::Master)
+    }
+
+    /// Commands for lists, sets, hashes and sorted sets, see
+    /// [`crate::typed::Command`].
+    fn handle_command(&self, request: &Request) -> (Response, Identity) {
+        if let Err(response) = self.authenticate(request.buf, request.client) {
+            return (response, Identity::Anonymous);
+        }
+
+        let response = self.key_value_store.handle_command_request(request.buf);
+        let mut response = Response::from_store(response);
+        response
+            .headers
+            .push(("Content-Type", "application/json".to_string()));
+
+        (response, Identity::Master)
+    }
+
+    fn handle_delete(&self, request: &Request) -> (Response, Identity) {
+        if let Err(response) = self.authenticate(request.buf, request.client) {
+            return (response, Identity::Anonymous);
+        }
+
+        let response = self.key_value_store.handle_delete_request(request.buf);
+        (Response::from_store(response), Identity::Master)
+    }
+
+    /// Authenticate the request, locking out clients and keys with too many
+    /// failed attempts.
+    ///
+    /// On failure, the error is the response to send.
+    fn authenticate(
+        &self,
+        buf: &[u8],
+        client: SocketAddr,
+    ) -> Result<(), Response> {
+        let subjects = Subject::from_request(client.ip(), buf);
+        let mut lockouts =
+            self.lockouts.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Err(retry_after) = lockouts.check(&subjects) {
+            eprintln!("Rejected request from locked out client {}", client);
+            let mut response = Response::json(lockout::locked_out_response());
+            response.headers.push((
+                "Retry-After",
+                lockout::retry_after_secs(retry_after).to_string(),
+            ));
+            return Err(response);
+        }
+
+        match auth::authenticate(buf, &self.encryption_key) {
+            Ok(()) => {
+                lockouts.record_success(&subjects);
+                Ok(())
+            }
+            Err(AuthError::WrongKey) => {
+                lockouts.record_failure(&subjects);
+                Err(Response::json(AuthError::WrongKey.response()))
+            }
+            Err(e) => Err(Response::json(e.response())),
+        }
+    }
+
+    /// Housekeeping that runs in the background every now and then: drop
+    /// old values of the store that neither a snapshot nor the retention
+    /// needs anymore, and write the audit log to disk.
+    pub fn run_maintenance(&self) {
+        self.key_value_store.collect_garbage();
+        self.flush();
+    }
+
+    /// Write everything that is still buffered to disk, e.g. the audit log
+    /// and its head.
+    pub fn flush(&self) {
+        if let Some(audit_log) = &self.audit_log {
+            let result = audit_log
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .sync();
+            if let Err(e) = result {
+                eprintln!("Error encountered: {}", e);
+            }
+        }
+    }
+
+    /// Append the request to the audit log, if there is one.
+    fn audit(&self, record: &AuditRecord) {
+        let audit_log = match &self.audit_log {
+            Some(audit_log) => audit_log,
+            None => return,
+        };
+
+        let result = audit_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .record(record);
+        if let Err(e) = result {
+            eprintln!("Error encountered: {}", e);
+        }
+    }
+}
+
+/// An event stream that is counted as open until it is dropped, see
+/// [`Server::open_event_stream`].
+struct CountedStream {
+    events: Box<dyn EventStream>,
+    open: Arc<AtomicUsize>,
+}
+
+impl EventStream for CountedStream {
+    fn poll(&mut self) -> Option<String> {
+        self.events.poll()
+    }
+
+    fn wakeup(&self) -> Arc<Wakeup> {
+        self.events.wakeup()
+    }
+}
+
+impl Drop for CountedStream {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answer a request that couldn't be read or isn't valid HTTP with 400.
+fn bad_request(error: &str) -> Response {
+    Response::json((
+        "HTTP/1.1 400 Bad Request".to_string(),
+        connection::json_error(error),
+    ))
+}
+
+/// Write the head of a streaming response, then its body a chunk at a time
+/// as it is produced, until it is done or the client goes away.
+fn send_body(
+    mut stream: &TcpStream,
+    response: &Response,
+    mut body: Box<dyn BodyStream>,
+) {
+    if stream.write_all(&response.head_bytes()).is_err() {
+        return;
+    }
+
+    while let Some(chunk) = body.next_chunk() {
+        if stream.write_all(chunk.as_bytes()).is_err() {
+            return;
+        }
+    }
+    let _ = stream.flush();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audit;
+    use std::fs;
+
+    const KEY: &str =
+        "606edace3053c4e9222515b7ba0e16e41648c40c56860edb464f813cd53c5726";
+
+    fn key() -> SecretKey {
+        SecretKey::from_hex(KEY).unwrap()
+    }
+
+    fn panicking_handler(_: &Server, _: &Request) -> (Response, Identity) {
+        panic!("Handler panicked.")
+    }
+
+    #[test]
+    fn test_panicking_requests_are_answered_and_audited() {
+        let path = std::env::temp_dir()
+            .join(format!("skv-test-server-panics-{}", std::process::id()));
+        let mut server = Server::new(
+            key(),
+            StoreConfig::new(1),
+            Some(AuditLog::open(&path, key()).unwrap()),
+            CorsConfig::default(),
+        );
+        server.router = Router::<Handler>::new().route(
+            RequestType::Get,
+            Pattern::Any,
+            panicking_handler,
+        );
+
+        let client = "127.0.0.1:50000".parse().unwrap();
+        for _ in 0..2 {
+            let request = b"GET /greeting HTTP/1.1\r\n\r\n".to_vec();
+            let response = server.handle_buffer(request, client);
+            assert_eq!(
+                response.status_line,
+                "HTTP/1.1 500 Internal Server Error"
+            );
+            assert!(response.body.contains("error"));
+        }
+        let request = b"PUT /greeting HTTP/1.1\r\n\r\nhello".to_vec();
+        let response = server.handle_buffer(request, client);
+        assert!(response.status_line.starts_with("HTTP/1.1 405"));
+
+        server
+            .audit_log
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .sync()
+            .unwrap();
+        let audited = audit::verify(&path, &key());
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.head", path.display()));
+
+        assert_eq!(server.metrics.panics(), 2);
+        assert_eq!(audited.unwrap().0, 3);
+    }
+}
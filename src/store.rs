@@ -0,0 +1,1326 @@
+use crate::connection::{
+    in_namespaces, json_error, parse_body_from_request, parse_key_from_request,
+    split_query, Commit, DataObject, EncryptedEntry, KeyValueStore,
+    StoreResponse,
+};
+use crate::crypto::{decrypt, SecretKey};
+use crate::history::{HistoryEntry, Retention, RetentionPolicy, TrashEntry};
+use crate::index::{IndexError, Indexes};
+use crate::typed::{self, Command};
+use crate::watch::{Events, Filter, Watch, WatchError, DEFAULT_WATCH_HISTORY};
+use serde_json::Value;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::iter;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard,
+    RwLockWriteGuard,
+};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A key-value store split into independently locked shards, so that
+/// requests for keys in different shards don't wait for each other.
+///
+/// Keys are assigned to shards by a keyed hash of the plaintext key, with a
+/// hash key that is random for every store. The shard of a key therefore
+/// says nothing about the key to anyone who can't see the server's memory.
+///
+/// Every write creates a new store version, and keys keep the values they had
+/// in older versions for as long as a [`Snapshot`] may need them. Reads of
+/// the whole store, e.g. `ls` or a backup, read a snapshot one shard at a
+/// time, so they see a consistent store without blocking writers for more
+/// than a moment. Operations that must change many keys at once, e.g. a
+/// restore, lock every shard, see [`ShardedStore::write_all`].
+///
+/// Keys may also keep old values for longer, so they can be looked at or
+/// restored later, see [`ShardedStore::history`].
+pub struct ShardedStore {
+    shards: Vec<RwLock<KeyValueStore>>,
+    hasher: RandomState,
+    encryption_key: Arc<SecretKey>,
+    retention: Arc<RetentionPolicy>,
+    indexes: Arc<Indexes>,
+    events: Arc<Events>,
+    /// Version of the latest write. Only increased while holding the lock of
+    /// every shard the write changes.
+    version: AtomicU64,
+    snapshots: Mutex<Snapshots>,
+}
+
+/// How a [`ShardedStore`] is set up.
+#[derive(Debug, Clone, Copy)]
+pub struct StoreConfig {
+    pub shards: usize,
+    /// Old values kept of keys in namespaces without a retention of their
+    /// own.
+    pub retention: Retention,
+    /// Changes to keys kept for watches that resume, see [`Events::new`].
+    pub watch_history: usize,
+}
+
+impl StoreConfig {
+    /// A store with `shards` shards that keeps no more old values than its
+    /// snapshots need.
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards,
+            retention: Retention::default(),
+            watch_history: DEFAULT_WATCH_HISTORY,
+        }
+    }
+}
+
+/// A value from the history of a key, see [`ShardedStore::history`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OldValue {
+    pub entry: HistoryEntry,
+    /// `None` if the key was deleted in this version.
+    pub value: Option<String>,
+}
+
+/// Store versions that are still being read.
+#[derive(Debug, Default)]
+struct Snapshots {
+    /// Readers of each version, e.g. an `ls` that is running.
+    readers: BTreeMap<u64, usize>,
+    /// Versions clients asked to keep, until the given time.
+    pinned: BTreeMap<u64, Instant>,
+    /// Versions older than this may already have been pruned.
+    horizon: u64,
+}
+
+impl Snapshots {
+    /// The oldest version that may still be read, given the version of the
+    /// latest write.
+    ///
+    /// Versions older than that can be pruned from then on.
+    fn oldest(&mut self, latest: u64) -> u64 {
+        let now = Instant::now();
+        self.pinned.retain(|_, expires| *expires > now);
+
+        let oldest = [self.readers.keys().next(), self.pinned.keys().next()]
+            .into_iter()
+            .flatten()
+            .fold(latest, |oldest, version| oldest.min(*version));
+        self.horizon = self.horizon.max(oldest);
+
+        oldest
+    }
+}
+
+/// A store version being read. Its values are kept until it is dropped.
+pub struct Snapshot<'a> {
+    store: &'a ShardedStore,
+    version: u64,
+}
+
+impl Snapshot<'_> {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        self.store.release(self.version);
+    }
+}
+
+/// Like [`Snapshot`], for readers that outlive the request that started
+/// them, e.g. an export that streams its response.
+pub struct SharedSnapshot {
+    store: Arc<ShardedStore>,
+    version: u64,
+}
+
+impl SharedSnapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn store(&self) -> &ShardedStore {
+        &self.store
+    }
+
+    /// The plaintext entries of one shard in this version, sorted by key.
+    /// Only that shard is locked, and only while its entries are copied.
+    ///
+    /// If `namespaces` isn't empty, only keys in those namespaces are
+    /// returned.
+    pub fn shard_entries(
+        &self,
+        shard: usize,
+        namespaces: &[String],
+    ) -> Result<Vec<(String, String)>, &'static str> {
+        let encrypted = self.store.shards[shard]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entries_at(self.version);
+
+        self.store.decrypt_entries(encrypted, namespaces)
+    }
+}
+
+impl Drop for SharedSnapshot {
+    fn drop(&mut self) {
+        self.store.release(self.version);
+    }
+}
+
+/// Every shard of a [`ShardedStore`], locked for writing. All changes made
+/// through it are part of the same store version.
+pub struct WriteShards<'a> {
+    store: &'a ShardedStore,
+    shards: Vec<RwLockWriteGuard<'a, KeyValueStore>>,
+    commit: Commit,
+}
+
+impl ShardedStore {
+    /// Create an empty store with `shards` shards, all encrypting their data
+    /// with the given key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn new(encryption_key: Arc<SecretKey>, shards: usize) -> Self {
+        Self::with_config(encryption_key, StoreConfig::new(shards))
+    }
+
+    /// Create an empty store set up by `config`, encrypting its data with the
+    /// given key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.shards` or `config.watch_history` is zero.
+    pub fn with_config(
+        encryption_key: Arc<SecretKey>,
+        config: StoreConfig,
+    ) -> Self {
+        assert!(config.shards > 0, "A store needs at least one shard.");
+        let retention = Arc::new(RetentionPolicy::new(config.retention));
+        let indexes = Arc::new(Indexes::default());
+        let events = Arc::new(Events::new(config.watch_history));
+
+        Self {
+            shards: (0..config.shards)
+                .map(|_| {
+                    RwLock::new(
+                        KeyValueStore::with_retention(
+                            encryption_key.clone(),
+                            Arc::clone(&retention),
+                        )
+                        .with_indexes(Arc::clone(&indexes))
+                        .with_events(Arc::clone(&events)),
+                    )
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            encryption_key,
+            retention,
+            indexes,
+            events,
+            version: AtomicU64::new(0),
+            snapshots: Mutex::new(Snapshots::default()),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Version of the latest write.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Index of the shard `key` belongs to.
+    pub fn shard_of(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    /// Lock the shard of `key` for reading.
+    pub fn read(&self, key: &str) -> RwLockReadGuard<'_, KeyValueStore> {
+        self.shards[self.shard_of(key)]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the shard of `key` for writing.
+    pub fn write(&self, key: &str) -> RwLockWriteGuard<'_, KeyValueStore> {
+        self.shards[self.shard_of(key)]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock every shard for writing, e.g. to replace many keys at once
+    /// without anyone seeing the store half way through.
+    ///
+    /// Shards are always locked in the same order, and operations on a single
+    /// key never hold more than one lock, so this can't deadlock.
+    pub fn write_all(&self) -> WriteShards<'_> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.write().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+
+        WriteShards {
+            store: self,
+            shards,
+            commit: self.commit(),
+        }
+    }
+
+    /// Start reading the latest version.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot {
+            store: self,
+            version: self.add_reader(),
+        }
+    }
+
+    /// Like [`ShardedStore::snapshot`], for a reader that needs to hold on to
+    /// the store, see [`SharedSnapshot`].
+    pub fn shared_snapshot(self: &Arc<Self>) -> SharedSnapshot {
+        SharedSnapshot {
+            store: Arc::clone(self),
+            version: self.add_reader(),
+        }
+    }
+
+    /// Start reading an older version, if it hasn't been pruned yet.
+    ///
+    /// On failure, the error is the response to send.
+    pub fn snapshot_at(
+        &self,
+        version: u64,
+    ) -> Result<Snapshot<'_>, StoreResponse> {
+        let mut snapshots = self.snapshots();
+        if version > self.version() {
+            return Err(StoreResponse::new((
+                "HTTP/1.1 400 Bad Request".to_string(),
+                format!("Version {} doesn't exist yet.", version),
+            )));
+        }
+        if version < snapshots.horizon {
+            return Err(StoreResponse::new((
+                "HTTP/1.1 410 Gone".to_string(),
+                format!(
+                    "Version {} is no longer available. Pin a snapshot with \
+                    PUT /admin/snapshots to keep a version around.",
+                    version
+                ),
+            )));
+        }
+        *snapshots.readers.entry(version).or_default() += 1;
+
+        Ok(Snapshot {
+            store: self,
+            version,
+        })
+    }
+
+    /// Keep the latest version readable for `ttl`, e.g. so a client can read
+    /// several keys as of the same version.
+    ///
+    /// Returns the pinned version.
+    pub fn pin(&self, ttl: Duration) -> u64 {
+        let mut snapshots = self.snapshots();
+        let version = self.version();
+        let expires = Instant::now() + ttl;
+        let pinned = snapshots.pinned.entry(version).or_insert(expires);
+        *pinned = (*pinned).max(expires);
+
+        version
+    }
+
+    /// Stop keeping a pinned version. Returns whether it was pinned.
+    pub fn unpin(&self, version: u64) -> bool {
+        self.snapshots().pinned.remove(&version).is_some()
+    }
+
+    /// Pinned versions with the time until they are released.
+    pub fn pinned(&self) -> Vec<(u64, Duration)> {
+        let mut snapshots = self.snapshots();
+        snapshots.oldest(self.version());
+
+        let now = Instant::now();
+        snapshots
+            .pinned
+            .iter()
+            .map(|(version, expires)| (*version, *expires - now))
+            .collect()
+    }
+
+    /// How many old values are kept in each namespace. Changes apply to every
+    /// shard.
+    pub fn retention(&self) -> &RetentionPolicy {
+        &self.retention
+    }
+
+    /// Changes to keys, for watches.
+    pub fn events(&self) -> &Arc<Events> {
+        &self.events
+    }
+
+    /// Watch the keys `filter` matches, see [`Events::watch`].
+    pub fn watch(
+        &self,
+        filter: Filter,
+        since: Option<u64>,
+    ) -> Result<Watch, WatchError> {
+        self.events.watch(filter, since)
+    }
+
+    /// The secondary indexes over the JSON documents of each namespace.
+    pub fn indexes(&self) -> &Indexes {
+        &self.indexes
+    }
+
+    /// Declare an index on a field of the JSON documents in the namespace
+    /// and build it. Returns the number of indexed keys.
+    pub fn create_index(
+        &self,
+        namespace: &str,
+        field: &str,
+    ) -> Result<usize, IndexError> {
+        self.indexes.create(namespace, field)?;
+        self.build_index(namespace, field)
+    }
+
+    /// Build an index again from the documents in the store, e.g. after a
+    /// restore. Returns the number of indexed keys.
+    ///
+    /// The store is scanned one shard at a time, so writes only wait for the
+    /// shard being scanned. Queries are answered by the old index until the
+    /// new one is done.
+    pub fn build_index(
+        &self,
+        namespace: &str,
+        field: &str,
+    ) -> Result<usize, IndexError> {
+        self.indexes.start_build(namespace, field)?;
+        for shard in &self.shards {
+            let shard = shard.read().unwrap_or_else(PoisonError::into_inner);
+            let documents = shard.json_documents(namespace);
+            self.indexes.load(namespace, field, &documents);
+        }
+
+        self.indexes.finish_build(namespace, field)
+    }
+
+    /// Every value of the key that is still kept, newest first. Empty if the
+    /// key isn't in the store.
+    ///
+    /// Besides the latest value, these are the values a snapshot may still
+    /// read and those the retention of the key's namespace keeps.
+    pub fn history(&self, key: &str) -> Vec<HistoryEntry> {
+        self.read(key)
+            .history(key)
+            .into_iter()
+            .map(|(entry, _)| entry)
+            .collect()
+    }
+
+    /// The value the key was given in store version `version`, if it is
+    /// still kept.
+    pub fn old_value(
+        &self,
+        key: &str,
+        version: u64,
+    ) -> Result<Option<OldValue>, &'static str> {
+        let found = self
+            .read(key)
+            .history(key)
+            .into_iter()
+            .find(|(entry, _)| entry.version == version);
+        let (entry, value) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let value = match value {
+            Some(value) => Some(decrypt(&value, &self.encryption_key)?),
+            None => None,
+        };
+        Ok(Some(OldValue { entry, value }))
+    }
+
+    /// Make the value the key was given in store version `version` its
+    /// latest value again, see [`KeyValueStore::restore`].
+    ///
+    /// Returns the version of the restored value.
+    pub fn restore_version(&self, key: &str, version: u64) -> Option<u64> {
+        let mut shard = self.write(key);
+        // Only create a new version if there is something to restore.
+        let restorable = shard
+            .history(key)
+            .iter()
+            .any(|(entry, value)| entry.version == version && value.is_some());
+        if !restorable {
+            return None;
+        }
+
+        let commit = self.commit();
+        shard
+            .restore(key, version, commit)
+            .then_some(commit.version)
+    }
+
+    /// Deleted keys that can still be restored with
+    /// [`ShardedStore::undelete`], sorted by key.
+    ///
+    /// If `namespaces` isn't empty, only keys in those namespaces are
+    /// returned.
+    pub fn trash(
+        &self,
+        namespaces: &[String],
+    ) -> Result<Vec<TrashEntry>, &'static str> {
+        let trashed: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                shard.read().unwrap_or_else(PoisonError::into_inner).trash()
+            })
+            .collect();
+
+        let now = SystemTime::now();
+        let mut trash = Vec::new();
+        for (key, version, deleted) in trashed {
+            let key = decrypt(&key, &self.encryption_key)?;
+            if !in_namespaces(&key, namespaces) {
+                continue;
+            }
+            let age = now.duration_since(deleted).unwrap_or_default();
+            let expires_in =
+                self.retention.for_key(&key).trash.saturating_sub(age);
+            trash.push(TrashEntry {
+                key,
+                version,
+                deleted,
+                expires_in,
+            });
+        }
+        trash.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(trash)
+    }
+
+    /// Restore a deleted key from the trash, with the value it had before it
+    /// was deleted.
+    ///
+    /// Returns the version of the restored value, or `None` if the key isn't
+    /// in the trash.
+    pub fn undelete(&self, key: &str) -> Option<u64> {
+        let mut shard = self.write(key);
+        let history = shard.history(key);
+        let version = match history.as_slice() {
+            [(deleted, _), (last, Some(_)), ..] if deleted.deleted => {
+                last.version
+            }
+            _ => return None,
+        };
+
+        let commit = self.commit();
+        shard
+            .restore(key, version, commit)
+            .then_some(commit.version)
+    }
+
+    /// Remove a deleted key from the trash for good, see
+    /// [`KeyValueStore::purge`]. Returns false if it isn't in the trash.
+    pub fn purge(&self, key: &str) -> bool {
+        let mut shard = self.write(key);
+        shard.purge(key, self.oldest_snapshot())
+    }
+
+    /// Purge every key in the trash, or only the keys in `namespaces` if it
+    /// isn't empty. Returns the number of purged keys.
+    pub fn empty_trash(&self, namespaces: &[String]) -> usize {
+        let oldest = self.oldest_snapshot();
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .empty_trash(namespaces, oldest)
+            })
+            .sum()
+    }
+
+    /// Drop the values no snapshot can see anymore and the retention doesn't
+    /// keep, e.g. of deleted keys that have been in the trash for long
+    /// enough.
+    ///
+    /// Writes already drop old values of the keys they change, this takes
+    /// care of the keys that aren't written again. Returns the number of
+    /// dropped values.
+    pub fn collect_garbage(&self) -> usize {
+        let oldest = self.oldest_snapshot();
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .prune(oldest)
+            })
+            .sum()
+    }
+
+    /// Handle a GET request, see [`KeyValueStore::handle_get_request`].
+    ///
+    /// `ls` lists the keys of every shard. With `?as_of=<version>`, keys and
+    /// values are read as they were in that version.
+    pub fn handle_get_request(&self, buf: &[u8]) -> StoreResponse {
+        let path = parse_key_from_request(buf).unwrap_or_default();
+        let (key, query) = split_query(&path);
+        let as_of = match query.get("as_of").map(|as_of| as_of.parse()) {
+            Some(Ok(as_of)) => Some(as_of),
+            Some(Err(_)) => {
+                return StoreResponse::new((
+                    "HTTP/1.1 400 Bad Request".to_string(),
+                    "as_of must be a version number.".to_string(),
+                ))
+            }
+            None => None,
+        };
+
+        if as_of.is_none() && path != "ls" {
+            // Without a key, any shard answers with the same error.
+            return self.read(&path).handle_get_request(buf, None);
+        }
+
+        let snapshot = match as_of {
+            Some(as_of) => match self.snapshot_at(as_of) {
+                Ok(snapshot) => snapshot,
+                Err(response) => return response,
+            },
+            None => self.snapshot(),
+        };
+        if key == "ls" {
+            return StoreResponse::new((
+                "HTTP/1.1 200 OK".to_string(),
+                self.list_keys(&snapshot),
+            ))
+            .with_version(snapshot.version);
+        }
+
+        self.read(key).handle_get_request(buf, as_of)
+    }
+
+    /// Handle a PUT request, see [`KeyValueStore::handle_put_request`].
+    pub fn handle_put_request(&self, buf: &[u8]) -> StoreResponse {
+        let key = parse_key_from_request(buf).unwrap_or_default();
+        let mut shard = self.write(&key);
+        shard.handle_put_request(buf, self.commit())
+    }
+
+    /// Handle a DELETE request, see [`KeyValueStore::handle_delete_request`].
+    pub fn handle_delete_request(&self, buf: &[u8]) -> StoreResponse {
+        let key = parse_key_from_request(buf).unwrap_or_default();
+        let mut shard = self.write(&key);
+        shard.handle_delete_request(buf, self.commit())
+    }
+
+    /// Handle a PATCH request, see [`KeyValueStore::handle_patch_request`].
+    ///
+    /// The value is read and written under the lock of its shard, so no
+    /// other write can get in between.
+    pub fn handle_patch_request(&self, buf: &[u8]) -> StoreResponse {
+        let path = parse_key_from_request(buf).unwrap_or_default();
+        let mut shard = self.write(split_query(&path).0);
+        shard.handle_patch_request(buf, self.commit())
+    }
+
+    /// Handle a POST request with a command for a typed value, see
+    /// [`Command`]. Answers in JSON.
+    ///
+    /// Commands that change the value read and write it under the lock of
+    /// its shard. `sinter` reads every set as of the same snapshot. The
+    /// request must already have been authenticated, see
+    /// [`crate::auth::authenticate`].
+    pub fn handle_command_request(&self, buf: &[u8]) -> StoreResponse {
+        let path = parse_key_from_request(buf).unwrap_or_default();
+        let (key, query) = split_query(&path);
+        if key.is_empty() {
+            return StoreResponse::new((
+                "HTTP/1.1 400 Bad Request".to_string(),
+                json_error("Key for key-value store not provided!"),
+            ));
+        }
+        let body = parse_body_from_request(buf).unwrap_or_default();
+        let command = match Command::from_query(&query, &body) {
+            Ok(command) => command,
+            Err(e) => return StoreResponse::new(e.response()),
+        };
+
+        let result = match &command {
+            Command::Intersect(keys) => self.intersect(key, keys),
+            _ if command.is_write() => self.run_write_command(key, &command),
+            _ => self
+                .read(key)
+                .typed_value(key, None)
+                .map_err(internal_error)
+                .and_then(|value| {
+                    command.apply(value).map_err(|e| e.response())
+                })
+                .map(|outcome| (outcome.reply, None)),
+        };
+
+        match result {
+            Ok((reply, version)) => {
+                let response = StoreResponse::new((
+                    "HTTP/1.1 200 OK".to_string(),
+                    reply.to_string(),
+                ));
+                match version {
+                    Some(version) => response.with_version(version),
+                    None => response,
+                }
+            }
+            Err(response) => StoreResponse::new(response),
+        }
+    }
+
+    /// Run a command that may change the value of the key. A new version is
+    /// only created if it does.
+    ///
+    /// Returns the reply, with the version of the new value if there is
+    /// one.
+    fn run_write_command(
+        &self,
+        key: &str,
+        command: &Command,
+    ) -> Result<(Value, Option<u64>), (String, String)> {
+        let mut shard = self.write(key);
+        let value = shard.typed_value(key, None).map_err(internal_error)?;
+        let outcome = command.apply(value).map_err(|e| e.response())?;
+        let mut reply = outcome.reply;
+        let value = match outcome.write {
+            Some(value) => value,
+            None => return Ok((reply, None)),
+        };
+
+        let commit = self.commit();
+        shard
+            .set_typed_value(key, value.as_ref(), commit)
+            .map_err(internal_error)?;
+        reply["version"] = commit.version.into();
+        Ok((reply, Some(commit.version)))
+    }
+
+    /// The members of the set at `key` that are in every set of `others`,
+    /// all read as of the same snapshot.
+    fn intersect(
+        &self,
+        key: &str,
+        others: &[String],
+    ) -> Result<(Value, Option<u64>), (String, String)> {
+        let snapshot = self.snapshot();
+        let mut sets = Vec::new();
+        for key in iter::once(key).chain(others.iter().map(String::as_str)) {
+            let value = self
+                .read(key)
+                .typed_value(key, Some(snapshot.version))
+                .map_err(internal_error)?;
+            sets.push(value);
+        }
+
+        let outcome = typed::intersect(sets).map_err(|e| e.response())?;
+        Ok((outcome.reply, Some(snapshot.version)))
+    }
+
+    /// See [`KeyValueStore::insert`].
+    pub fn insert(&self, key: &str, value: &str) -> Result<bool, &'static str> {
+        let mut shard = self.write(key);
+        shard.insert(key, value, self.commit())
+    }
+
+    /// Insert a key-value pair unless the key already exists, checked under
+    /// the same lock as the insert.
+    ///
+    /// Returns whether the pair was inserted.
+    pub fn insert_new(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<bool, &'static str> {
+        let mut shard = self.write(key);
+        if shard.contains_key(key) {
+            return Ok(false);
+        }
+        shard.insert(key, value, self.commit()).map(|_| true)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.read(key).contains_key(key)
+    }
+
+    /// Encrypt an entry with the store's key, e.g. to insert many of them
+    /// with [`WriteShards::insert_encrypted`] without failing half way.
+    pub fn encrypt_entry(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<EncryptedEntry, &'static str> {
+        EncryptedEntry::new(key, value, &self.encryption_key)
+    }
+
+    /// All key-value pairs in plaintext, sorted by key, as they are now.
+    ///
+    /// If `namespaces` isn't empty, only keys in those namespaces are
+    /// returned, see [`crate::connection::namespace_of`].
+    pub fn entries(
+        &self,
+        namespaces: &[String],
+    ) -> Result<Vec<(String, String)>, &'static str> {
+        self.entries_at(&self.snapshot(), namespaces)
+    }
+
+    /// Like [`ShardedStore::entries`], as the store was in the snapshot.
+    pub fn entries_at(
+        &self,
+        snapshot: &Snapshot,
+        namespaces: &[String],
+    ) -> Result<Vec<(String, String)>, &'static str> {
+        self.decrypt_entries(self.encrypted_entries(snapshot), namespaces)
+    }
+
+    /// Decrypt the entries whose keys are in `namespaces`, sorted by key.
+    fn decrypt_entries(
+        &self,
+        encrypted: Vec<(DataObject, DataObject)>,
+        namespaces: &[String],
+    ) -> Result<Vec<(String, String)>, &'static str> {
+        let mut entries = Vec::new();
+        for (key, value) in encrypted {
+            let key = decrypt(&key, &self.encryption_key)?;
+            if !in_namespaces(&key, namespaces) {
+                continue;
+            }
+            entries.push((key, decrypt(&value, &self.encryption_key)?));
+        }
+        entries.sort();
+
+        Ok(entries)
+    }
+
+    /// Every key in the snapshot, one per line.
+    fn list_keys(&self, snapshot: &Snapshot) -> String {
+        let mut keys = Vec::new();
+        for (key, _) in self.encrypted_entries(snapshot) {
+            // Every entry is encrypted with the store's key, so a failure
+            // here means the entry is corrupt. Never hand the error string
+            // out as if it were a key.
+            match decrypt(&key, &self.encryption_key) {
+                Ok(key) => keys.push(key),
+                Err(e) => eprintln!("Skipping entry in ls: {}", e),
+            }
+        }
+        keys.sort();
+
+        keys.join("\n")
+    }
+
+    /// The encrypted entries of the snapshot. Every shard is only locked
+    /// while its entries are copied, decrypting them is up to the caller.
+    fn encrypted_entries(
+        &self,
+        snapshot: &Snapshot,
+    ) -> Vec<(DataObject, DataObject)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .entries_at(snapshot.version)
+            })
+            .collect()
+    }
+
+    /// Create a new version for a write. Must be called while holding the
+    /// locks of the shards the write changes, so that a snapshot of the new
+    /// version can't read those shards before the write is done.
+    fn commit(&self) -> Commit {
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+
+        Commit {
+            version,
+            oldest_snapshot: self.oldest_snapshot(),
+        }
+    }
+
+    /// Count a new reader of the latest version, and return the version.
+    fn add_reader(&self) -> u64 {
+        let mut snapshots = self.snapshots();
+        let version = self.version();
+        *snapshots.readers.entry(version).or_default() += 1;
+
+        version
+    }
+
+    /// Stop counting a reader of `version`, see [`Snapshot`].
+    fn release(&self, version: u64) {
+        let mut snapshots = self.snapshots();
+        if let Some(readers) = snapshots.readers.get_mut(&version) {
+            *readers -= 1;
+            if *readers == 0 {
+                snapshots.readers.remove(&version);
+            }
+        }
+    }
+
+    fn oldest_snapshot(&self) -> u64 {
+        let mut snapshots = self.snapshots();
+        snapshots.oldest(self.version())
+    }
+
+    fn snapshots(&self) -> MutexGuard<'_, Snapshots> {
+        self.snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The response to a request the store failed to handle, e.g. because a
+/// value couldn't be decrypted.
+fn internal_error(error: &str) -> (String, String) {
+    (
+        "HTTP/1.1 500 Internal Server Error".to_string(),
+        json_error(error),
+    )
+}
+
+impl WriteShards<'_> {
+    /// See [`KeyValueStore::insert`].
+    pub fn insert(
+        &mut self,
+        key: &str,
+        value: &str,
+    ) -> Result<bool, &'static str> {
+        let shard = self.store.shard_of(key);
+        self.shards[shard].insert(key, value, self.commit)
+    }
+
+    /// See [`KeyValueStore::insert_encrypted`].
+    pub fn insert_encrypted(&mut self, entry: EncryptedEntry) -> bool {
+        let shard = self.store.shard_of(entry.key());
+        self.shards[shard].insert_encrypted(entry, self.commit)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.shards[self.store.shard_of(key)].contains_key(key)
+    }
+
+    /// See [`KeyValueStore::clear`].
+    pub fn clear(&mut self, namespaces: &[String]) -> usize {
+        let commit = self.commit;
+        self.shards
+            .iter_mut()
+            .map(|shard| shard.clear(namespaces, commit))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::generate_key;
+    use crate::watch::EventKind;
+    use std::thread;
+
+    fn new_store(shards: usize) -> ShardedStore {
+        ShardedStore::new(Arc::new(generate_key()), shards)
+    }
+
+    fn request(text: &str) -> Vec<u8> {
+        text.as_bytes().to_vec()
+    }
+
+    fn get(store: &ShardedStore, path: &str) -> StoreResponse {
+        store.handle_get_request(&request(&format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            path
+        )))
+    }
+
+    #[test]
+    fn test_keys_are_spread_over_shards() {
+        let store = new_store(8);
+        for i in 0..64 {
+            let key = format!("key{}", i);
+            assert_eq!(store.shard_of(&key), store.shard_of(&key));
+            store.insert(&key, "value").unwrap();
+        }
+
+        let used = store
+            .shards
+            .iter()
+            .filter(|shard| !shard.read().unwrap().entries_at(64).is_empty())
+            .count();
+        assert!(used > 1);
+        assert_eq!(store.entries(&[]).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_requests_across_shards() {
+        let store = new_store(4);
+        for key in ["a", "b", "c", "d", "e"] {
+            let put = request(&format!(
+                "PUT /{} HTTP/1.1\r\nContent-Length: 1\r\n\r\nx",
+                key
+            ));
+            let response = store.handle_put_request(&put);
+            assert_eq!(response.status_line, "HTTP/1.1 200 OK");
+        }
+
+        let response = get(&store, "ls");
+        assert_eq!(response.status_line, "HTTP/1.1 200 OK");
+        assert_eq!(response.body, "a\nb\nc\nd\ne");
+        assert_eq!(response.version, Some(5));
+
+        let delete = request("DELETE /c HTTP/1.1\r\n\r\n");
+        let response = store.handle_delete_request(&delete);
+        assert_eq!(response.status_line, "HTTP/1.1 200 OK");
+        assert_eq!(response.version, Some(6));
+        let response = get(&store, "c");
+        assert_eq!(response.status_line, "HTTP/1.1 404 NOT FOUND");
+        let response = get(&store, "");
+        assert_eq!(response.status_line, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn test_write_all_replaces_consistently() {
+        let store = Arc::new(new_store(4));
+        for i in 0..8 {
+            store.insert(&format!("old:{}", i), "x").unwrap();
+        }
+
+        // Readers must see either all old keys or all new ones.
+        let reader = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let entries = store.entries(&[]).unwrap();
+                    assert_eq!(entries.len(), 8);
+                    let old =
+                        entries.iter().filter(|(k, _)| k.starts_with("old"));
+                    assert!(old.count() % 8 == 0);
+                }
+            })
+        };
+
+        {
+            let mut shards = store.write_all();
+            assert_eq!(shards.clear(&[]), 8);
+            for i in 0..8 {
+                shards.insert(&format!("new:{}", i), "y").unwrap();
+            }
+            assert!(shards.contains_key("new:3"));
+        }
+        reader.join().unwrap();
+
+        assert!(store.contains_key("new:0"));
+        assert!(!store.contains_key("old:0"));
+    }
+
+    #[test]
+    fn test_insert_new_keeps_existing_keys() {
+        let store = new_store(4);
+        assert_eq!(store.insert_new("a", "x"), Ok(true));
+        assert_eq!(store.insert_new("a", "y"), Ok(false));
+        assert_eq!(store.version(), 1);
+        let response = get(&store, "a");
+        assert_eq!(response.body, "x");
+    }
+
+    #[test]
+    fn test_snapshots_see_old_values_until_dropped() {
+        let store = new_store(4);
+        store.insert("a", "1").unwrap();
+        store.insert("b", "1").unwrap();
+
+        let snapshot = store.snapshot();
+        store.insert("a", "2").unwrap();
+        store.write_all().clear(&["".to_string()]);
+        store.insert("c", "2").unwrap();
+
+        let old = store.entries_at(&snapshot, &[]).unwrap();
+        assert_eq!(
+            old,
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "1".to_string()),
+            ]
+        );
+        assert_eq!(get(&store, "a?as_of=2").body, "1");
+        assert_eq!(get(&store, "ls?as_of=2").body, "a\nb");
+        assert_eq!(get(&store, "ls").body, "c");
+
+        // Nothing needs the old values once the snapshot is gone: a and b
+        // are gone for good, along with their deletions.
+        drop(snapshot);
+        assert_eq!(store.collect_garbage(), 5);
+        let response = get(&store, "a?as_of=2");
+        assert_eq!(response.status_line, "HTTP/1.1 410 Gone");
+        let response = get(&store, "a?as_of=99");
+        assert_eq!(response.status_line, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn test_pinned_versions() {
+        let store = new_store(2);
+        store.insert("a", "1").unwrap();
+        let version = store.pin(Duration::from_secs(60));
+        store.insert("a", "2").unwrap();
+        store.collect_garbage();
+
+        let response = get(&store, &format!("a?as_of={}", version));
+        assert_eq!(response.body, "1");
+        assert_eq!(response.version, Some(1));
+        assert_eq!(store.pinned()[0].0, version);
+
+        assert!(store.unpin(version));
+        assert!(!store.unpin(version));
+        store.insert("b", "1").unwrap();
+        let response = get(&store, &format!("a?as_of={}", version));
+        assert_eq!(response.status_line, "HTTP/1.1 410 Gone");
+
+        let expired = store.pin(Duration::ZERO);
+        store.insert("a", "3").unwrap();
+        let response = get(&store, &format!("a?as_of={}", expired));
+        assert_eq!(response.status_line, "HTTP/1.1 410 Gone");
+    }
+
+    #[test]
+    fn test_history_is_kept_by_retention() {
+        let config = StoreConfig {
+            retention: Retention {
+                versions: 2,
+                ..Retention::default()
+            },
+            ..StoreConfig::new(2)
+        };
+        let store = ShardedStore::with_config(Arc::new(generate_key()), config);
+        for value in ["1", "2", "3", "4"] {
+            store.insert("a", value).unwrap();
+        }
+
+        let versions: Vec<u64> = store
+            .history("a")
+            .iter()
+            .map(|entry| entry.version)
+            .collect();
+        assert_eq!(versions, vec![4, 3, 2]);
+        let old = store.old_value("a", 2).unwrap().unwrap();
+        assert_eq!(old.value.as_deref(), Some("2"));
+        assert!(store.old_value("a", 1).unwrap().is_none());
+        assert!(store.history("missing").is_empty());
+
+        assert_eq!(store.restore_version("a", 2), Some(5));
+        assert_eq!(get(&store, "a").body, "2");
+        assert_eq!(store.restore_version("a", 1), None);
+
+        // A deleted key keeps its old values, and can be brought back.
+        let delete = request("DELETE /a HTTP/1.1\r\n\r\n");
+        store.handle_delete_request(&delete);
+        assert!(!store.contains_key("a"));
+        assert!(store.history("a")[0].deleted);
+        assert_eq!(store.restore_version("a", 5), Some(7));
+        assert_eq!(get(&store, "a").body, "2");
+
+        // Namespaces can keep values by age instead.
+        store.retention().set(
+            "logs",
+            Retention {
+                max_age: Duration::from_secs(60),
+                ..Retention::default()
+            },
+        );
+        for value in ["1", "2", "3"] {
+            store.insert("logs:x", value).unwrap();
+        }
+        store.collect_garbage();
+        assert_eq!(store.history("logs:x").len(), 3);
+
+        // Lowering the retention takes effect with the next collection.
+        store.retention().set("", Retention::default());
+        assert_eq!(store.collect_garbage(), 2);
+        assert_eq!(store.history("a").len(), 1);
+    }
+
+    #[test]
+    fn test_deleted_keys_go_to_trash() {
+        let config = StoreConfig {
+            retention: Retention {
+                trash: Duration::from_secs(60),
+                ..Retention::default()
+            },
+            ..StoreConfig::new(2)
+        };
+        let store = ShardedStore::with_config(Arc::new(generate_key()), config);
+        let delete = |key: &str| {
+            let request = request(&format!("DELETE /{} HTTP/1.1\r\n\r\n", key));
+            store.handle_delete_request(&request)
+        };
+        store.insert("a", "1").unwrap();
+        store.insert("users:b", "2").unwrap();
+        delete("a");
+        delete("users:b");
+        store.collect_garbage();
+
+        let trash = store.trash(&[]).unwrap();
+        let keys: Vec<&str> =
+            trash.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "users:b"]);
+        assert_eq!(trash[0].version, 3);
+        assert!(trash[0].expires_in <= Duration::from_secs(60));
+        assert_eq!(store.trash(&["users".to_string()]).unwrap().len(), 1);
+
+        assert_eq!(store.undelete("a"), Some(5));
+        assert_eq!(get(&store, "a").body, "1");
+        assert_eq!(store.undelete("a"), None);
+
+        assert!(store.purge("users:b"));
+        assert!(!store.purge("users:b"));
+        assert!(store.history("users:b").is_empty());
+
+        // Once the trash retention runs out, deleted keys are reaped.
+        store.retention().set(
+            "",
+            Retention {
+                trash: Duration::from_millis(10),
+                ..Retention::default()
+            },
+        );
+        delete("a");
+        assert_eq!(store.trash(&[]).unwrap().len(), 1);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.collect_garbage(), 2);
+        assert!(store.trash(&[]).unwrap().is_empty());
+        assert_eq!(store.undelete("a"), None);
+    }
+
+    #[test]
+    fn test_concurrent_appends_are_not_lost() {
+        let store = Arc::new(new_store(4));
+        store.insert("log", "").unwrap();
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    let patch = request(
+                        "PATCH /log?op=append HTTP/1.1\r\n\
+                        Content-Length: 1\r\n\r\nx",
+                    );
+                    for _ in 0..25 {
+                        let response = store.handle_patch_request(&patch);
+                        assert_eq!(response.status_line, "HTTP/1.1 200 OK");
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(get(&store, "log").body, "x".repeat(100));
+
+        let response = store.handle_patch_request(&request(
+            "PATCH /missing?op=append HTTP/1.1\r\n\r\n",
+        ));
+        assert_eq!(response.status_line, "HTTP/1.1 404 NOT FOUND");
+        let response = store.handle_patch_request(&request(
+            "PATCH /log?op=range&offset=101 HTTP/1.1\r\n\r\n",
+        ));
+        assert_eq!(response.status_line, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn test_watch_events() {
+        let config = StoreConfig {
+            retention: Retention {
+                trash: Duration::from_secs(60),
+                ..Retention::default()
+            },
+            ..StoreConfig::new(2)
+        };
+        let store = ShardedStore::with_config(Arc::new(generate_key()), config);
+        let mut watch =
+            store.watch(Filter::Key("a".to_string()), None).unwrap();
+
+        store.insert("a", "1").unwrap();
+        store.insert("b", "1").unwrap();
+        store.handle_delete_request(&request("DELETE /a HTTP/1.1\r\n\r\n"));
+        assert!(store.purge("a"));
+        let events: Vec<_> = watch
+            .next_events()
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.kind, event.version))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (EventKind::Put, Some(1)),
+                (EventKind::Delete, Some(3)),
+                (EventKind::Expire, None)
+            ]
+        );
+        assert_eq!(store.events().revision(), 4);
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let config = StoreConfig {
+            retention: Retention {
+                versions: 5,
+                ..Retention::default()
+            },
+            ..StoreConfig::new(4)
+        };
+        let store = ShardedStore::with_config(Arc::new(generate_key()), config);
+        let command = |path: &str, body: &str| {
+            store.handle_command_request(&request(&format!(
+                "POST /{} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                path,
+                body.len(),
+                body
+            )))
+        };
+
+        let response = command("tags?op=sadd", r#"["a", "b"]"#);
+        assert_eq!(response.status_line, "HTTP/1.1 200 OK");
+        assert_eq!(response.version, Some(1));
+        // Adding nothing new doesn't create a version.
+        let response = command("tags?op=sadd", r#"["a"]"#);
+        assert_eq!(response.version, None);
+        command("other?op=sadd", r#"["b", "c"]"#);
+        let response = command("tags?op=sinter&keys=other", "");
+        assert_eq!(response.body, r#"{"members":["b"]}"#);
+
+        // Typed values are stored as JSON, GET reads them as such.
+        assert_eq!(get(&store, "tags").body, r#"["a","b"]"#);
+        let response = command("tags?op=lpush", r#"["x"]"#);
+        assert_eq!(response.status_line, "HTTP/1.1 409 Conflict");
+
+        // Old values keep their type.
+        store.insert("tags", "plain").unwrap();
+        let response = command("tags?op=type", "");
+        assert_eq!(response.body, r#"{"type":"string"}"#);
+        assert_eq!(store.restore_version("tags", 1), Some(4));
+        let response = command("tags?op=smembers", "");
+        assert_eq!(response.body, r#"{"members":["a","b"]}"#);
+
+        let response = command("tags?op=srem", r#"["a", "b"]"#);
+        assert_eq!(response.status_line, "HTTP/1.1 200 OK");
+        assert!(!store.contains_key("tags"));
+        let response = command("?op=type", "");
+        assert_eq!(response.status_line, "HTTP/1.1 400 Bad Request");
+    }
+}
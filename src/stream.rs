@@ -0,0 +1,127 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Longest a stream goes without sending anything. After that it sends a
+/// comment, so that clients that went away are noticed and proxies don't
+/// close the connection.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Sent when there is nothing else to send, see [`KEEPALIVE_INTERVAL`].
+pub const KEEPALIVE: &str = ": keepalive\n\n";
+
+/// A response that keeps sending server-sent events until the client goes
+/// away or the stream ends, e.g. a watch of some keys.
+///
+/// Streams are served by whatever serves the connection, a worker thread or
+/// an async task, which sends the events as they come in.
+pub trait EventStream: Send {
+    /// The events that are ready, as frames to send, without waiting for
+    /// more. Empty if there are none yet, `None` once the stream has ended.
+    fn poll(&mut self) -> Option<String>;
+
+    /// Woken up whenever there may be new events.
+    fn wakeup(&self) -> Arc<Wakeup>;
+}
+
+/// A response body that is sent a chunk at a time as it is produced, e.g. an
+/// export of the whole store, instead of being built in memory first.
+///
+/// Producing a chunk may block, e.g. to decrypt a shard, so async servers
+/// ask for chunks on a blocking thread.
+pub trait BodyStream: Send {
+    /// The next chunk of the body, `None` once it is done.
+    fn next_chunk(&mut self) -> Option<String>;
+}
+
+/// Wakes up the streams waiting for new events, whether they wait on a
+/// thread or in an async task.
+#[derive(Debug, Default)]
+pub struct Wakeup {
+    /// Increased on every wake up, so that waiters can tell whether they
+    /// missed one.
+    generation: Mutex<u64>,
+    changed: Condvar,
+    notify: Notify,
+}
+
+impl Wakeup {
+    pub fn wake(&self) {
+        *self.lock() += 1;
+        self.changed.notify_all();
+        self.notify.notify_waiters();
+    }
+
+    /// Read before looking for events, and passed to [`Wakeup::wait`]
+    /// after finding none, so that events that come in between aren't
+    /// missed.
+    pub fn generation(&self) -> u64 {
+        *self.lock()
+    }
+
+    /// Block until woken up after `seen`, or until `timeout` runs out.
+    /// Returns false if it ran out.
+    pub fn wait(&self, seen: u64, timeout: Duration) -> bool {
+        let (generation, _) = self
+            .changed
+            .wait_timeout_while(self.lock(), timeout, |generation| {
+                *generation == seen
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        *generation != seen
+    }
+
+    /// Like [`Wakeup::wait`], without blocking the thread.
+    pub async fn wait_async(&self, seen: u64, timeout: Duration) -> bool {
+        let mut notified = std::pin::pin!(self.notify.notified());
+        // Registered before checking, so a wake up right after isn't lost.
+        notified.as_mut().enable();
+        if self.generation() != seen {
+            return true;
+        }
+
+        tokio::time::timeout(timeout, notified).await.is_ok()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, u64> {
+        self.generation
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A server-sent event, as it is sent. `data` must be a single line, e.g.
+/// compact JSON.
+pub fn frame(id: Option<u64>, event: &str, data: &str) -> String {
+    let mut frame = String::new();
+    if let Some(id) = id {
+        frame.push_str(&format!("id: {}\n", id));
+    }
+    frame.push_str(&format!("event: {}\ndata: {}\n\n", event, data));
+
+    frame
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_wakeup() {
+        let wakeup = Arc::new(Wakeup::default());
+        let seen = wakeup.generation();
+        assert!(!wakeup.wait(seen, Duration::from_millis(10)));
+
+        let waker = Arc::clone(&wakeup);
+        let handle = thread::spawn(move || waker.wake());
+        assert!(wakeup.wait(seen, Duration::from_secs(10)));
+        handle.join().unwrap();
+        // Woken up since, so it doesn't wait at all.
+        assert!(wakeup.wait(seen, Duration::from_secs(10)));
+
+        assert_eq!(
+            frame(Some(3), "put", "{}"),
+            "id: 3\nevent: put\ndata: {}\n\n"
+        );
+    }
+}
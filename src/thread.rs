@@ -1,9 +1,113 @@
-use std::sync::{mpsc, Arc, Mutex};
+use crate::metrics::Metrics;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often [`ThreadPool::shutdown`] checks whether the workers are done.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Jobs that can wait for a worker in a pool made with [`ThreadPool::new`].
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+/// How long workers above the minimum wait for a job before they stop, in a
+/// pool made with [`ThreadPool::new`].
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What to do with new work when the queue of a [`ThreadPool`] is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait for room in the queue. Backpressure builds up in the listen
+    /// backlog of the socket.
+    Block,
+    /// Turn the work away, see [`ThreadPool::is_full`].
+    Reject,
+}
+
+impl QueuePolicy {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "block" => Ok(QueuePolicy::Block),
+            "reject" => Ok(QueuePolicy::Reject),
+            _ => Err("Queue policy must be either 'block' or 'reject'."),
+        }
+    }
+}
+
+/// Sizing of a [`ThreadPool`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Workers started right away.
+    pub workers: usize,
+    /// The pool never shrinks below this many workers.
+    pub min_workers: usize,
+    /// The pool never grows beyond this many workers.
+    pub max_workers: usize,
+    /// Jobs that can wait for a worker.
+    pub queue_capacity: usize,
+    /// How long a worker waits for a job before it stops, as long as more
+    /// than `min_workers` are running.
+    pub idle_timeout: Duration,
+}
+
+impl PoolConfig {
+    /// A pool that always has `workers` workers.
+    pub fn fixed(workers: usize) -> Self {
+        Self {
+            workers,
+            min_workers: workers,
+            max_workers: workers,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.min_workers == 0 {
+            return Err("A thread pool needs at least one worker.");
+        }
+        if self.min_workers > self.workers || self.workers > self.max_workers {
+            return Err("Worker counts must satisfy min <= workers <= max.");
+        }
+        if self.queue_capacity == 0 {
+            return Err("Queue capacity must be at least 1.");
+        }
+
+        Ok(())
+    }
+}
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    tx: mpsc::Sender<Message>,
+    workers: Mutex<Vec<Worker>>,
+    tx: mpsc::SyncSender<Message>,
+    shared: Arc<Shared>,
+    next_id: AtomicUsize,
+    max_workers: usize,
+    queue_capacity: usize,
+}
+
+/// State shared by the pool and its workers.
+struct Shared {
+    rx: Mutex<mpsc::Receiver<Message>>,
+    /// Workers that are running.
+    live: AtomicUsize,
+    /// Workers waiting for a job.
+    idle: AtomicUsize,
+    min_workers: usize,
+    idle_timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Shared {
+    /// Give up one running worker, unless that would leave fewer than
+    /// `min_workers`.
+    fn try_retire(&self) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > self.min_workers).then(|| live - 1)
+            })
+            .is_ok()
+    }
 }
 
 impl ThreadPool {
@@ -13,42 +117,170 @@ impl ThreadPool {
     ///
     /// Panics if trying to create a thread pool with 0 or less threads.
     pub fn new(size: usize) -> Self {
-        assert!(size > 0);
+        Self::with_config(PoolConfig::fixed(size), Arc::new(Metrics::new()))
+    }
+
+    /// Create a new thread pool sized by `config`. Panicking jobs, the queue
+    /// and how busy each worker is are recorded in `metrics`.
+    ///
+    /// The pool starts a new worker whenever a job comes in while every
+    /// worker is busy, up to `max_workers`, and workers above `min_workers`
+    /// stop once they've been idle for `idle_timeout`. A job that panics
+    /// doesn't take its worker down, the worker carries on with the next
+    /// job.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config` is invalid, see [`PoolConfig::validate`].
+    pub fn with_config(config: PoolConfig, metrics: Arc<Metrics>) -> Self {
+        if let Err(e) = config.validate() {
+            panic!("{}", e);
+        }
 
-        let (tx, rx) = mpsc::channel();
-        let rx = Arc::new(Mutex::new(rx));
+        let (tx, rx) = mpsc::sync_channel(config.queue_capacity);
+        metrics.set_queue_capacity(config.queue_capacity);
 
-        let mut workers = Vec::with_capacity(size);
+        let pool = Self {
+            workers: Mutex::new(Vec::with_capacity(config.max_workers)),
+            tx,
+            shared: Arc::new(Shared {
+                rx: Mutex::new(rx),
+                live: AtomicUsize::new(config.workers),
+                idle: AtomicUsize::new(0),
+                min_workers: config.min_workers,
+                idle_timeout: config.idle_timeout,
+                metrics,
+            }),
+            next_id: AtomicUsize::new(0),
+            max_workers: config.max_workers,
+            queue_capacity: config.queue_capacity,
+        };
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&rx)));
+        for _ in 0..config.workers {
+            pool.spawn_worker();
         }
 
-        Self { tx, workers }
+        pool
     }
 
+    /// Queue a job for the next free worker, waiting for room in the queue
+    /// if it is full.
     pub fn execute<T>(&self, t: T)
     where
         T: FnOnce() + Send + 'static,
     {
         let job = Box::new(t);
 
-        self.tx.send(Message::NewJob(job)).unwrap();
+        if self.shared.idle.load(Ordering::SeqCst) == 0 {
+            self.grow();
+        }
+
+        // Counted before sending, a worker may pick the job up right away.
+        self.shared.metrics.record_enqueue();
+        self.tx.send(Message::NewJob(job, Instant::now())).unwrap();
+    }
+
+    /// Whether the queue is full, so [`ThreadPool::execute`] would block.
+    ///
+    /// Only meaningful if a single thread queues jobs, otherwise another
+    /// thread may fill the queue right after this returns.
+    pub fn is_full(&self) -> bool {
+        self.shared.metrics.queue_depth() >= self.queue_capacity as u64
+    }
+
+    /// Number of running workers.
+    pub fn workers(&self) -> usize {
+        self.shared.live.load(Ordering::SeqCst)
+    }
+
+    /// Let the workers finish every job that has already been queued, then
+    /// stop them, waiting at most `timeout`.
+    ///
+    /// Returns the number of workers that were still busy when the timeout
+    /// ran out. Those are left running and die with the process.
+    pub fn shutdown(&mut self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let workers = self
+            .workers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut terminates = self.shared.live.load(Ordering::SeqCst);
+
+        loop {
+            // Terminate messages queue up behind the pending jobs, so the
+            // jobs are all handled first. If the queue is full, try again
+            // once the workers made room.
+            while terminates > 0 {
+                match self.tx.try_send(Message::Terminate) {
+                    Ok(()) => terminates -= 1,
+                    Err(_) => break,
+                }
+            }
+
+            workers.retain_mut(|worker| !worker.join_if_finished());
+            if workers.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
+        let busy = workers.len();
+        // Nothing left for Drop to wait on.
+        workers.clear();
+        busy
+    }
+
+    /// Start another worker if the pool isn't at its maximum size yet.
+    fn grow(&self) {
+        let grown = self.shared.live.fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |live| (live < self.max_workers).then(|| live + 1),
+        );
+        if let Ok(live) = grown {
+            let id = self.spawn_worker();
+            eprintln!(
+                "All workers busy, started worker {} ({} running)",
+                id,
+                live + 1
+            );
+        }
+    }
+
+    /// Spawn a worker thread. The caller has to have counted it in
+    /// `shared.live` already.
+    fn spawn_worker(&self) -> usize {
+        let mut workers =
+            self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        // Clean up after workers that stopped because they were idle.
+        workers.retain_mut(|worker| !worker.join_if_finished());
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        workers.push(Worker::new(id, Arc::clone(&self.shared)));
+        id
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
+        let workers = self
+            .workers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Already shut down.
+        if workers.is_empty() {
+            return;
+        }
+
         eprintln!("Sending terminate message to all worker threads!");
 
-        for worker in &self.workers {
-            eprintln!("Worker {} told to terminate!", worker.id);
+        for _ in 0..self.shared.live.load(Ordering::SeqCst) {
             self.tx.send(Message::Terminate).unwrap();
         }
 
         eprintln!("Shutting down all workers!");
 
-        for worker in &mut self.workers {
+        for worker in workers.iter_mut() {
             eprintln!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
@@ -61,7 +293,8 @@ impl Drop for ThreadPool {
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 enum Message {
-    NewJob(Job),
+    /// A job and when it was queued.
+    NewJob(Job, Instant),
     Terminate,
 }
 
@@ -71,19 +304,55 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, rx: Arc<Mutex<mpsc::Receiver<Message>>>) -> Self {
-        let thread = thread::spawn(move || loop {
-            let message = rx.lock().unwrap().recv().unwrap();
+    fn new(id: usize, shared: Arc<Shared>) -> Self {
+        let stats = shared.metrics.register_worker(id);
+        // Counted as idle from the start, so the pool doesn't grow while the
+        // thread is still starting up.
+        shared.idle.fetch_add(1, Ordering::SeqCst);
 
-            match message {
-                Message::NewJob(job) => {
-                    job();
-                }
-                Message::Terminate => {
-                    eprintln!("Worker {}, told to terminate!", id);
-                    break;
+        let thread = thread::spawn(move || {
+            loop {
+                let message = shared
+                    .rx
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .recv_timeout(shared.idle_timeout);
+                shared.idle.fetch_sub(1, Ordering::SeqCst);
+
+                match message {
+                    Ok(Message::NewJob(job, queued)) => {
+                        shared.metrics.record_dequeue(queued.elapsed());
+                        let started = Instant::now();
+                        // The panic message has already been printed by the
+                        // panic hook.
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            eprintln!("Worker {} recovered from a panic.", id);
+                            shared.metrics.record_panic();
+                        }
+                        stats.record_job(started.elapsed());
+                    }
+                    Ok(Message::Terminate)
+                    | Err(RecvTimeoutError::Disconnected) => {
+                        eprintln!("Worker {}, told to terminate!", id);
+                        shared.live.fetch_sub(1, Ordering::SeqCst);
+                        break;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if shared.try_retire() {
+                            eprintln!(
+                                "Worker {} idle for {}s, stopping it",
+                                id,
+                                shared.idle_timeout.as_secs()
+                            );
+                            break;
+                        }
+                    }
                 }
+
+                shared.idle.fetch_add(1, Ordering::SeqCst);
             }
+
+            shared.metrics.retire_worker(&stats);
         });
 
         Self {
@@ -91,4 +360,128 @@ impl Worker {
             thread: Some(thread),
         }
     }
+
+    /// Join the thread if it has stopped. Returns whether it has.
+    fn join_if_finished(&mut self) -> bool {
+        if self.thread.as_ref().is_some_and(|t| t.is_finished()) {
+            self.thread.take().unwrap().join().unwrap();
+        }
+        self.thread.is_none()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(workers: usize, queue_capacity: usize) -> PoolConfig {
+        PoolConfig {
+            queue_capacity,
+            ..PoolConfig::fixed(workers)
+        }
+    }
+
+    #[test]
+    fn test_shutdown_drains_queued_jobs() {
+        let mut pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 0);
+        assert_eq!(done.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn test_shutdown_gives_up_after_timeout() {
+        let mut pool = ThreadPool::new(1);
+        pool.execute(|| thread::sleep(Duration::from_millis(500)));
+
+        assert_eq!(pool.shutdown(Duration::from_millis(20)), 1);
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_jobs() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pool =
+            ThreadPool::with_config(config(1, 4), Arc::clone(&metrics));
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..3 {
+            pool.execute(|| panic!("job failed"));
+        }
+        let counter = Arc::clone(&done);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 0);
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+        assert_eq!(metrics.panics(), 3);
+    }
+
+    #[test]
+    fn test_queue_is_bounded() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pool =
+            ThreadPool::with_config(config(1, 2), Arc::clone(&metrics));
+        let (release, blocked) = mpsc::channel::<()>();
+
+        // Keeps the only worker busy until released.
+        pool.execute(move || blocked.recv().unwrap());
+        while metrics.queue_depth() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        pool.execute(|| ());
+        assert!(!pool.is_full());
+        pool.execute(|| ());
+        assert!(pool.is_full());
+        assert_eq!(metrics.queue_depth(), 2);
+
+        release.send(()).unwrap();
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 0);
+        assert_eq!(metrics.queue_depth(), 0);
+    }
+
+    #[test]
+    fn test_pool_grows_and_shrinks() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pool = ThreadPool::with_config(
+            PoolConfig {
+                workers: 1,
+                min_workers: 1,
+                max_workers: 3,
+                queue_capacity: 8,
+                idle_timeout: Duration::from_millis(100),
+            },
+            Arc::clone(&metrics),
+        );
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+
+        // Every job blocks its worker, so each new one finds no idle worker.
+        for _ in 0..5 {
+            let blocked = Arc::clone(&blocked);
+            pool.execute(move || blocked.lock().unwrap().recv().unwrap());
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(pool.workers(), 3);
+
+        for _ in 0..5 {
+            release.send(()).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(pool.workers(), 1);
+
+        let workers = &metrics.to_json()["workers"];
+        assert_eq!(workers["started"], 3);
+        assert_eq!(workers["running"].as_array().unwrap().len(), 1);
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 0);
+    }
 }
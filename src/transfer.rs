@@ -0,0 +1,415 @@
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    io::{BufRead, Write},
+    path::Path,
+};
+
+/// Plaintext file formats for `skv export` and `skv import`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON array of `{"key": ..., "value": ...}` objects. Imports also
+    /// accept a single object mapping keys to values.
+    Json,
+    /// One `{"key": ..., "value": ...}` object per line.
+    Ndjson,
+    /// A `key,value` header row followed by one row per entry.
+    Csv,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "json" => Ok(Format::Json),
+            "ndjson" | "jsonl" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            _ => Err("Format must be one of 'json', 'ndjson' or 'csv'."),
+        }
+    }
+
+    /// Guess the format from the extension of `path`.
+    pub fn from_path(path: &Path) -> Result<Self, &'static str> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => Format::from_name(extension).map_err(|_| {
+                "Can't tell the format from the file extension, use --format."
+            }),
+            None => Err("File has no extension, use --format."),
+        }
+    }
+}
+
+/// What importing does with keys that already exist in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnExisting {
+    /// Replace the value of the existing key.
+    Overwrite,
+    /// Leave the existing key alone and count the row as skipped.
+    Skip,
+}
+
+impl OnExisting {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "overwrite" => Ok(OnExisting::Overwrite),
+            "skip" => Ok(OnExisting::Skip),
+            _ => Err("Existing keys must be either 'overwrite' or 'skip'."),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OnExisting::Overwrite => "overwrite",
+            OnExisting::Skip => "skip",
+        }
+    }
+}
+
+/// A row of an import file: the key-value pair, or why the row is invalid.
+pub type Row = Result<(String, String), String>;
+
+/// Read the rows of an import file one at a time, calling `on_row` with the
+/// row number (the line for NDJSON and CSV, the element for JSON) and the
+/// row.
+///
+/// Only the current row is held in memory. Invalid rows are passed to
+/// `on_row` and reading continues, but errors that make the rest of the file
+/// unreadable, or that `on_row` returns, stop reading.
+pub fn read_rows<R, F>(
+    format: Format,
+    reader: R,
+    mut on_row: F,
+) -> Result<(), String>
+where
+    R: BufRead,
+    F: FnMut(usize, Row) -> Result<(), String>,
+{
+    match format {
+        Format::Ndjson => {
+            for (number, line) in reader.lines().enumerate() {
+                let number = number + 1;
+                let line = line.map_err(|e| {
+                    format!("Failed to read line {}: {}", number, e)
+                })?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let row = match serde_json::from_str(&line) {
+                    Ok(entry) => row_from_entry(entry),
+                    Err(e) => Err(format!("Invalid JSON: {}", e)),
+                };
+                on_row(number, row)?;
+            }
+            Ok(())
+        }
+        Format::Json => {
+            let mut deserializer =
+                serde_json::Deserializer::from_reader(reader);
+            deserializer
+                .deserialize_any(RowVisitor(&mut on_row))
+                .map_err(|e| e.to_string())?;
+            deserializer.end().map_err(|e| e.to_string())
+        }
+        Format::Csv => {
+            let mut reader =
+                csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+            let headers = reader.headers().map_err(|e| e.to_string())?;
+            let column =
+                |name| headers.iter().position(|header| header == name);
+            let (key_column, value_column) =
+                match (column("key"), column("value")) {
+                    (Some(key), Some(value)) => (key, value),
+                    _ => {
+                        return Err("CSV header must have a 'key' and a \
+                            'value' column."
+                            .to_string())
+                    }
+                };
+
+            for record in reader.records() {
+                let (number, row) = match record {
+                    Ok(record) => {
+                        let number = record
+                            .position()
+                            .map_or(0, |position| position.line() as usize);
+                        let row = match (
+                            record.get(key_column),
+                            record.get(value_column),
+                        ) {
+                            (Some(key), Some(value)) => {
+                                Ok((key.to_string(), value.to_string()))
+                            }
+                            _ => Err("Row is missing the key or value column."
+                                .to_string()),
+                        };
+                        (number, row)
+                    }
+                    Err(e) => {
+                        let number = e
+                            .position()
+                            .map_or(0, |position| position.line() as usize);
+                        if !matches!(e.kind(), csv::ErrorKind::Utf8 { .. }) {
+                            return Err(e.to_string());
+                        }
+                        (number, Err(e.to_string()))
+                    }
+                };
+                on_row(number, row)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Streams the elements of a top-level JSON array, or the members of a
+/// top-level JSON object, to the row callback.
+struct RowVisitor<'a, F>(&'a mut F);
+
+impl<'de, F> Visitor<'de> for RowVisitor<'_, F>
+where
+    F: FnMut(usize, Row) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of entries or an object of key-value pairs")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut number = 0;
+        while let Some(entry) = seq.next_element::<Value>()? {
+            number += 1;
+            (self.0)(number, row_from_entry(entry))
+                .map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut number = 0;
+        while let Some((key, value)) = map.next_entry::<String, Value>()? {
+            number += 1;
+            (self.0)(number, Ok((key, value_to_string(value))))
+                .map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// The key-value pair of a `{"key": ..., "value": ...}` object.
+fn row_from_entry(mut entry: Value) -> Row {
+    let key = match entry.get_mut("key").map(Value::take) {
+        Some(Value::String(key)) => key,
+        Some(_) => return Err("Key must be a string.".to_string()),
+        None => return Err("Entry has no key.".to_string()),
+    };
+    match entry.get_mut("value").map(Value::take) {
+        Some(value) => Ok((key, value_to_string(value))),
+        None => Err("Entry has no value.".to_string()),
+    }
+}
+
+/// Strings are stored as they are, any other JSON value as its JSON text.
+fn value_to_string(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        value => value.to_string(),
+    }
+}
+
+/// Writes exported entries to a file in one of the plaintext formats, one
+/// entry at a time.
+pub struct ExportWriter<W: Write> {
+    sink: Sink<W>,
+    written: usize,
+}
+
+enum Sink<W: Write> {
+    Json(W),
+    Ndjson(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> ExportWriter<W> {
+    pub fn new(format: Format, mut writer: W) -> Result<Self, String> {
+        let sink = match format {
+            Format::Json => {
+                writer.write_all(b"[").map_err(|e| e.to_string())?;
+                Sink::Json(writer)
+            }
+            Format::Ndjson => Sink::Ndjson(writer),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                writer
+                    .write_record(["key", "value"])
+                    .map_err(|e| e.to_string())?;
+                Sink::Csv(Box::new(writer))
+            }
+        };
+
+        Ok(Self { sink, written: 0 })
+    }
+
+    pub fn write(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let result = match &mut self.sink {
+            Sink::Json(writer) => {
+                let separator = if self.written == 0 { "\n" } else { ",\n" };
+                write!(
+                    writer,
+                    "{}  {}",
+                    separator,
+                    json!({ "key": key, "value": value })
+                )
+            }
+            Sink::Ndjson(writer) => {
+                writeln!(writer, "{}", json!({ "key": key, "value": value }))
+            }
+            Sink::Csv(writer) => writer
+                .write_record([key, value])
+                .map_err(std::io::Error::from),
+        };
+        result.map_err(|e| e.to_string())?;
+
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Finish the file and flush it.
+    ///
+    /// Returns the number of entries written.
+    pub fn finish(self) -> Result<usize, String> {
+        let result = match self.sink {
+            Sink::Json(mut writer) => {
+                let end = if self.written == 0 { "]\n" } else { "\n]\n" };
+                writer
+                    .write_all(end.as_bytes())
+                    .and_then(|_| writer.flush())
+            }
+            Sink::Ndjson(mut writer) => writer.flush(),
+            Sink::Csv(mut writer) => writer.flush(),
+        };
+        result.map_err(|e| e.to_string())?;
+
+        Ok(self.written)
+    }
+}
+
+/// Outcome of an import, as reported to the user.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+    /// Row number and reason of every row that failed.
+    pub failed: Vec<(usize, String)>,
+}
+
+/// Failed rows listed in the report, the rest are only counted.
+const REPORTED_FAILURES: usize = 20;
+
+impl ImportSummary {
+    /// Whether every row was imported or skipped.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Human readable report of the import.
+    pub fn report(&self, dry_run: bool) -> String {
+        let total = self.imported + self.skipped + self.failed.len();
+        let mut report = format!(
+            "{}{} imported, {} skipped, {} failed ({} rows)",
+            if dry_run {
+                "Dry run, nothing written: "
+            } else {
+                ""
+            },
+            self.imported,
+            self.skipped,
+            self.failed.len(),
+            total,
+        );
+
+        for (number, reason) in self.failed.iter().take(REPORTED_FAILURES) {
+            report.push_str(&format!("\n  row {}: {}", number, reason));
+        }
+        if self.failed.len() > REPORTED_FAILURES {
+            report.push_str(&format!(
+                "\n  ... and {} more",
+                self.failed.len() - REPORTED_FAILURES
+            ));
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries() -> Vec<(String, String)> {
+        vec![
+            ("users:alice".to_string(), "{\"admin\": true}".to_string()),
+            ("motd".to_string(), "multi\nline, \"quoted\"".to_string()),
+        ]
+    }
+
+    fn read_all(format: Format, input: &str) -> Vec<(usize, Row)> {
+        let mut rows = Vec::new();
+        read_rows(format, input.as_bytes(), |number, row| {
+            rows.push((number, row));
+            Ok(())
+        })
+        .unwrap();
+        rows
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        for format in [Format::Json, Format::Ndjson, Format::Csv] {
+            let mut output = Vec::new();
+            let mut writer = ExportWriter::new(format, &mut output).unwrap();
+            for (key, value) in entries() {
+                writer.write(&key, &value).unwrap();
+            }
+            assert_eq!(writer.finish().unwrap(), 2);
+
+            let rows: Vec<(String, String)> =
+                read_all(format, std::str::from_utf8(&output).unwrap())
+                    .into_iter()
+                    .map(|(_, row)| row.unwrap())
+                    .collect();
+            assert_eq!(rows, entries(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_invalid_rows_are_reported() {
+        let ndjson = "{\"key\":\"a\",\"value\":1}\nnot json\n\n\
+            {\"value\":\"b\"}\n";
+        let rows = read_all(Format::Ndjson, ndjson);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], (1, Ok(("a".to_string(), "1".to_string()))));
+        assert_eq!(rows[1].0, 2);
+        assert!(rows[1].1.is_err());
+        assert_eq!(rows[2], (4, Err("Entry has no key.".to_string())));
+
+        let json = r#"{"a": "x", "b": [1, 2]}"#;
+        assert_eq!(
+            read_all(Format::Json, json),
+            vec![
+                (1, Ok(("a".to_string(), "x".to_string()))),
+                (2, Ok(("b".to_string(), "[1,2]".to_string()))),
+            ]
+        );
+
+        let csv = "value,key\nx,a\nonly one column\n";
+        let rows = read_all(Format::Csv, csv);
+        assert_eq!(rows[0], (2, Ok(("a".to_string(), "x".to_string()))));
+        assert_eq!(rows[1].0, 3);
+        assert!(rows[1].1.is_err());
+
+        assert!(
+            read_rows(Format::Csv, "k,v\n".as_bytes(), |_, _| Ok(())).is_err()
+        );
+    }
+}
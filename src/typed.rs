@@ -0,0 +1,831 @@
+use crate::connection::json_error;
+use crate::document;
+use crate::patch::{Number, PatchError};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ops::Range;
+
+/// The type of a value. Values written by PUT are strings, the other types
+/// are created by the commands that work with them, see [`Command`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueType {
+    #[default]
+    String,
+    List,
+    Set,
+    Hash,
+    SortedSet,
+    /// A JSON document, see [`crate::document`].
+    Json,
+}
+
+impl ValueType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::List => "list",
+            ValueType::Set => "set",
+            ValueType::Hash => "hash",
+            ValueType::SortedSet => "zset",
+            ValueType::Json => "json",
+        }
+    }
+}
+
+/// A value with its type.
+///
+/// Values other than strings are stored, and encrypted, as JSON, see
+/// [`TypedValue::encode`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedValue {
+    String(String),
+    List(VecDeque<String>),
+    Set(BTreeSet<String>),
+    Hash(BTreeMap<String, String>),
+    /// Members with their scores.
+    SortedSet(BTreeMap<String, f64>),
+    Json(Value),
+}
+
+impl TypedValue {
+    /// An empty value of the type.
+    pub fn empty(value_type: ValueType) -> Self {
+        match value_type {
+            ValueType::String => TypedValue::String(String::new()),
+            ValueType::List => TypedValue::List(VecDeque::new()),
+            ValueType::Set => TypedValue::Set(BTreeSet::new()),
+            ValueType::Hash => TypedValue::Hash(BTreeMap::new()),
+            ValueType::SortedSet => TypedValue::SortedSet(BTreeMap::new()),
+            ValueType::Json => TypedValue::Json(Value::Null),
+        }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            TypedValue::String(_) => ValueType::String,
+            TypedValue::List(_) => ValueType::List,
+            TypedValue::Set(_) => ValueType::Set,
+            TypedValue::Hash(_) => ValueType::Hash,
+            TypedValue::SortedSet(_) => ValueType::SortedSet,
+            TypedValue::Json(_) => ValueType::Json,
+        }
+    }
+
+    /// Whether the value is an empty collection. Keys aren't kept with
+    /// empty collections, like they aren't kept without a value.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            TypedValue::String(_) | TypedValue::Json(_) => false,
+            TypedValue::List(list) => list.is_empty(),
+            TypedValue::Set(set) => set.is_empty(),
+            TypedValue::Hash(hash) => hash.is_empty(),
+            TypedValue::SortedSet(zset) => zset.is_empty(),
+        }
+    }
+
+    /// The plaintext the value is stored as. Strings are stored as they
+    /// are, everything else as JSON.
+    pub fn encode(&self) -> String {
+        match self {
+            TypedValue::String(string) => string.clone(),
+            TypedValue::List(list) => json!(list).to_string(),
+            TypedValue::Set(set) => json!(set).to_string(),
+            TypedValue::Hash(hash) => json!(hash).to_string(),
+            TypedValue::SortedSet(zset) => json!(zset).to_string(),
+            TypedValue::Json(document) => document.to_string(),
+        }
+    }
+
+    /// The value stored as `plaintext`, see [`TypedValue::encode`].
+    pub fn decode(
+        value_type: ValueType,
+        plaintext: String,
+    ) -> Result<Self, &'static str> {
+        let corrupt = |_| "Stored value does not match its type.";
+        Ok(match value_type {
+            ValueType::String => TypedValue::String(plaintext),
+            ValueType::List => TypedValue::List(
+                serde_json::from_str(&plaintext).map_err(corrupt)?,
+            ),
+            ValueType::Set => TypedValue::Set(
+                serde_json::from_str(&plaintext).map_err(corrupt)?,
+            ),
+            ValueType::Hash => TypedValue::Hash(
+                serde_json::from_str(&plaintext).map_err(corrupt)?,
+            ),
+            ValueType::SortedSet => TypedValue::SortedSet(
+                serde_json::from_str(&plaintext).map_err(corrupt)?,
+            ),
+            ValueType::Json => TypedValue::Json(
+                serde_json::from_str(&plaintext).map_err(corrupt)?,
+            ),
+        })
+    }
+}
+
+/// Why a command couldn't be run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// The request doesn't describe a command that can be run, e.g. it has
+    /// an unknown op.
+    Invalid(String),
+    /// The key holds a value of another type than the command works with.
+    WrongType,
+    /// The command only reads the key, e.g. `json.get`, and it doesn't
+    /// exist.
+    KeyNotFound,
+    /// Nothing is at the path in the JSON document.
+    PathNotFound(String),
+    /// The value to increment isn't a number.
+    NotANumber,
+    /// The incremented value doesn't fit into a number.
+    Overflow,
+}
+
+impl CommandError {
+    /// Status line and JSON body to answer the failed request with.
+    ///
+    /// Values of the wrong type are a 409, with the `type` of the error in
+    /// the body so that clients can tell them apart.
+    pub fn response(&self) -> (String, String) {
+        match self {
+            CommandError::Invalid(message) => {
+                ("HTTP/1.1 400 Bad Request".to_string(), json_error(message))
+            }
+            CommandError::WrongType => wrong_type_response(),
+            CommandError::KeyNotFound => (
+                "HTTP/1.1 404 NOT FOUND".to_string(),
+                json_error("Key not found in key-value store."),
+            ),
+            CommandError::PathNotFound(pointer) => (
+                "HTTP/1.1 404 NOT FOUND".to_string(),
+                json_error(&format!("Nothing found at '{}'.", pointer)),
+            ),
+            CommandError::NotANumber => PatchError::NotANumber.response(),
+            CommandError::Overflow => PatchError::Overflow.response(),
+        }
+    }
+}
+
+/// The response to an operation on a key that holds a value of another
+/// type.
+pub fn wrong_type_response() -> (String, String) {
+    (
+        "HTTP/1.1 409 Conflict".to_string(),
+        json!({
+            "error": "WRONGTYPE Operation against a key holding the wrong \
+                kind of value.",
+            "type": "wrong_type",
+        })
+        .to_string(),
+    )
+}
+
+/// An operation on a typed value, sent as `POST /<key>?op=<op>`.
+///
+/// Values to add are sent in the body as JSON: an array of strings, an
+/// object of fields and values for `hset`, or of members and scores for
+/// `zadd`. Indexes count from zero, negative ones from the end, and ranges
+/// include both ends.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// `type`: the type of the value, or `none`.
+    Type,
+    /// `lpush`, `rpush`: add values to the front or back of a list.
+    Push { front: bool, values: Vec<String> },
+    /// `lpop`, `rpop`: remove `count` values from the front or back.
+    Pop { front: bool, count: usize },
+    /// `lrange`: the values from `start` to `stop`.
+    ListRange { start: i64, stop: i64 },
+    /// `llen`
+    ListLength,
+    /// `sadd`
+    SetAdd(Vec<String>),
+    /// `srem`
+    SetRemove(Vec<String>),
+    /// `smembers`
+    Members,
+    /// `sismember`
+    IsMember(String),
+    /// `sinter`: the members that are also in every set of `keys`.
+    Intersect(Vec<String>),
+    /// `hset`
+    HashSet(BTreeMap<String, String>),
+    /// `hget`
+    HashGet(String),
+    /// `hdel`
+    HashDelete(Vec<String>),
+    /// `hgetall`
+    HashGetAll,
+    /// `zadd`: add members with scores, or change their score.
+    SortedAdd(BTreeMap<String, f64>),
+    /// `zrem`
+    SortedRemove(Vec<String>),
+    /// `zrange`: the members from rank `start` to `stop`, lowest score
+    /// first.
+    RankRange { start: i64, stop: i64 },
+    /// `zrangebyscore`: the members with a score from `min` to `max`.
+    ScoreRange { min: f64, max: f64 },
+    /// `zscore`
+    Score(String),
+    /// `json.get`: the value at `path` in a JSON document, see
+    /// [`crate::document`].
+    JsonGet(String),
+    /// `json.set`: put the JSON body at `path`.
+    JsonSet(String, Value),
+    /// `json.del`: remove the value at `path`.
+    JsonDelete(String),
+    /// `json.append`: add the JSON body to the array at `path`.
+    JsonAppend(String, Value),
+    /// `json.incr`: add `by` to the number at `path`.
+    JsonIncrement(String, Number),
+}
+
+/// What running a command does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// JSON to answer the request with.
+    pub reply: Value,
+    /// The new value of the key, if the command changes it. `Some(None)`
+    /// deletes the key.
+    pub write: Option<Option<TypedValue>>,
+}
+
+impl Command {
+    /// The command asked for by the query parameters of a request, with its
+    /// body.
+    pub fn from_query(
+        query: &HashMap<String, String>,
+        body: &str,
+    ) -> Result<Self, CommandError> {
+        let param = |name: &str| {
+            query.get(name).cloned().ok_or_else(|| {
+                CommandError::Invalid(format!("{} is required.", name))
+            })
+        };
+        let int = |name: &str, default: i64| match query.get(name) {
+            Some(text) => text.parse().map_err(|_| {
+                CommandError::Invalid(format!("{} must be an integer.", name))
+            }),
+            None => Ok(default),
+        };
+        let score = |name: &str, default: f64| match query.get(name) {
+            Some(text) => text.parse().map_err(|_| {
+                CommandError::Invalid(format!("{} must be a number.", name))
+            }),
+            None => Ok(default),
+        };
+
+        // JSON Pointers are only ever sent as the path parameter.
+        let path = query.get("path").cloned().unwrap_or_default();
+
+        let op = query.get("op").map(String::as_str).unwrap_or_default();
+        Ok(match op {
+            "type" => Command::Type,
+            "lpush" | "rpush" => Command::Push {
+                front: op == "lpush",
+                values: strings_from_body(body)?,
+            },
+            "lpop" | "rpop" => Command::Pop {
+                front: op == "lpop",
+                count: usize::try_from(int("count", 1)?).map_err(|_| {
+                    CommandError::Invalid("count must not be negative.".into())
+                })?,
+            },
+            "lrange" => Command::ListRange {
+                start: int("start", 0)?,
+                stop: int("stop", -1)?,
+            },
+            "llen" => Command::ListLength,
+            "sadd" => Command::SetAdd(strings_from_body(body)?),
+            "srem" => Command::SetRemove(strings_from_body(body)?),
+            "smembers" => Command::Members,
+            "sismember" => Command::IsMember(param("member")?),
+            "sinter" => Command::Intersect(
+                param("keys")?
+                    .split(',')
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            "hset" => {
+                Command::HashSet(serde_json::from_str(body).map_err(|_| {
+                    CommandError::Invalid(
+                        "Body must be a JSON object of strings.".to_string(),
+                    )
+                })?)
+            }
+            "hget" => Command::HashGet(param("field")?),
+            "hdel" => Command::HashDelete(strings_from_body(body)?),
+            "hgetall" => Command::HashGetAll,
+            "zadd" => {
+                let scores: BTreeMap<String, f64> = serde_json::from_str(body)
+                    .map_err(|_| {
+                        CommandError::Invalid(
+                            "Body must be a JSON object of scores.".to_string(),
+                        )
+                    })?;
+                if scores.values().any(|score| !score.is_finite()) {
+                    return Err(CommandError::Invalid(
+                        "Scores must be finite.".to_string(),
+                    ));
+                }
+                Command::SortedAdd(scores)
+            }
+            "zrem" => Command::SortedRemove(strings_from_body(body)?),
+            "zrange" => Command::RankRange {
+                start: int("start", 0)?,
+                stop: int("stop", -1)?,
+            },
+            "zrangebyscore" => Command::ScoreRange {
+                min: score("min", f64::NEG_INFINITY)?,
+                max: score("max", f64::INFINITY)?,
+            },
+            "zscore" => Command::Score(param("member")?),
+            "json.get" => Command::JsonGet(path),
+            "json.set" => Command::JsonSet(path, json_from_body(body)?),
+            "json.del" => Command::JsonDelete(path),
+            "json.append" => Command::JsonAppend(path, json_from_body(body)?),
+            "json.incr" => {
+                let by = match query.get("by") {
+                    Some(by) => Number::parse(by).ok_or_else(|| {
+                        CommandError::Invalid("by must be a number.".into())
+                    })?,
+                    None => Number::Int(1),
+                };
+                Command::JsonIncrement(path, by)
+            }
+            _ => {
+                return Err(CommandError::Invalid(format!(
+                    "Unknown op '{}'.",
+                    op
+                )))
+            }
+        })
+    }
+
+    /// The type of value the command works with. `None` if it works with
+    /// any.
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            Command::Type => None,
+            Command::Push { .. }
+            | Command::Pop { .. }
+            | Command::ListRange { .. }
+            | Command::ListLength => Some(ValueType::List),
+            Command::SetAdd(_)
+            | Command::SetRemove(_)
+            | Command::Members
+            | Command::IsMember(_)
+            | Command::Intersect(_) => Some(ValueType::Set),
+            Command::HashSet(_)
+            | Command::HashGet(_)
+            | Command::HashDelete(_)
+            | Command::HashGetAll => Some(ValueType::Hash),
+            Command::SortedAdd(_)
+            | Command::SortedRemove(_)
+            | Command::RankRange { .. }
+            | Command::ScoreRange { .. }
+            | Command::Score(_) => Some(ValueType::SortedSet),
+            Command::JsonGet(_)
+            | Command::JsonSet(..)
+            | Command::JsonDelete(_)
+            | Command::JsonAppend(..)
+            | Command::JsonIncrement(..) => Some(ValueType::Json),
+        }
+    }
+
+    /// Whether the command may change the value.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Push { .. }
+                | Command::Pop { .. }
+                | Command::SetAdd(_)
+                | Command::SetRemove(_)
+                | Command::HashSet(_)
+                | Command::HashDelete(_)
+                | Command::SortedAdd(_)
+                | Command::SortedRemove(_)
+                | Command::JsonSet(..)
+                | Command::JsonDelete(_)
+                | Command::JsonAppend(..)
+                | Command::JsonIncrement(..)
+        )
+    }
+
+    /// Run the command on the value of the key, `None` if the key doesn't
+    /// exist.
+    ///
+    /// [`Command::Intersect`] only returns the members of the key's own set
+    /// here, see [`intersect`] for the other sets.
+    pub fn apply(
+        &self,
+        value: Option<TypedValue>,
+    ) -> Result<Outcome, CommandError> {
+        let value = match (value, self.value_type()) {
+            // Unlike an empty list, an empty document isn't something the
+            // key could hold.
+            (None, Some(_)) if matches!(self, Command::JsonGet(_)) => {
+                return Err(CommandError::KeyNotFound)
+            }
+            (None, Some(value_type)) => TypedValue::empty(value_type),
+            (Some(value), Some(value_type))
+                if value.value_type() != value_type =>
+            {
+                return Err(CommandError::WrongType)
+            }
+            (Some(value), _) => value,
+            (None, None) => {
+                return Ok(read(json!({ "type": "none" })));
+            }
+        };
+
+        match (self, value) {
+            (Command::Type, value) => {
+                Ok(read(json!({ "type": value.value_type().name() })))
+            }
+            (Command::Push { front, values }, TypedValue::List(mut list)) => {
+                for value in values {
+                    match front {
+                        true => list.push_front(value.clone()),
+                        false => list.push_back(value.clone()),
+                    }
+                }
+                let reply = json!({ "length": list.len() });
+                Ok(write(reply, TypedValue::List(list)))
+            }
+            (Command::Pop { front, count }, TypedValue::List(mut list)) => {
+                let popped: Vec<String> = (0..*count)
+                    .map_while(|_| match front {
+                        true => list.pop_front(),
+                        false => list.pop_back(),
+                    })
+                    .collect();
+                match popped.is_empty() {
+                    true => Ok(read(json!({ "values": popped }))),
+                    false => Ok(write(
+                        json!({ "values": popped }),
+                        TypedValue::List(list),
+                    )),
+                }
+            }
+            (Command::ListRange { start, stop }, TypedValue::List(list)) => {
+                let range = index_range(list.len(), *start, *stop);
+                let values: Vec<&String> = list.range(range).collect();
+                Ok(read(json!({ "values": values })))
+            }
+            (Command::ListLength, TypedValue::List(list)) => {
+                Ok(read(json!({ "length": list.len() })))
+            }
+            (Command::SetAdd(members), TypedValue::Set(mut set)) => {
+                let added = members
+                    .iter()
+                    .filter(|member| set.insert(member.to_string()))
+                    .count();
+                changed(json!({ "added": added }), added, TypedValue::Set(set))
+            }
+            (Command::SetRemove(members), TypedValue::Set(mut set)) => {
+                let removed =
+                    members.iter().filter(|member| set.remove(*member)).count();
+                let reply = json!({ "removed": removed });
+                changed(reply, removed, TypedValue::Set(set))
+            }
+            (Command::Members, TypedValue::Set(set))
+            | (Command::Intersect(_), TypedValue::Set(set)) => {
+                Ok(read(json!({ "members": set })))
+            }
+            (Command::IsMember(member), TypedValue::Set(set)) => {
+                Ok(read(json!({ "member": set.contains(member) })))
+            }
+            (Command::HashSet(fields), TypedValue::Hash(mut hash)) => {
+                let mut added = 0;
+                for (field, value) in fields {
+                    if hash.insert(field.clone(), value.clone()).is_none() {
+                        added += 1;
+                    }
+                }
+                // Changed fields are a change even if none was added.
+                let reply = json!({ "added": added });
+                changed(reply, fields.len(), TypedValue::Hash(hash))
+            }
+            (Command::HashGet(field), TypedValue::Hash(hash)) => {
+                Ok(read(json!({ "value": hash.get(field) })))
+            }
+            (Command::HashDelete(fields), TypedValue::Hash(mut hash)) => {
+                let removed = fields
+                    .iter()
+                    .filter(|field| hash.remove(*field).is_some())
+                    .count();
+                let reply = json!({ "removed": removed });
+                changed(reply, removed, TypedValue::Hash(hash))
+            }
+            (Command::HashGetAll, TypedValue::Hash(hash)) => {
+                Ok(read(json!({ "fields": hash })))
+            }
+            (Command::SortedAdd(scores), TypedValue::SortedSet(mut zset)) => {
+                let mut added = 0;
+                for (member, score) in scores {
+                    if zset.insert(member.clone(), *score).is_none() {
+                        added += 1;
+                    }
+                }
+                let reply = json!({ "added": added });
+                changed(reply, scores.len(), TypedValue::SortedSet(zset))
+            }
+            (
+                Command::SortedRemove(members),
+                TypedValue::SortedSet(mut zset),
+            ) => {
+                let removed = members
+                    .iter()
+                    .filter(|member| zset.remove(*member).is_some())
+                    .count();
+                let reply = json!({ "removed": removed });
+                changed(reply, removed, TypedValue::SortedSet(zset))
+            }
+            (
+                Command::RankRange { start, stop },
+                TypedValue::SortedSet(zset),
+            ) => {
+                let ranked = by_score(&zset);
+                let range = index_range(ranked.len(), *start, *stop);
+                Ok(read(json!({ "members": scored_json(&ranked[range]) })))
+            }
+            (Command::ScoreRange { min, max }, TypedValue::SortedSet(zset)) => {
+                let ranked: Vec<(&String, f64)> = by_score(&zset)
+                    .into_iter()
+                    .filter(|(_, score)| score >= min && score <= max)
+                    .collect();
+                Ok(read(json!({ "members": scored_json(&ranked) })))
+            }
+            (Command::Score(member), TypedValue::SortedSet(zset)) => {
+                Ok(read(json!({ "score": zset.get(member) })))
+            }
+            (Command::JsonGet(path), TypedValue::Json(document)) => {
+                let value = document::get(&document, path)?;
+                Ok(read(json!({ "value": value })))
+            }
+            (Command::JsonSet(path, value), TypedValue::Json(mut document)) => {
+                document::set(&mut document, path, value.clone())?;
+                Ok(write(json!({ "path": path }), TypedValue::Json(document)))
+            }
+            (Command::JsonDelete(path), TypedValue::Json(mut document)) => {
+                let removed = document::remove(&mut document, path)?;
+                let reply = json!({ "removed": removed });
+                Ok(write(reply, TypedValue::Json(document)))
+            }
+            (
+                Command::JsonAppend(path, value),
+                TypedValue::Json(mut document),
+            ) => {
+                let length =
+                    document::append(&mut document, path, value.clone())?;
+                let reply = json!({ "length": length });
+                Ok(write(reply, TypedValue::Json(document)))
+            }
+            (
+                Command::JsonIncrement(path, by),
+                TypedValue::Json(mut document),
+            ) => {
+                let number = document::increment(&mut document, path, *by)?;
+                let reply = json!({ "value": number.to_json() });
+                Ok(write(reply, TypedValue::Json(document)))
+            }
+            _ => Err(CommandError::WrongType),
+        }
+    }
+}
+
+/// The members that are in every set, for [`Command::Intersect`]. Keys that
+/// don't exist are empty sets.
+pub fn intersect(
+    sets: Vec<Option<TypedValue>>,
+) -> Result<Outcome, CommandError> {
+    let mut intersection: Option<BTreeSet<String>> = None;
+    for value in sets {
+        let set = match value {
+            Some(TypedValue::Set(set)) => set,
+            Some(_) => return Err(CommandError::WrongType),
+            None => BTreeSet::new(),
+        };
+        intersection = Some(match intersection {
+            Some(intersection) => {
+                intersection.intersection(&set).cloned().collect()
+            }
+            None => set,
+        });
+    }
+
+    Ok(read(json!({ "members": intersection.unwrap_or_default() })))
+}
+
+fn read(reply: Value) -> Outcome {
+    Outcome { reply, write: None }
+}
+
+fn write(reply: Value, value: TypedValue) -> Outcome {
+    let value = (!value.is_empty_collection()).then_some(value);
+    Outcome {
+        reply,
+        write: Some(value),
+    }
+}
+
+/// Write the value only if `changes` isn't zero.
+fn changed(
+    reply: Value,
+    changes: usize,
+    value: TypedValue,
+) -> Result<Outcome, CommandError> {
+    match changes {
+        0 => Ok(read(reply)),
+        _ => Ok(write(reply, value)),
+    }
+}
+
+/// The members of a sorted set, lowest score first. Members with the same
+/// score are sorted by name.
+fn by_score(zset: &BTreeMap<String, f64>) -> Vec<(&String, f64)> {
+    let mut ranked: Vec<(&String, f64)> = zset
+        .iter()
+        .map(|(member, score)| (member, *score))
+        .collect();
+    ranked.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+    ranked
+}
+
+fn scored_json(members: &[(&String, f64)]) -> Value {
+    members
+        .iter()
+        .map(|(member, score)| json!({ "member": member, "score": score }))
+        .collect()
+}
+
+/// The indexes from `start` to `stop` of a collection of `len` values.
+/// Negative indexes count from the end, `-1` is the last value. Indexes
+/// past either end are clamped to it.
+fn index_range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = i64::try_from(len).unwrap_or(i64::MAX);
+    let resolve = |index: i64| match index < 0 {
+        true => len.saturating_add(index).max(0),
+        false => index.min(len),
+    };
+    let start = resolve(start);
+    let stop = resolve(stop).saturating_add(1).min(len);
+
+    start as usize..stop.max(start) as usize
+}
+
+fn json_from_body(body: &str) -> Result<Value, CommandError> {
+    serde_json::from_str(body).map_err(|e| {
+        CommandError::Invalid(format!("Body is not valid JSON: {}", e))
+    })
+}
+
+fn strings_from_body(body: &str) -> Result<Vec<String>, CommandError> {
+    serde_json::from_str(body).map_err(|_| {
+        CommandError::Invalid(
+            "Body must be a JSON array of strings.".to_string(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(op: &str, params: &[(&str, &str)], body: &str) -> Command {
+        let mut query: HashMap<String, String> = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        query.insert("op".to_string(), op.to_string());
+        Command::from_query(&query, body).unwrap()
+    }
+
+    /// Run the commands one after the other, returning the last reply and
+    /// the value they leave.
+    fn run(commands: &[Command]) -> (Value, Option<TypedValue>) {
+        let mut value = None;
+        let mut reply = Value::Null;
+        for command in commands {
+            let outcome = command.apply(value.clone()).unwrap();
+            reply = outcome.reply;
+            if let Some(written) = outcome.write {
+                value = written;
+            }
+        }
+        (reply, value)
+    }
+
+    #[test]
+    fn test_lists() {
+        let (reply, value) = run(&[
+            command("rpush", &[], r#"["b", "c"]"#),
+            command("lpush", &[], r#"["a"]"#),
+            command("lrange", &[("start", "1")], ""),
+        ]);
+        assert_eq!(reply, json!({ "values": ["b", "c"] }));
+
+        let encoded = value.unwrap().encode();
+        assert_eq!(encoded, r#"["a","b","c"]"#);
+        let value = TypedValue::decode(ValueType::List, encoded).unwrap();
+        let pop = command("rpop", &[("count", "5")], "");
+        let outcome = pop.apply(Some(value)).unwrap();
+        assert_eq!(outcome.reply, json!({ "values": ["c", "b", "a"] }));
+        // Popping the last value deletes the key.
+        assert_eq!(outcome.write, Some(None));
+
+        assert_eq!(index_range(3, 0, -1), 0..3);
+        assert_eq!(index_range(3, -2, 10), 1..3);
+        assert_eq!(index_range(3, 2, 1), 2..2);
+
+        let (reply, _) = run(&[
+            command("rpush", &[], r#"["a", "b"]"#),
+            command(
+                "lrange",
+                &[
+                    ("start", "-9223372036854775808"),
+                    ("stop", "9223372036854775807"),
+                ],
+                "",
+            ),
+        ]);
+        assert_eq!(reply, json!({ "values": ["a", "b"] }));
+        assert_eq!(index_range(3, i64::MAX, i64::MIN), 3..3);
+    }
+
+    #[test]
+    fn test_sets_hashes_and_sorted_sets() {
+        let (reply, _) = run(&[
+            command("sadd", &[], r#"["a", "b", "a"]"#),
+            command("srem", &[], r#"["b"]"#),
+            command("smembers", &[], ""),
+        ]);
+        assert_eq!(reply, json!({ "members": ["a"] }));
+        let sets = vec![
+            Some(TypedValue::Set(["a", "b"].map(String::from).into())),
+            Some(TypedValue::Set(["b", "c"].map(String::from).into())),
+        ];
+        assert_eq!(intersect(sets).unwrap().reply, json!({ "members": ["b"] }));
+
+        let (reply, _) = run(&[
+            command("hset", &[], r#"{"name": "alice", "role": "admin"}"#),
+            command("hget", &[("field", "role")], ""),
+        ]);
+        assert_eq!(reply, json!({ "value": "admin" }));
+
+        let (reply, value) = run(&[
+            command("zadd", &[], r#"{"a": 3, "b": 1, "c": 2}"#),
+            command("zrange", &[("start", "0"), ("stop", "1")], ""),
+        ]);
+        assert_eq!(
+            reply,
+            json!({ "members": [
+                { "member": "b", "score": 1.0 },
+                { "member": "c", "score": 2.0 },
+            ] })
+        );
+        let by_score = command("zrangebyscore", &[("min", "2.5")], "");
+        assert_eq!(
+            by_score.apply(value.clone()).unwrap().reply,
+            json!({ "members": [{ "member": "a", "score": 3.0 }] })
+        );
+
+        let type_of = command("type", &[], "");
+        assert_eq!(
+            type_of.apply(value.clone()).unwrap().reply,
+            json!({ "type": "zset" })
+        );
+        assert_eq!(
+            type_of.apply(None).unwrap().reply,
+            json!({ "type": "none" })
+        );
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let string = Some(TypedValue::String("hello".to_string()));
+        let push = command("lpush", &[], r#"["a"]"#);
+        assert_eq!(push.apply(string), Err(CommandError::WrongType));
+
+        let set = Some(TypedValue::Set(BTreeSet::new()));
+        let hget = command("hget", &[("field", "a")], "");
+        assert_eq!(hget.apply(set.clone()), Err(CommandError::WrongType));
+        assert_eq!(
+            intersect(vec![set, Some(TypedValue::List(VecDeque::new()))]),
+            Err(CommandError::WrongType)
+        );
+
+        let json_get = command("json.get", &[], "");
+        assert_eq!(json_get.apply(None), Err(CommandError::KeyNotFound));
+
+        let mut query = HashMap::new();
+        query.insert("op".to_string(), "lpush".to_string());
+        assert!(matches!(
+            Command::from_query(&query, "not json"),
+            Err(CommandError::Invalid(_))
+        ));
+    }
+}
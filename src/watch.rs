@@ -0,0 +1,322 @@
+use crate::stream::{self, EventStream, Wakeup};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Events kept for watches that resume, see [`Events::new`].
+pub const DEFAULT_WATCH_HISTORY: usize = 1024;
+
+/// What happened to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// The key was written, by any method.
+    Put,
+    Delete,
+    /// The key was deleted before and has now left the trash for good, see
+    /// [`crate::store::ShardedStore::trash`].
+    Expire,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Put => "put",
+            EventKind::Delete => "delete",
+            EventKind::Expire => "expire",
+        }
+    }
+}
+
+/// A change to a key, as watches see it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Numbers the events of a store one after the other, from 1.
+    pub revision: u64,
+    pub kind: EventKind,
+    pub key: String,
+    /// Store version of the write, `None` for [`EventKind::Expire`].
+    pub version: Option<u64>,
+}
+
+impl Event {
+    /// The event as a server-sent event, with the revision as its id.
+    fn to_frame(&self) -> String {
+        let mut data = json!({ "key": self.key });
+        if let Some(version) = self.version {
+            data["version"] = version.into();
+        }
+        stream::frame(Some(self.revision), self.kind.name(), &data.to_string())
+    }
+}
+
+/// Which keys a watch is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Key(String),
+    /// Every key starting with the prefix, all keys if it is empty.
+    Prefix(String),
+}
+
+impl Filter {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            Filter::Key(watched) => key == watched,
+            Filter::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// Why a watch can't start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// The events after the revision to resume from aren't kept anymore, or
+    /// the revision is from before the server restarted. `revision` is the
+    /// latest one.
+    Gone { revision: u64 },
+}
+
+impl WatchError {
+    /// Status line and JSON body to answer the failed request with.
+    pub fn response(&self) -> (String, String) {
+        match self {
+            WatchError::Gone { revision } => (
+                "HTTP/1.1 410 Gone".to_string(),
+                json!({
+                    "error": "Events after this revision aren't kept \
+                        anymore. Read the keys again and watch from the \
+                        latest revision.",
+                    "revision": revision,
+                })
+                .to_string(),
+            ),
+        }
+    }
+}
+
+/// The latest changes to the keys of a store, shared by all its shards, so
+/// that clients can watch keys instead of polling them.
+///
+/// Keys of the events are kept in memory unencrypted.
+#[derive(Debug)]
+pub struct Events {
+    log: Mutex<Log>,
+    wakeup: Arc<Wakeup>,
+}
+
+#[derive(Debug)]
+struct Log {
+    /// The latest events, oldest first.
+    events: VecDeque<Event>,
+    /// Revision of the latest event.
+    revision: u64,
+    capacity: usize,
+}
+
+impl Log {
+    /// Revision of the oldest event that is still kept, or the next one if
+    /// none are.
+    fn oldest(&self) -> u64 {
+        self.revision + 1 - self.events.len() as u64
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new(DEFAULT_WATCH_HISTORY)
+    }
+}
+
+impl Events {
+    /// Keep the last `capacity` events. Watches can resume as long as they
+    /// haven't missed more than that.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "The watch history needs room for an event.");
+
+        Self {
+            log: Mutex::new(Log {
+                events: VecDeque::with_capacity(capacity),
+                revision: 0,
+                capacity,
+            }),
+            wakeup: Arc::default(),
+        }
+    }
+
+    /// Record a change to `key` and wake up the watches.
+    pub fn publish(&self, kind: EventKind, key: &str, version: Option<u64>) {
+        let mut log = self.lock();
+        log.revision += 1;
+        let event = Event {
+            revision: log.revision,
+            kind,
+            key: key.to_string(),
+            version,
+        };
+        if log.events.len() == log.capacity {
+            log.events.pop_front();
+        }
+        log.events.push_back(event);
+        drop(log);
+
+        self.wakeup.wake();
+    }
+
+    /// Revision of the latest event.
+    pub fn revision(&self) -> u64 {
+        self.lock().revision
+    }
+
+    pub fn wakeup(&self) -> &Arc<Wakeup> {
+        &self.wakeup
+    }
+
+    /// Watch the keys `filter` matches, from the events after revision
+    /// `since` on, or only new events if it is `None`.
+    pub fn watch(
+        self: &Arc<Self>,
+        filter: Filter,
+        since: Option<u64>,
+    ) -> Result<Watch, WatchError> {
+        let log = self.lock();
+        let cursor = match since {
+            Some(since) if since > log.revision || since + 1 < log.oldest() => {
+                return Err(WatchError::Gone {
+                    revision: log.revision,
+                })
+            }
+            Some(since) => since,
+            None => log.revision,
+        };
+
+        Ok(Watch {
+            events: Arc::clone(self),
+            filter,
+            cursor,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A client watching keys, see [`Events::watch`].
+#[derive(Debug)]
+pub struct Watch {
+    events: Arc<Events>,
+    filter: Filter,
+    /// Revision of the last event the watch has seen.
+    cursor: u64,
+}
+
+impl Watch {
+    /// Revision of the last event the watch has seen.
+    pub fn revision(&self) -> u64 {
+        self.cursor
+    }
+
+    /// The events for the watched keys that came in since the last call.
+    ///
+    /// `None` if the watch fell so far behind that events it hasn't seen
+    /// were dropped. It has to resume from its revision then, which fails
+    /// with [`WatchError::Gone`].
+    pub fn next_events(&mut self) -> Option<Vec<Event>> {
+        let log = self.events.lock();
+        if self.cursor + 1 < log.oldest() {
+            return None;
+        }
+
+        let skip = (self.cursor + 1 - log.oldest()) as usize;
+        let events = log
+            .events
+            .iter()
+            .skip(skip)
+            .filter(|event| self.filter.matches(&event.key))
+            .cloned()
+            .collect();
+        self.cursor = log.revision;
+
+        Some(events)
+    }
+}
+
+impl EventStream for Watch {
+    fn poll(&mut self) -> Option<String> {
+        let events = self.next_events()?;
+        Some(events.iter().map(Event::to_frame).collect())
+    }
+
+    fn wakeup(&self) -> Arc<Wakeup> {
+        Arc::clone(&self.events.wakeup)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keys(events: &[Event]) -> Vec<(&str, EventKind)> {
+        events
+            .iter()
+            .map(|event| (event.key.as_str(), event.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_watch_and_resume() {
+        let events = Arc::new(Events::new(4));
+        let mut all =
+            events.watch(Filter::Prefix(String::new()), None).unwrap();
+        let mut alice = events
+            .watch(Filter::Key("users:alice".to_string()), None)
+            .unwrap();
+
+        events.publish(EventKind::Put, "users:alice", Some(1));
+        events.publish(EventKind::Put, "users:bob", Some(2));
+        events.publish(EventKind::Delete, "users:alice", Some(3));
+        assert_eq!(
+            keys(&alice.next_events().unwrap()),
+            [
+                ("users:alice", EventKind::Put),
+                ("users:alice", EventKind::Delete)
+            ]
+        );
+        assert_eq!(alice.revision(), 3);
+        assert_eq!(all.next_events().unwrap().len(), 3);
+        assert_eq!(all.next_events().unwrap(), []);
+
+        // Resuming replays what came after the revision.
+        let mut resumed = events
+            .watch(Filter::Prefix("users:".to_string()), Some(1))
+            .unwrap();
+        let replayed = resumed.next_events().unwrap();
+        assert_eq!(replayed[0].revision, 2);
+        assert_eq!(replayed.len(), 2);
+
+        events.publish(EventKind::Expire, "users:alice", None);
+        events.publish(EventKind::Put, "users:carol", Some(4));
+        // Revision 1 isn't kept anymore.
+        assert_eq!(
+            events
+                .watch(Filter::Key("users:alice".to_string()), Some(0))
+                .unwrap_err(),
+            WatchError::Gone { revision: 5 }
+        );
+        assert!(events.watch(Filter::Key("a".to_string()), Some(1)).is_ok());
+        assert!(events.watch(Filter::Key("a".to_string()), Some(9)).is_err());
+        assert_eq!(
+            keys(&alice.next_events().unwrap()),
+            [("users:alice", EventKind::Expire)]
+        );
+
+        // Watches that fall too far behind end.
+        for _ in 0..4 {
+            events.publish(EventKind::Put, "users:bob", Some(5));
+        }
+        assert!(resumed.next_events().is_none());
+    }
+}
@@ -0,0 +1,110 @@
+// Every integration test uses only some of these helpers.
+#![allow(dead_code)]
+
+use skv::client::{self, ClientResponse};
+use std::{
+    fs,
+    net::TcpListener,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for a server to come up.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Values of `--server-mode`, every test of the API runs against each.
+pub const SERVER_MODES: [&str; 2] = ["threads", "async"];
+
+/// An skv server running in a child process, killed when dropped.
+pub struct TestServer {
+    pub port: String,
+    pub key: String,
+    key_file: PathBuf,
+    process: Child,
+}
+
+impl TestServer {
+    /// Start a server on a free port and wait until it accepts requests.
+    pub fn start(name: &str) -> Self {
+        Self::start_with_args(name, &[])
+    }
+
+    /// Like [`TestServer::start`], in the given `--server-mode`.
+    pub fn start_in_mode(name: &str, mode: &str) -> Self {
+        Self::start_with_args(
+            &format!("{}-{}", name, mode),
+            &["--server-mode", mode],
+        )
+    }
+
+    /// Like [`TestServer::start`], with extra command line arguments.
+    pub fn start_with_args(name: &str, args: &[&str]) -> Self {
+        let port = free_port();
+        let key_file = std::env::temp_dir().join(format!(
+            "skv-test-{}-{}.key",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&key_file);
+
+        let process = Command::new(env!("CARGO_BIN_EXE_skv"))
+            .args(["-p", &port, "-k"])
+            .arg(&key_file)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start skv server.");
+
+        // The key file is written once the server is listening.
+        let started = Instant::now();
+        let key = loop {
+            match fs::read_to_string(&key_file) {
+                Ok(key) if key.trim().len() == 64 => break key.trim().into(),
+                _ if started.elapsed() > STARTUP_TIMEOUT => {
+                    panic!("skv server did not start in time.")
+                }
+                _ => thread::sleep(Duration::from_millis(20)),
+            }
+        };
+
+        Self {
+            port,
+            key,
+            key_file,
+            process,
+        }
+    }
+
+    /// Send a request, authenticated with the server's key.
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> ClientResponse {
+        client::send_request(
+            &self.port,
+            method,
+            path,
+            &[("key", self.key.as_str())],
+            body,
+        )
+        .expect("Failed to send request.")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_file(&self.key_file);
+    }
+}
+
+/// A port nothing is listening on right now.
+fn free_port() -> String {
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    listener.local_addr().unwrap().port().to_string()
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use common::{TestServer, SERVER_MODES};
+use skv::client;
+use std::{
+    net::TcpStream,
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+#[test]
+fn test_concurrent_clients() {
+    for mode in SERVER_MODES {
+        let server = Arc::new(TestServer::start_in_mode("concurrent", mode));
+
+        let clients: Vec<_> = (0..8)
+            .map(|client| {
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    for request in 0..5 {
+                        let key = format!("client{}:{}", client, request);
+                        let response = server.request("PUT", &key, "value");
+                        assert_eq!(response.status, 200, "{}", mode);
+                        let response = server.request("GET", &key, "");
+                        assert_eq!(response.body, "value", "{}", mode);
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_async_server_is_not_starved_by_idle_connections() {
+    let server = TestServer::start_in_mode("idle", "async");
+    let address = format!("localhost:{}", server.port);
+
+    // Far more connections that never send a request than there are
+    // threads, each would hold a worker in the thread pool.
+    let idle: Vec<TcpStream> = (0..500)
+        .map(|_| TcpStream::connect(&address).unwrap())
+        .collect();
+    // Give the server time to accept all of them.
+    thread::sleep(Duration::from_millis(200));
+
+    let response = server.request("PUT", "greeting", "hello");
+    assert_eq!(response.status, 200);
+    let response = server.request("GET", "admin/metrics", "");
+    let metrics: serde_json::Value =
+        serde_json::from_str(&response.body).unwrap();
+    assert!(metrics["connections"]["open"].as_u64().unwrap() > 500);
+
+    drop(idle);
+}
+
+#[test]
+fn test_event_streams_do_not_hold_workers() {
+    for mode in SERVER_MODES {
+        let server = Arc::new(TestServer::start_with_args(
+            &format!("streams-{}", mode),
+            &["--server-mode", mode, "--workers", "2"],
+        ));
+
+        // If streams held the workers, the client would wait forever, either
+        // to open a stream or for the GET.
+        let (sender, receiver) = mpsc::channel();
+        let client = Arc::clone(&server);
+        thread::spawn(move || {
+            // Twice as many streams as workers, each lasts until it is
+            // dropped.
+            let streams: Vec<_> = [
+                "admin/watch?prefix=jobs:",
+                "admin/subscribe?channel=jobs",
+                "admin/watch?key=jobs:1",
+                "admin/subscribe?pattern=jobs.*",
+            ]
+            .iter()
+            .map(|path| {
+                client::send_request_streaming(
+                    &client.port,
+                    "GET",
+                    path,
+                    &[("key", client.key.as_str())],
+                    "",
+                )
+                .unwrap()
+            })
+            .collect();
+
+            client.request("PUT", "jobs:1", "a");
+            let response = client.request("GET", "jobs:1", "");
+            let statuses: Vec<_> =
+                streams.iter().map(|stream| stream.status).collect();
+            let _ = sender.send((statuses, response));
+        });
+        let (streams, response) = receiver
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap_or_else(|_| panic!("Streams starved requests in {}", mode));
+        assert_eq!(streams, [200; 4], "{}", mode);
+        assert_eq!(response.body, "a", "{}", mode);
+    }
+}
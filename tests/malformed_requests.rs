@@ -0,0 +1,62 @@
+mod common;
+
+use common::TestServer;
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+};
+
+/// Send raw bytes to the server and return the raw response.
+fn send_raw(server: &TestServer, request: &[u8]) -> String {
+    let mut stream =
+        TcpStream::connect(format!("localhost:{}", server.port)).unwrap();
+    stream.write_all(request).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[test]
+fn test_server_survives_garbage_threads() {
+    server_survives_garbage("threads");
+}
+
+#[test]
+fn test_server_survives_garbage_async() {
+    server_survives_garbage("async");
+}
+
+fn server_survives_garbage(mode: &str) {
+    let server = TestServer::start_in_mode("garbage", mode);
+
+    let content_length =
+        format!("PUT /a HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+    let garbage: [&[u8]; 6] = [
+        b"this is not http\r\n\r\n",
+        b"\x00\xff\xfe\x01 binary nonsense",
+        b"GET\r\n\r\n",
+        b"",
+        // An empty path, then a valid looking request line.
+        b"A  B GET / HTTP/1.1\r\n\r\n",
+        content_length.as_bytes(),
+    ];
+    for request in garbage {
+        let response = send_raw(&server, request);
+        assert!(
+            response.starts_with("HTTP/1.1 400"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    // A connection that is dropped without sending anything.
+    drop(TcpStream::connect(format!("localhost:{}", server.port)).unwrap());
+
+    let response = server.request("PUT", "greeting", "hello");
+    assert_eq!(response.status, 200);
+    let response = server.request("GET", "greeting", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "hello");
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use common::TestServer;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+#[test]
+fn test_full_queue_rejects_with_retry_after() {
+    let server = TestServer::start_with_args(
+        "queue",
+        &["--queue-depth", "1", "--queue-policy", "reject"],
+    );
+    let address = format!("localhost:{}", server.port);
+
+    // Connections that never send a request keep a worker busy waiting for
+    // it. One for each of the four workers and one to fill the queue, spaced
+    // out so the workers have picked up their connection before the next
+    // one comes in.
+    let idle: Vec<TcpStream> = (0..5)
+        .map(|_| {
+            let stream = TcpStream::connect(&address).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            stream
+        })
+        .collect();
+
+    let mut stream = TcpStream::connect(&address).unwrap();
+    stream
+        .write_all(b"GET /ls HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 503"),
+        "unexpected response: {}",
+        response
+    );
+    assert!(response.contains("Retry-After: 1\r\n"));
+
+    // Once the idle connections go away, requests are handled again.
+    drop(idle);
+    thread::sleep(Duration::from_millis(300));
+    let response = server.request("GET", "admin/metrics", "");
+    assert_eq!(response.status, 200);
+    let metrics: serde_json::Value =
+        serde_json::from_str(&response.body).unwrap();
+    assert_eq!(metrics["rejected"], 1);
+    assert_eq!(metrics["queue"]["capacity"], 1);
+}
@@ -0,0 +1,433 @@
+mod common;
+
+use common::{TestServer, SERVER_MODES};
+use skv::client;
+use std::io::BufRead;
+use std::time::Duration;
+
+/// Run `test` against a server of its own in each server mode.
+fn in_each_mode(name: &str, test: impl Fn(&TestServer)) {
+    for mode in SERVER_MODES {
+        test(&TestServer::start_in_mode(name, mode));
+    }
+}
+
+/// Read the next server-sent event: its id, type and data.
+fn next_event(reader: &mut impl BufRead) -> (String, String, String) {
+    let (mut id, mut event, mut data) =
+        (String::new(), String::new(), String::new());
+    loop {
+        let mut line = String::new();
+        assert!(reader.read_line(&mut line).unwrap() > 0, "Stream ended.");
+        let line = line.trim_end();
+        if line.is_empty() && !event.is_empty() {
+            return (id, event, data);
+        }
+        match line.split_once(": ") {
+            Some(("id", value)) => id = value.to_string(),
+            Some(("event", value)) => event = value.to_string(),
+            Some(("data", value)) => data = value.to_string(),
+            _ => (),
+        }
+    }
+}
+
+#[test]
+fn test_keys() {
+    in_each_mode("routes-keys", |server| {
+        // PUT /<key>, which needs the encryption key like every other write.
+        let response =
+            client::send_request(&server.port, "PUT", "users:alice", &[], "a")
+                .unwrap();
+        assert_eq!(response.status, 401);
+        let response = server.request("GET", "ls", "");
+        assert_eq!(response.body, "");
+        let response = server.request("PUT", "users:alice", "a");
+        assert_eq!(response.status, 200);
+
+        // Values naming files on the server are stored as they are.
+        let file = std::env::temp_dir()
+            .join(format!("skv-test-put-path-{}", std::process::id()));
+        std::fs::write(&file, "secret").unwrap();
+        let path = file.to_str().unwrap();
+        server.request("PUT", "users:path", path);
+        let response = server.request("GET", "users:path", "");
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(response.body, path);
+        server.request("DELETE", "users:path", "");
+
+        // GET /<key> and GET /ls.
+        let response = server.request("GET", "users:alice", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, "a");
+        let response = server.request("GET", "ls", "");
+        assert_eq!(response.body, "users:alice");
+        let response =
+            client::send_request(&server.port, "GET", "users:alice", &[], "")
+                .unwrap();
+        assert_eq!(response.status, 401);
+
+        // DELETE /<key> deletes, the key ends up in the trash.
+        let response = server.request("DELETE", "users:alice", "");
+        assert_eq!(response.status, 200);
+        let response = server.request("GET", "users:alice", "");
+        assert_eq!(response.status, 404);
+        let response = server.request("GET", "admin/trash", "");
+        assert!(response.body.contains("users:alice"), "{}", response.body);
+        let response = server.request("DELETE", "users:alice", "");
+        assert_eq!(response.status, 404);
+    });
+}
+
+#[test]
+fn test_admin_endpoints() {
+    in_each_mode("routes-admin-endpoints", |server| {
+        // GET /admin/<endpoint>.
+        let response = server.request("GET", "admin/metrics", "");
+        assert_eq!(response.status, 200);
+        let response = server.request("GET", "admin/nothing", "");
+        assert_eq!(response.status, 404);
+
+        // Methods nothing is routed for.
+        let response = server.request("PUT", "admin/metrics", "");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET"));
+        let response = server.request("POST", "admin/trash/users:alice", "");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("PUT, DELETE"));
+        let response = server.request("BREW", "users:alice", "");
+        assert_eq!(response.status, 400);
+
+        // Exports are streamed, and end with the connection.
+        for i in 0..10 {
+            server.request("PUT", &format!("export:{}", i), "value");
+        }
+        let response = server.request("GET", "admin/export?prefix=export:", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("application/x-ndjson")
+        );
+        assert_eq!(response.header("Content-Length"), None);
+        assert_eq!(response.body.lines().count(), 10);
+        assert!(response.body.lines().all(|line| line.contains("\"value\"")));
+    });
+}
+
+#[test]
+fn test_head_and_options() {
+    in_each_mode("routes-head-and-options", |server| {
+        // HEAD /<key> checks for a key without sending its value.
+        server.request("PUT", "users:bob", "value");
+        let response = server.request("HEAD", "users:bob", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, "");
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        let etag = server.request("GET", "users:bob", "");
+        assert_eq!(response.header("ETag"), etag.header("ETag"));
+        assert!(response.header("ETag").is_some());
+        // A missing key is a 404 either way.
+        let response = server.request("HEAD", "users:carol", "");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, "");
+        let response = server.request("GET", "users:carol", "");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, "Key not found in key-value store.");
+
+        // OPTIONS lists the methods of a path, without the encryption key.
+        let response = client::send_request(
+            &server.port,
+            "OPTIONS",
+            "admin/trash",
+            &[],
+            "",
+        )
+        .unwrap();
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("Allow"), Some("GET, DELETE, OPTIONS"));
+    });
+}
+
+#[test]
+fn test_patch() {
+    in_each_mode("routes-patch", |server| {
+        // PATCH /<key> changes part of a value.
+        server.request("PUT", "users:bob", "value");
+        let response = server.request("PATCH", "users:bob?op=append", "s!");
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("\"length\":7"), "{}", response.body);
+        assert!(response.header("Skv-Version").is_some());
+        server.request("PATCH", "users:bob?op=prepend", "my ");
+        server.request("PATCH", "users:bob?op=range&offset=3", "V");
+        let response = server.request("GET", "users:bob", "");
+        assert_eq!(response.body, "my Values!");
+        let response = server.request("PATCH", "users:carol?op=append", "x");
+        assert_eq!(response.status, 404);
+        let response = server.request("PATCH", "users:bob?op=shuffle", "x");
+        assert_eq!(response.status, 400);
+
+        // Counters count from zero and can be kept within bounds.
+        let response = server.request("PATCH", "hits?op=incr&by=5", "");
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("\"value\":5"), "{}", response.body);
+        let response = server.request("PATCH", "hits?op=decr&by=9&min=0", "");
+        assert!(response.body.contains("\"value\":0"), "{}", response.body);
+        server.request("PATCH", "hits?op=incr&by=1.5", "");
+        assert_eq!(server.request("GET", "hits", "").body, "1.5");
+        let response = server.request("PATCH", "users:bob?op=incr", "");
+        assert_eq!(response.status, 409);
+        assert!(response.body.contains("not_a_number"), "{}", response.body);
+    });
+}
+
+#[test]
+fn test_commands() {
+    in_each_mode("routes-commands", |server| {
+        // POST /<key> runs commands on lists, sets, hashes and sorted sets.
+        let response =
+            server.request("POST", "queue?op=rpush", r#"["a", "b"]"#);
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("\"length\":2"), "{}", response.body);
+        let response = server.request("POST", "queue?op=lpop", "");
+        assert!(response.body.contains("[\"a\"]"), "{}", response.body);
+        let response = server.request("POST", "queue?op=type", "");
+        assert_eq!(response.body, r#"{"type":"list"}"#);
+        let response = server.request("POST", "queue?op=sadd", r#"["a"]"#);
+        assert_eq!(response.status, 409);
+        assert!(response.body.contains("wrong_type"), "{}", response.body);
+        let response = server.request("PATCH", "queue?op=append", "x");
+        assert_eq!(response.status, 409);
+        let response = client::send_request(
+            &server.port,
+            "POST",
+            "queue?op=llen",
+            &[],
+            "",
+        )
+        .unwrap();
+        assert_eq!(response.status, 401);
+    });
+}
+
+#[test]
+fn test_documents() {
+    in_each_mode("routes-documents", |server| {
+        // Values PUT as JSON are documents whose parts can be read and changed.
+        let put_json = |body: &str| {
+            client::send_request(
+                &server.port,
+                "PUT",
+                "config",
+                &[
+                    ("key", server.key.as_str()),
+                    ("Content-Type", "application/json"),
+                ],
+                body,
+            )
+            .unwrap()
+        };
+        assert_eq!(put_json("{not json").status, 400);
+        assert_eq!(put_json(r#"{"retries": 1, "hosts": []}"#).status, 200);
+        server.request("POST", "config?op=json.incr&path=/retries&by=2", "");
+        server.request("POST", "config?op=json.append&path=/hosts", r#""a""#);
+        let response =
+            server.request("POST", "config?op=json.get&path=/hosts/0", "");
+        assert_eq!(response.body, r#"{"value":"a"}"#);
+        let response = server.request("GET", "config", "");
+        assert_eq!(response.body, r#"{"hosts":["a"],"retries":3}"#);
+        let response = server.request("POST", "config?op=json.get&path=/x", "");
+        assert_eq!(response.status, 404);
+        server.request("POST", "queue?op=rpush", r#"["a"]"#);
+        let response = server.request("POST", "queue?op=json.get", "");
+        assert_eq!(response.status, 409);
+        let response = server.request("POST", "missing?op=json.get", "");
+        assert_eq!(response.status, 404);
+
+        // Secondary indexes find documents by a field.
+        let response = server.request(
+            "PUT",
+            "admin/indexes?namespace=&field=/retries",
+            "",
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, r#"{"keys":1}"#);
+        let response = server.request(
+            "GET",
+            "admin/indexes/query?namespace=&field=/retries&min=2",
+            "",
+        );
+        assert_eq!(response.body, r#"{"keys":["config"]}"#);
+    });
+}
+
+#[test]
+fn test_watch() {
+    in_each_mode("routes-watch", |server| {
+        // GET /admin/watch streams changes to keys as server-sent events.
+        let watch = |query: &str| {
+            client::send_request_streaming(
+                &server.port,
+                "GET",
+                &format!("admin/watch?{}", query),
+                &[("key", server.key.as_str())],
+                "",
+            )
+            .unwrap()
+        };
+        let mut events = watch("prefix=jobs:");
+        assert_eq!(events.status, 200);
+        assert!(events
+            .headers
+            .contains(&("Content-Type".into(), "text/event-stream".into())));
+        events
+            .body
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        server.request("PUT", "jobs:1", "a");
+        server.request("PUT", "other", "b");
+        server.request("DELETE", "jobs:1", "");
+        let (revision, event, data) = next_event(&mut events.body);
+        assert_eq!(event, "put");
+        assert!(data.contains("\"key\":\"jobs:1\""), "{}", data);
+        assert_eq!(next_event(&mut events.body).1, "delete");
+
+        // Watches resume after the last revision they saw.
+        let mut resumed = watch(&format!("key=jobs:1&since={}", revision));
+        assert_eq!(next_event(&mut resumed.body).1, "delete");
+        assert_eq!(watch("key=jobs:1&since=999999").status, 410);
+        assert_eq!(watch("since=1").status, 400);
+        let response = client::send_request(
+            &server.port,
+            "GET",
+            "admin/watch?key=a",
+            &[],
+            "",
+        )
+        .unwrap();
+        assert_eq!(response.status, 401);
+
+        // Outside of admin/, watch is a key like any other.
+        server.request("PUT", "watch", "value");
+        assert_eq!(server.request("GET", "watch", "").body, "value");
+    });
+}
+
+#[test]
+fn test_publish_and_subscribe() {
+    in_each_mode("routes-publish-and-subscribe", |server| {
+        // GET /admin/subscribe streams the messages POSTed to
+        // /admin/publish.
+        let mut messages = client::send_request_streaming(
+            &server.port,
+            "GET",
+            "admin/subscribe?channel=alerts&pattern=orders.*",
+            &[("key", server.key.as_str())],
+            "",
+        )
+        .unwrap();
+        assert_eq!(messages.status, 200);
+        messages
+            .body
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let response =
+            server.request("POST", "admin/publish?channel=orders.eu", "new");
+        assert_eq!(response.body, r#"{"receivers":1}"#);
+        let (_, event, data) = next_event(&mut messages.body);
+        assert_eq!(event, "message");
+        assert_eq!(data, r#"{"channel":"orders.eu","message":"new"}"#);
+        let response =
+            server.request("POST", "admin/publish?channel=other", "x");
+        assert_eq!(response.body, r#"{"receivers":0}"#);
+        let response = server.request("GET", "admin/metrics", "");
+        assert!(
+            response.body.contains(r#""patterns":{"orders.*":1}"#),
+            "{}",
+            response.body
+        );
+        assert_eq!(server.request("GET", "admin/subscribe", "").status, 400);
+        let patterns = ["p*"; 9].join(",");
+        let path = format!("admin/subscribe?pattern={}", patterns);
+        assert_eq!(server.request("GET", &path, "").status, 400);
+        let path = format!("admin/publish?channel={}", "c".repeat(257));
+        assert_eq!(server.request("POST", &path, "x").status, 400);
+        assert_eq!(server.request("POST", "admin/publish", "x").status, 400);
+        let response = server.request("PUT", "admin/publish", "x");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("POST"));
+        let response = client::send_request(
+            &server.port,
+            "POST",
+            "admin/publish?channel=alerts",
+            &[],
+            "x",
+        )
+        .unwrap();
+        assert_eq!(response.status, 401);
+    });
+}
+
+#[test]
+fn test_cors() {
+    for mode in SERVER_MODES {
+        cors(mode);
+    }
+}
+
+fn cors(mode: &str) {
+    let server = TestServer::start_with_args(
+        &format!("cors-{}", mode),
+        &[
+            "--server-mode",
+            mode,
+            "--cors-origin",
+            "http://dash",
+            "--cors-header",
+            "key",
+        ],
+    );
+    let send = |method: &str, path: &str, headers: &[(&str, &str)]| {
+        client::send_request(&server.port, method, path, headers, "").unwrap()
+    };
+
+    let response = send(
+        "OPTIONS",
+        "greeting",
+        &[
+            ("Origin", "http://dash"),
+            ("Access-Control-Request-Method", "DELETE"),
+        ],
+    );
+    assert_eq!(response.status, 204);
+    assert_eq!(
+        response.header("Access-Control-Allow-Origin"),
+        Some("http://dash")
+    );
+    assert_eq!(
+        response.header("Access-Control-Allow-Methods"),
+        Some("OPTIONS, GET, HEAD, PUT, PATCH, POST, DELETE")
+    );
+    assert_eq!(response.header("Access-Control-Allow-Headers"), Some("key"));
+
+    // Responses to the request itself carry the origin too, errors included.
+    let response = send("GET", "greeting", &[("Origin", "http://dash")]);
+    assert_eq!(response.status, 401);
+    assert_eq!(
+        response.header("Access-Control-Allow-Origin"),
+        Some("http://dash")
+    );
+
+    let response = send("GET", "greeting", &[("Origin", "http://evil")]);
+    assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+    let response = send(
+        "OPTIONS",
+        "greeting",
+        &[
+            ("Origin", "http://evil"),
+            ("Access-Control-Request-Method", "DELETE"),
+        ],
+    );
+    assert_eq!(response.header("Access-Control-Allow-Methods"), None);
+}